@@ -1,13 +1,24 @@
 use std::collections::HashSet;
-use std::fs::{self, File};
-use std::io::Write;
+use std::fs;
 use std::path::{Path, PathBuf};
 use std::process::exit;
+use chrono::Local;
 use ndarray::{Array1, Array2, Array3};
+use serde::Serialize;
+use crate::apbs_param::{PBASet, PBESet};
 use crate::atom_property::AtomProperty;
+use crate::error::{MmpbsaError, Result};
+use crate::membrane::MembraneSet;
+use crate::mol_scripts::write_view_scripts;
 use crate::parse_tpr::Residue;
+use crate::pdb::{EnergyUnit, write_bf_pdb};
+use crate::plot::write_plots;
+use crate::report::write_report;
+use crate::results_json::{write_results_json, write_results_jsonl};
+use crate::results_parquet::write_results_parquet;
 use crate::settings::Settings;
 use crate::utils::{get_input, get_input_selection, range2list, get_outfile};
+use crate::xvg::{XVG_TERMS, write_traj_xvg, write_res_avg_xvg, write_res_traj_xvg};
 
 pub struct Results {
     pub aps: AtomProperty,
@@ -20,12 +31,16 @@ pub struct Results {
     pub mm: Array1<f64>,
     pub pb: Array1<f64>,
     pub sa: Array1<f64>,
+    pub cav: Array1<f64>,
+    pub disp: Array1<f64>,
     pub elec: Array1<f64>,
     pub vdw: Array1<f64>,
     pub dh_res: Array2<f64>,
     pub mm_res: Array2<f64>,
     pub pb_res: Array2<f64>,
     pub sa_res: Array2<f64>,
+    pub cav_res: Array2<f64>,
+    pub disp_res: Array2<f64>,
     pub elec_res: Array2<f64>,
     pub vdw_res: Array2<f64>,
 }
@@ -35,11 +50,14 @@ impl Results {
                ndx_rec: &Vec<usize>, ndx_lig: &Vec<usize>,
                times: &Array1<f64>, coord: Array3<f64>, 
                elec_res: &Array2<f64>, vdw_res: &Array2<f64>, 
-               pb_res: &Array2<f64>, sa_res: &Array2<f64>) -> Results {
+               pb_res: &Array2<f64>, sa_res: &Array2<f64>,
+               cav_res: &Array2<f64>, disp_res: &Array2<f64>) -> Results {
         let mut dh: Array1<f64> = Array1::zeros(times.len());
         let mut mm: Array1<f64> = Array1::zeros(times.len());
         let mut pb: Array1<f64> = Array1::zeros(times.len());
         let mut sa: Array1<f64> = Array1::zeros(times.len());
+        let mut cav: Array1<f64> = Array1::zeros(times.len());
+        let mut disp: Array1<f64> = Array1::zeros(times.len());
         let mut elec: Array1<f64> = Array1::zeros(times.len());
         let mut vdw: Array1<f64> = Array1::zeros(times.len());
         for idx in 0..times.len() {
@@ -48,11 +66,13 @@ impl Results {
             mm[idx] = elec[idx] + vdw[idx];
             pb[idx] = pb_res.row(idx).iter().sum();
             sa[idx] = sa_res.row(idx).iter().sum();
-            dh[idx] = mm[idx] + pb[idx] + sa[idx];
+            cav[idx] = cav_res.row(idx).iter().sum();
+            disp[idx] = disp_res.row(idx).iter().sum();
+            dh[idx] = mm[idx] + pb[idx] + sa[idx] + cav[idx] + disp[idx];
         }
 
         let mm_res: Array2<f64> = elec_res + vdw_res;
-        let dh_res: Array2<f64> = &mm_res + pb_res + sa_res;
+        let dh_res: Array2<f64> = &mm_res + pb_res + sa_res + cav_res + disp_res;
 
         Results {
             aps: aps.to_owned(),
@@ -65,19 +85,34 @@ impl Results {
             mm,
             pb,
             sa,
+            cav,
+            disp,
             elec,
             vdw,
             dh_res,
             mm_res,
             pb_res: pb_res.to_owned(),
             sa_res: sa_res.to_owned(),
+            cav_res: cav_res.to_owned(),
+            disp_res: disp_res.to_owned(),
             elec_res: elec_res.to_owned(),
             vdw_res: vdw_res.to_owned(),
         }
     }
 
+    // whether each residue has ligand atoms
+    pub fn ligand_residues(&self) -> Vec<bool> {
+        let mut is_lig = vec![false; self.residues.len()];
+        if self.ndx_lig[0] != self.ndx_rec[0] {
+            for &i in &self.ndx_lig {
+                is_lig[self.aps.atm_resid[i]] = true;
+            }
+        }
+        is_lig
+    }
+
     // totally time average and ts
-    fn summary(&self, temperature: f64, settings: &Settings) -> (f64, f64, f64, f64, f64, f64, f64, f64, f64) {
+    pub fn summary(&self, temperature: f64, settings: &Settings) -> (f64, f64, f64, f64, f64, f64, f64, f64, f64, f64, f64) {
         let rt2kj = 8.314462618 * temperature / 1e3;
 
         let dh_avg = self.dh.iter().sum::<f64>() / self.dh.len() as f64;
@@ -86,6 +121,8 @@ impl Results {
         let vdw_avg = self.vdw.iter().sum::<f64>() / self.vdw.len() as f64;
         let pb_avg = self.pb.iter().sum::<f64>() / self.pb.len() as f64;
         let sa_avg = self.sa.iter().sum::<f64>() / self.sa.len() as f64;
+        let cav_avg = self.cav.iter().sum::<f64>() / self.cav.len() as f64;
+        let disp_avg = self.disp.iter().sum::<f64>() / self.disp.len() as f64;
 
         let tds = match settings.use_ts {
             true => {
@@ -95,11 +132,53 @@ impl Results {
         };
         let dg = dh_avg - tds;
         let ki = f64::exp(dg / rt2kj) * 1e9;    // nM
-        return (dh_avg, mm_avg, pb_avg, sa_avg, elec_avg, vdw_avg, tds, dg, ki);
+        return (dh_avg, mm_avg, pb_avg, sa_avg, cav_avg, disp_avg, elec_avg, vdw_avg, tds, dg, ki);
     }
 }
 
-pub fn analyze_controller(results: &Results, temperature: f64, sys_name: &String, wd: &Path, total_at_num: usize, settings: &Settings) {
+// inputs and parameters of the calculation, written with the results
+#[derive(Serialize, Clone)]
+pub struct RunInfo {
+    pub topology: String,
+    pub trajectory: String,
+    pub index: String,
+    pub receptor: String,
+    pub ligand: Option<String>,
+    pub radius_type: String,
+    pub pbe_set: PBESet,
+    pub pba_set: PBASet,
+    pub mem_set: Option<MembraneSet>,
+    pub settings: Settings,
+    pub started: String,
+    pub finished: String,
+}
+
+impl RunInfo {
+    pub fn new(topology: &str, trajectory: &str, index: &str, receptor: &str, ligand: Option<&str>, radius_type: &str,
+               pbe_set: &PBESet, pba_set: &PBASet, mem_set: Option<&MembraneSet>, settings: &Settings) -> RunInfo {
+        RunInfo {
+            topology: topology.to_string(),
+            trajectory: trajectory.to_string(),
+            index: index.to_string(),
+            receptor: receptor.to_string(),
+            ligand: ligand.map(|l| l.to_string()),
+            radius_type: radius_type.to_string(),
+            pbe_set: pbe_set.clone(),
+            pba_set: pba_set.clone(),
+            mem_set: mem_set.cloned(),
+            settings: settings.clone(),
+            started: Local::now().to_rfc3339(),
+            finished: String::new(),
+        }
+    }
+
+    pub fn finish(&mut self) {
+        self.finished = Local::now().to_rfc3339();
+    }
+}
+
+pub fn analyze_controller(results: &Results, temperature: f64, sys_name: &String, wd: &Path,
+                          info: &RunInfo, settings: &Settings) {
     loop {
         println!("\n                 ************ MM-PBSA analyzation ************");
         println!("-1 Write residue-wised bind energy at specific time to pdb file");
@@ -108,60 +187,131 @@ pub fn analyze_controller(results: &Results, temperature: f64, sys_name: &String
         println!(" 2 Output binding energy terms by trajectory");
         println!(" 3 Output binding energy terms by residue at specific time");
         println!(" 4 Output residue-wised binding energy terms by time as default names");
+        println!(" 5 Output all results to JSON file");
+        println!(" 6 Output all results to JSON Lines file, one line per frame");
+        println!(" 7 Output residue-wised binding energy terms of all frames to Parquet file");
+        println!(" 8 Output binding energy terms to xvg file");
+        println!(" 9 Draw energy terms by time, top residues and residue-wised heat map");
+        println!("10 Write HTML report with parameters, summary, plots, hot-spot residues and convergence");
         let sel_fun: i32 = get_input_selection();
         match sel_fun {
-            -1 => write_energy_to_bf(results, wd, sys_name, total_at_num),
+            -1 => write_energy_to_bf(results, wd, sys_name),
             0 => exit(0),
-            1 => analyze_summary(results, temperature, wd, sys_name, settings),
-            2 => analyze_traj(results, wd, sys_name),
-            3 => analyze_res(results, wd, sys_name),
-            4 => output_all_details(results, wd, sys_name),
+            1 => print_error(analyze_summary(results, temperature, wd, sys_name, settings)),
+            2 => print_error(analyze_traj(results, wd, sys_name)),
+            3 => print_error(analyze_res(results, wd, sys_name)),
+            4 => print_error(output_all_details(results, wd, sys_name)),
+            5 => {
+                let def_name = get_outfile(&format!("MMPBSA_{}.json", sys_name));
+                match write_results_json(results, temperature, sys_name, info, &wd.join(&def_name)) {
+                    Ok(_) => println!("Results have been written to {}", def_name),
+                    Err(e) => println!("Error: {}", e)
+                }
+            }
+            6 => {
+                let def_name = get_outfile(&format!("MMPBSA_{}.jsonl", sys_name));
+                match write_results_jsonl(results, temperature, sys_name, info, &wd.join(&def_name)) {
+                    Ok(_) => println!("Results have been written to {}", def_name),
+                    Err(e) => println!("Error: {}", e)
+                }
+            }
+            7 => {
+                let def_name = get_outfile(&format!("MMPBSA_{}_res.parquet", sys_name));
+                match write_results_parquet(results, sys_name, info, &wd.join(&def_name)) {
+                    Ok(_) => println!("Residue-wised binding energy terms have been written to {}", def_name),
+                    Err(e) => println!("Error: {}", e)
+                }
+            }
+            8 => analyze_xvg(results, wd, sys_name),
+            9 => analyze_plots(results, wd, sys_name),
+            10 => {
+                let def_name = get_outfile(&format!("MMPBSA_{}_report.html", sys_name));
+                println!("Input number of top residues in hot-spot table and plots (default: 20):");
+                let top_n = get_input(20);
+                match write_report(results, temperature, sys_name, info, top_n, &wd.join(&def_name)) {
+                    Ok(_) => println!("Report has been written to {}", def_name),
+                    Err(e) => println!("Error: {}", e)
+                }
+            }
             _ => println!("Invalid input")
         }
     }
 }
 
-fn write_energy_to_bf(results: &Results, wd: &Path, sys_name: &String, total_at_num: usize) {
+fn print_error(result: Result<()>) {
+    if let Err(e) = result {
+        println!("Error: {}", e);
+    }
+}
+
+// write csv lines at once
+fn write_csv(file: &Path, content: String) -> Result<()> {
+    fs::write(file, content).map_err(MmpbsaError::io(file.to_string_lossy()))
+}
+
+fn write_energy_to_bf(results: &Results, wd: &Path, sys_name: &String) {
     println!("Input the time point (in ns) to write pdb (default: all):");
     let ts = get_input(-1.0);
+    let ts_ids: Vec<usize> = match ts != -1.0 {
+        true => vec![get_time_index(ts, results)],
+        false => (0..results.times.len()).collect()
+    };
+    let mut multi_model = true;
+    if ts_ids.len() > 1 {
+        println!("Write frames to:");
+        println!(" 1 One pdb file with a model of each frame");
+        println!(" 2 One pdb file of each frame");
+        println!("Input selection (default: 1):");
+        multi_model = get_input(1) != 2;
+    }
+    println!("Input energy unit of B-factor column, 1: kcal/mol, 2: kJ/mol (default: 1):");
+    let unit = match get_input(1) {
+        2 => EnergyUnit::KJ,
+        _ => EnergyUnit::Kcal
+    };
+    println!("Input number of top residues to label in PyMOL and VMD scripts (default: 10):");
+    let top_n = get_input(10);
+    // the scripts load all frames as states, or only the first one
+    let mut script_ts_ids = ts_ids.to_vec();
+    if !multi_model {
+        println!("Load all frames as states in PyMOL and VMD scripts? [Y/n]");
+        if !get_input(String::from("y")).to_lowercase().starts_with('y') {
+            script_ts_ids.truncate(1);
+        }
+    }
     println!("Writing pdb file(s)...");
-    if ts != -1.0 {
-        let ts_id = get_time_index(ts, results);
-        write_bf_pdb(results, sys_name, ts_id, wd, total_at_num);
-    } else {
-        for ts_id in 0..results.times.len() {
-            write_bf_pdb(results, sys_name, ts_id, wd, total_at_num);
+    let pdbs: Vec<(String, Vec<usize>)> = match multi_model && ts_ids.len() > 1 {
+        true => vec![(format!("MMPBSA_binding_energy_{}.pdb", sys_name), ts_ids.to_vec())],
+        false => ts_ids.iter().map(|&ts_id| (format!("MMPBSA_binding_energy_{}_{}ns.pdb", sys_name, results.times[ts_id] / 1000.0), vec![ts_id])).collect()
+    };
+    for (pdb, ids) in &pdbs {
+        if let Err(e) = write_bf_pdb(results, ids, unit, &wd.join(pdb)) {
+            println!("Error: {}", e);
+            return
         }
     }
     println!("Finished writing pdb file(s) with binding energy information.");
-}
-
-fn write_bf_pdb(results: &Results, sys_name: &String, ts_id: usize, wd: &Path, total_at_num: usize) {
-    let mut f = fs::File::create(wd.join(&format!("MMPBSA_binding_energy_{}_{}ns.pdb", sys_name, results.times[ts_id] / 1000.0))).unwrap();
-    let coord = &results.coord;
-    writeln!(f, "REMARK  The B-factor column is filled with the INVERSED residue-wised binding energy (ΔH), in kcal/mol").unwrap();
-    for atom_id in 0..total_at_num {
-        let res_id = results.aps.atm_resid[atom_id];
-        let atom_name = results.aps.atm_name[atom_id].as_str();
-        write_atom_line(res_id, atom_id, atom_name, &results, 
-            coord[[ts_id, atom_id, 0]], coord[[ts_id, atom_id, 1]], coord[[ts_id, atom_id, 2]], &mut f);
+    let pdbs: Vec<String> = pdbs.into_iter().take(script_ts_ids.len()).map(|p| p.0).collect();
+    match write_view_scripts(results, sys_name, &pdbs, &script_ts_ids, unit, top_n, wd) {
+        Ok(outs) => {
+            for out in outs {
+                println!("Script has been written to {}", out.file_name().unwrap().to_string_lossy());
+            }
+        }
+        Err(e) => println!("Error: {}", e)
     }
 }
 
-fn write_atom_line(res_id: usize, atom_id: usize, atom_name: &str, results: &Results, x: f64, y: f64, z: f64, f: &mut File) {
-    writeln!(f, "ATOM  {:5} {:<4} {:<3} A{:4}    {:8.3}{:8.3}{:8.3}  1.00{:6.2}           {:<2}", 
-                 atom_id + 1, atom_name, results.residues[res_id].name, results.residues[res_id].nr, x, y, z, 
-                 -results.dh_res[[results.dh_res.shape()[0] - 1, res_id]] / 4.18, atom_name.get(0..1).unwrap()).unwrap();
-}
-
-fn analyze_summary(results: &Results, temperature: f64, wd: &Path, sys_name: &String, settings: &Settings) {
-    let (dh_avg, mm_avg, pb_avg, sa_avg, elec_avg,
+fn analyze_summary(results: &Results, temperature: f64, wd: &Path, sys_name: &String, settings: &Settings) -> Result<()> {
+    let (dh_avg, mm_avg, pb_avg, sa_avg, cav_avg, disp_avg, elec_avg,
         vdw_avg, tds, dg, ki) = results.summary(temperature, settings);
     println!("Energy terms summary:");
     println!("ΔH: {:.3} kJ/mol", dh_avg);
     println!("ΔMM: {:.3} kJ/mol", mm_avg);
     println!("ΔPB: {:.3} kJ/mol", pb_avg);
     println!("ΔSA: {:.3} kJ/mol", sa_avg);
+    println!("ΔCAV: {:.3} kJ/mol", cav_avg);
+    println!("ΔDISP: {:.3} kJ/mol", disp_avg);
     println!();
     println!("Δelec: {:.3} kJ/mol", elec_avg);
     println!("Δvdw: {:.3} kJ/mol", vdw_avg);
@@ -172,37 +322,42 @@ fn analyze_summary(results: &Results, temperature: f64, wd: &Path, sys_name: &St
 
     let def_name = get_outfile(&format!("MMPBSA_{}.csv", sys_name));
     println!("Writing binding energy terms...");
-    let mut energy_sum = fs::File::create(wd.join(&def_name)).unwrap();
-    write!(energy_sum, "Energy Term,value,info\n").unwrap();
-    write!(energy_sum, "ΔH,{:.3},ΔH=ΔMM+ΔPB+ΔSA (kJ/mol)\n", dh_avg).unwrap();
-    write!(energy_sum, "ΔMM,{:.3},ΔMM=Δelec+ΔvdW (kJ/mol)\n", mm_avg).unwrap();
-    write!(energy_sum, "ΔPB,{:.3},(kJ/mol)\n", pb_avg).unwrap();
-    write!(energy_sum, "ΔSA,{:.3},(kJ/mol)\n", sa_avg).unwrap();
-    write!(energy_sum, "\n").unwrap();
-    write!(energy_sum, "Δelec,{:.3},(kJ/mol)\n", elec_avg).unwrap();
-    write!(energy_sum, "ΔvdW,{:.3},(kJ/mol)\n", vdw_avg).unwrap();
-    write!(energy_sum, "\n").unwrap();
-    write!(energy_sum, "TΔS,{:.3},(kJ/mol)\n", tds).unwrap();
-    write!(energy_sum, "ΔG,{:.3},ΔG=ΔH-TΔS (kJ/mol)\n", dg).unwrap();
-    write!(energy_sum, "Ki,{:.3e},Ki=exp(ΔG/RT) (nM)\n", ki).unwrap();
+    let mut energy_sum = String::from("Energy Term,value,info\n");
+    energy_sum.push_str(&format!("ΔH,{:.3},ΔH=ΔMM+ΔPB+ΔSA+ΔCAV+ΔDISP (kJ/mol)\n", dh_avg));
+    energy_sum.push_str(&format!("ΔMM,{:.3},ΔMM=Δelec+ΔvdW (kJ/mol)\n", mm_avg));
+    energy_sum.push_str(&format!("ΔPB,{:.3},(kJ/mol)\n", pb_avg));
+    energy_sum.push_str(&format!("ΔSA,{:.3},(kJ/mol)\n", sa_avg));
+    energy_sum.push_str(&format!("ΔCAV,{:.3},(kJ/mol)\n", cav_avg));
+    energy_sum.push_str(&format!("ΔDISP,{:.3},(kJ/mol)\n", disp_avg));
+    energy_sum.push('\n');
+    energy_sum.push_str(&format!("Δelec,{:.3},(kJ/mol)\n", elec_avg));
+    energy_sum.push_str(&format!("ΔvdW,{:.3},(kJ/mol)\n", vdw_avg));
+    energy_sum.push('\n');
+    energy_sum.push_str(&format!("TΔS,{:.3},(kJ/mol)\n", tds));
+    energy_sum.push_str(&format!("ΔG,{:.3},ΔG=ΔH-TΔS (kJ/mol)\n", dg));
+    energy_sum.push_str(&format!("Ki,{:.3e},Ki=exp(ΔG/RT) (nM)\n", ki));
+    write_csv(&wd.join(&def_name), energy_sum)?;
     println!("Binding energy terms have been writen to {}", &def_name);
+    Ok(())
 }
 
-fn analyze_traj(results: &Results, wd: &Path, sys_name: &String) {
+fn analyze_traj(results: &Results, wd: &Path, sys_name: &String) -> Result<()> {
     println!("Writing binding energy terms...");
     let def_name = &get_outfile(&format!("MMPBSA_{}_traj.csv", sys_name));
-    let mut energy_sum = fs::File::create(wd.join(&def_name)).unwrap();
-    write!(energy_sum, "Time (ns),ΔH,ΔMM,ΔPB,ΔSA,Δelec,ΔvdW,(kJ/mol)\n").unwrap();
+    let mut energy_sum = String::from("Time (ns),ΔH,ΔMM,ΔPB,ΔSA,ΔCAV,ΔDISP,Δelec,ΔvdW,(kJ/mol)\n");
     for i in 0..results.times.len() {
-        write!(energy_sum, "{},{:.3},{:.3},{:.3},{:.3},{:.3},{:.3}\n",
+        energy_sum.push_str(&format!("{},{:.3},{:.3},{:.3},{:.3},{:.3},{:.3},{:.3},{:.3}\n",
                             results.times[i] / 1000.0, results.dh[i],
                             results.mm[i], results.pb[i], results.sa[i],
-                            results.elec[i], results.vdw[i]).unwrap();
+                            results.cav[i], results.disp[i],
+                            results.elec[i], results.vdw[i]));
     }
+    write_csv(&wd.join(def_name), energy_sum)?;
     println!("Binding energy terms have been writen to {}", &def_name);
+    Ok(())
 }
 
-fn analyze_res(results: &Results, wd: &Path, sys_name: &String) {
+fn analyze_res(results: &Results, wd: &Path, sys_name: &String) -> Result<()> {
     println!("Determine the residue range to output:");
     println!(" 1 Ligand and receptor residues within 3 A");
     println!(" 2 Ligand and receptor residues within 5 A");
@@ -237,7 +392,7 @@ fn analyze_res(results: &Results, wd: &Path, sys_name: &String) {
         },
         _ => {
             println!("Invalid selection");
-            return
+            return Ok(())
         }
     };
     
@@ -247,51 +402,130 @@ fn analyze_res(results: &Results, wd: &Path, sys_name: &String) {
     if ts != -1.0 {
         let ts_id = get_time_index(ts, results);
         let def_name = wd.join(&format!("MMPBSA_{}_res_{}ns.csv", sys_name, results.times[ts_id] / 1000.0));
-        write_res_csv(results, ts_id, wd, &target_res, &def_name);
+        write_res_csv(results, ts_id, &target_res, &def_name)?;
     } else {
         let def_name = wd.join(&format!("MMPBSA_{}_res_avg.csv", sys_name));
-        write_res_avg_csv(results, wd, &target_res, &def_name);
+        write_res_avg_csv(results, &target_res, &def_name)?;
     }
 
     println!("Finished writing residue-wised binding energy file(s).");
+    Ok(())
 }
 
-fn write_res_csv(results: &Results, ts_id: usize, wd: &Path, target_res: &HashSet<usize>, def_name: &PathBuf) {
-    let mut energy_res = fs::File::create(wd.join(def_name)).unwrap();
-    energy_res.write_all("id,name,ΔH,ΔMM,ΔPB,ΔSA,Δelec,ΔvdW\n".as_bytes()).unwrap();
+fn analyze_xvg(results: &Results, wd: &Path, sys_name: &String) {
+    println!("Select the xvg file to write:");
+    println!(" 1 Binding energy terms by trajectory");
+    println!(" 2 Residue-wised time averaged binding energy terms");
+    println!(" 3 Binding energy term of residues by time");
+    let i: i32 = get_input_selection();
+    let (def_name, result) = match i {
+        1 => {
+            let def_name = get_outfile(&format!("MMPBSA_{}_traj.xvg", sys_name));
+            let result = write_traj_xvg(results, sys_name, &wd.join(&def_name));
+            (def_name, result)
+        }
+        2 | 3 => {
+            println!("Input the residue range you want to output (e.g., 1-3, 5), default: all");
+            let res_range = get_input(String::new());
+            let target_res: Vec<usize> = match res_range.len() {
+                0 => (0..results.residues.len()).collect(),
+                _ => {
+                    let res_range = range2list(&res_range);
+                    (0..results.residues.len()).filter(|&i| res_range.contains(&results.residues[i].nr)).collect()
+                }
+            };
+            if target_res.is_empty() {
+                println!("No residue in the range.");
+                return
+            }
+            if i == 2 {
+                let def_name = get_outfile(&format!("MMPBSA_{}_res_avg.xvg", sys_name));
+                let result = write_res_avg_xvg(results, sys_name, &target_res, &wd.join(&def_name));
+                (def_name, result)
+            } else {
+                println!("Select the energy term (default: 0):");
+                for (k, t) in XVG_TERMS.iter().enumerate() {
+                    println!(" {} {}", k, t.0);
+                }
+                let term: usize = get_input(0);
+                if term >= XVG_TERMS.len() {
+                    println!("Invalid selection");
+                    return
+                }
+                let def_name = get_outfile(&format!("MMPBSA_{}_res_{}.xvg", sys_name, XVG_TERMS[term].0));
+                let result = write_res_traj_xvg(results, sys_name, term, &target_res, &wd.join(&def_name));
+                (def_name, result)
+            }
+        }
+        _ => {
+            println!("Invalid selection");
+            return
+        }
+    };
+    match result {
+        Ok(_) => println!("Binding energy terms have been writen to {}", def_name),
+        Err(e) => println!("Error: {}", e)
+    }
+}
+
+fn analyze_plots(results: &Results, wd: &Path, sys_name: &String) {
+    println!("Input image format, svg or png (default: svg):");
+    let ext = get_input(String::from("svg"));
+    if ext != "svg" && ext != "png" {
+        println!("Invalid format");
+        return
+    }
+    println!("Input number of top residues to draw (default: 20):");
+    let top_n = get_input(20);
+    println!("Drawing plots...");
+    match write_plots(results, sys_name, wd, &ext, top_n) {
+        Ok(outs) => {
+            for out in outs {
+                println!("Plot has been written to {}", out.file_name().unwrap().to_string_lossy());
+            }
+        }
+        Err(e) => println!("Error: {}", e)
+    }
+}
+
+fn write_res_csv(results: &Results, ts_id: usize, target_res: &HashSet<usize>, def_name: &PathBuf) -> Result<()> {
+    let mut energy_res = String::from("id,name,ΔH,ΔMM,ΔPB,ΔSA,ΔCAV,ΔDISP,Δelec,ΔvdW\n");
     for (i, res) in results.residues.iter().enumerate() {
         if !target_res.contains(&res.id) {
             continue;
         }
-        write!(energy_res, "{},{},{:.3},{:.3},{:.3},{:.3},{:.3},{:.3}\n", 
+        energy_res.push_str(&format!("{},{},{:.3},{:.3},{:.3},{:.3},{:.3},{:.3},{:.3},{:.3}\n",
             res.nr, res.name,
             results.dh_res[[ts_id, i]],
             results.mm_res[[ts_id, i]],
             results.pb_res[[ts_id, i]],
             results.sa_res[[ts_id, i]],
+            results.cav_res[[ts_id, i]],
+            results.disp_res[[ts_id, i]],
             results.elec_res[[ts_id, i]],
-            results.vdw_res[[ts_id, i]])
-            .expect("Error while writing residue-wised energy file");
+            results.vdw_res[[ts_id, i]]));
     }
+    write_csv(def_name, energy_res)
 }
 
-fn write_res_avg_csv(results: &Results, wd: &Path, target_res: &HashSet<usize>, def_name: &PathBuf) {
-    let mut energy_res = fs::File::create(wd.join(def_name)).unwrap();
-    energy_res.write_all("id,name,ΔH,ΔMM,ΔPB,ΔSA,Δelec,ΔvdW\n".as_bytes()).unwrap();
+fn write_res_avg_csv(results: &Results, target_res: &HashSet<usize>, def_name: &PathBuf) -> Result<()> {
+    let mut energy_res = String::from("id,name,ΔH,ΔMM,ΔPB,ΔSA,ΔCAV,ΔDISP,Δelec,ΔvdW\n");
     for (i, res) in results.residues.iter().enumerate() {
         if !target_res.contains(&res.id) {
             continue;
         }
-        write!(energy_res, "{},{},{:.3},{:.3},{:.3},{:.3},{:.3},{:.3}\n", 
-            res.nr, res.name, 
+        energy_res.push_str(&format!("{},{},{:.3},{:.3},{:.3},{:.3},{:.3},{:.3},{:.3},{:.3}\n",
+            res.nr, res.name,
             results.dh_res.column(i).mean().unwrap(),
             results.mm_res.column(i).mean().unwrap(),
             results.pb_res.column(i).mean().unwrap(),
             results.sa_res.column(i).mean().unwrap(),
+            results.cav_res.column(i).mean().unwrap(),
+            results.disp_res.column(i).mean().unwrap(),
             results.elec_res.column(i).mean().unwrap(),
-            results.vdw_res.column(i).mean().unwrap())
-            .expect("Error while writing residue-wised energy file");
+            results.vdw_res.column(i).mean().unwrap()));
     }
+    write_csv(def_name, energy_res)
 }
 
 fn get_residue_range(results: &Results, cutoff: f64) -> HashSet<usize> {
@@ -320,115 +554,32 @@ fn get_residue_range(results: &Results, cutoff: f64) -> HashSet<usize> {
     res_range
 }
 
-fn analyze_dh_res_traj(results: &Results, wd: &Path, def_name: &String) {
-    println!("Writing binding energy terms...");
-    let mut energy_res = fs::File::create(wd.join(&def_name)).unwrap();
-    energy_res.write_all("Time (ns)".as_bytes()).unwrap();
-    for res in &results.residues {
-        energy_res.write_all(format!(",{}#{}", res.nr, res.name).as_bytes()).unwrap();
-    }
-    for i in 0..results.times.len() {
-        energy_res.write_all(format!("\n{}", results.times[i] / 1000.0).as_bytes()).unwrap();
-        for dh in &results.dh_res.row(i) {
-            energy_res.write_all(format!(",{:.3}", dh).as_bytes()).unwrap();
-        }
-    }
-    energy_res.write_all("\n".as_bytes()).unwrap();
-    println!("Binding energy terms have been writen to {}", &def_name);
-}
-
-fn analyze_mm_res_traj(results: &Results, wd: &Path, def_name: &String) {
-    println!("Writing binding energy terms...");
-    let mut energy_res = fs::File::create(wd.join(&def_name)).unwrap();
-    energy_res.write_all("Time (ns)".as_bytes()).unwrap();
-    for res in &results.residues {
-        energy_res.write_all(format!(",{}#{}", res.nr, res.name).as_bytes()).unwrap();
-    }
-    for i in 0..results.times.len() {
-        energy_res.write_all(format!("\n{}", results.times[i] / 1000.0).as_bytes()).unwrap();
-        for mm in &results.mm_res.row(i) {
-            energy_res.write_all(format!(",{:.3}", mm).as_bytes()).unwrap();
-        }
-    }
-    energy_res.write_all("\n".as_bytes()).unwrap();
-    println!("Binding energy terms have been writen to {}", &def_name);
-}
-
-fn analyze_pb_res_traj(results: &Results, wd: &Path, def_name: &String) {
+// energy term of all residues by time
+fn analyze_res_traj(results: &Results, term_res: &Array2<f64>, wd: &Path, def_name: &String) -> Result<()> {
     println!("Writing binding energy terms...");
-    let mut energy_res = fs::File::create(wd.join(&def_name)).unwrap();
-    energy_res.write_all("Time (ns)".as_bytes()).unwrap();
+    let mut energy_res = String::from("Time (ns)");
     for res in &results.residues {
-        energy_res.write_all(format!(",{}#{}", res.nr, res.name).as_bytes()).unwrap();
+        energy_res.push_str(&format!(",{}#{}", res.nr, res.name));
     }
     for i in 0..results.times.len() {
-        energy_res.write_all(format!("\n{}", results.times[i] / 1000.0).as_bytes()).unwrap();
-        for pb in &results.pb_res.row(i) {
-            energy_res.write_all(format!(",{:.3}", pb).as_bytes()).unwrap();
+        energy_res.push_str(&format!("\n{}", results.times[i] / 1000.0));
+        for v in &term_res.row(i) {
+            energy_res.push_str(&format!(",{:.3}", v));
         }
     }
-    energy_res.write_all("\n".as_bytes()).unwrap();
-    println!("Binding energy terms have been writen to {}", &def_name);
-}
-
-fn analyze_sa_res_traj(results: &Results, wd: &Path, def_name: &String) {
-    println!("Writing binding energy terms...");
-    let mut energy_res = fs::File::create(wd.join(&def_name)).unwrap();
-    energy_res.write_all("Time (ns)".as_bytes()).unwrap();
-    for res in &results.residues {
-        energy_res.write_all(format!(",{}#{}", res.nr, res.name).as_bytes()).unwrap();
-    }
-    for i in 0..results.times.len() {
-        energy_res.write_all(format!("\n{}", results.times[i] / 1000.0).as_bytes()).unwrap();
-        for sa in &results.sa_res.row(i) {
-            energy_res.write_all(format!(",{:.3}", sa).as_bytes()).unwrap();
-        }
-    }
-    energy_res.write_all("\n".as_bytes()).unwrap();
-    println!("Binding energy terms have been writen to {}", &def_name);
-}
-
-fn analyze_elec_res_traj(results: &Results, wd: &Path, def_name: &String) {
-    println!("Writing binding energy terms...");
-    let mut energy_res = fs::File::create(wd.join(&def_name)).unwrap();
-    energy_res.write_all("Time (ns)".as_bytes()).unwrap();
-    for res in &results.residues {
-        energy_res.write_all(format!(",{}#{}", res.nr, res.name).as_bytes()).unwrap();
-    }
-    for i in 0..results.times.len() {
-        energy_res.write_all(format!("\n{}", results.times[i] / 1000.0).as_bytes()).unwrap();
-        for elec in &results.elec_res.row(i) {
-            energy_res.write_all(format!(",{:.3}", elec).as_bytes()).unwrap();
-        }
-    }
-    energy_res.write_all("\n".as_bytes()).unwrap();
-    println!("Binding energy terms have been writen to {}", &def_name);
-}
-
-fn analyze_vdw_res_traj(results: &Results, wd: &Path, def_name: &String) {
-    println!("Writing binding energy terms...");
-    let mut energy_res = fs::File::create(wd.join(def_name)).unwrap();
-    energy_res.write_all("Time (ns)".as_bytes()).unwrap();
-    for res in &results.residues {
-        energy_res.write_all(format!(",{}#{}", res.nr, res.name).as_bytes()).unwrap();
-    }
-    for i in 0..results.times.len() {
-        energy_res.write_all(format!("\n{}", results.times[i] / 1000.0).as_bytes()).unwrap();
-        for vdw in &results.vdw_res.row(i) {
-            energy_res.write_all(format!(",{:.3}", vdw).as_bytes()).unwrap();
-        }
-    }
-    energy_res.write_all("\n".as_bytes()).unwrap();
+    energy_res.push('\n');
+    write_csv(&wd.join(def_name), energy_res)?;
     println!("Binding energy terms have been writen to {}", def_name);
+    Ok(())
 }
 
-pub fn output_all_details(results: &Results, wd: &Path, sys_name: &String) {
-    analyze_dh_res_traj(results, wd, &format!("MMPBSA_{}_res_ΔH.csv", sys_name));
-    analyze_mm_res_traj(results, wd, &format!("MMPBSA_{}_res_ΔMM.csv", sys_name));
-    analyze_pb_res_traj(results, wd, &format!("MMPBSA_{}_res_ΔPB.csv", sys_name));
-    analyze_sa_res_traj(results, wd, &format!("MMPBSA_{}_res_ΔSA.csv", sys_name));
-    analyze_elec_res_traj(results, wd, &format!("MMPBSA_{}_res_Δelec.csv", sys_name));
-    analyze_vdw_res_traj(results, wd, &format!("MMPBSA_{}_res_ΔvdW.csv", sys_name));
+pub fn output_all_details(results: &Results, wd: &Path, sys_name: &String) -> Result<()> {
+    for (term, term_res) in [("ΔH", &results.dh_res), ("ΔMM", &results.mm_res), ("ΔPB", &results.pb_res),
+                             ("ΔSA", &results.sa_res), ("ΔCAV", &results.cav_res), ("ΔDISP", &results.disp_res),
+                             ("Δelec", &results.elec_res), ("ΔvdW", &results.vdw_res)] {
+        analyze_res_traj(results, term_res, wd, &format!("MMPBSA_{}_res_{}.csv", sys_name, term))?;
+    }
+    Ok(())
 }
 
 fn get_time_index(ts: f64, results: &Results) -> usize {
@@ -0,0 +1,139 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use xdrfile::Frame;
+use serde::{Serialize, Deserialize};
+use ndarray::s;
+use crate::apbs_grid::plan_grids;
+use crate::apbs_param::{PBASet, PBESet};
+use crate::atom_property::AtomProperty;
+use crate::atom_radius::RADIUS_TYPES;
+use crate::error::{MmpbsaError, Result};
+use crate::mmpbsa::{get_atoms_trj, get_frames_range};
+use crate::prepare_apbs::{prepare_pqr, write_apbs_input};
+use crate::settings::Settings;
+use crate::trajectory::read_trajectory;
+
+// how APBS calculations are done: run by s_mmpbsa, only write inputs, or read outputs run elsewhere
+pub const APBS_MODES: [&str; 3] = ["local", "export", "ingest"];
+
+pub const MANIFEST_NAME: &str = "apbs_manifest.yaml";
+
+// APBS jobs of exported frames, inputs of a frame are run in order in the directory of manifest,
+// with the output of {input}.apbs redirected to {input}.out
+#[derive(Serialize, Deserialize)]
+pub struct ApbsManifest {
+    pub system: String,
+    // radius type of the pqr files, grids depend on it
+    pub radius_type: String,
+    // grid settings used to write the inputs, also used to read mg-para outputs
+    pub cfac: f64,
+    pub fadd: f64,
+    pub df: f64,
+    pub nlev: usize,
+    pub max_mem: f64,
+    pub frames: Vec<ManifestFrame>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ManifestFrame {
+    pub time: f64,
+    pub inputs: Vec<String>,
+    pub outputs: Vec<String>,
+}
+
+impl ApbsManifest {
+    pub fn load<T: AsRef<Path>>(dir: T) -> Result<ApbsManifest> {
+        let file = dir.as_ref().join(MANIFEST_NAME).to_string_lossy().to_string();
+        let manifest = fs::read_to_string(&file).map_err(MmpbsaError::io(&file))?;
+        let manifest: ApbsManifest = serde_yaml::from_str(manifest.as_str()).map_err(|e| MmpbsaError::parse(&file, e.to_string()))?;
+        if !(1..=8).contains(&manifest.nlev) || manifest.max_mem <= 0.0 {
+            return Err(MmpbsaError::parse(&file, format!("invalid nlev {} or max_mem {}", manifest.nlev, manifest.max_mem)));
+        }
+        Ok(manifest)
+    }
+
+    // exported radius type should be used again to get the same grids and atoms
+    pub fn check_radius(&self, settings: &Settings) -> Result<()> {
+        match self.radius_type == RADIUS_TYPES[settings.rad_type] {
+            true => Ok(()),
+            false => Err(MmpbsaError::Apbs(format!("inputs were exported with {} radius, but current radius type is {}",
+                self.radius_type, RADIUS_TYPES[settings.rad_type])))
+        }
+    }
+
+    // trajectory frames at the exported times
+    pub fn get_frame_ids(&self, frames: &Vec<Rc<Frame>>) -> Result<Vec<usize>> {
+        self.frames.iter().map(|mf| {
+            frames.iter().position(|f| (f.time as f64 - mf.time).abs() < 1e-3)
+                .ok_or_else(|| MmpbsaError::Apbs(format!("exported frame at {} ns not found in trajectory", mf.time / 1000.0)))
+        }).collect()
+    }
+
+    // settings of the exported grids
+    pub fn apply(&self, settings: &mut Settings) {
+        settings.cfac = self.cfac;
+        settings.fadd = self.fadd;
+        settings.df = self.df;
+        settings.nlev = self.nlev;
+        settings.max_mem = self.max_mem;
+    }
+}
+
+// write pqr and apbs files of all frames, with the manifest and a shell script to run them
+pub fn export_apbs_inputs(trj: &String, temp_dir: &PathBuf, sys_name: &String, aps: &AtomProperty,
+                          ndx_com: &Vec<usize>, ndx_rec: &Vec<usize>, ndx_lig: &Vec<usize>,
+                          bt: f64, et: f64, dt: f64, pbe_set: &PBESet, pba_set: &PBASet, settings: &Settings) -> Result<()> {
+    println!("Reading trajectory file...");
+    let frames = read_trajectory(trj)?;
+    let (coordinates, _) = get_atoms_trj(&frames);
+    let (bf, ef, dframe, total_frames) = get_frames_range(&frames, bt, et, dt);
+
+    println!("Preparing pqr files...");
+    prepare_pqr(&frames, bf, ef, dframe, total_frames, &temp_dir,
+                sys_name, &coordinates, ndx_com, &ndx_rec, ndx_lig, aps)?;
+
+    println!("Preparing apbs files...");
+    let mut manifest_frames: Vec<ManifestFrame> = vec![];
+    for cur_frm in (bf..=ef).step_by(dframe) {
+        let coord = coordinates.slice(s![cur_frm, .., ..]);
+        let f_name = format!("{}_{}ns", sys_name, frames[cur_frm].time / 1000.0);
+        let grids = plan_grids(ndx_rec, ndx_lig, &coord, &aps.atm_radius, settings, true);
+        write_apbs_input(ndx_rec, ndx_lig, &grids, pbe_set, pba_set, temp_dir, &f_name, None, false)?;
+        let mut inputs = vec![f_name.to_string()];
+        let partitions = grids.iter().map(|(_, plan)| plan.partitions()).max().unwrap();
+        if partitions > 1 {
+            inputs.extend((0..partitions).map(|i| format!("{}_PE{}", f_name, i)));
+        }
+        manifest_frames.push(ManifestFrame {
+            time: frames[cur_frm].time as f64,
+            outputs: inputs.iter().map(|i| format!("{}.out", i)).collect(),
+            inputs: inputs.iter().map(|i| format!("{}.apbs", i)).collect(),
+        });
+    }
+
+    let mut script = String::from("#!/bin/bash\n# run in this directory, or split the lines to scheduler jobs\nAPBS=${APBS:-apbs}\n");
+    for frame in &manifest_frames {
+        for (input, output) in frame.inputs.iter().zip(&frame.outputs) {
+            script.push_str(&format!("$APBS {} > {}\n", input, output));
+        }
+    }
+    let script_file = temp_dir.join("run_apbs.sh");
+    fs::write(&script_file, script).map_err(MmpbsaError::io(script_file.to_string_lossy()))?;
+
+    let manifest = ApbsManifest {
+        system: sys_name.to_string(),
+        radius_type: RADIUS_TYPES[settings.rad_type].to_string(),
+        cfac: settings.cfac,
+        fadd: settings.fadd,
+        df: settings.df,
+        nlev: settings.nlev,
+        max_mem: settings.max_mem,
+        frames: manifest_frames,
+    };
+    let manifest_file = temp_dir.join(MANIFEST_NAME);
+    fs::write(&manifest_file, serde_yaml::to_string(&manifest).unwrap()).map_err(MmpbsaError::io(manifest_file.to_string_lossy()))?;
+    println!("APBS inputs of {} frames have been written to {}/", total_frames, temp_dir.display());
+    println!("Run them with {}, then ingest the outputs in the same directory.", script_file.display());
+    Ok(())
+}
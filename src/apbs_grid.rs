@@ -0,0 +1,270 @@
+use std::fs;
+use std::path::Path;
+use ndarray::{Array1, ArrayView2};
+use crate::error::{MmpbsaError, Result};
+use crate::prepare_apbs::gen_mesh_params;
+use crate::settings::Settings;
+
+// estimated memory of APBS multigrid per grid point (bytes), as psize.py
+const GRID_MEM: f64 = 200.0;
+// overlap fraction between neighbouring mg-para partitions
+const OFRAC: f64 = 0.1;
+// at most 4 x 4 x 4 partitions
+const MAX_PDIME: usize = 4;
+
+// grid of one APBS calculation, coarse grid is shared by com, rec and lig, fine grid is around the molecule
+// mg-para (pdime > 1) splits the fine grid to partitions run one by one (async), each within the memory limit
+pub struct GridPlan {
+    pub nlev: usize,
+    pub dime: [usize; 3],
+    pub pdime: [usize; 3],
+    pub cglen: [f64; 3],
+    pub fglen: [f64; 3],
+    pub cgcent: [f64; 3],
+    pub fgcent: [f64; 3],
+}
+
+impl GridPlan {
+    // mol_box and com_box: [min_x, min_y, min_z, max_x, max_y, max_z]
+    pub fn new(mol_box: &[f64; 6], com_box: &[f64; 6], settings: &Settings, allow_para: bool) -> GridPlan {
+        let mut fglen = [0.0; 3];
+        let mut cglen = [0.0; 3];
+        let mut fgcent = [0.0; 3];
+        let mut cgcent = [0.0; 3];
+        for d in 0..3 {
+            let mol_len = (mol_box[d + 3] - mol_box[d]).max(0.1);
+            let com_len = (com_box[d + 3] - com_box[d]).max(0.1);
+            fgcent[d] = (mol_box[d + 3] + mol_box[d]) / 2.0;
+            cgcent[d] = (com_box[d + 3] + com_box[d]) / 2.0;
+            fglen[d] = (mol_len + settings.fadd).min(mol_len * settings.cfac);
+            // fine grid should be inside the coarse grid
+            cglen[d] = (com_len * settings.cfac).max(2.0 * (fgcent[d] - cgcent[d]).abs() + fglen[d]);
+        }
+        let mut plan = GridPlan { nlev: settings.nlev, dime: [0; 3], pdime: [1; 3], cglen, fglen, cgcent, fgcent };
+        let mut df = settings.df;
+        plan.set_dime(df);
+        // the least grid of nlev can not be reduced anymore
+        let min_dime = get_valid_dime(1.0, settings.nlev);
+        while plan.memory() > settings.max_mem && plan.dime.iter().any(|&n| n > min_dime) {
+            if allow_para && plan.pdime.iter().any(|&p| p < MAX_PDIME) {
+                // split the longest partition
+                let d = (0..3).filter(|&d| plan.pdime[d] < MAX_PDIME)
+                    .max_by(|&a, &b| (plan.fglen[a] / plan.pdime[a] as f64).total_cmp(&(plan.fglen[b] / plan.pdime[b] as f64))).unwrap();
+                plan.pdime[d] += 1;
+            } else {
+                df *= 1.1;
+            }
+            plan.set_dime(df);
+        }
+        plan
+    }
+
+    // least valid dime to reach spacing df at the fine grid (or each partition)
+    fn set_dime(&mut self, df: f64) {
+        for d in 0..3 {
+            let len = match self.pdime[d] {
+                1 => self.fglen[d],
+                p => self.fglen[d] / p as f64 * (1.0 + 2.0 * OFRAC)
+            };
+            self.dime[d] = get_valid_dime(len / df + 1.0, self.nlev);
+        }
+    }
+
+    pub fn is_para(&self) -> bool {
+        self.pdime.iter().product::<usize>() > 1
+    }
+
+    pub fn partitions(&self) -> usize {
+        self.pdime.iter().product()
+    }
+
+    // estimated memory per APBS process (MB)
+    pub fn memory(&self) -> f64 {
+        self.dime.iter().map(|&n| n as f64).product::<f64>() * GRID_MEM / 1024.0 / 1024.0
+    }
+
+    pub fn cg_spacing(&self) -> [f64; 3] {
+        [0, 1, 2].map(|d| self.cglen[d] / (self.dime[d] - 1) as f64)
+    }
+
+    pub fn fg_spacing(&self) -> [f64; 3] {
+        [0, 1, 2].map(|d| match self.pdime[d] {
+            1 => self.fglen[d],
+            p => self.fglen[d] / p as f64 * (1.0 + 2.0 * OFRAC)
+        } / (self.dime[d] - 1) as f64)
+    }
+
+    // mg-para partition owning the position, numbered as APBS: i + px * (j + py * k)
+    pub fn owner(&self, x: [f64; 3]) -> usize {
+        let p: Vec<usize> = (0..3).map(|d| {
+            let w = self.fglen[d] / self.pdime[d] as f64;
+            let i = ((x[d] - self.fgcent[d] + self.fglen[d] / 2.0) / w).floor();
+            (i.max(0.0) as usize).min(self.pdime[d] - 1)
+        }).collect();
+        p[0] + self.pdime[0] * (p[1] + self.pdime[1] * p[2])
+    }
+
+    // mg-auto grid, or mg-para grid of partition async_id
+    pub fn to_apbs(&self, mol_index: i32, async_id: Option<usize>) -> String {
+        let [n_x, n_y, n_z] = self.dime;
+        let [c_x, c_y, c_z] = self.cglen;
+        let [f_x, f_y, f_z] = self.fglen;
+        let [cx, cy, cz] = self.cgcent;
+        let [fx, fy, fz] = self.fgcent;
+        let mut grid = format!("  {}\n  mol    {mol_index:7}\
+            \n  dime   {n_x:7}  {n_y:7}  {n_z:7}\
+            \n  cglen  {c_x:7.3}  {c_y:7.3}  {c_z:7.3}\
+            \n  fglen  {f_x:7.3}  {f_y:7.3}  {f_z:7.3}\
+            \n  cgcent {cx:7.3}  {cy:7.3}  {cz:7.3}\
+            \n  fgcent {fx:7.3}  {fy:7.3}  {fz:7.3}\n", match async_id {
+                Some(_) => "mg-para",
+                None => "mg-auto"
+            });
+        if let Some(async_id) = async_id {
+            let [p_x, p_y, p_z] = self.pdime;
+            grid.push_str(&format!("  pdime  {p_x:7}  {p_y:7}  {p_z:7}\n  ofrac  {OFRAC:7}\n  async  {async_id:7}\n"));
+        }
+        grid
+    }
+}
+
+// dime of APBS multigrid should be c * 2^(nlev + 1) + 1
+pub fn get_valid_dime(n: f64, nlev: usize) -> usize {
+    let t = 2_usize.pow(nlev as u32 + 1);
+    ((n - 1.0) / t as f64).ceil().max(1.0) as usize * t + 1
+}
+
+// grids of com, rec and lig (or only rec if no ligand), in the order of pqr files
+pub fn plan_grids(ndx_rec: &Vec<usize>, ndx_lig: &Vec<usize>, coord: &ArrayView2<f64>, atm_radius: &Array1<f64>,
+                  settings: &Settings, allow_para: bool) -> Vec<(String, GridPlan)> {
+    let (rec_box, lig_box, com_box) = gen_mesh_params(ndx_rec, ndx_lig, coord, atm_radius);
+    match ndx_lig[0] != ndx_rec[0] {
+        true => vec![
+            ("com".to_string(), GridPlan::new(&com_box, &com_box, settings, allow_para)),
+            ("rec".to_string(), GridPlan::new(&rec_box, &com_box, settings, allow_para)),
+            ("lig".to_string(), GridPlan::new(&lig_box, &com_box, settings, allow_para)),
+        ],
+        false => vec![("rec".to_string(), GridPlan::new(&rec_box, &rec_box, settings, allow_para))]
+    }
+}
+
+// grids of every frame before running APBS
+pub fn write_grid_report(grids: &Vec<(f64, Vec<(String, GridPlan)>)>, settings: &Settings, report: &Path) -> Result<()> {
+    let mut lines = String::new();
+    lines.push_str(&format!("{:>12}{:>6}{:>9}{:>18}{:>10}{:>24}{:>24}{:>12}\n",
+        "time (ns)", "mol", "method", "dime", "pdime", "coarse spacing (A)", "fine spacing (A)", "memory (MB)"));
+    let mut max_mem: f64 = 0.0;
+    let mut max_df: f64 = 0.0;
+    let mut n_para = 0;
+    for (time, plans) in grids {
+        for (sys, plan) in plans {
+            let [n_x, n_y, n_z] = plan.dime;
+            let [p_x, p_y, p_z] = plan.pdime;
+            let [c_x, c_y, c_z] = plan.cg_spacing();
+            let [f_x, f_y, f_z] = plan.fg_spacing();
+            lines.push_str(&format!("{:12.4}{:>6}{:>9}{:>18}{:>10}{:>24}{:>24}{:12.1}\n", time / 1000.0, sys,
+                match plan.is_para() {
+                    true => "mg-para",
+                    false => "mg-auto"
+                }, format!("{} {} {}", n_x, n_y, n_z), format!("{} {} {}", p_x, p_y, p_z),
+                format!("{:.3} {:.3} {:.3}", c_x, c_y, c_z), format!("{:.3} {:.3} {:.3}", f_x, f_y, f_z),
+                plan.memory()));
+            max_mem = max_mem.max(plan.memory());
+            max_df = max_df.max(f_x).max(f_y).max(f_z);
+            if plan.is_para() {
+                n_para += 1;
+            }
+        }
+    }
+    fs::write(report, lines).map_err(MmpbsaError::io(report.to_string_lossy()))?;
+    println!("Estimated APBS memory per process: {:.1} MB at most (limit {} MB), fine grid spacing {:.3} A at most (desired {} A).",
+        max_mem, settings.max_mem, max_df, settings.df);
+    if n_para > 0 {
+        println!("{} APBS calculations will be split by mg-para to fit the memory limit.", n_para);
+    }
+    if max_df > settings.df * 1.001 {
+        println!("Warning: fine grid is coarser than desired to fit the memory limit, consider a larger memory limit.");
+    }
+    println!("Grid details of all frames have been written to {}", report.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(max_mem: f64) -> Settings {
+        let mut settings = Settings::new();
+        settings.max_mem = max_mem;
+        settings
+    }
+
+    #[test]
+    fn valid_dime() {
+        for nlev in 1..=6 {
+            let t = 2_usize.pow(nlev as u32 + 1);
+            for n in [0.5, 1.0, 2.0, 17.3, 33.0, 65.0, 100.5, 400.0] {
+                let dime = get_valid_dime(n, nlev);
+                assert_eq!((dime - 1) % t, 0);
+                // the least one not less than n
+                assert!(dime as f64 >= n && (dime == t + 1 || ((dime - t) as f64) < n));
+            }
+        }
+        assert_eq!(get_valid_dime(33.0, 4), 33);
+        assert_eq!(get_valid_dime(34.0, 4), 65);
+        assert_eq!(get_valid_dime(100.5, 4), 129);
+        assert_eq!(get_valid_dime(100.5, 2), 105);
+    }
+
+    #[test]
+    fn plan_without_limit() {
+        let mol_box = [0.0, 0.0, 0.0, 40.0, 30.0, 20.0];
+        let plan = GridPlan::new(&mol_box, &mol_box, &settings(1e6), true);
+        assert!(!plan.is_para());
+        assert_eq!(plan.fglen, [50.0, 40.0, 30.0]);
+        assert_eq!(plan.cglen, [120.0, 90.0, 60.0]);
+        assert_eq!(plan.dime, [get_valid_dime(101.0, 4), get_valid_dime(81.0, 4), get_valid_dime(61.0, 4)]);
+        assert!(plan.fg_spacing().iter().all(|&s| s <= 0.5));
+    }
+
+    #[test]
+    fn plan_shrinks_under_max_mem() {
+        let mol_box = [0.0, 0.0, 0.0, 80.0, 60.0, 50.0];
+        let full = GridPlan::new(&mol_box, &mol_box, &settings(1e6), false);
+        // coarser fine grid with mg-auto
+        let plan = GridPlan::new(&mol_box, &mol_box, &settings(200.0), false);
+        assert!(full.memory() > 200.0);
+        assert!(plan.memory() <= 200.0);
+        assert!(!plan.is_para());
+        assert!(plan.fg_spacing()[0] > full.fg_spacing()[0]);
+        // partitions of the same spacing with mg-para
+        let plan = GridPlan::new(&mol_box, &mol_box, &settings(200.0), true);
+        assert!(plan.memory() <= 200.0);
+        assert!(plan.is_para());
+        assert!(plan.pdime[0] >= plan.pdime[2]);
+        assert!(plan.fg_spacing().iter().all(|&s| s <= 0.5));
+        // the least grid is kept if the limit can not be reached
+        let plan = GridPlan::new(&mol_box, &mol_box, &settings(0.001), false);
+        assert_eq!(plan.dime, [get_valid_dime(1.0, 4); 3]);
+    }
+
+    #[test]
+    fn owner_at_boundaries() {
+        let plan = GridPlan { nlev: 4, dime: [33; 3], pdime: [2, 1, 1], cglen: [30.0; 3], fglen: [10.0; 3],
+            cgcent: [0.0; 3], fgcent: [0.0; 3] };
+        assert_eq!(plan.owner([-5.0, 0.0, 0.0]), 0);
+        assert_eq!(plan.owner([-1e-6, 0.0, 0.0]), 0);
+        assert_eq!(plan.owner([0.0, 0.0, 0.0]), 1);
+        assert_eq!(plan.owner([5.0, 0.0, 0.0]), 1);
+        // outside of the fine grid
+        assert_eq!(plan.owner([-20.0, 0.0, 0.0]), 0);
+        assert_eq!(plan.owner([20.0, 9.0, -9.0]), 1);
+        // numbered as i + px * (j + py * k)
+        let plan = GridPlan { pdime: [2, 3, 2], fgcent: [1.0; 3], ..plan };
+        assert_eq!(plan.owner([0.9, -3.0, 0.9]), 0);
+        assert_eq!(plan.owner([1.0, -3.0, 0.9]), 1);
+        assert_eq!(plan.owner([0.9, 1.0, 0.9]), 2);
+        assert_eq!(plan.owner([1.0, 6.0, 1.0]), 1 + 2 * (2 + 3));
+        assert_eq!(plan.partitions(), 12);
+    }
+}
@@ -1,10 +1,9 @@
 use std::{fmt, fs};
 use std::fmt::Formatter;
-use std::fs::File;
-use std::io::Write;
 use std::marker::Copy;
 use std::path::Path;
 use serde::{Serialize, Deserialize};
+use crate::error::{MmpbsaError, Result};
 
 #[derive(Serialize, Deserialize)]
 pub struct PBESet {
@@ -68,16 +67,14 @@ impl PBESet {
         return new_pbe_set;
     }
 
-    pub fn load_params<T: AsRef<Path>>(file: T) -> PBESet {
-        let pbe_set = fs::read_to_string(&file).expect("Read PB parameters file error.");
-        let pbe_set: PBESet = serde_yaml::from_str(pbe_set.as_str()).expect("Error format with PB parameters file.");
-        pbe_set
+    pub fn load_params<T: AsRef<Path>>(file: T) -> Result<PBESet> {
+        let file = file.as_ref().to_string_lossy().to_string();
+        let pbe_set = fs::read_to_string(&file).map_err(MmpbsaError::io(&file))?;
+        serde_yaml::from_str(pbe_set.as_str()).map_err(|e| MmpbsaError::parse(&file, e.to_string()))
     }
 
-    pub fn save_params<T: AsRef<Path>>(&self, file: T) {
-        let mut f = File::create(&file).expect("Save SA parameters error.");
-        f.write_all(serde_yaml::to_string(self).unwrap().as_bytes())
-            .expect("Save PB parameters error.");
+    pub fn save_params<T: AsRef<Path>>(&self, file: T) -> Result<()> {
+        save_yaml(self, file)
     }
 }
 
@@ -124,7 +121,7 @@ impl Clone for PBESet {
 pub struct Ion {
     pub charge: f64,
     pub conc: f64,
-    radius: f64,
+    pub radius: f64,
 }
 
 impl fmt::Display for Ion {
@@ -141,15 +138,20 @@ impl Clone for Ion {
     }
 }
 
+// AMBER-PB4, 0.0072 kcal/(mol·A^2), the surface tension fixed in older versions
+const LEGACY_GAMMA: f64 = 0.030125;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct PBASet {
     temp: f64,
     srfm: String,
     swin: f64,
-    srad: f64,
+    pub srad: f64,
     pub gamma: f64,
-    press: f64,
-    bconc: f64,
+    #[serde(default)]
+    pub bias: f64,
+    pub press: f64,
+    pub bconc: f64,
     sdens: f64,
     dpos: f64,
     grid: (f64, f64, f64),
@@ -164,7 +166,8 @@ impl PBASet {
             srfm: "sacc".to_string(),
             swin: 0.3,
             srad: 1.4,
-            gamma: 1.0,
+            gamma: LEGACY_GAMMA,
+            bias: 0.0,
             press: 0.0,
             bconc: 0.0,
             sdens: 10.0,
@@ -182,6 +185,7 @@ impl PBASet {
             swin: pba_set.swin,
             srad: pba_set.srad,
             gamma: pba_set.gamma,
+            bias: pba_set.bias,
             press: pba_set.press,
             bconc: pba_set.bconc,
             sdens: pba_set.sdens,
@@ -192,19 +196,40 @@ impl PBASet {
         }
     }
 
-    pub fn load_params<T: AsRef<Path>>(file: T) -> PBASet {
-        let pba_set = fs::read_to_string(&file).expect("Read SA parameters file error.");
-        let pba_set: PBASet = serde_yaml::from_str(pba_set.as_str()).expect("Error format with SA parameters file.");
+    // APBS only calculates SASA (gamma = 1), the nonpolar energies are calculated by s_mmpbsa
+    pub fn to_apbs(&self) -> PBASet {
+        let mut pba_set = PBASet::from(self);
+        pba_set.gamma = 1.0;
+        pba_set.press = 0.0;
+        pba_set.bconc = 0.0;
         pba_set
     }
 
-    pub fn save_params<T: AsRef<Path>>(&self, file: T) {
-        let mut f = File::create(&file).expect("Save SA parameters error.");
-        f.write_all(serde_yaml::to_string(self).unwrap().as_bytes())
-            .expect("Save SA parameters error.");
+    pub fn load_params<T: AsRef<Path>>(file: T) -> Result<PBASet> {
+        let file = file.as_ref().to_string_lossy().to_string();
+        let pba_set = fs::read_to_string(&file).map_err(MmpbsaError::io(&file))?;
+        let mut pba_set: PBASet = serde_yaml::from_str(pba_set.as_str()).map_err(|e| MmpbsaError::parse(&file, e.to_string()))?;
+        // older versions saved gamma = 1 passed to APBS and used a fixed surface tension of 0.030125
+        if pba_set.gamma == 1.0 {
+            println!("Warning: gamma = 1 in {} is from an older version, where it was passed to APBS only.\n\
+                gamma is now the surface tension in kJ/(mol·A^2), {} will be used as before.",
+                file, LEGACY_GAMMA);
+            pba_set.gamma = LEGACY_GAMMA;
+        }
+        Ok(pba_set)
+    }
+
+    pub fn save_params<T: AsRef<Path>>(&self, file: T) -> Result<()> {
+        save_yaml(self, file)
     }
 }
 
+fn save_yaml<S: Serialize, T: AsRef<Path>>(params: &S, file: T) -> Result<()> {
+    let file = file.as_ref().to_string_lossy().to_string();
+    let params = serde_yaml::to_string(params).map_err(|e| MmpbsaError::parse(&file, e.to_string()))?;
+    fs::write(&file, params).map_err(MmpbsaError::io(&file))
+}
+
 impl fmt::Display for PBASet {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(f,
@@ -215,8 +240,8 @@ impl fmt::Display for PBASet {
             \n  srad  {:7}  # 探测半径(A)\
             \n  gamma {:7}  # 表面张力(kJ/mol-A^2)\
             \n  \
-            \n  press {:7}  # 压力(kJ/mol-A^3)\
-            \n  bconc {:7}  # 溶剂本体密度(A^3)\
+            \n  press {:7}  # 压力(kJ/mol-A^3), 用于空腔项, 0不计算\
+            \n  bconc {:7}  # 溶剂本体密度(A^-3), 用于色散项, 0不计算\
             \n  sdens {:7}\
             \n  dpos  {:7}\
             \n  grid  {:7} {:5} {:5}\
@@ -238,3 +263,21 @@ impl Clone for PBASet {
         PBASet::from(self)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_gamma() {
+        let file = std::env::temp_dir().join(format!("s_mmpbsa_sa_{}.yaml", std::process::id()));
+        let mut pba_set = PBASet::new(298.15);
+        pba_set.gamma = 1.0;
+        pba_set.save_params(&file).unwrap();
+        assert_eq!(PBASet::load_params(&file).unwrap().gamma, LEGACY_GAMMA);
+        pba_set.gamma = 0.0227;
+        pba_set.save_params(&file).unwrap();
+        assert_eq!(PBASet::load_params(&file).unwrap().gamma, 0.0227);
+        fs::remove_file(&file).unwrap();
+    }
+}
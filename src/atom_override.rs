@@ -0,0 +1,116 @@
+use std::fs;
+use std::path::Path;
+use serde::{Serialize, Deserialize};
+use crate::atom_property::AtomProperty;
+use crate::error::{MmpbsaError, Result};
+
+// radius (A) and/or charge (e) of selected atoms
+// atoms are selected by resname and/or atomname, atom type, or atom index (starts at 1, as index file),
+// all given selectors should be matched
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AtomOverride {
+    pub resname: Option<String>,
+    pub atomname: Option<String>,
+    pub atom_type: Option<usize>,
+    pub index: Option<usize>,
+    pub radius: Option<f64>,
+    pub charge: Option<f64>,
+}
+
+impl AtomOverride {
+    fn is_match(&self, aps: &AtomProperty, idx: usize, sys_index: usize) -> bool {
+        self.resname.as_ref().map_or(true, |r| r.eq(&aps.atm_resname[idx]))
+            && self.atomname.as_ref().map_or(true, |n| n.eq(&aps.atm_name[idx]))
+            && self.atom_type.map_or(true, |t| t == aps.atm_typeindex[idx])
+            && self.index.map_or(true, |i| i == sys_index + 1)
+    }
+
+    fn selector(&self) -> String {
+        let mut s: Vec<String> = vec![];
+        if let Some(r) = &self.resname {
+            s.push(format!("resname {}", r));
+        }
+        if let Some(n) = &self.atomname {
+            s.push(format!("atomname {}", n));
+        }
+        if let Some(t) = self.atom_type {
+            s.push(format!("type {}", t));
+        }
+        if let Some(i) = self.index {
+            s.push(format!("index {}", i));
+        }
+        s.join(", ")
+    }
+}
+
+pub fn load_overrides<T: AsRef<Path>>(file: T) -> Result<Vec<AtomOverride>> {
+    let file = file.as_ref().to_string_lossy().to_string();
+    let overrides = fs::read_to_string(&file).map_err(MmpbsaError::io(&file))?;
+    let overrides: Vec<AtomOverride> = serde_yaml::from_str(overrides.as_str()).map_err(|e| MmpbsaError::parse(&file, e.to_string()))?;
+    Ok(overrides.into_iter().filter(|o| {
+        let valid = o.resname.is_some() || o.atomname.is_some() || o.atom_type.is_some() || o.index.is_some();
+        if !valid {
+            println!("Warning: atom override without resname, atomname, atom_type or index is ignored.");
+        }
+        valid
+    }).collect())
+}
+
+// template of override file
+pub fn write_override_template<T: AsRef<Path>>(file: T) -> Result<()> {
+    let file = file.as_ref().to_string_lossy().to_string();
+    fs::write(&file, "\
+# Radius (A) and charge (e) overrides, applied after the atom radius type.\n\
+# Select atoms by resname and/or atomname, atom_type (type index as in paras_structure.txt),\n\
+# or index (system atom index, starts at 1). Later entries overwrite the earlier ones.\n\
+- resname: CU\n\
+  atomname: CU\n\
+  radius: 1.4\n\
+  charge: 2.0\n\
+# - atom_type: 12\n\
+#   radius: 1.5\n\
+# - index: 3001\n\
+#   charge: -0.5\n\
+# Bonded model charges of a metal site (e.g. from MCPB.py) are given by index:\n\
+# - index: 1502   # SG of coordinating CYS\n\
+#   charge: -0.45\n").map_err(MmpbsaError::io(&file))
+}
+
+impl AtomProperty {
+    // ndx_com: system atom indexes of complex, in the same order of atom properties
+    pub fn apply_overrides(&mut self, overrides: &Vec<AtomOverride>, ndx_com: &Vec<usize>, report: &Path) -> Result<()> {
+        let mut lines = String::new();
+        lines.push_str(&format!("{:>7}{:>7}{:>9}{:>7}{:>10}{:>10}{:>10}{:>10}  rule\n",
+            "index", "name", "resname", "type", "radius", "new_rad", "charge", "new_chg"));
+        let mut ndx_sorted = ndx_com.to_vec();
+        ndx_sorted.sort();
+        let mut count = 0;
+        let charge_total = self.atm_charge.sum();
+        for idx in 0..self.atm_radius.len() {
+            let (radius, charge) = (self.atm_radius[idx], self.atm_charge[idx]);
+            let matched: Vec<&AtomOverride> = overrides.iter().filter(|o| o.is_match(self, idx, ndx_sorted[idx])).collect();
+            let mut rules: Vec<String> = vec![];
+            for o in matched {
+                if let Some(r) = o.radius {
+                    self.atm_radius[idx] = r;
+                }
+                if let Some(q) = o.charge {
+                    self.atm_charge[idx] = q;
+                }
+                rules.push(o.selector());
+            }
+            if !rules.is_empty() {
+                lines.push_str(&format!("{:7}{:>7}{:>9}{:7}{:10.4}{:10.4}{:10.4}{:10.4}  {}\n",
+                    ndx_sorted[idx] + 1, self.atm_name[idx], self.atm_resname[idx], self.atm_typeindex[idx],
+                    radius, self.atm_radius[idx], charge, self.atm_charge[idx], rules.join("; ")));
+                count += 1;
+            }
+        }
+        fs::write(report, lines).map_err(MmpbsaError::io(report.to_string_lossy()))?;
+        println!("Radius or charge of {} atoms overridden, see {}", count, report.display());
+        if charge_total != self.atm_charge.sum() {
+            println!("Note: total charge changed from {:.4} to {:.4} by overrides.", charge_total, self.atm_charge.sum());
+        }
+        Ok(())
+    }
+}
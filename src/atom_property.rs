@@ -1,5 +1,8 @@
+use std::collections::{HashMap, HashSet};
 use ndarray::{Array1, Array2};
+use crate::error::{MmpbsaError, Result};
 use crate::parse_tpr::TPR;
+use crate::element::{get_elements, get_neighbors};
 use indicatif::{ProgressBar, ProgressStyle};
 
 #[derive(Clone)]
@@ -8,15 +11,27 @@ pub struct AtomProperty {
     pub c12: Array2<f64>,
     pub atm_charge: Array1::<f64>,
     pub atm_radius: Array1::<f64>,
+    pub atm_radius_ff: Array1::<f64>,                   // force field radius at current lambda
+    pub atm_radius_rule: Vec<String>,                   // how the radius was assigned
+    pub atm_element: Array1<usize>,                     // atomic number, 0 for unknown
+    pub atm_bonded_elements: Vec<Vec<usize>>,           // atomic numbers of bonded atoms
+    pub atm_mass: Array1<f64>,
     pub atm_typeindex: Array1<usize>,
+    pub atm_typeindex_b: Array1<usize>,
     pub atm_index: Array1<usize>,
     pub atm_name: Array1<String>,
     pub atm_resname: Array1<String>,
     pub atm_resid: Array1<usize>,
+    pub atm_molid: Array1<usize>,                       // molecule in the system, counted by instances
+    pub atm_molname: Array1<String>,                    // name of the molecule type
+    pub atm_excls: Vec<HashSet<usize>>,                 // excluded atoms of each atom
+    pub pairs_14: HashMap<(usize, usize), (f64, f64, f64)>, // (i, j) with i < j -> (c6, c12, fudgeQQ) of LJ-14
+    pub fudge_qq: f64,
+    pub fep_lambda: f64,                                // 0: A-state, 1: B-state, others: interpolated
 }
 
 impl AtomProperty {
-    pub fn new(tpr: &TPR, ndx_com: &Vec<usize>) -> AtomProperty {
+    pub fn new(tpr: &TPR, ndx_com: &Vec<usize>, fep_lambda: f64) -> Result<AtomProperty> {
         // c6 and c12
         let mut c6: Array2<f64> = Array2::zeros((tpr.atom_types_num, tpr.atom_types_num));
         let mut c12: Array2<f64> = Array2::zeros((tpr.atom_types_num, tpr.atom_types_num));
@@ -29,15 +44,29 @@ impl AtomProperty {
 
         let mut atm_charge: Array1::<f64> = Array1::zeros(ndx_com.len());
         let mut atm_radius: Array1::<f64> = Array1::zeros(ndx_com.len());
+        let mut atm_element: Array1<usize> = Array1::zeros(ndx_com.len());
+        let mut atm_bonded_elements: Vec<Vec<usize>> = vec![vec![]; ndx_com.len()];
+        let mut atm_mass: Array1<f64> = Array1::zeros(ndx_com.len());
         let mut atm_typeindex: Array1<usize> = Array1::zeros(ndx_com.len());
+        let mut atm_typeindex_b: Array1<usize> = Array1::zeros(ndx_com.len());
         let mut atm_index: Array1<usize> = Array1::zeros(ndx_com.len());
         let mut atm_name: Array1<String> = Array1::default(ndx_com.len());
         let mut atm_resname: Array1<String> = Array1::default(ndx_com.len());
         let mut atm_resid: Array1<usize> = Array1::zeros(ndx_com.len());
+        let mut atm_molid: Array1<usize> = Array1::zeros(ndx_com.len());
+        let mut atm_molname: Array1<String> = Array1::default(ndx_com.len());
+        let mut atm_excls: Vec<HashSet<usize>> = vec![HashSet::new(); ndx_com.len()];
+        let mut pairs_14: HashMap<(usize, usize), (f64, f64, f64)> = HashMap::new();
+
+        // system atom index -> atom index in complex
+        let mut ndx_sorted = ndx_com.to_vec();
+        ndx_sorted.sort();
+        let ndx_map: HashMap<usize, usize> = ndx_sorted.iter().enumerate().map(|(i, &a)| (a, i)).collect();
 
         let mut idx_total = 0;
         let mut idx = 0;
         let mut resind_offset = 0;      // residues number that has been overpast
+        let mut molid = 0;
 
         let mut size: u64 = 0;
         for mol in &tpr.molecules {
@@ -51,16 +80,43 @@ impl AtomProperty {
             .progress_chars("=>-"));
 
         for mol in &tpr.molecules {
+            let elements = get_elements(mol);
+            let neighbors = get_neighbors(mol);
             for _ in 0..tpr.molecule_types[mol.molecule_type_id].molecules_num {
-                for atom in &mol.atoms {
+                // exclusions and LJ-14 pairs of current molecule
+                for (i, excl) in mol.excls.iter().enumerate() {
+                    if let Some(&ii) = ndx_map.get(&(idx_total + i)) {
+                        atm_excls[ii].extend(excl.iter().filter_map(|j| ndx_map.get(&(idx_total + j))));
+                    }
+                }
+                for pair in &mol.pairs {
+                    if let (Some(&ii), Some(&jj)) = (ndx_map.get(&(idx_total + pair.i)), ndx_map.get(&(idx_total + pair.j))) {
+                        // pairs of function types not collected, e.g. unsupported pair functions
+                        let (lj14, lj14_b) = match (tpr.lj14_params.get(&pair.func_id), tpr.lj14_params_b.get(&pair.func_id)) {
+                            (Some(lj14), Some(lj14_b)) => (lj14, lj14_b),
+                            _ => return Err(MmpbsaError::parse(&tpr.name, format!("LJ-14 pair {}-{} of molecule {} uses unknown function type {}",
+                                pair.i + 1, pair.j + 1, mol.molecule_name, pair.func_id)))
+                        };
+                        let fudge_qq = *tpr.lj14_fudge_qq.get(&pair.func_id).unwrap_or(&tpr.fudge_qq);
+                        pairs_14.insert((ii.min(jj), ii.max(jj)), 
+                            (interpolate(lj14.c6, lj14_b.c6, fep_lambda), interpolate(lj14.c12, lj14_b.c12, fep_lambda), fudge_qq));
+                    }
+                }
+                for (i, atom) in mol.atoms.iter().enumerate() {
                     if ndx_com.contains(&idx_total) {
-                        atm_charge[idx] = atom.charge;
-                        atm_radius[idx] = atom.radius;
+                        atm_element[idx] = elements[i];
+                        atm_bonded_elements[idx] = neighbors[i].iter().map(|&j| elements[j]).collect();
+                        atm_mass[idx] = atom.mass;
+                        atm_charge[idx] = interpolate(atom.charge, atom.charge_b, fep_lambda);
+                        atm_radius[idx] = interpolate(atom.radius, atom.radius_b, fep_lambda);
                         atm_typeindex[idx] = atom.type_id;
+                        atm_typeindex_b[idx] = atom.type_id_b;
                         atm_index[idx] = atom.id;
                         atm_name[idx] = atom.name.to_string();
                         atm_resname[idx] = mol.residues[atom.resind].name.to_string();
                         atm_resid[idx] = atom.resind + resind_offset;
+                        atm_molid[idx] = molid;
+                        atm_molname[idx] = mol.molecule_name.to_string();
                         idx += 1;
                     }
                     idx_total += 1;
@@ -68,6 +124,7 @@ impl AtomProperty {
                     pb.set_message(format!("eta. {} s", pb.eta().as_secs()));
                 }
                 resind_offset += mol.residues.len();
+                molid += 1;
             }
         }
 
@@ -76,16 +133,44 @@ impl AtomProperty {
 
         pb.finish();
 
-        AtomProperty {
+        Ok(AtomProperty {
             c6,
             c12,
             atm_charge,
+            atm_radius_ff: atm_radius.clone(),
+            atm_radius_rule: vec![String::from("ff"); ndx_com.len()],
             atm_radius,
+            atm_element,
+            atm_bonded_elements,
+            atm_mass,
             atm_typeindex,
+            atm_typeindex_b,
             atm_index,
             atm_name,
             atm_resname,
-            atm_resid
+            atm_resid,
+            atm_molid,
+            atm_molname,
+            atm_excls,
+            pairs_14,
+            fudge_qq: tpr.fudge_qq,
+            fep_lambda,
+        })
+    }
+
+    // c6 and c12 between atom i and j at current lambda
+    pub fn get_lj_params(&self, i: usize, j: usize) -> (f64, f64) {
+        let (ci, cj) = (self.atm_typeindex[i], self.atm_typeindex[j]);
+        if self.fep_lambda == 0.0 {
+            return (self.c6[[ci, cj]], self.c12[[ci, cj]]);
         }
+        let (ci_b, cj_b) = (self.atm_typeindex_b[i], self.atm_typeindex_b[j]);
+        (interpolate(self.c6[[ci, cj]], self.c6[[ci_b, cj_b]], self.fep_lambda),
+         interpolate(self.c12[[ci, cj]], self.c12[[ci_b, cj_b]], self.fep_lambda))
     }
+}
+
+// linear interpolation between A- and B-state parameters, as gromacs does
+fn interpolate(a: f64, b: f64, lambda: f64) -> f64 {
+    (1.0 - lambda) * a + lambda * b
 }
\ No newline at end of file
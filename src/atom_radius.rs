@@ -1,99 +1,182 @@
 use std::collections::HashMap;
-use std::env::current_exe;
 use std::fs;
+use std::path::Path;
 use crate::atom_property::AtomProperty;
-use crate::parse_tpr::TPR;
+use crate::error::{MmpbsaError, Result};
+use crate::element::{UNITED_C_MASS, get_element_symbol};
+use crate::settings::get_dat_dir;
 use indicatif::{ProgressBar, ProgressStyle};
 
+// kinds of radius types, radius sets other than ff are loaded from dat/
+pub const RADIUS_TYPES: [&str; 8] = ["ff", "amber", "Bondi", "mBondi", "mBondi2", "PARSE", "ZAP9", "Roux"];
+
+// radius type by index or name (case insensitive)
+pub fn get_radius_type(s: &str) -> Option<usize> {
+    match s.parse::<usize>() {
+        Ok(i) => Some(i).filter(|&i| i < RADIUS_TYPES.len()),
+        Err(_) => RADIUS_TYPES.iter().position(|t| t.eq_ignore_ascii_case(s))
+    }
+}
+
 impl AtomProperty {
     // ff_radius would not be used if radius_type not 0
-    pub fn apply_radius(&mut self, radius_type: usize, tpr: &TPR, total_at_num: usize, radius_types: &Vec<&str>) {
-        let mut idx = 0;
-        
-        let pb = ProgressBar::new(total_at_num as u64);
+    pub fn apply_radius(&mut self, radius_type: usize, radius_types: &Vec<&str>) -> Result<()> {
+        let pb = ProgressBar::new(self.atm_radius.len() as u64);
         pb.set_style(ProgressStyle::with_template(
             "[{elapsed_precise}] {bar:50.cyan/cyan} {percent}% {msg}").unwrap()
             .progress_chars("=>-"));
         match radius_type {
             0 => {
-                for mol in &tpr.molecules {
-                    if idx >= total_at_num {
-                        break;
-                    }
-                    for _ in 0..tpr.molecule_types[mol.molecule_type_id].molecules_num {
-                        if idx >= total_at_num {
-                            break;
-                        }
-                        else {
-                            for atom in &mol.atoms {
-                                if idx >= total_at_num {
-                                    break;
-                                } else {
-                                    self.atm_radius[idx] = atom.radius;
-                                    idx += 1;
-                                    pb.inc(1);
-                                    pb.set_message(format!("eta. {} s", pb.eta().as_secs()));
-                                }
-                            }
-                        }
-                    }
-                };
+                self.atm_radius = self.atm_radius_ff.clone();
+                self.atm_radius_rule.fill(String::from("ff"));
+                pb.inc(self.atm_radius.len() as u64);
             }
             _ => {
                 let mut radii_table: HashMap<&str, f64> = HashMap::new();
                 let rad_type = radius_types[radius_type];
-                let radii_file = current_exe().expect("Cannot get current s_mmpbsa program path.")
-                    .parent().expect("Cannot get current s_mmpbsa program directory.")
-                    .join("dat").join(format!("{}.dat", &rad_type))
-                    .to_str().expect("The atom radius data files (dat/) not found.").to_string();
-                let radii_file = fs::read_to_string(radii_file)
-                    .expect(format!("The atom radius data file not found: {}.dat", rad_type).as_str());
-                for l in radii_file.split("\n").filter(|p| !p.trim().starts_with("//")) {
+                let radii_file = get_dat_dir().join(format!("{}.dat", &rad_type)).to_string_lossy().to_string();
+                let radii = fs::read_to_string(&radii_file).map_err(MmpbsaError::io(&radii_file))?;
+                for l in radii.split("\n").filter(|p| !p.trim().starts_with("//") && p.contains(':')) {
                     let k_v: Vec<&str> = l.split(":").collect();
-                    radii_table.insert(k_v[0], k_v[1].trim().parse::<f64>().unwrap());
+                    let radius = k_v[1].trim().parse::<f64>()
+                        .map_err(|_| MmpbsaError::parse(&radii_file, format!("invalid radius: {}", l.trim())))?;
+                    radii_table.insert(k_v[0].trim(), radius);
                 }
 
-                for mol in &tpr.molecules {
-                    if idx >= total_at_num {
-                        break;
-                    }
-                    for _ in 0..tpr.molecule_types[mol.molecule_type_id].molecules_num {
-                        if idx >= total_at_num {
-                            break;
-                        }
-                        for atom in &mol.atoms {
-                            if idx >= total_at_num {
-                                break;
-                            }
-                            self.atm_radius[idx] = get_radii(&radii_table, &atom.name.as_str().to_uppercase());
-                            idx += 1;
-                            pb.inc(1);
-                            pb.set_message(format!("eta. {} s", pb.eta().as_secs()));
+                for idx in 0..self.atm_radius.len() {
+                    let resname = self.atm_resname[idx].to_uppercase();
+                    let name = self.atm_name[idx].to_uppercase();
+                    let (radius, rule) = match get_residue_radii(&radii_table, &resname, &name, self.atm_element[idx]) {
+                        Some(r) => r,
+                        None => match self.atm_element[idx] {
+                            0 => get_radii(&radii_table, &name),
+                            e => get_element_radii(&radii_table, e, self.atm_mass[idx], &self.atm_bonded_elements[idx])
                         }
-                    }
+                    };
+                    self.atm_radius[idx] = radius;
+                    self.atm_radius_rule[idx] = rule;
+                    pb.inc(1);
+                    pb.set_message(format!("eta. {} s", pb.eta().as_secs()));
                 }
             }
         }
         pb.finish();
+        Ok(())
     }
 }
 
-// get atom radius from dat
-pub fn get_radii(radii_table: &HashMap<&str, f64>, at_type: &str) -> f64 {
-    if at_type.len() >= 2 {
-        match radii_table.get(&at_type[0..2]) {
-            Some(&m) => m,
-            _ => {
-                match radii_table.get(&at_type[0..1]) {
-                    Some(&m) => m,
-                    _ => radii_table["*"]
-                }
+impl AtomProperty {
+    // radius and matched rule of each distinct (resname, atom name, atom type),
+    // with wildcard fallbacks and zero-LJ atoms using the default ff radius highlighted
+    pub fn write_radius_report(&self, rad_type: &str, rad_ff_default: f64, report: &Path) -> Result<()> {
+        let mut keys: Vec<(&str, &str, usize, &str)> = vec![];
+        let mut atoms: HashMap<(&str, &str, usize, &str), (usize, usize)> = HashMap::new();   // first atom, count
+        for idx in 0..self.atm_radius.len() {
+            let key = (self.atm_resname[idx].as_str(), self.atm_name[idx].as_str(),
+                self.atm_typeindex[idx], self.atm_radius_rule[idx].as_str());
+            atoms.entry(key).and_modify(|p| p.1 += 1).or_insert_with(|| {
+                keys.push(key);
+                (idx, 1)
+            });
+        }
+
+        let mut lines = String::new();
+        lines.push_str(&format!("Atom radius type: {}\n", rad_type));
+        lines.push_str(&format!("{:>9}{:>7}{:>7}{:>8}{:>9}{:>7}  {:<16}note\n", "resname", "name", "type", "element", "radius", "count", "rule"));
+        let mut warnings: Vec<String> = vec![];
+        for key in &keys {
+            let (idx, count) = atoms[key];
+            let t = self.atm_typeindex[idx];
+            let zero_lj = self.c6[[t, t]] == 0.0 || self.c12[[t, t]] == 0.0;
+            let note = if key.3 == "*" {
+                "wildcard fallback"
+            } else if key.3 == "ff" && zero_lj && self.atm_radius[idx] == rad_ff_default {
+                "zero LJ, default radius"
+            } else {
+                ""
+            };
+            let line = format!("{:>9}{:>7}{:7}{:>8}{:9.3}{:7}  {:<16}{}", key.0, key.1, key.2,
+                get_element_symbol(self.atm_element[idx]), self.atm_radius[idx], count, key.3, note).trim_end().to_string();
+            lines.push_str(&format!("{}\n", line));
+            if !note.is_empty() {
+                warnings.push(line);
+            }
+        }
+        fs::write(report, lines).map_err(MmpbsaError::io(report.to_string_lossy()))?;
+        println!("Radius of {} distinct atoms have been written to {}", keys.len(), report.display());
+        if !warnings.is_empty() {
+            println!("Warning: {} distinct atoms may have improper radius:", warnings.len());
+            println!("{:>9}{:>7}{:>7}{:>8}{:>9}{:>7}  {:<16}note", "resname", "name", "type", "element", "radius", "count", "rule");
+            for w in warnings {
+                println!("{}", w);
+            }
+        }
+        Ok(())
+    }
+}
+
+// get atom radius from dat by residue and atom name, e.g. "ALA CB" or "* CA" for any residue
+// "* name" only works for organic atoms, avoiding metal ions such as calcium named CA
+// returns radius and the matched key in dat
+pub fn get_residue_radii(radii_table: &HashMap<&str, f64>, resname: &str, name: &str, element: usize) -> Option<(f64, String)> {
+    let key = format!("{} {}", resname, name);
+    if let Some(&r) = radii_table.get(key.as_str()) {
+        return Some((r, key));
+    }
+    let key = format!("* {}", name);
+    match [1, 6, 7, 8, 15, 16].contains(&element) {
+        true => radii_table.get(key.as_str()).map(|&r| (r, key)),
+        false => None
+    }
+}
+
+// get atom radius from dat by element, hydrogen radius depends on the bonded atom (e.g. HN, HO)
+// and united-atom carbon (AMBER types C1, C2 and C3) uses CUA, as AMBER changeradii does
+pub fn get_element_radii(radii_table: &HashMap<&str, f64>, element: usize, mass: f64, bonded_elements: &Vec<usize>) -> (f64, String) {
+    if element == 6 && mass > UNITED_C_MASS {
+        if let Some(&r) = radii_table.get("CUA") {
+            return (r, String::from("CUA"));
+        }
+    }
+    if element == 1 {
+        if let Some(&e) = bonded_elements.first() {
+            let key = format!("H{}", get_element_symbol(e).to_uppercase());
+            if let Some(&r) = radii_table.get(key.as_str()) {
+                return (r, key);
             }
         }
-    } else {
-        match radii_table.get(at_type) {
-            Some(&m) => m,
-            _ => radii_table["*"]
+    }
+    let key = get_element_symbol(element).to_uppercase();
+    match radii_table.get(key.as_str()) {
+        Some(&r) => (r, key),
+        None => (radii_table["*"], String::from("*"))
+    }
+}
+
+// get atom radius from dat by atom name, for atoms without element
+pub fn get_radii(radii_table: &HashMap<&str, f64>, at_type: &str) -> (f64, String) {
+    for len in [2, 1] {
+        if at_type.len() >= len {
+            if let Some(&m) = radii_table.get(&at_type[0..len]) {
+                return (m, format!("{} (by name)", &at_type[0..len]));
+            }
         }
     }
+    (radii_table["*"], String::from("*"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn united_atom_carbon() {
+        let table: HashMap<&str, f64> = HashMap::from([("C", 1.7), ("CUA", 2.2), ("HC", 1.3), ("H", 1.2), ("*", 1.5)]);
+        assert_eq!(get_element_radii(&table, 6, 12.01, &vec![1, 6]), (1.7, String::from("C")));
+        assert_eq!(get_element_radii(&table, 6, 14.027, &vec![6, 6]), (2.2, String::from("CUA")));
+        assert_eq!(get_element_radii(&table, 1, 1.008, &vec![6]), (1.3, String::from("HC")));
+        // radius sets without united-atom carbon
+        let table: HashMap<&str, f64> = HashMap::from([("C", 1.87), ("*", 1.8)]);
+        assert_eq!(get_element_radii(&table, 6, 15.035, &vec![6]), (1.87, String::from("C")));
+    }
 }
@@ -0,0 +1,102 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use crate::error::{MmpbsaError, Result};
+
+// OpenDX scalar map on a regular grid, as written and read by APBS
+// data in x-major order: index = (i * ny + j) * nz + k
+pub struct DxGrid {
+    pub counts: [usize; 3],
+    pub origin: [f64; 3],
+    pub delta: [f64; 3],
+    pub data: Vec<f64>,
+}
+
+impl DxGrid {
+    pub fn from<T: AsRef<Path>>(file: T) -> Result<DxGrid> {
+        let file = file.as_ref().to_string_lossy().to_string();
+        let content = fs::read_to_string(&file).map_err(MmpbsaError::io(&file))?;
+        let parse_f64 = |v: &str| v.parse::<f64>().map_err(|_| MmpbsaError::parse(&file, format!("invalid value {}", v)));
+        let mut counts = [0; 3];
+        let mut origin = [0.0; 3];
+        let mut delta = [0.0; 3];
+        let mut n_delta = 0;
+        let mut data: Vec<f64> = vec![];
+        let mut in_data = false;
+        for line in content.lines().map(|l| l.trim()).filter(|l| !l.starts_with('#') && !l.is_empty()) {
+            if in_data {
+                if line.starts_with("attribute") || line.starts_with("object") {
+                    in_data = false;
+                    continue;
+                }
+                for v in line.split_whitespace() {
+                    data.push(parse_f64(v)?);
+                }
+            } else if line.starts_with("object") && line.contains("gridpositions") {
+                let v: Vec<usize> = line.split_whitespace().rev().take(3)
+                    .map(|v| v.parse().map_err(|_| MmpbsaError::parse(&file, format!("invalid counts: {}", line))))
+                    .collect::<Result<_>>()?;
+                counts = [v[2], v[1], v[0]];
+            } else if line.starts_with("origin") {
+                let v: Vec<f64> = line.split_whitespace().skip(1).map(parse_f64).collect::<Result<_>>()?;
+                if v.len() != 3 {
+                    return Err(MmpbsaError::parse(&file, format!("invalid origin: {}", line)));
+                }
+                origin = [v[0], v[1], v[2]];
+            } else if line.starts_with("delta") {
+                let v: Vec<f64> = line.split_whitespace().skip(1).map(parse_f64).collect::<Result<_>>()?;
+                if v.len() != 3 || n_delta > 2 {
+                    return Err(MmpbsaError::parse(&file, format!("invalid delta: {}", line)));
+                }
+                delta[n_delta] = v[n_delta];
+                n_delta += 1;
+            } else if line.starts_with("object") && line.contains("data follows") {
+                in_data = true;
+            }
+        }
+        if data.len() != counts[0] * counts[1] * counts[2] {
+            return Err(MmpbsaError::parse(&file, format!("{} values found, {} expected",
+                data.len(), counts[0] * counts[1] * counts[2])));
+        }
+        Ok(DxGrid { counts, origin, delta, data })
+    }
+
+    pub fn index(&self, i: usize, j: usize, k: usize) -> usize {
+        (i * self.counts[1] + j) * self.counts[2] + k
+    }
+
+    // coordinates of grid point
+    pub fn position(&self, i: usize, j: usize, k: usize) -> [f64; 3] {
+        [self.origin[0] + i as f64 * self.delta[0],
+         self.origin[1] + j as f64 * self.delta[1],
+         self.origin[2] + k as f64 * self.delta[2]]
+    }
+
+    pub fn write<T: AsRef<Path>>(&self, file: T, comment: &str) -> Result<()> {
+        let file = file.as_ref().to_string_lossy().to_string();
+        self.write_lines(&file, comment).map_err(MmpbsaError::io(&file))
+    }
+
+    fn write_lines(&self, file: &str, comment: &str) -> io::Result<()> {
+        let mut f = BufWriter::new(File::create(file)?);
+        let [nx, ny, nz] = self.counts;
+        writeln!(f, "# {}", comment)?;
+        writeln!(f, "object 1 class gridpositions counts {} {} {}", nx, ny, nz)?;
+        writeln!(f, "origin {:.6e} {:.6e} {:.6e}", self.origin[0], self.origin[1], self.origin[2])?;
+        writeln!(f, "delta {:.6e} {:.6e} {:.6e}", self.delta[0], 0.0, 0.0)?;
+        writeln!(f, "delta {:.6e} {:.6e} {:.6e}", 0.0, self.delta[1], 0.0)?;
+        writeln!(f, "delta {:.6e} {:.6e} {:.6e}", 0.0, 0.0, self.delta[2])?;
+        writeln!(f, "object 2 class gridconnections counts {} {} {}", nx, ny, nz)?;
+        writeln!(f, "object 3 class array type double rank 0 items {} data follows", self.data.len())?;
+        for chunk in self.data.chunks(3) {
+            let line: Vec<String> = chunk.iter().map(|v| format!("{:.6e}", v)).collect();
+            writeln!(f, "{}", line.join(" "))?;
+        }
+        writeln!(f, "attribute \"dep\" string \"positions\"")?;
+        writeln!(f, "object \"regular positions regular connections\" class field")?;
+        writeln!(f, "component \"positions\" value 1")?;
+        writeln!(f, "component \"connections\" value 2")?;
+        writeln!(f, "component \"data\" value 3")?;
+        f.flush()
+    }
+}
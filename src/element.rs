@@ -0,0 +1,117 @@
+use crate::parse_tpr::Molecule;
+
+// symbols and standard atomic weights of elements 1-86
+const ELEMENTS: [(&str, f64); 86] = [
+    ("H", 1.008), ("He", 4.0026), ("Li", 6.94), ("Be", 9.0122), ("B", 10.81),
+    ("C", 12.011), ("N", 14.007), ("O", 15.999), ("F", 18.998), ("Ne", 20.180),
+    ("Na", 22.990), ("Mg", 24.305), ("Al", 26.982), ("Si", 28.085), ("P", 30.974),
+    ("S", 32.06), ("Cl", 35.45), ("Ar", 39.948), ("K", 39.098), ("Ca", 40.078),
+    ("Sc", 44.956), ("Ti", 47.867), ("V", 50.942), ("Cr", 51.996), ("Mn", 54.938),
+    ("Fe", 55.845), ("Co", 58.933), ("Ni", 58.693), ("Cu", 63.546), ("Zn", 65.38),
+    ("Ga", 69.723), ("Ge", 72.630), ("As", 74.922), ("Se", 78.971), ("Br", 79.904),
+    ("Kr", 83.798), ("Rb", 85.468), ("Sr", 87.62), ("Y", 88.906), ("Zr", 91.224),
+    ("Nb", 92.906), ("Mo", 95.95), ("Tc", 98.0), ("Ru", 101.07), ("Rh", 102.91),
+    ("Pd", 106.42), ("Ag", 107.87), ("Cd", 112.41), ("In", 114.82), ("Sn", 118.71),
+    ("Sb", 121.76), ("Te", 127.60), ("I", 126.90), ("Xe", 131.29), ("Cs", 132.91),
+    ("Ba", 137.33), ("La", 138.91), ("Ce", 140.12), ("Pr", 140.91), ("Nd", 144.24),
+    ("Pm", 145.0), ("Sm", 150.36), ("Eu", 151.96), ("Gd", 157.25), ("Tb", 158.93),
+    ("Dy", 162.50), ("Ho", 164.93), ("Er", 167.26), ("Tm", 168.93), ("Yb", 173.05),
+    ("Lu", 174.97), ("Hf", 178.49), ("Ta", 180.95), ("W", 183.84), ("Re", 186.21),
+    ("Os", 190.23), ("Ir", 192.22), ("Pt", 195.08), ("Au", 196.97), ("Hg", 200.59),
+    ("Tl", 204.38), ("Pb", 207.2), ("Bi", 208.98), ("Po", 209.0), ("At", 210.0),
+    ("Rn", 222.0),
+];
+
+const MAX_H_MASS: f64 = 4.5;    // hydrogen may be heavier with hydrogen mass repartitioning
+
+// united-atom CH, CH2, CH3 and CH4 (e.g. GROMOS, OPLS-UA), CH2 and CH4 are close to nitrogen and oxygen
+const UNITED_CH_MASSES: [f64; 4] = [13.019, 14.027, 15.035, 16.043];
+pub const UNITED_C_MASS: f64 = 13.0;    // carbons heavier than this are united-atom groups
+
+// element symbol of atomic number, "X" for unknown
+pub fn get_element_symbol(atomic_number: usize) -> &'static str {
+    match atomic_number {
+        1..=86 => ELEMENTS[atomic_number - 1].0,
+        _ => "X"
+    }
+}
+
+// alkali, alkaline earth, transition, lanthanide and post-transition metals
+pub fn is_metal(atomic_number: usize) -> bool {
+    matches!(atomic_number, 3 | 4 | 11..=13 | 19..=31 | 37..=50 | 55..=83)
+}
+
+// atomic numbers of atoms in molecule, 0 for unknown (e.g. virtual sites)
+// use atomic numbers from topology if exist, otherwise guess by masses
+pub fn get_elements(mol: &Molecule) -> Vec<usize> {
+    let neighbors = get_neighbors(mol);
+    mol.atoms.iter().enumerate().map(|(i, atom)| {
+        if atom.atomic_number > 0 {
+            return atom.atomic_number as usize;
+        }
+        if atom.mass <= 0.0 {
+            return 0;
+        }
+        if atom.mass < MAX_H_MASS {
+            return 1;
+        }
+        // give back the mass repartitioned to bonded hydrogens
+        let mass = atom.mass + neighbors[i].iter()
+            .map(|&j| mol.atoms[j].mass)
+            .filter(|&m| m > 0.0 && m < MAX_H_MASS)
+            .map(|m| m - ELEMENTS[0].1).sum::<f64>();
+        guess_by_mass(mass).unwrap_or_else(|| guess_by_name(&atom.name))
+    }).collect()
+}
+
+// bonded atoms of each atom in molecule
+pub fn get_neighbors(mol: &Molecule) -> Vec<Vec<usize>> {
+    let mut neighbors: Vec<Vec<usize>> = vec![vec![]; mol.atoms_num];
+    for &(i, j) in &mol.bonds {
+        neighbors[i].push(j);
+        neighbors[j].push(i);
+    }
+    neighbors
+}
+
+// nearest element by mass, None if no element is close enough
+fn guess_by_mass(mass: f64) -> Option<usize> {
+    if UNITED_CH_MASSES.iter().any(|m| (m - mass).abs() < 0.005) {
+        return Some(6);
+    }
+    let (z, diff) = ELEMENTS.iter().enumerate()
+        .map(|(i, &(_, m))| (i + 1, (m - mass).abs()))
+        .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap()).unwrap();
+    match diff < 0.5 {
+        true => Some(z),
+        false => None
+    }
+}
+
+// two-letter then one-letter element symbol at the beginning of atom name
+fn guess_by_name(name: &str) -> usize {
+    let name: String = name.chars().filter(|c| c.is_alphabetic()).collect::<String>().to_uppercase();
+    for len in [2, 1] {
+        if name.len() >= len {
+            if let Some(i) = ELEMENTS.iter().position(|&(s, _)| s.to_uppercase() == name[0..len]) {
+                return i + 1;
+            }
+        }
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn united_atom_masses() {
+        assert_eq!(guess_by_mass(14.027), Some(6));
+        assert_eq!(guess_by_mass(16.043), Some(6));
+        assert_eq!(guess_by_mass(14.007), Some(7));
+        assert_eq!(guess_by_mass(14.01), Some(7));
+        assert_eq!(guess_by_mass(15.999), Some(8));
+        assert_eq!(guess_by_mass(12.011), Some(6));
+    }
+}
@@ -0,0 +1,65 @@
+use std::fmt;
+use std::fmt::Formatter;
+use std::io;
+use std::str::FromStr;
+use regex::{Captures, Regex};
+
+// errors of the engine, the interactive program reports them and exits
+#[derive(Debug)]
+pub enum MmpbsaError {
+    Io { path: String, source: io::Error },
+    Parse { file: String, msg: String },       // topology, index or settings file
+    Trajectory { file: String, msg: String },
+    Index(String),                              // atom or group selection
+    Apbs(String),                               // running APBS or reading its outputs
+}
+
+pub type Result<T> = std::result::Result<T, MmpbsaError>;
+
+impl MmpbsaError {
+    pub fn io<T: AsRef<str>>(path: T) -> impl FnOnce(io::Error) -> MmpbsaError {
+        let path = path.as_ref().to_string();
+        move |source| MmpbsaError::Io { path, source }
+    }
+
+    pub fn parse<T: AsRef<str>, M: AsRef<str>>(file: T, msg: M) -> MmpbsaError {
+        MmpbsaError::Parse { file: file.as_ref().to_string(), msg: msg.as_ref().to_string() }
+    }
+
+    pub fn trajectory<T: AsRef<str>, M: AsRef<str>>(file: T, msg: M) -> MmpbsaError {
+        MmpbsaError::Trajectory { file: file.as_ref().to_string(), msg: msg.as_ref().to_string() }
+    }
+}
+
+impl fmt::Display for MmpbsaError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            MmpbsaError::Io { path, source } => write!(f, "{}: {}", path, source),
+            MmpbsaError::Parse { file, msg } => write!(f, "failed to parse {}: {}", file, msg),
+            MmpbsaError::Trajectory { file, msg } => write!(f, "failed to read trajectory {}: {}", file, msg),
+            MmpbsaError::Index(msg) => write!(f, "invalid index: {}", msg),
+            MmpbsaError::Apbs(msg) => write!(f, "APBS failed: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for MmpbsaError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MmpbsaError::Io { source, .. } => Some(source),
+            _ => None
+        }
+    }
+}
+
+// group i of the match, parsed as T, or parse error of the line in file
+pub fn parse_cap<T: FromStr>(c: &Captures, i: usize, file: &str) -> Result<T> {
+    c.get(i).and_then(|m| m.as_str().trim().parse().ok())
+        .ok_or_else(|| MmpbsaError::parse(file, format!("unexpected line: {}", c.get(0).unwrap().as_str().trim())))
+}
+
+// group i of the first match in line, parsed as T
+pub fn get_cap<T: FromStr>(re: &Regex, line: &str, i: usize, file: &str) -> Result<T> {
+    let c = re.captures(line).ok_or_else(|| MmpbsaError::parse(file, format!("unexpected line: {}", line.trim())))?;
+    parse_cap(&c, i, file)
+}
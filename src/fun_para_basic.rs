@@ -1,10 +1,10 @@
 use std::io::stdin;
 use std::path::Path;
-use crate::settings::Settings;
-use crate::utils::get_input_selection;
+use s_mmpbsa::settings::Settings;
+use s_mmpbsa::utils::get_input_selection;
 use crate::{convert_cur_dir, confirm_file_validity, check_apbs};
 use crate::fun_para_trj::set_para_trj;
-use crate::parse_tpr::TPR;
+use s_mmpbsa::parse_tpr::TPR;
 
 pub fn set_para_basic(trj: &String, tpr: &mut TPR, ndx: &String, wd: &Path, tpr_name: &str, settings: &mut Settings) {
     let mut trj = String::from(trj);
@@ -19,7 +19,7 @@ pub fn set_para_basic(trj: &String, tpr: &mut TPR, ndx: &String, wd: &Path, tpr_
             None => String::from("Not set")
         });
         println!("  0 Go to next step");
-        println!("  1 Assign trajectory file (xtc or trr), current: {}", match trj.len() {
+        println!("  1 Assign trajectory file (xtc, trr, dcd or nc), current: {}", match trj.len() {
             0 => "undefined",
             _ => trj.as_str()
         });
@@ -57,7 +57,7 @@ pub fn set_para_basic(trj: &String, tpr: &mut TPR, ndx: &String, wd: &Path, tpr_
                     trj = "?md.xtc".to_string();
                 }
                 trj = convert_cur_dir(&trj, &settings);
-                trj = confirm_file_validity(&mut trj, vec!["xtc", "trr"], &settings);
+                trj = confirm_file_validity(&mut trj, vec!["xtc", "trr", "dcd", "nc", "ncdf"], &settings);
             }
             2 => {
                 println!("Input index file path, default: ?index.ndx (if in the same directory with tpr, then simply input (e.g.) `?index.ndx`):");
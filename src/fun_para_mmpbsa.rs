@@ -1,19 +1,29 @@
 use std::io::stdin;
 use std::path::Path;
-use crate::utils::{get_input, get_input_selection, append_new_name};
-use crate::index_parser::{Index, IndexGroup};
-use crate::settings::Settings;
-use crate::apbs_param::{PBASet, PBESet};
+use crate::exit_on_error;
+use s_mmpbsa::utils::{get_input, get_input_selection, append_new_name};
+use s_mmpbsa::index_parser::{Index, IndexGroup, normalize_index};
+use s_mmpbsa::settings::{Settings, get_dat_dir};
+use s_mmpbsa::apbs_param::{PBASet, PBESet};
 use std::io::Write;
 use std::fs::{File, self};
-use std::cmp::Ordering;
-use crate::atom_property::AtomProperty;
-use crate::parse_tpr::TPR;
-use crate::mmpbsa::{self, get_residues};
-use crate::analyzation;
-use crate::utils::{convert_tpr, trjconv};
+use s_mmpbsa::atom_property::AtomProperty;
+use s_mmpbsa::atom_radius::{RADIUS_TYPES, get_radius_type};
+use s_mmpbsa::atom_override::{load_overrides, write_override_template};
+use s_mmpbsa::membrane::MembraneSet;
+use s_mmpbsa::pot_maps::write_pot_maps;
+use s_mmpbsa::apbs_export::{APBS_MODES, MANIFEST_NAME, ApbsManifest, export_apbs_inputs};
+use s_mmpbsa::metal_site::{METAL_SITES, get_metal_site, assign_metals};
+use s_mmpbsa::parse_tpr::TPR;
+use s_mmpbsa::mmpbsa::{self, get_residues};
+use s_mmpbsa::analyzation::{self, RunInfo};
+use s_mmpbsa::utils::{convert_tpr, trjconv};
+use s_mmpbsa::trajectory::{read_trajectory, write_xtc};
+use std::rc::Rc;
+use xdrfile::Frame;
 
-pub fn set_para_mmpbsa(trj: &String, tpr: &mut TPR, ndx: &Index, wd: &Path,
+// frames: trajectory already read for time information of non-gromacs topology
+pub fn set_para_mmpbsa(trj: &String, frames: Option<&Vec<Rc<Frame>>>, tpr: &mut TPR, ndx: &Index, wd: &Path,
                        tpr_name: &str, ndx_name: &str,
                        receptor_grp: usize, ligand_grp: Option<usize>,
                        bt: f64, et: f64, dt: f64, settings: &mut Settings) {
@@ -44,101 +54,132 @@ pub fn set_para_mmpbsa(trj: &String, tpr: &mut TPR, ndx: &Index, wd: &Path,
         
     // atom properties
     println!("Parsing atom properties...");
-    let mut aps = AtomProperty::new(tpr, &ndx_com);
+    let mut aps = exit_on_error(AtomProperty::new(tpr, &ndx_com, settings.fep_lambda));
     println!("Collecting residues list...");
     let residues = get_residues(tpr, &ndx_com);
 
+    let ndx_com_sys = ndx_com.to_vec();     // system atom indexes of complex
+
     // pre-treat trajectory: fix pbc
     println!("Extracting trajectory...");
 
-    let trj_whole = append_new_name(trj, "_whole.xtc", "_MMPBSA_"); // get trj output file name
-    let trj_center = append_new_name(trj, "_center.xtc", "_MMPBSA_");
-    let trj_cluster = append_new_name(trj, "_cluster.xtc", "_MMPBSA_");
-    let trj_mmpbsa = append_new_name(trj, ".xtc", "_MMPBSA_");
-    let tpr_name = append_new_name(tpr_name, ".tpr", "");       // fuck the tpr name is dump
+    let (trj_mmpbsa, ndx_com, ndx_rec, ndx_lig) = match tpr_name.ends_with(".dump") {
+        true => {
+            let trj_whole = append_new_name(trj, "_whole.xtc", "_MMPBSA_"); // get trj output file name
+            let trj_center = append_new_name(trj, "_center.xtc", "_MMPBSA_");
+            let trj_cluster = append_new_name(trj, "_cluster.xtc", "_MMPBSA_");
+            let trj_mmpbsa = append_new_name(trj, ".xtc", "_MMPBSA_");
+            let tpr_name = append_new_name(tpr_name, ".tpr", "");       // fuck the tpr name is dump
     
-    // add a Complex group to index file
-    let com_group = IndexGroup::new("Complex", &ndx_com);
-    let mut new_ndx = ndx.clone();
-    new_ndx.rm_group("Complex");
-    new_ndx.push(&com_group);
-    let ndx_whole = append_new_name(ndx_name, "_whole.ndx", "_MMPBSA_"); // get extracted index file name
-    new_ndx.to_ndx(&ndx_whole);
+            // add a Complex group to index file
+            let com_group = IndexGroup::new("Complex", &ndx_com);
+            let mut new_ndx = ndx.clone();
+            new_ndx.rm_group("Complex");
+            new_ndx.push(&com_group);
+            let ndx_whole = append_new_name(ndx_name, "_whole.ndx", "_MMPBSA_"); // get extracted index file name
+            exit_on_error(new_ndx.to_ndx(&ndx_whole));
     
-    // echo "Complex" | gmx trjconv -f md.xtc -s md.tpr -n index.idx -o md_trj_whole.xtc -pbc whole
-    trjconv("Complex", wd, settings, trj, &tpr_name, &ndx_whole, &trj_whole, &["-pbc", "whole"], settings.debug_mode);
-    // echo "Complex" | gmx convert-tpr -s md.tpr -n index.idx -o md_trj_com.tpr
-    let tpr_mmpbsa = append_new_name(&tpr_name, ".tpr", "_MMPBSA_"); // get extracted tpr file name
-    convert_tpr("Complex", wd, settings, &tpr_name, &ndx_whole, &tpr_mmpbsa, settings.debug_mode);
-    if !settings.debug_mode {
-        fs::remove_file(&ndx_whole).unwrap();
-    }
+            // echo "Complex" | gmx trjconv -f md.xtc -s md.tpr -n index.idx -o md_trj_whole.xtc -pbc whole
+            trjconv("Complex", wd, settings, trj, &tpr_name, &ndx_whole, &trj_whole, &["-pbc", "whole"], settings.debug_mode);
+            // echo "Complex" | gmx convert-tpr -s md.tpr -n index.idx -o md_trj_com.tpr
+            let tpr_mmpbsa = append_new_name(&tpr_name, ".tpr", "_MMPBSA_"); // get extracted tpr file name
+            convert_tpr("Complex", wd, settings, &tpr_name, &ndx_whole, &tpr_mmpbsa, settings.debug_mode);
+            if !settings.debug_mode {
+                fs::remove_file(&ndx_whole).unwrap();
+            }
 
-    // Index normalization
-    let (ndx_com, ndx_rec, ndx_lig) = normalize_index(ndx_rec, ndx_lig);
+            // Index normalization
+            let (ndx_com, ndx_rec, ndx_lig) = normalize_index(ndx_rec, ndx_lig);
 
-    // extract index file
-    let ndx_mmpbsa = match ligand_grp {
-        Some(ligand_grp) => {
-            Index::new(vec![
-                IndexGroup::new("Complex", &ndx_com), 
-                IndexGroup::new(&ndx.groups[receptor_grp].name, &ndx_rec),
-                IndexGroup::new(&ndx.groups[ligand_grp].name, &ndx_lig)
-            ])
-        },
-        None => {
-            Index::new(vec![IndexGroup::new("Receptor", &ndx_com)])
-        }
-    };
-    ndx_mmpbsa.to_ndx(Path::new(wd).join("_MMPBSA_index.ndx").to_str().unwrap());
-    let ndx_mmpbsa = Path::new(wd).join("_MMPBSA_index.ndx");
-    let ndx_mmpbsa = ndx_mmpbsa.to_str().unwrap();
+            // extract index file
+            let ndx_mmpbsa = match ligand_grp {
+                Some(ligand_grp) => {
+                    Index::new(vec![
+                        IndexGroup::new("Complex", &ndx_com), 
+                        IndexGroup::new(&ndx.groups[receptor_grp].name, &ndx_rec),
+                        IndexGroup::new(&ndx.groups[ligand_grp].name, &ndx_lig)
+                    ])
+                },
+                None => {
+                    Index::new(vec![IndexGroup::new("Receptor", &ndx_com)])
+                }
+            };
+            exit_on_error(ndx_mmpbsa.to_ndx(Path::new(wd).join("_MMPBSA_index.ndx").to_str().unwrap()));
+            let ndx_mmpbsa = Path::new(wd).join("_MMPBSA_index.ndx");
+            let ndx_mmpbsa = ndx_mmpbsa.to_str().unwrap();
 
-    let trj_mmpbsa = if settings.fix_pbc {
-        println!("Fixing PBC conditions...");
-        match ligand_grp {
-            Some(ligand_grp) => {
-                println!("Fixing PBC 0/3...");
-                // echo -e "$lig\n$com" | $trjconv  -s $tpx -n $idx -f $trjwho -o $pdb    &>>$err -pbc mol -center
-                trjconv(&(ndx.groups[ligand_grp].name.to_owned() + " Complex"),
-                    wd, settings, &trj_whole, &tpr_mmpbsa, &ndx_mmpbsa, &trj_center, &["-pbc", "mol", "-center"], settings.debug_mode);
-                println!("Fixing PBC 1/3...");
-                // echo -e "$com\n$com" | $trjconv  -s $tpx -n $idx -f $trjcnt -o $trjcls &>>$err -pbc cluster
-                trjconv("Complex Complex",
-                    wd, settings, &trj_center, &tpr_mmpbsa, &ndx_mmpbsa, &trj_cluster, &["-pbc", "cluster"], settings.debug_mode);
-                println!("Fixing PBC 2/3...");
-                // echo -e "$lig\n$com" | $trjconv  -s $tpx -n $idx -f $trjcls -o $pdb    &>>$err -fit rot+trans
-                trjconv("1 0",
-                    wd, settings, &trj_cluster, &tpr_mmpbsa, &ndx_mmpbsa, &trj_mmpbsa, &["-fit", "rot+trans"], settings.debug_mode);
+            let trj_mmpbsa = if settings.fix_pbc {
+                println!("Fixing PBC conditions...");
+                match ligand_grp {
+                    Some(ligand_grp) => {
+                        println!("Fixing PBC 0/3...");
+                        // echo -e "$lig\n$com" | $trjconv  -s $tpx -n $idx -f $trjwho -o $pdb    &>>$err -pbc mol -center
+                        trjconv(&(ndx.groups[ligand_grp].name.to_owned() + " Complex"),
+                            wd, settings, &trj_whole, &tpr_mmpbsa, &ndx_mmpbsa, &trj_center, &["-pbc", "mol", "-center"], settings.debug_mode);
+                        println!("Fixing PBC 1/3...");
+                        // echo -e "$com\n$com" | $trjconv  -s $tpx -n $idx -f $trjcnt -o $trjcls &>>$err -pbc cluster
+                        trjconv("Complex Complex",
+                            wd, settings, &trj_center, &tpr_mmpbsa, &ndx_mmpbsa, &trj_cluster, &["-pbc", "cluster"], settings.debug_mode);
+                        println!("Fixing PBC 2/3...");
+                        // echo -e "$lig\n$com" | $trjconv  -s $tpx -n $idx -f $trjcls -o $pdb    &>>$err -fit rot+trans
+                        trjconv("1 0",
+                            wd, settings, &trj_cluster, &tpr_mmpbsa, &ndx_mmpbsa, &trj_mmpbsa, &["-fit", "rot+trans"], settings.debug_mode);
+                        if !settings.debug_mode {
+                            fs::remove_file(&trj_center).unwrap();
+                            fs::remove_file(&trj_cluster).unwrap();
+                        }
+                    },
+                    None => {
+                        // echo -e "$lig\n$com" | $trjconv  -s $tpx -n $idx -f $trjwho -o $trjcnt &>>$err -pbc mol -center
+                        println!("Fixing PBC 0/1...");
+                        trjconv("0 0 0", 
+                            wd, settings, &trj_whole, &tpr_mmpbsa, &ndx_mmpbsa, &trj_mmpbsa, &["-pbc", "mol", "-center", "-fit", "rot+trans"], settings.debug_mode);
+                    }
+                }
                 if !settings.debug_mode {
-                    fs::remove_file(&trj_center).unwrap();
-                    fs::remove_file(&trj_cluster).unwrap();
+                    fs::remove_file(&trj_whole).unwrap();
                 }
-            },
-            None => {
-                // echo -e "$lig\n$com" | $trjconv  -s $tpx -n $idx -f $trjwho -o $trjcnt &>>$err -pbc mol -center
-                println!("Fixing PBC 0/1...");
-                trjconv("0 0 0", 
-                    wd, settings, &trj_whole, &tpr_mmpbsa, &ndx_mmpbsa, &trj_mmpbsa, &["-pbc", "mol", "-center", "-fit", "rot+trans"], settings.debug_mode);
+                trj_mmpbsa
+            } else {
+                trj_whole
+            };
+            if !settings.debug_mode {
+                fs::remove_file(&tpr_mmpbsa).unwrap();
+                fs::remove_file(&ndx_mmpbsa).unwrap();
             }
+            println!("Fixing PBC finished.");
+            (trj_mmpbsa, ndx_com, ndx_rec, ndx_lig)
         }
-        if !settings.debug_mode {
-            fs::remove_file(&trj_whole).unwrap();
+        false => {
+            // non-gromacs topology, trajectory should be PBC-fixed by other programs (e.g. cpptraj, VMD)
+            if settings.fix_pbc {
+                println!("Note: fixing PBC needs gromacs topology, trajectory will be used as is.");
+            }
+            let trj_mmpbsa = append_new_name(trj, ".xtc", "_MMPBSA_");
+            let frames = match frames {
+                Some(frames) => frames.to_vec(),
+                None => exit_on_error(read_trajectory(trj))
+            };
+            exit_on_error(write_xtc(&frames, &ndx_com, &trj_mmpbsa));
+            // Index normalization
+            let (ndx_com, ndx_rec, ndx_lig) = normalize_index(ndx_rec, ndx_lig);
+            (trj_mmpbsa, ndx_com, ndx_rec, ndx_lig)
         }
-        trj_mmpbsa
-    } else {
-        trj_whole
     };
-    if !settings.debug_mode {
-        fs::remove_file(&tpr_mmpbsa).unwrap();
-        fs::remove_file(&ndx_mmpbsa).unwrap();
-    }
-    println!("Fixing PBC finished.");
     
     // kinds of radius types
-    let radius_types = vec!["ff", "amber", "Bondi", "mBondi", "mBondi2"];
+    let radius_types = RADIUS_TYPES.to_vec();
     let mut pbe_set = PBESet::new(tpr.temp);
+    // screened force fields (e.g., epsilon-r = 15 for Martini) use the same dielectric for solute in MM and PB
+    if tpr.epsilon_r > 1.0 {
+        pbe_set.pdie = tpr.epsilon_r;
+        println!("Note: epsilon-r = {} of simulation will be used as solute dielectric (pdie).", tpr.epsilon_r);
+    }
     let mut pba_set = PBASet::new(tpr.temp);
+    let mut mem_set: Option<MembraneSet> = None;
+    let mut apbs_mode = 0;
+    let mut ingest_dir = String::new();
+    let mut map_time: Option<f64> = None;
     loop {
         println!("\n                 ************ MM/PB-SA Parameters ************");
         println!("-10 Return");
@@ -156,6 +197,32 @@ pub fn set_para_mmpbsa(trj: &String, tpr: &mut TPR, ndx: &Index, wd: &Path,
         println!("  8 Prepare PB parameters for APBS");
         println!("  9 Prepare SA parameters for APBS");
         println!(" 10 Toggle whether to do alanine scanning, current: {}", settings.if_alanine_scanning);
+        println!(" 11 Select state of free energy topology, current: {}", show_fep_state(settings.fep_lambda));
+        println!(" 12 Set atom radius and charge override file, current: {}", match settings.atom_override.len() {
+            0 => "None",
+            _ => settings.atom_override.as_str()
+        });
+        println!(" 13 Set metal site handling, current: metals in {}, radius: {}", METAL_SITES[settings.metal_site],
+            match settings.metal_radius.len() {
+                0 => radius_types[settings.rad_type],
+                _ => settings.metal_radius.as_str()
+            });
+        println!(" 14 Prepare implicit membrane parameters, current: {}", match &mem_set {
+            Some(mem_set) => match mem_set.auto_detect {
+                true => format!("mdie = {}, auto detected from lipids", mem_set.mdie),
+                false => format!("mdie = {}, thickness = {} A, center = {} A", mem_set.mdie, mem_set.thickness, mem_set.center)
+            },
+            None => String::from("None")
+        });
+        println!(" 15 Set APBS multigrid levels and memory limit, current: nlev = {}, {} MB per process", settings.nlev, settings.max_mem);
+        println!(" 16 Select APBS execution mode, current: {}", match apbs_mode {
+            2 => format!("ingest outputs from {}", ingest_dir),
+            _ => APBS_MODES[apbs_mode].to_string()
+        });
+        println!(" 17 Write potential and dielectric maps of a frame, current: {}", match map_time {
+            Some(t) => format!("at {} ns", t),
+            None => "no".to_string()
+        });
         let i = get_input_selection();
         match i {
             -10 => return,
@@ -173,6 +240,10 @@ pub fn set_para_mmpbsa(trj: &String, tpr: &mut TPR, ndx: &Index, wd: &Path,
                     }
                 }
                 paras.write_all(format!("Atom radius type: {}\n", radius_types[settings.rad_type]).as_bytes()).unwrap();
+                paras.write_all(format!("Topology state: {}\n", show_fep_state(settings.fep_lambda)).as_bytes()).unwrap();
+                paras.write_all(format!("Atom override file: {}\n", settings.atom_override).as_bytes()).unwrap();
+                paras.write_all(format!("Metal site: {}, metal radius: {}\n", METAL_SITES[settings.metal_site],
+                    settings.metal_radius).as_bytes()).unwrap();
                 paras.write_all(format!("Atoms:\n     id   name   type   charge   radius   resnum  resname\n").as_bytes()).unwrap();
                 for idx in 0..ndx_com.len() {
                     paras.write_all(format!("{:7}{:>7}{:7}{:9.2}{:9.2}{:9}{:>9}\n", 
@@ -203,20 +274,94 @@ pub fn set_para_mmpbsa(trj: &String, tpr: &mut TPR, ndx: &Index, wd: &Path,
             }
             -3 => {
                 let mut paras = File::create(wd.join("paras_pbsa.txt")).unwrap();
+                paras.write_all("# gamma of SA settings is the surface tension (kJ/mol-A^2) applied to SASA by s_mmpbsa,\n\
+                    # APBS is always run with gamma = 1; SA files of older versions with gamma = 1 use 0.030125\n\n".as_bytes()).unwrap();
                 paras.write_all(format!("Use Debye-Huckel shielding method: {}\n", settings.use_dh).as_bytes()).unwrap();
                 paras.write_all(format!("Use entropy contribution: {}\n", settings.use_ts).as_bytes()).unwrap();
                 paras.write_all(format!("Atom radius type: {}\n", radius_types[settings.rad_type]).as_bytes()).unwrap();
                 paras.write_all(format!("Atom distance cutoff for MM calculation (A): {}\n", settings.r_cutoff).as_bytes()).unwrap();
                 paras.write_all(format!("Coarse grid expand factor (cfac): {}\n", settings.cfac).as_bytes()).unwrap();
                 paras.write_all(format!("Fine grid expand amount (fadd): {} A\n", settings.fadd).as_bytes()).unwrap();
-                paras.write_all(format!("Fine mesh spacing (df): {} A\n\n", settings.df).as_bytes()).unwrap();
+                paras.write_all(format!("Fine mesh spacing (df): {} A\n", settings.df).as_bytes()).unwrap();
+                paras.write_all(format!("Multigrid levels (nlev): {}\n", settings.nlev).as_bytes()).unwrap();
+                paras.write_all(format!("Memory limit per APBS process: {} MB\n", settings.max_mem).as_bytes()).unwrap();
+                paras.write_all(format!("APBS execution mode: {}\n\n", APBS_MODES[apbs_mode]).as_bytes()).unwrap();
+                paras.write_all(format!("Simulation reference temperature (K): {:?}\n", tpr.ref_t).as_bytes()).unwrap();
+                paras.write_all(format!("Simulation electrostatics: {}, rcoulomb = {} nm, epsilon-r = {}, fudgeQQ = {}\n\n", 
+                    tpr.coulombtype, tpr.rcoulomb, tpr.epsilon_r, tpr.fudge_qq).as_bytes()).unwrap();
                 paras.write_all(format!("PB settings:\n{}\n\n", pbe_set).as_bytes()).unwrap();
                 paras.write_all(format!("SA settings:\n{}\n", pba_set).as_bytes()).unwrap();
+                paras.write_all(format!("SA constant (bias): {} kJ/mol\n", pba_set.bias).as_bytes()).unwrap();
+                if let Some(mem_set) = &mem_set {
+                    paras.write_all(format!("\nMembrane settings:\n{}\n", mem_set).as_bytes()).unwrap();
+                }
                 println!("PBSA parameters have been written to paras_pbsa.txt");
             }
             0 => {
+                // mode may be chosen before the membrane
+                if apbs_mode != 0 && mem_set.is_some() {
+                    println!("Membrane maps are drawn between APBS runs, {} mode is not supported with membrane.", APBS_MODES[apbs_mode]);
+                    continue;
+                }
                 println!("Applying {} radius...", radius_types[settings.rad_type]);
-                aps.apply_radius(settings.rad_type, tpr, ndx_com.len(), &radius_types);
+                exit_on_error(aps.apply_radius(settings.rad_type, &radius_types));
+                if !settings.metal_radius.is_empty() {
+                    exit_on_error(aps.apply_metal_radius(&settings.metal_radius));
+                }
+                exit_on_error(aps.write_radius_report(radius_types[settings.rad_type], settings.rad_ff_default, &wd.join("radius_report.txt")));
+                // overrides only work on this calculation, keep topology charges for later ones
+                let mut aps = aps.clone();
+                if !settings.atom_override.is_empty() {
+                    println!("Applying atom overrides from {}...", settings.atom_override);
+                    let overrides = exit_on_error(load_overrides(&settings.atom_override));
+                    exit_on_error(aps.apply_overrides(&overrides, &ndx_com_sys, &wd.join("atom_override_report.txt")));
+                }
+                // metal site
+                let metals = aps.get_metals();
+                let (ndx_rec, ndx_lig) = assign_metals(&metals, &ndx_rec, &ndx_lig, settings.metal_site);
+                if !metals.is_empty() {
+                    exit_on_error(aps.write_metal_report(&metals, &ndx_rec, &ndx_com_sys, &wd.join("metal_site_report.txt")));
+                }
+
+                // membrane
+                let mut mem_set = mem_set.clone();
+                if let Some(mem_set) = &mut mem_set {
+                    if mem_set.auto_detect {
+                        println!("Detecting membrane from lipids...");
+                        exit_on_error(mem_set.detect(tpr, trj, &trj_mmpbsa, &ndx_com_sys));
+                    }
+                    let lipids = aps.atm_resname.iter().filter(|r| mem_set.is_lipid(r)).count();
+                    if lipids > 0 {
+                        println!("{} lipid atoms in complex will be excluded from SA.", lipids);
+                    }
+                }
+
+                let mut info = RunInfo::new(tpr_name, trj, ndx_name, &ndx.groups[receptor_grp].name,
+                                            ligand_grp.map(|l| ndx.groups[l].name.as_str()), radius_types[settings.rad_type],
+                                            &pbe_set, &pba_set, mem_set.as_ref(), settings);
+
+                // ingest outputs of apbs run elsewhere, with the exported system name and grids
+                if apbs_mode == 2 {
+                    let manifest = exit_on_error(ApbsManifest::load(&ingest_dir));
+                    if let Err(e) = manifest.check_radius(settings) {
+                        println!("Error: {}", e);
+                        continue;
+                    }
+                    let mut settings = settings.clone();
+                    manifest.apply(&mut settings);
+                    info.settings = settings.clone();
+                    info.mem_set = None;
+                    let results = exit_on_error(mmpbsa::fun_mmpbsa_calculations(&trj_mmpbsa, &Path::new(&ingest_dir).to_path_buf(),
+                                                                    &manifest.system, &aps,
+                                                                    &ndx_com, &ndx_rec, &ndx_lig, &residues,
+                                                                    bt, et, dt, &pbe_set, &pba_set, None, Some(&manifest), &settings));
+                    info.finish();
+                    if !settings.debug_mode {
+                        fs::remove_file(&trj_mmpbsa).unwrap();
+                    }
+                    analyzation::analyze_controller(&results, pbe_set.temp, &manifest.system, wd, &info, &settings);
+                    continue;
+                }
 
                 // Temp directory for PBSA
                 let mut sys_name = String::from("_system");
@@ -227,7 +372,7 @@ pub fn set_para_mmpbsa(trj: &String, tpr: &mut TPR, ndx: &Index, wd: &Path,
                     sys_name = input.trim().to_string();
                 }
                 let temp_dir = wd.join(&sys_name);
-                if let Some(_) = settings.apbs.as_ref() {
+                if settings.apbs.is_some() || apbs_mode == 1 {
                     println!("Temporary files will be placed at {}/", temp_dir.display());
                     if !temp_dir.is_dir() {
                         fs::create_dir(&temp_dir).expect(format!("Failed to create temp directory: {}.", &sys_name).as_str());
@@ -243,14 +388,30 @@ pub fn set_para_mmpbsa(trj: &String, tpr: &mut TPR, ndx: &Index, wd: &Path,
                 } else {
                     println!("Note: Since APBS not found, solvation energy will not be calculated.");
                 };
-                let results = mmpbsa::fun_mmpbsa_calculations(&trj_mmpbsa, &temp_dir, &sys_name, &aps,
+                // write apbs inputs to be run elsewhere, then stop
+                if apbs_mode == 1 {
+                    exit_on_error(export_apbs_inputs(&trj_mmpbsa, &temp_dir, &sys_name, &aps, &ndx_com, &ndx_rec, &ndx_lig,
+                                                     bt, et, dt, &pbe_set, &pba_set, settings));
+                    if !settings.debug_mode {
+                        fs::remove_file(&trj_mmpbsa).unwrap();
+                    }
+                    return;
+                }
+                if let Some(t) = map_time {
+                    if let Err(e) = write_pot_maps(&trj_mmpbsa, wd, &sys_name, &aps, &ndx_com, &ndx_rec, &ndx_lig, t,
+                                                   &pbe_set, &pba_set, mem_set.as_ref(), settings) {
+                        println!("Warning: potential maps not written, {}", e);
+                    }
+                }
+                let results = exit_on_error(mmpbsa::fun_mmpbsa_calculations(&trj_mmpbsa, &temp_dir, &sys_name, &aps,
                                                                 &ndx_com, &ndx_rec, &ndx_lig, &residues,
-                                                                bt, et, dt, &pbe_set, &pba_set, settings);
+                                                                bt, et, dt, &pbe_set, &pba_set, mem_set.as_ref(), None, settings));
+                info.finish();
                 // Clean trj
                 if !settings.debug_mode {
                     fs::remove_file(&trj_mmpbsa).unwrap();
                 }
-                analyzation::analyze_controller(&results, pbe_set.temp, &sys_name, wd, ndx_com.len(), settings);
+                analyzation::analyze_controller(&results, pbe_set.temp, &sys_name, wd, &info, settings);
             }
             1 => {
                 settings.use_dh = !settings.use_dh;
@@ -259,7 +420,7 @@ pub fn set_para_mmpbsa(trj: &String, tpr: &mut TPR, ndx: &Index, wd: &Path,
                 settings.use_ts = !settings.use_ts;
             }
             3 => {
-                println!("Input atom radius type (default mBondi), Supported:{}", {
+                println!("Input atom radius type or its name (default mBondi), Supported:{}", {
                     let mut s = String::new();
                     for (k, v) in radius_types.iter().enumerate() {
                         s.push_str(format!("\n{}):\t{}", k, v).as_str());
@@ -271,14 +432,12 @@ pub fn set_para_mmpbsa(trj: &String, tpr: &mut TPR, ndx: &Index, wd: &Path,
                 if s.trim().is_empty() {
                     settings.rad_type = 3;
                 } else {
-                    let s = s.trim().parse().expect("Input not valid number.");
-                    if s == 0 {
-                        settings.rad_type = 0;
-                    } else if s < radius_types.len() {
-                        settings.rad_type = s;
-                    } else {
-                        println!("Radius type {} not supported. Will use mBondi instead.", radius_types[s]);
-                        settings.rad_type = 3;
+                    match get_radius_type(s.trim()) {
+                        Some(t) => settings.rad_type = t,
+                        None => {
+                            println!("Radius type {} not supported. Will use mBondi instead.", s.trim());
+                            settings.rad_type = 3;
+                        }
                     }
                 }
             }
@@ -327,54 +486,179 @@ pub fn set_para_mmpbsa(trj: &String, tpr: &mut TPR, ndx: &Index, wd: &Path,
             }
             8 => {
                 let pb_fpath = wd.join("PB_settings.yaml");
-                pbe_set.save_params(&pb_fpath);
+                if let Err(e) = pbe_set.save_params(&pb_fpath) {
+                    println!("Error: {}", e);
+                    continue;
+                }
                 println!("PB parameters have been wrote to {0}.\n\
                     Edit it and input its path to reload (default: {0}).", &pb_fpath.to_str().unwrap());
                 let pb_fpath = get_input(pb_fpath.to_str().unwrap().to_string());
-                pbe_set = PBESet::load_params(pb_fpath);
+                match PBESet::load_params(pb_fpath) {
+                    Ok(params) => pbe_set = params,
+                    Err(e) => println!("Error: {}, PB parameters not changed.", e)
+                }
             }
             9 => {
                 let sa_fpath = wd.join("SA_settings.yaml");
-                pba_set.save_params(&sa_fpath);
+                if let Err(e) = pba_set.save_params(&sa_fpath) {
+                    println!("Error: {}", e);
+                    continue;
+                }
                 println!("SA parameters have been wrote to {0}.\n\
                     Edit it and input its path to reload (default: {0}).", &sa_fpath.to_str().unwrap());
                 let sa_fpath = get_input(sa_fpath.to_str().unwrap().to_string());
-                pba_set = PBASet::load_params(sa_fpath);
+                match PBASet::load_params(sa_fpath) {
+                    Ok(params) => pba_set = params,
+                    Err(e) => println!("Error: {}, SA parameters not changed.", e)
+                }
             }
             10 => {
                 println!("We will proceed with the alanine scanning proposal\nput forward by the Chinese representative.");
                 settings.if_alanine_scanning = !settings.if_alanine_scanning;
             }
+            11 => {
+                println!("Input state of free energy topology, A, B or lambda value in [0, 1] (default: A):");
+                let s: String = get_input(String::from("A"));
+                let fep_lambda = match s.as_str() {
+                    "A" | "a" => Some(0.0),
+                    "B" | "b" => Some(1.0),
+                    _ => s.parse().ok().filter(|l| (0.0..=1.0).contains(l))
+                };
+                match fep_lambda {
+                    Some(fep_lambda) => {
+                        println!("Re-parsing atom properties at {}...", show_fep_state(fep_lambda));
+                        match AtomProperty::new(tpr, &ndx_com_sys, fep_lambda) {
+                            Ok(new_aps) => {
+                                aps = new_aps;
+                                settings.fep_lambda = fep_lambda;
+                            }
+                            Err(e) => println!("Error: {}", e)
+                        }
+                    }
+                    None => println!("Invalid state: {}", s)
+                }
+            }
+            12 => {
+                let override_fpath = wd.join("atom_override.yaml");
+                if !override_fpath.is_file() {
+                    if let Err(e) = write_override_template(&override_fpath) {
+                        println!("Error: {}", e);
+                        continue;
+                    }
+                    println!("Atom override template has been wrote to {}.", override_fpath.to_str().unwrap());
+                }
+                println!("Edit the override file and input its path (default: {}, input \"n\" to disable):",
+                    override_fpath.to_str().unwrap());
+                let s: String = get_input(override_fpath.to_str().unwrap().to_string());
+                if s == "n" || s == "N" {
+                    settings.atom_override.clear();
+                } else if Path::new(&s).is_file() {
+                    settings.atom_override = s;
+                } else {
+                    println!("Not file: {}", s);
+                }
+            }
+            13 => {
+                println!("Input group of metal atoms (default: index), Supported:{}", {
+                    let mut s = String::new();
+                    for (k, v) in METAL_SITES.iter().enumerate() {
+                        s.push_str(format!("\n{}):\t{}", k, v).as_str());
+                    }
+                    s
+                });
+                let s: String = get_input(String::from("index"));
+                match get_metal_site(&s) {
+                    Some(t) => settings.metal_site = t,
+                    None => println!("Metal site {} not supported.", s)
+                }
+                println!("Input radius of metal atoms (default: as atom radius type):\n\
+                    Rmin: Rmin/2 of the metal's LJ parameters in topology\n\
+                    or name of a radius set in dat/, or radius file with lines like \"ZN: 1.271\"");
+                let s: String = get_input(String::new());
+                if s.is_empty() || s.eq_ignore_ascii_case("Rmin") || Path::new(&s).is_file()
+                    || get_dat_dir().join(format!("{}.dat", s)).is_file() {
+                    settings.metal_radius = s;
+                } else {
+                    println!("Not file or radius set in dat/: {}", s);
+                }
+            }
+            14 => {
+                let mem_fpath = wd.join("MEM_settings.yaml");
+                if let Err(e) = mem_set.clone().unwrap_or_else(MembraneSet::new).save_params(&mem_fpath) {
+                    println!("Error: {}", e);
+                    continue;
+                }
+                println!("Membrane parameters have been wrote to {0}.\n\
+                    Membrane is a slab along z, center and thickness are detected from lipids if auto_detect is true.\n\
+                    Edit it and input its path to reload (default: {0}, input \"n\" to disable):", &mem_fpath.to_str().unwrap());
+                let mem_fpath = get_input(mem_fpath.to_str().unwrap().to_string());
+                if mem_fpath == "n" || mem_fpath == "N" {
+                    mem_set = None;
+                } else {
+                    match MembraneSet::load_params(mem_fpath) {
+                        Ok(params) => mem_set = Some(params),
+                        Err(e) => println!("Error: {}, membrane parameters not changed.", e)
+                    }
+                }
+            }
+            15 => {
+                println!("Input multigrid levels (nlev), default 4:");
+                let nlev: usize = get_input(4);
+                if !(1..=8).contains(&nlev) {
+                    println!("Multigrid levels should be in 1-8, settings not changed.");
+                    continue;
+                }
+                println!("Input memory limit per APBS process (MB), default 2048:");
+                let max_mem: f64 = get_input(2048.0);
+                if max_mem <= 0.0 {
+                    println!("Memory limit should be positive, settings not changed.");
+                    continue;
+                }
+                settings.nlev = nlev;
+                settings.max_mem = max_mem;
+            }
+            16 => {
+                println!("APBS execution modes:");
+                println!("  0 local: run APBS by s_mmpbsa");
+                println!("  1 export: write pqr and APBS inputs of all frames with a manifest, then stop");
+                println!("  2 ingest: read outputs of exported APBS inputs from a directory");
+                let mode: usize = get_input(0);
+                match mode {
+                    0 => apbs_mode = 0,
+                    1 if mem_set.is_some() => println!("Membrane maps are drawn between APBS runs, export is not supported with membrane."),
+                    1 => apbs_mode = 1,
+                    2 => {
+                        println!("Input directory of exported inputs and APBS outputs (containing {}):", MANIFEST_NAME);
+                        let dir = get_input(String::new());
+                        if Path::new(&dir).join(MANIFEST_NAME).is_file() {
+                            ingest_dir = dir;
+                            apbs_mode = 2;
+                        } else {
+                            println!("{} not found in {}", MANIFEST_NAME, dir);
+                        }
+                    }
+                    _ => println!("Invalid input")
+                }
+            }
+            17 => {
+                println!("Input the time point (in ns) to write maps, -1 for not writing (default: {} ns):", bt / 1000.0);
+                let t: f64 = get_input(bt / 1000.0);
+                map_time = match t < 0.0 {
+                    true => None,
+                    false => Some(t)
+                };
+            }
             _ => println!("Invalid input")
         }
     }
 }
 
-// convert rec and lig to begin at 0 and continous
-pub fn normalize_index(ndx_rec: &Vec<usize>, ndx_lig: Option<&Vec<usize>>) -> (Vec<usize>, Vec<usize>, Vec<usize>) {
-    let offset = match ndx_lig {
-        Some(ndx_lig) => ndx_lig[0].min(ndx_rec[0]),
-        None => ndx_rec[0]
-    };
-    let mut ndx_rec: Vec<usize> = ndx_rec.iter().map(|p| p - offset).collect();
-    let mut ndx_lig = match ndx_lig {
-        Some(ndx_lig) => ndx_lig.iter().map(|p| p - offset).collect(),
-        None => ndx_rec.clone()
-    };
-    let ndx_com = match ndx_lig[0].cmp(&ndx_rec[0]) {
-        Ordering::Greater => {
-            ndx_lig = ndx_lig.iter().map(|p| p - ndx_lig[0] + ndx_rec.len()).collect();
-            let mut ndx_com = ndx_rec.to_vec();
-            ndx_com.extend(&ndx_lig);
-            ndx_com
-        }
-        Ordering::Less => {
-            ndx_rec = ndx_rec.iter().map(|p| p - ndx_rec[0] + ndx_lig.len()).collect();
-            let mut ndx_com = ndx_lig.to_vec();
-            ndx_com.extend(&ndx_rec);
-            ndx_com
-        }
-        Ordering::Equal => Vec::from_iter(0..ndx_rec.len())
-    };
-    (ndx_com, ndx_rec, ndx_lig)
+fn show_fep_state(fep_lambda: f64) -> String {
+    if fep_lambda == 0.0 {
+        String::from("A-state")
+    } else if fep_lambda == 1.0 {
+        String::from("B-state")
+    } else {
+        format!("lambda = {}", fep_lambda)
+    }
 }
@@ -1,18 +1,43 @@
 use std::path::Path;
-use crate::settings::Settings;
-use crate::utils::get_input_selection;
+use s_mmpbsa::settings::Settings;
+use s_mmpbsa::utils::get_input_selection;
+use crate::exit_on_error;
 use crate::fun_para_mmpbsa::set_para_mmpbsa;
-use crate::index_parser::Index;
-use crate::parse_tpr::TPR;
+use s_mmpbsa::index_parser::Index;
+use s_mmpbsa::parse_tpr::TPR;
+use s_mmpbsa::trajectory::read_trajectory;
+use std::rc::Rc;
+use xdrfile::Frame;
 
 pub fn set_para_trj(trj: &String, tpr: &mut TPR, ndx_name: &String, wd: &Path, tpr_name: &str, settings: &mut Settings) {
     let mut receptor_grp: Option<usize> = None;
     let mut ligand_grp: Option<usize> = None;
-    let mut bt: f64 = 0.0;                                  // ps
-    let mut et: f64 = tpr.dt * tpr.nsteps as f64;           // ps
-    let mut dt: f64 = tpr.dt * tpr.nstxout as f64;          // ps
-    let unit_dt: f64 = tpr.dt * tpr.nstxout as f64;         // ps
-    let ndx = Index::from(ndx_name);
+    // no time information in non-gromacs topology, get it from trajectory
+    // the frames are kept to be extracted later without reading the trajectory again
+    let mut frames: Option<Vec<Rc<Frame>>> = None;
+    if tpr.nsteps == 0 {
+        let trj_frames = exit_on_error(read_trajectory(trj));
+        if trj_frames.len() > 1 {
+            // round to fs since time may be saved in single precision
+            tpr.tinit = (trj_frames[0].time as f64 * 1000.0).round() / 1000.0;
+            tpr.dt = ((trj_frames[1].time - trj_frames[0].time) as f64 * 1000.0).round() / 1000.0;
+            tpr.nsteps = trj_frames.len() as u64 - 1;
+        }
+        frames = Some(trj_frames);
+        tpr.nstxout = 1;
+        tpr.nstxout_compressed = 1;
+    }
+    // trr is written by nstxout, xtc by nstxout-compressed
+    let nstxout = match trj.ends_with(".trr") {
+        true => tpr.nstxout,
+        false => tpr.nstxout_compressed
+    };
+    let t_end: f64 = tpr.tinit + tpr.dt * tpr.nsteps as f64;   // ps
+    let mut bt: f64 = tpr.tinit;                            // ps
+    let mut et: f64 = t_end;                                // ps
+    let mut dt: f64 = tpr.dt * nstxout as f64;              // ps
+    let unit_dt: f64 = tpr.dt * nstxout as f64;             // ps
+    let ndx = exit_on_error(Index::from(ndx_name));
     loop {
         println!("\n                 ************ Trajectory Parameters ************");
         println!("-10 Return");
@@ -32,7 +57,7 @@ pub fn set_para_trj(trj: &String, tpr: &mut TPR, ndx_name: &String, wd: &Path, t
             0 => {
                 match receptor_grp {
                     Some(receptor_grp) => {
-                        set_para_mmpbsa(trj, tpr, &ndx, wd, tpr_name, ndx_name,
+                        set_para_mmpbsa(trj, frames.as_ref(), tpr, &ndx, wd, tpr_name, ndx_name,
                             receptor_grp,
                             ligand_grp,
                             bt, et, dt,
@@ -59,7 +84,7 @@ pub fn set_para_trj(trj: &String, tpr: &mut TPR, ndx_name: &String, wd: &Path, t
             3 => {
                 println!("Input start time (ns), should be divisible of {} ps:", dt);
                 let mut new_bt = get_input_selection::<f64>() * 1000.0;
-                while new_bt * 1000.0 % dt != 0.0 || new_bt > tpr.nsteps as f64 * tpr.dt as f64 || new_bt < 0.0 {
+                while new_bt * 1000.0 % dt != 0.0 || new_bt > t_end || new_bt < tpr.tinit {
                     println!("The input {} ns not a valid time in trajectory.", new_bt / 1000.0);
                     println!("Input start time (ns) again, should be divisible of {} fs:", dt);
                     new_bt = get_input_selection::<f64>() * 1000.0;
@@ -69,7 +94,7 @@ pub fn set_para_trj(trj: &String, tpr: &mut TPR, ndx_name: &String, wd: &Path, t
             4 => {
                 println!("Input end time (ns), should be divisible of {} ps:", dt);
                 let mut new_et = get_input_selection::<f64>() * 1000.0;
-                while new_et * 1000.0 % dt != 0.0 || new_et > tpr.nsteps as f64 * tpr.dt as f64 || new_et < 0.0 {
+                while new_et * 1000.0 % dt != 0.0 || new_et > t_end || new_et < tpr.tinit {
                     println!("The input {} ns not a valid time in trajectory.", new_et / 1000.0);
                     println!("Input end time (ns) again, should be divisible of {} fs:", dt);
                     new_et = get_input_selection::<f64>() * 1000.0;
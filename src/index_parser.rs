@@ -1,5 +1,8 @@
 use std::{fs::{self, File}, io::Write};
+use std::cmp::Ordering;
 use regex::Regex;
+use crate::error::{MmpbsaError, Result};
+use crate::parse_tpr::TPR;
 
 #[derive(Clone)]
 pub struct IndexGroup {
@@ -23,8 +26,8 @@ impl Index {
         Index{ groups }
     }
 
-    pub fn from(index_file: &String) -> Index {
-        let ndx = fs::read_to_string(index_file).expect("Failed reading index file");
+    pub fn from(index_file: &String) -> Result<Index> {
+        let ndx = fs::read_to_string(index_file).map_err(MmpbsaError::io(index_file))?;
         let re = Regex::new(r"\[\s*(.+?)\s*]").unwrap();
         let mut group_names: Vec<String> = vec![];          // name of each group
         for cap in re.captures_iter(&ndx) {
@@ -39,22 +42,34 @@ impl Index {
                 let atom_list: Vec<&str> = ndx[i].trim().split(" ").collect();
                 let mut at_list: Vec<usize> = vec![];
                 for atom in atom_list {
-                    let at: usize = atom.parse().expect("Failed to get index atom");
                     // gromacs index file starts at 1, but we need 0 as index slice
-                    at_list.push(at - 1);
+                    match atom.parse::<usize>() {
+                        Ok(at) if at > 0 => at_list.push(at - 1),
+                        _ => return Err(MmpbsaError::parse(index_file, format!("invalid atom index: {}", atom)))
+                    }
                 }
                 group_atoms.push(at_list);
             }
         }
-        let mut groups: Vec<IndexGroup> = vec![];
-        while group_names.len() > 0 {
-            groups.insert(0, IndexGroup {
-                name: group_names.pop().expect("Error reading index file."),
-                indexes: group_atoms.pop().expect("Error reading index file."),
-            });
+        if group_names.len() != group_atoms.len() {
+            return Err(MmpbsaError::parse(index_file, "empty or unnamed index group"));
         }
-        let index = Index { groups };
-        return index;
+        let groups: Vec<IndexGroup> = group_names.into_iter().zip(group_atoms)
+            .map(|(name, indexes)| IndexGroup { name, indexes }).collect();
+        Ok(Index { groups })
+    }
+
+    // default groups for topologies without index file: System and each molecule block
+    pub fn from_tpr(tpr: &TPR) -> Index {
+        let mut groups = vec![IndexGroup::new("System", &(0..tpr.n_atoms).collect())];
+        let mut first = 0;
+        for mt in &tpr.molecule_types {
+            let mol = &tpr.molecules[mt.id];
+            let last = first + mol.atoms_num * mt.molecules_num as usize;
+            groups.push(IndexGroup::new(&mol.molecule_name, &(first..last).collect()));
+            first = last;
+        }
+        Index { groups }
     }
 
     pub fn list_groups(&self) {
@@ -67,24 +82,48 @@ impl Index {
         self.groups.push(ng.to_owned());
     }
 
-    pub fn to_ndx(&self, file_name: &str) {
-        let mut f = File::create(file_name).unwrap();
+    pub fn to_ndx(&self, file_name: &str) -> Result<()> {
+        let mut f = File::create(file_name).map_err(MmpbsaError::io(file_name))?;
         for ig in &self.groups {
-            writeln!(f, "[ {} ]", ig.name).unwrap();
-            for i in 0..(ig.indexes.len() / 10)  {
-                for j in 0..10 {
-                    write!(f, "{:7}", ig.indexes[i * 10 + j] + 1).unwrap();
-                }
-                writeln!(f).unwrap();
-            }
-            for i in 0..(ig.indexes.len() % 10) {
-                write!(f, "{:7}", ig.indexes[(ig.indexes.len() / 10) * 10 + i] + 1).unwrap();
+            writeln!(f, "[ {} ]", ig.name).map_err(MmpbsaError::io(file_name))?;
+            for line in ig.indexes.chunks(10) {
+                let line: String = line.iter().map(|i| format!("{:7}", i + 1)).collect();
+                writeln!(f, "{}", line).map_err(MmpbsaError::io(file_name))?;
             }
-            writeln!(f).unwrap();
         }
+        Ok(())
     }
 
     pub fn rm_group(&mut self, name: &str) {
         self.groups.retain(|g| g.name.ne(name));
     }
-}
\ No newline at end of file
+}
+
+// convert rec and lig to begin at 0 and continous
+pub fn normalize_index(ndx_rec: &Vec<usize>, ndx_lig: Option<&Vec<usize>>) -> (Vec<usize>, Vec<usize>, Vec<usize>) {
+    let offset = match ndx_lig {
+        Some(ndx_lig) => ndx_lig[0].min(ndx_rec[0]),
+        None => ndx_rec[0]
+    };
+    let mut ndx_rec: Vec<usize> = ndx_rec.iter().map(|p| p - offset).collect();
+    let mut ndx_lig = match ndx_lig {
+        Some(ndx_lig) => ndx_lig.iter().map(|p| p - offset).collect(),
+        None => ndx_rec.clone()
+    };
+    let ndx_com = match ndx_lig[0].cmp(&ndx_rec[0]) {
+        Ordering::Greater => {
+            ndx_lig = ndx_lig.iter().map(|p| p - ndx_lig[0] + ndx_rec.len()).collect();
+            let mut ndx_com = ndx_rec.to_vec();
+            ndx_com.extend(&ndx_lig);
+            ndx_com
+        }
+        Ordering::Less => {
+            ndx_rec = ndx_rec.iter().map(|p| p - ndx_rec[0] + ndx_lig.len()).collect();
+            let mut ndx_com = ndx_lig.to_vec();
+            ndx_com.extend(&ndx_rec);
+            ndx_com
+        }
+        Ordering::Equal => Vec::from_iter(0..ndx_rec.len())
+    };
+    (ndx_com, ndx_rec, ndx_lig)
+}
@@ -0,0 +1,37 @@
+// s_mmpbsa engine: topology and trajectory parsers, index handling, atom properties,
+// MM/PB-SA calculations and results, the interactive program is a front end of it
+pub mod error;
+pub mod index_parser;
+pub mod mmpbsa;
+pub mod parse_tpr;
+pub mod analyzation;
+pub mod atom_radius;
+pub mod apbs_param;
+pub mod prepare_apbs;
+pub mod apbs_grid;
+pub mod apbs_export;
+pub mod settings;
+pub mod atom_property;
+pub mod coefficients;
+pub mod utils;
+pub mod parse_prmtop;
+pub mod parse_psf;
+pub mod trajectory;
+pub mod element;
+pub mod atom_override;
+pub mod metal_site;
+pub mod dx;
+pub mod membrane;
+pub mod nonpolar;
+pub mod pot_maps;
+pub mod results_json;
+pub mod results_parquet;
+pub mod xvg;
+pub mod plot;
+pub mod report;
+pub mod mol_scripts;
+pub mod pdb;
+#[cfg(feature = "python")]
+pub mod python;
+
+pub use error::{MmpbsaError, Result};
@@ -1,39 +1,30 @@
-mod index_parser;
-mod mmpbsa;
-mod parse_tpr;
-mod analyzation;
 mod fun_para_basic;
 mod fun_para_trj;
 mod fun_para_mmpbsa;
-mod atom_radius;
-mod apbs_param;
-mod prepare_apbs;
-mod settings;
-mod atom_property;
-mod coefficients;
-mod utils;
 
 use std::fs;
 use std::env;
 use std::fs::File;
 use std::io::{stdin, Write};
 use std::path::Path;
-use std::process::Command;
+use std::process::{Command, exit};
 use regex::Regex;
-use crate::parse_tpr::TPR;
-use settings::{Settings, get_base_settings, get_settings_in_use};
+use s_mmpbsa::parse_tpr::TPR;
+use s_mmpbsa::index_parser::Index;
+use s_mmpbsa::settings::{Settings, get_base_settings, get_settings_in_use};
 
 fn main() {
     let args: Vec<String> = env::args().collect();
     let mut tpr_dump = String::new();        // may be dump file
     let mut trj = String::from("");
     let mut ndx = String::from("");
+    let mut prm: Vec<String> = vec![];          // CHARMM parameter files for psf
 
     welcome();
     // initialize parameters
     let mut settings = match get_settings_in_use() {
         Some(settings_file) => {
-            Settings::from(&settings_file)
+            exit_on_error(Settings::from(&settings_file))
         }
         None => {
             println!("Note: settings.ini not found, will use 1 kernel.");
@@ -46,7 +37,7 @@ fn main() {
 
     match args.len() {
         1 => {
-            println!("Input path of .tpr or .dump file, e.g. D:/Conan/Haibara_Ai.tpr or D:/Conan/Haibara_Ai.dump");
+            println!("Input path of .tpr, .dump, .prmtop or .psf file, e.g. D:/Conan/Haibara_Ai.tpr or D:/Conan/Haibara_Ai.dump");
             println!("Hint: input \"o\" to simply load last-opened .tpr or .dump file");
             loop {
                 stdin().read_line(&mut tpr_dump).expect("Failed to read tpr or dumped file.");
@@ -71,6 +62,7 @@ fn main() {
                     "-f" => { trj = args[i + 1].to_string() }
                     "-s" => { tpr_dump = args[i + 1].to_string() }
                     "-n" => { ndx = args[i + 1].to_string() }
+                    "-p" => { prm = args[i + 1].split(',').map(|p| p.trim().to_string()).collect() }
                     _ => {
                         println!("Omitted invalid option: {}", args[i])
                     }
@@ -78,7 +70,7 @@ fn main() {
             }
         }
     }
-    tpr_dump = confirm_file_validity(&mut tpr_dump, vec!["tpr", "dump"], &settings);
+    tpr_dump = confirm_file_validity(&mut tpr_dump, vec!["tpr", "dump", "prmtop", "parm7", "psf"], &settings);
 
     settings.last_opened = fs::canonicalize(Path::new(&tpr_dump))
         .expect("Cannot convert to absolute path.").display().to_string();
@@ -92,21 +84,43 @@ fn main() {
     println!("Currently working at path: {}", Path::new(&tpr_dir).display());
 
     // It names tpr but exactly dump file _(:qゝ∠)_
-    let tpr_name = match tpr_dump.ends_with(".tpr") {
-        true => {
+    let ext = tpr_dump_path.extension().unwrap().to_str().unwrap();
+    let tpr_name = match ext {
+        "tpr" => {
             println!("Found tpr file: {}", tpr_dump);
             let gmx = settings.gmx.as_ref().unwrap();
             let dump_to = dump_path.to_str().unwrap().to_string();
             dump_tpr(&tpr_dump, &dump_to, gmx);
             dump_to
         }
-        false => {
-            println!("Found dump file: {}", tpr_dump);
+        _ => {
+            println!("Found {} file: {}", ext, tpr_dump);
             tpr_dump.to_string()
         }
     };
 
-    let mut tpr = TPR::new(&tpr_name, &settings);
+    let mut tpr = exit_on_error(match ext {
+        "prmtop" | "parm7" => TPR::from_prmtop(&tpr_name, &settings),
+        "psf" => {
+            if prm.is_empty() {
+                println!("Input CHARMM parameter files (prm, par or str) for LJ parameters, separated by comma:");
+                let mut s = String::new();
+                stdin().read_line(&mut s).expect("Failed to read parameter files.");
+                prm = s.split(',').filter(|p| !p.trim().is_empty())
+                    .map(|p| confirm_file_validity(&p.trim().to_string(), vec!["prm", "par", "str", "inp", "rtf"], &settings))
+                    .collect();
+            }
+            TPR::from_psf(&tpr_name, &prm, &settings)
+        }
+        _ => TPR::new(&tpr_name, &settings)
+    });
+    // non-gromacs topology may come without index file
+    if ext != "tpr" && ext != "dump" && ndx.is_empty() {
+        let ndx_path = tpr_dir.join(tpr_dump_name.to_string() + "_index.ndx");
+        ndx = ndx_path.to_str().unwrap().to_string();
+        exit_on_error(Index::from_tpr(&tpr).to_ndx(&ndx));
+        println!("Generated default index file: {}", ndx);
+    }
     println!("\nFinished loading tpr.");
     match settings.debug_mode {
         true => println!("Debug mode open."),
@@ -117,6 +131,17 @@ fn main() {
     fun_para_basic::set_para_basic(&trj, &mut tpr, &ndx, &tpr_dir, tpr_name.as_str(), &mut settings);
 }
 
+// errors of the engine stop the interactive program
+pub fn exit_on_error<T>(result: s_mmpbsa::Result<T>) -> T {
+    match result {
+        Ok(v) => v,
+        Err(e) => {
+            println!("Error: {}", e);
+            exit(1);
+        }
+    }
+}
+
 fn welcome() {
     println!("\
         ========================================================================\n\
@@ -130,7 +155,9 @@ fn welcome() {
         Usage 2: run `s_mmpbsa Miyano_Shiho.tpr` to directly load tpr file.\n\
         Usage 3: run `s_mmpbsa Miyano_Shiho.dump` to directly load dumped tpr file.\n\
         Usage 4: run `s_mmpbsa -f md.xtc -s md.tpr -n index.ndx` to assign all files.\n\
-        Usage 5: run `s_mmpbsa -f md.xtc -s md.dump -n index.ndx` to assign all files.\n");
+        Usage 5: run `s_mmpbsa -f md.xtc -s md.dump -n index.ndx` to assign all files.\n\
+        Usage 6: run `s_mmpbsa -f md.nc -s md.prmtop` to use AMBER topology and trajectory.\n\
+        Usage 7: run `s_mmpbsa -f md.dcd -s md.psf -p par_all36m_prot.prm,par_all36_cgenff.prm` to use CHARMM files.\n");
 }
 
 // 把ext_list改成enum
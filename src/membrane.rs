@@ -0,0 +1,143 @@
+use std::fmt;
+use std::fmt::Formatter;
+use std::fs;
+use std::path::Path;
+use serde::{Serialize, Deserialize};
+use crate::dx::DxGrid;
+use crate::element::get_elements;
+use crate::error::{MmpbsaError, Result};
+use crate::parse_tpr::TPR;
+use crate::trajectory::read_first_frame;
+
+// residue names of common lipids (CHARMM36, Slipids, Lipid21 and Martini)
+const LIPID_RESNAMES: [&str; 30] = [
+    "POPC", "POPE", "POPG", "POPS", "POPA", "DOPC", "DOPE", "DOPG", "DOPS", "DPPC",
+    "DPPE", "DPPG", "DMPC", "DMPE", "DLPC", "DSPC", "DLPE", "PSM", "SSM", "CHL1",
+    "CHOL", "CHL", "PC", "PE", "PS", "PGR", "PA", "OL", "ST", "MY",
+];
+
+// hydrophobic thickness is estimated as phosphate distance minus the headgroups (2 x 5 A)
+const HEADGROUP_THICKNESS: f64 = 10.0;
+
+// low-dielectric slab along z in PB grid, analogous to APBS draw_membrane
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MembraneSet {
+    pub mdie: f64,          // dielectric constant of membrane
+    pub thickness: f64,     // thickness of slab (A)
+    pub center: f64,        // z of slab center in complex coordinates (A)
+    pub auto_detect: bool,  // get center and thickness from phosphorus atoms of lipids
+    pub lipids: Vec<String>,
+}
+
+impl MembraneSet {
+    pub fn new() -> MembraneSet {
+        MembraneSet {
+            mdie: 2.0,
+            thickness: 30.0,
+            center: 0.0,
+            auto_detect: true,
+            lipids: LIPID_RESNAMES.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    pub fn load_params<T: AsRef<Path>>(file: T) -> Result<MembraneSet> {
+        let file = file.as_ref().to_string_lossy().to_string();
+        let mem_set = fs::read_to_string(&file).map_err(MmpbsaError::io(&file))?;
+        serde_yaml::from_str(mem_set.as_str()).map_err(|e| MmpbsaError::parse(&file, e.to_string()))
+    }
+
+    pub fn save_params<T: AsRef<Path>>(&self, file: T) -> Result<()> {
+        let file = file.as_ref().to_string_lossy().to_string();
+        let mem_set = serde_yaml::to_string(self).map_err(|e| MmpbsaError::parse(&file, e.to_string()))?;
+        fs::write(&file, mem_set).map_err(MmpbsaError::io(&file))
+    }
+
+    pub fn is_lipid(&self, resname: &str) -> bool {
+        self.lipids.iter().any(|l| l == resname)
+    }
+
+    // membrane center and thickness from phosphorus atoms of lipids at the first frame
+    // the system trajectory is shifted to the complex trajectory (e.g. centered by trjconv) along z
+    pub fn detect(&mut self, tpr: &TPR, trj: &str, trj_mmpbsa: &str, ndx_com: &Vec<usize>) -> Result<()> {
+        let mut ndx_p: Vec<usize> = vec![];
+        let mut idx = 0;
+        for mol in &tpr.molecules {
+            let elements = get_elements(mol);
+            for _ in 0..tpr.molecule_types[mol.molecule_type_id].molecules_num {
+                for (i, atom) in mol.atoms.iter().enumerate() {
+                    // PO4 is the phosphate bead of Martini lipids
+                    if self.is_lipid(&mol.residues[atom.resind].name) && (elements[i] == 15 || atom.name == "PO4") {
+                        ndx_p.push(idx);
+                    }
+                    idx += 1;
+                }
+            }
+        }
+        if ndx_p.is_empty() {
+            println!("Warning: no lipid phosphorus atoms found, membrane center {} A and thickness {} A will be used.",
+                self.center, self.thickness);
+            return Ok(());
+        }
+        let frame = read_first_frame(trj)?;
+        let frame_com = read_first_frame(trj_mmpbsa)?;
+        let z_p: Vec<f64> = ndx_p.iter().map(|&i| frame.coords[i][2] as f64 * 10.0).collect();
+        let center = z_p.iter().sum::<f64>() / z_p.len() as f64;
+        let upper: Vec<f64> = z_p.iter().filter(|&&z| z > center).copied().collect();
+        let lower: Vec<f64> = z_p.iter().filter(|&&z| z <= center).copied().collect();
+        if upper.is_empty() || lower.is_empty() {
+            println!("Warning: only one leaflet found, membrane center {} A and thickness {} A will be used.",
+                self.center, self.thickness);
+            return Ok(());
+        }
+        let dist_p = upper.iter().sum::<f64>() / upper.len() as f64 - lower.iter().sum::<f64>() / lower.len() as f64;
+        let z_com = ndx_com.iter().map(|&i| frame.coords[i][2] as f64).sum::<f64>() * 10.0 / ndx_com.len() as f64;
+        let z_com_mmpbsa = frame_com.coords.iter().map(|c| c[2] as f64).sum::<f64>() * 10.0 / frame_com.num_atoms() as f64;
+        self.center = center + z_com_mmpbsa - z_com;
+        self.thickness = (dist_p - HEADGROUP_THICKNESS).max(0.0);
+        println!("Found {} lipid phosphorus atoms, P-P distance {:.2} A, membrane center {:.2} A, thickness {:.2} A.",
+            ndx_p.len(), dist_p, self.center, self.thickness);
+        Ok(())
+    }
+
+    // set dielectric of solvent to mdie and ion accessibility to 0 in the slab
+    // maps are written by APBS mg-dummy, dielx/y/z are shifted by half grid spacing along x/y/z
+    pub fn draw(&self, temp_dir: &Path, map_name: &str, sdie: f64) -> Result<()> {
+        let (z_min, z_max) = (self.center - self.thickness / 2.0, self.center + self.thickness / 2.0);
+        for (axis, suffix) in ["dielx", "diely", "dielz", "kappa"].iter().enumerate() {
+            let map_file = temp_dir.join(format!("{}_{}.dx", map_name, suffix));
+            let mut map = DxGrid::from(&map_file)?;
+            let shift = match axis {
+                2 => map.delta[2] / 2.0,
+                _ => 0.0
+            };
+            for i in 0..map.counts[0] {
+                for j in 0..map.counts[1] {
+                    for k in 0..map.counts[2] {
+                        let z = map.position(i, j, k)[2] + shift;
+                        if z < z_min || z > z_max {
+                            continue;
+                        }
+                        let idx = map.index(i, j, k);
+                        if axis == 3 {
+                            map.data[idx] = 0.0;
+                        } else if map.data[idx] > sdie - 1e-3 {
+                            map.data[idx] = self.mdie;
+                        }
+                    }
+                }
+            }
+            map.write(&map_file, "membrane drawn by s_mmpbsa")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for MembraneSet {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "  mdie      {:7}  # 膜介电常数\
+            \n  thickness {:7}  # 膜厚度(A)\
+            \n  center    {:7}  # 膜中心z坐标(A)\
+            \n  auto      {:>7}  # 由脂质磷原子确定膜中心和厚度\
+            \n  lipids    {}", self.mdie, self.thickness, self.center, self.auto_detect, self.lipids.join(" "))
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use crate::atom_property::AtomProperty;
+use crate::element::{get_element_symbol, is_metal};
+use crate::error::{MmpbsaError, Result};
+use crate::parse_tpr::get_lj_radius;
+use crate::settings::get_dat_dir;
+
+// which group the metal atoms belong to, "index" keeps the selected index groups
+pub const METAL_SITES: [&str; 3] = ["index", "receptor", "ligand"];
+
+// metal site by index or name (case insensitive)
+pub fn get_metal_site(s: &str) -> Option<usize> {
+    match s.parse::<usize>() {
+        Ok(i) => Some(i).filter(|&i| i < METAL_SITES.len()),
+        Err(_) => METAL_SITES.iter().position(|t| t.eq_ignore_ascii_case(s))
+    }
+}
+
+impl AtomProperty {
+    // indexes of metal atoms in complex
+    pub fn get_metals(&self) -> Vec<usize> {
+        (0..self.atm_element.len()).filter(|&i| is_metal(self.atm_element[i])).collect()
+    }
+
+    // metal radius: "Rmin" for Rmin/2 of the metal's LJ parameters in topology,
+    // otherwise a radius set in dat/ or the path of a radius file, with lines like "ZN: 1.271"
+    pub fn apply_metal_radius(&mut self, metal_radius: &str) -> Result<()> {
+        let metals = self.get_metals();
+        if metals.is_empty() {
+            return Ok(());
+        }
+        if metal_radius.eq_ignore_ascii_case("Rmin") {
+            for &idx in &metals {
+                let (c6, c12) = self.get_lj_params(idx, idx);
+                if c6 == 0.0 || c12 == 0.0 {
+                    println!("Warning: {} {} has no LJ parameters, radius {} kept.",
+                        self.atm_resname[idx], self.atm_name[idx], self.atm_radius[idx]);
+                    continue;
+                }
+                // Rmin/2 = 2^(1/6) σ / 2
+                self.atm_radius[idx] = get_lj_radius(c6, c12, 0.0) * 2.0_f64.powf(1.0 / 6.0);
+                self.atm_radius_rule[idx] = String::from("metal Rmin/2");
+            }
+        } else {
+            let radii_file = match Path::new(metal_radius).is_file() {
+                true => Path::new(metal_radius).to_path_buf(),
+                false => get_dat_dir().join(format!("{}.dat", metal_radius))
+            };
+            let radii_file = radii_file.to_string_lossy().to_string();
+            let radii = fs::read_to_string(&radii_file).map_err(MmpbsaError::io(&radii_file))?;
+            let mut radii_table: HashMap<&str, f64> = HashMap::new();
+            for l in radii.split("\n").filter(|p| !p.trim().starts_with("//") && p.contains(':')) {
+                let k_v: Vec<&str> = l.split(":").collect();
+                let radius = k_v[1].trim().parse::<f64>()
+                    .map_err(|_| MmpbsaError::parse(&radii_file, format!("invalid radius: {}", l.trim())))?;
+                radii_table.insert(k_v[0].trim(), radius);
+            }
+            for &idx in &metals {
+                let key = get_element_symbol(self.atm_element[idx]).to_uppercase();
+                match radii_table.get(key.as_str()) {
+                    Some(&r) => {
+                        self.atm_radius[idx] = r;
+                        self.atm_radius_rule[idx] = format!("metal {}", key);
+                    }
+                    None => println!("Warning: {} not found in metal radius file {}, radius {} kept.",
+                        key, metal_radius, self.atm_radius[idx])
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // metal atoms, their group and bonded partners (bonded model), and total charge of each metal residue
+    pub fn write_metal_report(&self, metals: &Vec<usize>, ndx_rec: &Vec<usize>, ndx_com: &Vec<usize>, report: &Path) -> Result<()> {
+        let mut ndx_sorted = ndx_com.to_vec();
+        ndx_sorted.sort();
+        let mut lines = String::new();
+        lines.push_str(&format!("{:>7}{:>7}{:>9}{:>8}{:>10}{:>10}{:>10}  bonded\n",
+            "index", "name", "resname", "element", "group", "radius", "charge"));
+        for &idx in metals {
+            let bonded: Vec<&str> = self.atm_bonded_elements[idx].iter().map(|&e| get_element_symbol(e)).collect();
+            lines.push_str(&format!("{:7}{:>7}{:>9}{:>8}{:>10}{:10.4}{:10.4}  {}\n",
+                ndx_sorted[idx] + 1, self.atm_name[idx], self.atm_resname[idx], get_element_symbol(self.atm_element[idx]),
+                match ndx_rec.binary_search(&idx).is_ok() {
+                    true => "receptor",
+                    false => "ligand"
+                },
+                self.atm_radius[idx], self.atm_charge[idx], match bonded.len() {
+                    0 => String::from("none (nonbonded model)"),
+                    _ => bonded.join(" ")
+                }));
+        }
+        fs::write(report, lines).map_err(MmpbsaError::io(report.to_string_lossy()))?;
+        println!("Found {} metal atoms, see {}", metals.len(), report.display());
+        if metals.iter().all(|&i| self.atm_bonded_elements[i].is_empty()) && metals.iter().any(|&i| self.atm_charge[i].abs() >= 2.0) {
+            println!("Note: metals carry full formal charges, consider bonded model charges with the atom override file.");
+        }
+        Ok(())
+    }
+}
+
+// move metal atoms to receptor (metal_site = 1) or ligand (metal_site = 2), indexes are kept in ascending order
+pub fn assign_metals(metals: &Vec<usize>, ndx_rec: &Vec<usize>, ndx_lig: &Vec<usize>, metal_site: usize) -> (Vec<usize>, Vec<usize>) {
+    let (mut ndx_rec, mut ndx_lig) = (ndx_rec.to_vec(), ndx_lig.to_vec());
+    if metal_site == 0 || metals.is_empty() {
+        return (ndx_rec, ndx_lig);
+    }
+    if ndx_rec[0] == ndx_lig[0] {
+        println!("Note: no ligand group, metals will stay in receptor.");
+        return (ndx_rec, ndx_lig);
+    }
+    let (from, to) = match metal_site {
+        1 => (&mut ndx_lig, &mut ndx_rec),
+        _ => (&mut ndx_rec, &mut ndx_lig)
+    };
+    let moved: Vec<usize> = from.iter().filter(|i| metals.contains(i)).copied().collect();
+    if moved.len() == from.len() {
+        println!("Note: group of {} atoms has only metals, metals will not be moved.", from.len());
+        return (ndx_rec, ndx_lig);
+    }
+    from.retain(|i| !moved.contains(i));
+    to.extend(&moved);
+    to.sort();
+    println!("{} metal atoms moved to {}.", moved.len(), METAL_SITES[metal_site]);
+    (ndx_rec, ndx_lig)
+}
@@ -1,11 +1,13 @@
 use std::cmp::Ordering;
-use std::fs::{self, File};
-use std::io::Write;
+use std::collections::HashMap;
+use std::fs;
 use std::path::PathBuf;
 use xdrfile::*;
+use crate::error::{MmpbsaError, Result};
 use crate::settings::Settings;
+use crate::trajectory::read_trajectory;
 use ndarray::parallel::prelude::*;
-use ndarray::{ArrayBase, OwnedRepr, ViewRepr, Dim, Array1, Array2, Array3, s};
+use ndarray::{ArrayBase, OwnedRepr, ViewRepr, Dim, Array1, Array2, Array3, Axis, s};
 use std::process::Command;
 use std::rc::Rc;
 use std::env;
@@ -16,40 +18,56 @@ use crate::analyzation::Results;
 use crate::parse_tpr::{Residue, TPR};
 use crate::apbs_param::{PBASet, PBESet};
 use crate::atom_property::AtomProperty;
-use crate::prepare_apbs::{prepare_pqr, write_apbs_input};
+use crate::membrane::MembraneSet;
+use crate::nonpolar::calc_nonpolar;
+use crate::prepare_apbs::{prepare_pqr, write_apbs_input, get_membrane_maps};
+use crate::apbs_grid::{GridPlan, plan_grids, write_grid_report};
+use crate::apbs_export::{ApbsManifest, ManifestFrame};
+
+// retries of failed apbs calculation with adjusted grids
+const APBS_RETRIES: usize = 2;
+// lowercase keywords of apbs error messages
+const APBS_ERRORS: [&str; 5] = ["error", "failed", "off the mesh", "out of memory", "malloc"];
 
 pub fn fun_mmpbsa_calculations(trj: &String, temp_dir: &PathBuf,
                                sys_name: &String, aps: &AtomProperty,
                                ndx_com: &Vec<usize>, ndx_rec: &Vec<usize>, ndx_lig: &Vec<usize>, 
                                residues: &Vec<Residue>, bt: f64, et: f64, dt: f64,
-                               pbe_set: &PBESet, pba_set: &PBASet, settings: &Settings)
-                               -> Results {
+                               pbe_set: &PBESet, pba_set: &PBASet, mem_set: Option<&MembraneSet>,
+                               manifest: Option<&ApbsManifest>, settings: &Settings) -> Result<Results> {
     // run MM/PB-SA calculations
     println!("Running MM/PB-SA calculations of {}...", sys_name);
     println!("Preparing parameters...");
     
     println!("Reading trajectory file...");
-    let trj = XTCTrajectory::open_read(trj).expect("Error reading trajectory");
-    let frames: Vec<Rc<Frame>> = trj.into_iter().map(|p| p.unwrap()).collect();
+    let frames = read_trajectory(trj)?;
 
     println!("Extracting atoms coordination...");
     let (coordinates, _) = get_atoms_trj(&frames);   // frames x atoms(3x1)
 
-    let (bf, ef, dframe, total_frames) = get_frames_range(&frames, bt, et, dt);
-
-    if let Some(_) = settings.apbs.as_ref() {
-        println!("Preparing pqr files...");
-        prepare_pqr(&frames, bf, ef, dframe, total_frames, &temp_dir,
-                    sys_name, &coordinates, ndx_com, &ndx_rec, ndx_lig, aps);
-    }
+    // frames of the manifest if ingesting outputs of apbs run elsewhere, whose pqr files were read there
+    let frame_ids: Vec<usize> = match manifest {
+        Some(manifest) => {
+            println!("Reading APBS outputs of {} exported frames...", manifest.frames.len());
+            manifest.get_frame_ids(&frames)?
+        }
+        None => {
+            let (bf, ef, dframe, total_frames) = get_frames_range(&frames, bt, et, dt);
+            if settings.apbs.is_some() {
+                println!("Preparing pqr files...");
+                prepare_pqr(&frames, bf, ef, dframe, total_frames, &temp_dir,
+                            sys_name, &coordinates, ndx_com, &ndx_rec, ndx_lig, aps)?;
+            }
+            (bf..=ef).step_by(dframe).collect()
+        }
+    };
 
     // calculate MM and PBSA
-    calculate_mmpbsa(&frames, &coordinates, bf, ef, dframe, 
-        total_frames, aps, &temp_dir, &ndx_com, &ndx_rec, &ndx_lig, residues,
-        sys_name, pbe_set, pba_set, settings)
+    calculate_mmpbsa(&frames, &coordinates, &frame_ids, aps, &temp_dir, &ndx_com, &ndx_rec, &ndx_lig, residues,
+        sys_name, pbe_set, pba_set, mem_set, manifest, settings)
 }
 
-fn get_atoms_trj(frames: &Vec<Rc<Frame>>) -> (Array3<f64>, Array3<f64>) {
+pub fn get_atoms_trj(frames: &Vec<Rc<Frame>>) -> (Array3<f64>, Array3<f64>) {
     let num_frames = frames.len();
     let num_atoms = frames[0].num_atoms();
     let mut coord_matrix: Array3<f64> = Array3::zeros((num_frames, num_atoms, 3));
@@ -79,7 +97,7 @@ pub fn set_style(pb: &ProgressBar) {
         .progress_chars("=>-"));
 }
 
-fn get_frames_range(frames: &Vec<Rc<Frame>>, bt: f64, et: f64, dt: f64) -> (usize, usize, usize, usize) {
+pub fn get_frames_range(frames: &Vec<Rc<Frame>>, bt: f64, et: f64, dt: f64) -> (usize, usize, usize, usize) {
     // decide frame step according to time step
     let time_step = (frames[1].time - frames[0].time) as f64;
     let bf = ((bt - frames[0].time as f64) / time_step) as usize;
@@ -92,54 +110,76 @@ fn get_frames_range(frames: &Vec<Rc<Frame>>, bt: f64, et: f64, dt: f64) -> (usiz
     (bf, ef, dframe, total_frames)
 }
 
-fn calculate_mmpbsa(frames: &Vec<Rc<Frame>>, coordinates: &Array3<f64>, bf: usize, ef: usize, 
-                    dframe: usize, total_frames: usize, aps: &AtomProperty, temp_dir: &PathBuf,
+fn calculate_mmpbsa(frames: &Vec<Rc<Frame>>, coordinates: &Array3<f64>, frame_ids: &Vec<usize>,
+                    aps: &AtomProperty, temp_dir: &PathBuf,
                     ndx_com_norm: &Vec<usize>, ndx_rec_norm: &Vec<usize>, ndx_lig_norm: &Vec<usize>,
                     residues: &Vec<Residue>, sys_name: &String, 
-                    pbe_set: &PBESet, pba_set: &PBASet, settings: &Settings) -> Results {
+                    pbe_set: &PBESet, pba_set: &PBASet, mem_set: Option<&MembraneSet>,
+                    manifest: Option<&ApbsManifest>, settings: &Settings) -> Result<Results> {
+    let total_frames = frame_ids.len();
     let mut elec_res: Array2<f64> = Array2::zeros((total_frames, residues.len()));
     let mut vdw_res: Array2<f64> = Array2::zeros((total_frames, residues.len()));
     let mut pb_res: Array2<f64> = Array2::zeros((total_frames, residues.len()));
     let mut sa_res: Array2<f64> = Array2::zeros((total_frames, residues.len()));
+    let mut cav_res: Array2<f64> = Array2::zeros((total_frames, residues.len()));
+    let mut disp_res: Array2<f64> = Array2::zeros((total_frames, residues.len()));
     
     // parameters for elec calculation
     let coeff = Coefficients::new(pbe_set);
 
     // Time list of trajectory
-    let times: Array1<f64> = (bf..=ef).step_by(dframe)
-        .map(|p| frames[p].time as f64).collect();
+    let times: Array1<f64> = frame_ids.iter().map(|&p| frames[p].time as f64).collect();
 
     // start calculation
     env::set_var("OMP_NUM_THREADS", settings.nkernels.to_string());
     let t_start = Local::now();
     
+    // grids of all frames, the membrane maps are not supported by mg-para
+    if settings.apbs.is_some() && manifest.is_none() {
+        let grids: Vec<(f64, Vec<(String, GridPlan)>)> = frame_ids.iter().map(|&cur_frm| {
+            let coord = coordinates.slice(s![cur_frm, .., ..]);
+            (frames[cur_frm].time as f64, plan_grids(ndx_rec_norm, ndx_lig_norm, &coord, &aps.atm_radius, settings, mem_set.is_none()))
+        }).collect();
+        write_grid_report(&grids, settings, &temp_dir.parent().unwrap().join(format!("{}_grid_report.txt", sys_name)))?;
+    }
+
     println!("Calculating MM/PB-SA binding energy...");
 
     let pgb = ProgressBar::new(total_frames as u64);
     set_style(&pgb);
     pgb.inc(0);
-    let mut idx = 0;
-    for cur_frm in (bf..=ef).step_by(dframe) {
+    let mut valid_frames: Vec<usize> = vec![];
+    let mut failures: Vec<(f64, MmpbsaError)> = vec![];
+    for (idx, &cur_frm) in frame_ids.iter().enumerate() {
         // MM
         let coord = coordinates.slice(s![cur_frm, .., ..]);
-        if ndx_lig_norm[0] != ndx_rec_norm[0] {
-            let (res_elec, res_vdw) = 
-                calc_mm(&ndx_rec_norm, &ndx_lig_norm, aps, &coord, residues, &coeff, &settings);
-            elec_res.row_mut(idx).assign(&res_elec);
-            vdw_res.row_mut(idx).assign(&res_vdw);
-        }
+        let (res_elec, res_vdw) = 
+            calc_mm(&ndx_rec_norm, &ndx_lig_norm, aps, &coord, residues, &coeff, &settings);
+        elec_res.row_mut(idx).assign(&res_elec);
+        vdw_res.row_mut(idx).assign(&res_vdw);
 
-        // PBSA
-        calc_pbsa(idx, &coord, frames, ndx_rec_norm, ndx_lig_norm, ndx_com_norm,
-            &mut pb_res, &mut sa_res, cur_frm, sys_name, temp_dir, aps, pbe_set, pba_set, settings);
+        // PBSA, frames with failed apbs calculation are marked missing
+        match calc_pbsa(idx, &coord, frames, ndx_rec_norm, ndx_lig_norm, ndx_com_norm,
+            &mut pb_res, &mut sa_res, cur_frm, sys_name, temp_dir, aps, pbe_set, pba_set, mem_set,
+            manifest.map(|m| &m.frames[idx]), settings) {
+            Ok(_) => {
+                // cavity and dispersion
+                calc_cav_disp(idx, &coord, ndx_rec_norm, ndx_lig_norm, ndx_com_norm,
+                    &mut cav_res, &mut disp_res, aps, pba_set, mem_set);
+                valid_frames.push(idx);
+            }
+            Err(e) => {
+                pgb.println(format!("Warning: frame at {} ns will be skipped, {}", times[idx] / 1000.0, e));
+                failures.push((times[idx], e));
+            }
+        }
 
         pgb.inc(1);
         pgb.set_message(format!("at {} ns, ΔH={:.2} kJ/mol, eta. {} s", 
                                         times[idx] / 1000.0,
-                                        vdw_res.row(idx).sum() + elec_res.row(idx).sum() + pb_res.row(idx).sum() + sa_res.row(idx).sum(),
+                                        vdw_res.row(idx).sum() + elec_res.row(idx).sum() + pb_res.row(idx).sum() + sa_res.row(idx).sum()
+                                            + cav_res.row(idx).sum() + disp_res.row(idx).sum(),
                                         pgb.eta().as_secs()));
-
-        idx += 1;
     }
     pgb.finish();
 
@@ -149,25 +189,38 @@ fn calculate_mmpbsa(frames: &Vec<Rc<Frame>>, coordinates: &Array3<f64>, bf: usiz
     println!("MM/PB-SA calculation of {} finished. Total time cost: {} s", sys_name, t_spend as f64 / 1000.0);
     env::remove_var("OMP_NUM_THREADS");
     
+    if !failures.is_empty() {
+        let report = temp_dir.parent().unwrap().join(format!("{}_apbs_failures.txt", sys_name));
+        write_apbs_failures(&failures, &report)?;
+        println!("Warning: APBS failed at {} of {} frames, which are excluded from results, see {}",
+            failures.len(), total_frames, report.display());
+        println!("Outputs of failed APBS calculations are kept at {}/", temp_dir.display());
+        if valid_frames.is_empty() {
+            return Err(MmpbsaError::Apbs("no frame has been successfully calculated".to_string()));
+        }
+    }
+
     // whether remove temp directory
-    if !settings.debug_mode {
+    if !settings.debug_mode && failures.is_empty() && manifest.is_none() {
         if settings.apbs.is_some() {
-            fs::remove_dir_all(&temp_dir).expect("Remove dir failed");
+            fs::remove_dir_all(&temp_dir).map_err(MmpbsaError::io(temp_dir.to_string_lossy()))?;
         }
     }
 
-    Results::new(
+    Ok(Results::new(
         aps,
         residues,
         ndx_rec_norm,
         ndx_lig_norm,
-        &times,
-        coordinates.clone(),
-        &elec_res,
-        &vdw_res,
-        &pb_res,
-        &sa_res,
-    )
+        &times.select(Axis(0), &valid_frames),
+        coordinates.select(Axis(0), &valid_frames.iter().map(|&i| frame_ids[i]).collect::<Vec<usize>>()),
+        &elec_res.select(Axis(0), &valid_frames),
+        &vdw_res.select(Axis(0), &valid_frames),
+        &pb_res.select(Axis(0), &valid_frames),
+        &sa_res.select(Axis(0), &valid_frames),
+        &cav_res.select(Axis(0), &valid_frames),
+        &disp_res.select(Axis(0), &valid_frames),
+    ))
 }
 
 pub fn get_residues(tpr: &TPR, ndx_com: &Vec<usize>) -> Vec<Residue> {
@@ -182,10 +235,10 @@ pub fn get_residues(tpr: &TPR, ndx_com: &Vec<usize>) -> Vec<Residue> {
     for mol in &tpr.molecules {
         for _ in 0..tpr.molecule_types[mol.molecule_type_id].molecules_num {
             for atom in &mol.atoms {
-                idx += 1;
                 if ndx_com.contains(&idx) && residues.len() <= atom.resind + resind_offset {
                     residues.push(mol.residues[atom.resind].to_owned());
                 }
+                idx += 1;
                 pb.inc(1);
                 pb.set_message(format!("eta. {} s", pb.eta().as_secs()));
             }
@@ -206,7 +259,6 @@ fn calc_mm(ndx_rec_norm: &Vec<usize>, ndx_lig_norm: &Vec<usize>, aps: &AtomPrope
 
     for &i in ndx_rec_norm {
         let qi = aps.atm_charge[i];
-        let ci = aps.atm_typeindex[i];
         let xi = coord[[i, 0]];
         let yi = coord[[i, 1]];
         let zi = coord[[i, 2]];
@@ -215,18 +267,26 @@ fn calc_mm(ndx_rec_norm: &Vec<usize>, ndx_lig_norm: &Vec<usize>, aps: &AtomPrope
                 continue;
             }
             let qj = aps.atm_charge[j];
-            let cj = aps.atm_typeindex[j];
             let xj = coord[[j, 0]];
             let yj = coord[[j, 1]];
             let zj = coord[[j, 2]];
+            // LJ-14 pairs use their own parameters and scaled charges, other excluded pairs are skipped
+            let (fudge_qq, c6, c12) = match aps.pairs_14.get(&(i.min(j), i.max(j))) {
+                Some(&(c6, c12, fudge_qq)) => (fudge_qq, c6, c12),
+                None if aps.atm_excls[i].contains(&j) => continue,
+                None => {
+                    let (c6, c12) = aps.get_lj_params(i, j);
+                    (1.0, c6, c12)
+                }
+            };
             let r = f64::sqrt((xi - xj).powi(2) + (yi - yj).powi(2) + (zi - zj).powi(2));
             if r < settings.r_cutoff {
                 let e_elec = match settings.use_dh {
-                    false => qi * qj / r,
-                    _ => qi * qj / r * f64::exp(-kap * r)   // doi: 10.1088/0256-307X/38/1/018701
+                    false => fudge_qq * qi * qj / r,
+                    _ => fudge_qq * qi * qj / r * f64::exp(-kap * r)   // doi: 10.1088/0256-307X/38/1/018701
                 };
                 let r = r / 10.0;
-                let e_vdw = (aps.c12[[ci, cj]] / r.powi(6) - aps.c6[[ci, cj]]) / r.powi(6);
+                let e_vdw = (c12 / r.powi(6) - c6) / r.powi(6);
                 de_elec[aps.atm_resid[i]] += e_elec;
                 de_elec[aps.atm_resid[j]] += e_elec;
                 de_vdw[aps.atm_resid[i]] += e_vdw;
@@ -235,6 +295,7 @@ fn calc_mm(ndx_rec_norm: &Vec<usize>, ndx_lig_norm: &Vec<usize>, aps: &AtomPrope
         }
     }
 
+    // pdie is the solute dielectric of both MM and PB, epsilon-r of simulation by default if larger than 1
     de_elec.par_iter_mut().for_each(|p| *p *= kj_elec / (2.0 * pdie));
     de_vdw.par_iter_mut().for_each(|p| *p /= 2.0);
 
@@ -245,144 +306,349 @@ fn calc_pbsa(idx: usize, coord: &ArrayBase<ViewRepr<&f64>, Dim<[usize; 2]>>, fra
             ndx_rec_norm: &Vec<usize>, ndx_lig_norm: &Vec<usize>, ndx_com_norm: &Vec<usize>,
             pb_res: &mut ArrayBase<OwnedRepr<f64>, Dim<[usize; 2]>>, sa_res: &mut ArrayBase<OwnedRepr<f64>, Dim<[usize; 2]>>,
             cur_frm: usize, sys_name: &String, temp_dir: &PathBuf, 
-            aps: &AtomProperty, pbe_set: &PBESet, pba_set: &PBASet, settings: &Settings) {
-    // From AMBER-PB4, the surface extension constant γ=0.0072 kcal/(mol·Å2)=0.030125 kJ/(mol·Å^2)
-    // but the default gamma parameter for apbs calculation is set to 1, in order to directly obtain the surface area
-    // then the SA energy term is calculated by s_mmpbsa
-    let gamma = 0.030125;
-    let bias = 0.0;
+            aps: &AtomProperty, pbe_set: &PBESet, pba_set: &PBASet, mem_set: Option<&MembraneSet>,
+            manifest_frame: Option<&ManifestFrame>, settings: &Settings) -> Result<()> {
+    // the gamma parameter for apbs calculation is set to 1, in order to directly obtain the surface area
+    // then the SA energy term is calculated by s_mmpbsa with γ and bias of SA settings
+    let gamma = pba_set.gamma;
+    let bias = pba_set.bias;
     let f_name = format!("{}_{}ns", sys_name, frames[cur_frm].time / 1000.0);
-    if let Some(apbs) = &settings.apbs {
-        write_apbs_input(ndx_rec_norm, ndx_lig_norm, coord, &aps.atm_radius,
-                pbe_set, pba_set, temp_dir, &f_name, settings);
-        // invoke apbs program to do apbs calculations
-        let apbs_result = Command::new(apbs).arg(format!("{}.apbs", f_name)).current_dir(temp_dir).output().expect("running apbs failed.");
-        let apbs_err = String::from_utf8(apbs_result.stderr).expect("Failed to parse apbs output.");
-        let apbs_result = String::from_utf8(apbs_result.stdout).expect("Failed to parse apbs output.");
-        if settings.debug_mode {
-            let mut outfile = File::create(temp_dir.join(format!("{}.out", f_name))).expect("Failed to create output file.");
-            outfile.write_all(apbs_result.as_bytes()).expect("Failed to write apbs output.");
-            let mut errfile = File::create(temp_dir.join(format!("{}.err", f_name))).expect("Failed to create err file.");
-            errfile.write_all(apbs_err.as_bytes()).expect("Failed to write apbs output.");
+    let (apbs_result, grids) = match (manifest_frame, &settings.apbs) {
+        (Some(manifest_frame), _) => read_apbs_outputs(ndx_rec_norm, ndx_lig_norm, coord, aps, temp_dir, manifest_frame, settings)?,
+        (None, Some(apbs)) => run_apbs_frame(apbs, ndx_rec_norm, ndx_lig_norm, coord, aps,
+                                              temp_dir, &f_name, pbe_set, pba_set, mem_set, false, settings)?,
+        (None, None) => return Ok(())
+    };
+
+    // electrostatic potentials at atoms (kT/e) written by apbs, the finest grid for focusing
+    // solvation energy of atom = q (φ_sol - φ_vac) kT / 2
+    let kt = 8.314462618 * pbe_set.temp / 1e3;
+    let calc_pb = |sys: &str, ndx: &Vec<usize>| -> Result<Array1<f64>> {
+        let plan = &grids.iter().find(|(s, _)| s == sys).unwrap().1;
+        let (pot_sol, pot_vac) = match plan.is_para() {
+            false => (read_atompot(temp_dir, &format!("{}_{}_SOL_atompot", f_name, sys), None, ndx.len())?,
+                      read_atompot(temp_dir, &format!("{}_{}_VAC_atompot", f_name, sys), None, ndx.len())?),
+            true => {
+                // potential of atom is taken from the partition it belongs to
+                let mut pot_sol: Vec<Vec<f64>> = vec![];
+                let mut pot_vac: Vec<Vec<f64>> = vec![];
+                for async_id in 0..plan.partitions() {
+                    pot_sol.push(read_atompot(temp_dir, &format!("{}_{}_SOL_atompot_PE{}", f_name, sys, async_id), Some(async_id), ndx.len())?);
+                    pot_vac.push(read_atompot(temp_dir, &format!("{}_{}_VAC_atompot_PE{}", f_name, sys, async_id), Some(async_id), ndx.len())?);
+                }
+                let owners: Vec<usize> = ndx.iter().map(|&i| plan.owner([coord[[i, 0]], coord[[i, 1]], coord[[i, 2]]])).collect();
+                (owners.iter().enumerate().map(|(k, &o)| pot_sol[o][k]).collect(),
+                 owners.iter().enumerate().map(|(k, &o)| pot_vac[o][k]).collect())
+            }
+        };
+        Ok(ndx.iter().enumerate().map(|(k, &i)| aps.atm_charge[i] * (pot_sol[k] - pot_vac[k]) * kt / 2.0).collect())
+    };
+    // apbs has no file output of atomic SASA, which is read from its output by calculation name and atom index
+    let sasa = parse_apbs_sasa(&apbs_result);
+    let calc_sa = |sys: &str, ndx: &Vec<usize>| -> Result<Array1<f64>> {
+        let name = format!("{}_{}_SAS", f_name, sys);
+        let sasa = sasa.get(&name).ok_or_else(|| MmpbsaError::Apbs(format!("no SASA of calculation {}", name)))?;
+        let mut sa: Array1<f64> = Array1::zeros(ndx.len());
+        for &(i, v) in sasa {
+            if i >= ndx.len() {
+                return Err(MmpbsaError::Apbs(format!("atom {} out of {} atoms in calculation {}", i, ndx.len(), name)));
+            }
+            sa[i] = gamma * v + bias / ndx.len() as f64;
         }
-        // let apbs_result = fs::read_to_string(temp_dir.join(format!("{}.out", f_name))).expect("Failed to parse apbs output.");
+        match sasa.len() == ndx.len() {
+            true => Ok(sa),
+            false => Err(MmpbsaError::Apbs(format!("{} SASA values of {} atoms from calculation {}", sasa.len(), ndx.len(), name)))
+        }
+    };
+
+    let rec_pb = calc_pb("rec", ndx_rec_norm)?;
+    let rec_sa = calc_sa("rec", ndx_rec_norm)?;
+    let (com_pb, com_sa, lig_pb, lig_sa) = match ndx_rec_norm[0] != ndx_lig_norm[0] {
+        true => (calc_pb("com", ndx_com_norm)?, calc_sa("com", ndx_com_norm)?,
+                 calc_pb("lig", ndx_lig_norm)?, calc_sa("lig", ndx_lig_norm)?),
+        false => (Array1::zeros(0), Array1::zeros(0), Array1::zeros(0), Array1::zeros(0))
+    };
+
+    // residue decomposition
+    // atoms in rec and lig pqr are in the order of complex, groups may not be continuous (e.g. moved metals)
+    let pos_rec = |i: usize| ndx_rec_norm.binary_search(&i).unwrap();
+    let pos_lig = |i: usize| ndx_lig_norm.binary_search(&i).unwrap();
+    // lipids are part of the implicit membrane, not solvent accessible
+    let sa_scale = |i: usize| match mem_set {
+        Some(mem_set) if mem_set.is_lipid(&aps.atm_resname[i]) => 0.0,
+        _ => 1.0
+    };
 
-        // preserve CALCULATION, Atom and SASA lines
-        let apbs_result: Vec<&str> = apbs_result.split("\n").filter_map(|p|
-            if p.trim().starts_with("CALCULATION") || p.trim().starts_with("Atom") || p.trim().starts_with("SASA") {
-                Some(p.trim())
+    if ndx_rec_norm[0] == ndx_lig_norm[0] {
+        // if no ligand, pb_com = pb_lig = 0, so real energy is inversed rec_pbsa
+        for &i in ndx_com_norm {
+            pb_res[[idx, aps.atm_resid[i]]] += rec_pb[pos_rec(i)];
+            sa_res[[idx, aps.atm_resid[i]]] += rec_sa[pos_rec(i)] * sa_scale(i);
+        }
+    } else {
+        for &i in ndx_com_norm {
+            if ndx_rec_norm.binary_search(&i).is_ok() {
+                pb_res[[idx, aps.atm_resid[i]]] += com_pb[i] - rec_pb[pos_rec(i)];
+                sa_res[[idx, aps.atm_resid[i]]] += (com_sa[i] - rec_sa[pos_rec(i)]) * sa_scale(i);
             } else {
-                None
+                pb_res[[idx, aps.atm_resid[i]]] += com_pb[i] - lig_pb[pos_lig(i)];
+                sa_res[[idx, aps.atm_resid[i]]] += (com_sa[i] - lig_sa[pos_lig(i)]) * sa_scale(i);
             }
-        ).collect();
-
-        // extract apbs results
-        let indexes: Vec<usize> = apbs_result.iter().enumerate().filter_map(|(i, &p)| match p.starts_with("CAL") {
-            true => Some(i),
-            false => None
-        }).collect();
+        }
+    }
+    Ok(())
+}
 
-        let mut com_pb_sol: Vec<f64> = vec![];
-        let mut com_pb_vac: Vec<f64> = vec![];
-        let mut rec_pb_sol: Vec<f64> = vec![];
-        let mut rec_pb_vac: Vec<f64> = vec![];
-        let mut lig_pb_sol: Vec<f64> = vec![];
-        let mut lig_pb_vac: Vec<f64> = vec![];
-        let mut com_sa: Vec<f64> = vec![];
-        let mut rec_sa: Vec<f64> = vec![];
-        let mut lig_sa: Vec<f64> = vec![];
-
-        let mut skip_pb = true;     // the first time PB calculation should be wasted
-        for (i, &idx) in indexes.iter().enumerate() {
-            let st = idx + 1;
-            let ed = match indexes.get(i + 1) {
-                Some(&idx) => idx,
-                None => apbs_result.len()
-            };
-            if apbs_result[idx].contains(&"_com_SOL") {
-                if !skip_pb {
-                    apbs_result[st..ed].par_iter().map(|&p| parse_apbs_line(p)).collect_into_vec(&mut com_pb_sol);
-                }
-                skip_pb = !skip_pb;
-            } else if apbs_result[idx].contains(&"_com_VAC") {
-                if !skip_pb {
-                    apbs_result[st..ed].par_iter().map(|&p| parse_apbs_line(p)).collect_into_vec(&mut com_pb_vac);
-                }
-                skip_pb = !skip_pb;
-            } else if apbs_result[idx].contains(&"_rec_SOL") {
-                if !skip_pb {
-                    apbs_result[st..ed].par_iter().map(|&p| parse_apbs_line(p)).collect_into_vec(&mut rec_pb_sol);
+// write apbs input and run it, failed apbs is rerun with enlarged grids, or coarser grids if out of memory
+// write_maps: also write potential and dielectric maps, which need a single fine grid without mg-para
+pub fn run_apbs_frame(apbs: &str, ndx_rec_norm: &Vec<usize>, ndx_lig_norm: &Vec<usize>,
+                      coord: &ArrayBase<ViewRepr<&f64>, Dim<[usize; 2]>>, aps: &AtomProperty, temp_dir: &PathBuf, f_name: &String,
+                      pbe_set: &PBESet, pba_set: &PBASet, mem_set: Option<&MembraneSet>, write_maps: bool,
+                      settings: &Settings) -> Result<(String, Vec<(String, GridPlan)>)> {
+    // rerun failed apbs with enlarged grids, or coarser grids if out of memory
+    let mut grid_set = settings.clone();
+    let mut retry = 0;
+    loop {
+        // membrane maps are not supported by mg-para
+        let grids = plan_grids(ndx_rec_norm, ndx_lig_norm, coord, &aps.atm_radius, &grid_set, mem_set.is_none() && !write_maps);
+        write_apbs_input(ndx_rec_norm, ndx_lig_norm, &grids,
+                pbe_set, pba_set, temp_dir, &f_name, mem_set, write_maps)?;
+        let apbs_result = match mem_set {
+            // dielectric and kappa maps by mg-dummy, then draw membrane on them
+            Some(mem_set) => run_apbs(apbs, &format!("{}_maps", f_name), temp_dir, settings).and_then(|_| {
+                for map_name in get_membrane_maps(&f_name, ndx_rec_norm, ndx_lig_norm) {
+                    mem_set.draw(temp_dir, &map_name, pbe_set.sdie)?;
                 }
-                skip_pb = !skip_pb;
-            } else if apbs_result[idx].contains(&"_rec_VAC") {
-                if !skip_pb {
-                    apbs_result[st..ed].par_iter().map(|&p| parse_apbs_line(p)).collect_into_vec(&mut rec_pb_vac);
-                }
-                skip_pb = !skip_pb;
-            } else if apbs_result[idx].contains(&"_lig_SOL") {
-                if !skip_pb {
-                    apbs_result[st..ed].par_iter().map(|&p| parse_apbs_line(p)).collect_into_vec(&mut lig_pb_sol);
-                }
-                skip_pb = !skip_pb;
-            } else if apbs_result[idx].contains(&"_lig_VAC") {
-                if !skip_pb {
-                    apbs_result[st..ed].par_iter().map(|&p| parse_apbs_line(p)).collect_into_vec(&mut lig_pb_vac);
+                run_apbs(apbs, &f_name, temp_dir, settings)
+            }),
+            None => run_apbs(apbs, &f_name, temp_dir, settings)
+        };
+        // mg-para partitions
+        let partitions = grids.iter().map(|(_, plan)| plan.partitions()).max().unwrap();
+        let apbs_result = (0..partitions).filter(|_| partitions > 1)
+            .fold(apbs_result, |result, async_id| result.and_then(|result|
+                run_apbs(apbs, &format!("{}_PE{}", f_name, async_id), temp_dir, settings).map(|_| result)));
+        match apbs_result {
+            Ok(apbs_result) => return Ok((apbs_result, grids)),
+            Err(e) if retry == APBS_RETRIES => return Err(e),
+            Err(e) => {
+                let e = e.to_string().to_lowercase();
+                if e.contains("memory") || e.contains("alloc") {
+                    grid_set.df *= 1.5;
+                } else {
+                    grid_set.cfac *= 1.5;
+                    grid_set.fadd += 5.0;
                 }
-                skip_pb = !skip_pb;
-            } else if apbs_result[idx].contains(&"_com_SAS") {
-                apbs_result[st..ed].par_iter().map(|&p| parse_apbs_line(p)).collect_into_vec(&mut com_sa);
-            } else if apbs_result[idx].contains(&"_rec_SAS") {
-                apbs_result[st..ed].par_iter().map(|&p| parse_apbs_line(p)).collect_into_vec(&mut rec_sa);
-            } else if apbs_result[idx].contains(&"_lig_SAS") {
-                apbs_result[st..ed].par_iter().map(|&p| parse_apbs_line(p)).collect_into_vec(&mut lig_sa);
+                retry += 1;
             }
         }
+    }
+}
 
-        let com_pb: Array1<f64> = Array1::from_vec(com_pb_sol) - Array1::from_vec(com_pb_vac);
-        let com_sa: Array1<f64> = Array1::from_vec(com_sa.par_iter().map(|i| gamma * *i + bias / com_sa.len() as f64).collect());
-        let rec_pb: Array1<f64> = Array1::from_vec(rec_pb_sol) - Array1::from_vec(rec_pb_vac);
-        let rec_sa: Array1<f64> = Array1::from_vec(rec_sa.par_iter().map(|i| gamma * *i + bias / rec_sa.len() as f64).collect());
-        let lig_pb: Array1<f64> = Array1::from_vec(lig_pb_sol) - Array1::from_vec(lig_pb_vac);
-        let lig_sa: Array1<f64> = Array1::from_vec(lig_sa.par_iter().map(|i| gamma * *i + bias / lig_sa.len() as f64).collect());
-
-        // residue decomposition
-        let offset_rec = match ndx_lig_norm[0].cmp(&ndx_rec_norm[0]) {
-            Ordering::Less => 0,
-            Ordering::Greater => ndx_rec_norm.len(),
-            Ordering::Equal => 0
-        };
-        let offset_lig = match ndx_rec_norm[0].cmp(&ndx_lig_norm[0]) {
-            Ordering::Less => 0,
-            Ordering::Greater => ndx_lig_norm.len(),
-            Ordering::Equal => 0
-        };
+// outputs of exported apbs inputs run elsewhere, grids are planned as exported
+fn read_apbs_outputs(ndx_rec_norm: &Vec<usize>, ndx_lig_norm: &Vec<usize>,
+                     coord: &ArrayBase<ViewRepr<&f64>, Dim<[usize; 2]>>, aps: &AtomProperty, temp_dir: &PathBuf,
+                     manifest_frame: &ManifestFrame, settings: &Settings) -> Result<(String, Vec<(String, GridPlan)>)> {
+    // grids are planned again to locate atoms in mg-para partitions, they should be the exported ones
+    let grids = plan_grids(ndx_rec_norm, ndx_lig_norm, coord, &aps.atm_radius, settings, true);
+    let partitions = grids.iter().map(|(_, plan)| plan.partitions()).max().unwrap();
+    let inputs_num = match partitions {
+        1 => 1,
+        p => p + 1
+    };
+    if inputs_num != manifest_frame.inputs.len() {
+        return Err(MmpbsaError::Apbs(format!("{} APBS inputs exported but {} expected, atoms or grids have been changed",
+            manifest_frame.inputs.len(), inputs_num)));
+    }
+    let mut apbs_result = String::new();
+    for (input, output) in manifest_frame.inputs.iter().zip(&manifest_frame.outputs) {
+        let output = fs::read_to_string(temp_dir.join(output))
+            .map_err(|_| MmpbsaError::Apbs(format!("no output {}", output)))?;
+        let errors = get_apbs_errors(&output, "");
+        if !errors.is_empty() {
+            return Err(MmpbsaError::Apbs(format!("{}: {}", input, errors.join("; "))));
+        }
+        apbs_result.push_str(&output);
+    }
+    Ok((apbs_result, grids))
+}
+
+// run apbs with input file {f_name}.apbs, returns its output, or the error messages if failed
+fn run_apbs(apbs: &str, f_name: &str, temp_dir: &PathBuf, settings: &Settings) -> Result<String> {
+    let apbs_result = Command::new(apbs).arg(format!("{}.apbs", f_name)).current_dir(temp_dir).output()
+        .map_err(|e| MmpbsaError::Apbs(format!("running {}: {}", apbs, e)))?;
+    let apbs_out = String::from_utf8_lossy(&apbs_result.stdout).to_string();
+    let apbs_err = String::from_utf8_lossy(&apbs_result.stderr).to_string();
+    let errors = get_apbs_errors(&apbs_out, &apbs_err);
+    // outputs of failed runs are always kept for checking
+    if settings.debug_mode || !apbs_result.status.success() || !errors.is_empty() {
+        let out = temp_dir.join(format!("{}.out", f_name));
+        fs::write(&out, apbs_out.as_bytes()).map_err(MmpbsaError::io(out.to_string_lossy()))?;
+        let err = temp_dir.join(format!("{}.err", f_name));
+        fs::write(&err, apbs_err.as_bytes()).map_err(MmpbsaError::io(err.to_string_lossy()))?;
+    }
+    if !apbs_result.status.success() {
+        Err(MmpbsaError::Apbs(format!("{}.apbs exited with {}: {}", f_name, apbs_result.status, errors.join("; "))))
+    } else if !errors.is_empty() {
+        Err(MmpbsaError::Apbs(format!("{}.apbs: {}", f_name, errors.join("; "))))
+    } else {
+        Ok(apbs_out)
+    }
+}
+
+// error messages of apbs, e.g. atoms off the mesh, memory allocation failed
+fn get_apbs_errors(apbs_out: &str, apbs_err: &str) -> Vec<String> {
+    let mut errors: Vec<String> = vec![];
+    for line in apbs_out.lines().chain(apbs_err.lines()).map(|l| l.trim()) {
+        let l = line.to_lowercase();
+        if APBS_ERRORS.iter().any(|e| l.contains(e)) && !errors.iter().any(|e| e == line) {
+            errors.push(line.to_string());
+        }
+    }
+    errors.truncate(3);
+    errors
+}
 
-        if ndx_rec_norm[0] == ndx_lig_norm[0] {
-            // if no ligand, pb_com = pb_lig = 0, so real energy is inversed rec_pbsa
-            for &i in ndx_com_norm {
-                pb_res[[idx, aps.atm_resid[i]]] += rec_pb[i - offset_lig];
-                sa_res[[idx, aps.atm_resid[i]]] += rec_sa[i - offset_lig];
+// write failed frames and reasons
+fn write_apbs_failures(failures: &Vec<(f64, MmpbsaError)>, report: &PathBuf) -> Result<()> {
+    let mut lines = format!("{:>12}  reason\n", "time (ns)");
+    for (time, e) in failures {
+        lines.push_str(&format!("{:12.4}  {}\n", time / 1000.0, e));
+    }
+    fs::write(report, lines).map_err(MmpbsaError::io(report.to_string_lossy()))
+}
+
+fn calc_cav_disp(idx: usize, coord: &ArrayBase<ViewRepr<&f64>, Dim<[usize; 2]>>,
+                 ndx_rec_norm: &Vec<usize>, ndx_lig_norm: &Vec<usize>, ndx_com_norm: &Vec<usize>,
+                 cav_res: &mut ArrayBase<OwnedRepr<f64>, Dim<[usize; 2]>>, disp_res: &mut ArrayBase<OwnedRepr<f64>, Dim<[usize; 2]>>,
+                 aps: &AtomProperty, pba_set: &PBASet, mem_set: Option<&MembraneSet>) {
+    if pba_set.press == 0.0 && pba_set.bconc == 0.0 {
+        return;
+    }
+    let pos_rec = |i: usize| ndx_rec_norm.binary_search(&i).unwrap();
+    let pos_lig = |i: usize| ndx_lig_norm.binary_search(&i).unwrap();
+    // lipids are part of the implicit membrane, as SA
+    let scale = |i: usize| match mem_set {
+        Some(mem_set) if mem_set.is_lipid(&aps.atm_resname[i]) => 0.0,
+        _ => 1.0
+    };
+    let (rec_cav, rec_disp) = calc_nonpolar(ndx_rec_norm, coord, aps, pba_set);
+    if ndx_rec_norm[0] == ndx_lig_norm[0] {
+        for &i in ndx_com_norm {
+            cav_res[[idx, aps.atm_resid[i]]] += rec_cav[pos_rec(i)] * scale(i);
+            disp_res[[idx, aps.atm_resid[i]]] += rec_disp[pos_rec(i)] * scale(i);
+        }
+    } else {
+        let (com_cav, com_disp) = calc_nonpolar(ndx_com_norm, coord, aps, pba_set);
+        let (lig_cav, lig_disp) = calc_nonpolar(ndx_lig_norm, coord, aps, pba_set);
+        for &i in ndx_com_norm {
+            let (cav, disp) = match ndx_rec_norm.binary_search(&i).is_ok() {
+                true => (rec_cav[pos_rec(i)], rec_disp[pos_rec(i)]),
+                false => (lig_cav[pos_lig(i)], lig_disp[pos_lig(i)])
+            };
+            cav_res[[idx, aps.atm_resid[i]]] += (com_cav[i] - cav) * scale(i);
+            disp_res[[idx, aps.atm_resid[i]]] += (com_disp[i] - disp) * scale(i);
+        }
+    }
+}
+
+// potentials at atoms in apbs atompot flat file, in the order of pqr
+// apbs may append -PE{async_id} to the file of mg-para partition
+fn read_atompot(temp_dir: &PathBuf, stem: &str, async_id: Option<usize>, n: usize) -> Result<Vec<f64>> {
+    let file = match async_id.map(|i| temp_dir.join(format!("{}-PE{}.txt", stem, i))) {
+        Some(file) if file.is_file() => file,
+        _ => temp_dir.join(format!("{}.txt", stem))
+    };
+    let content = fs::read_to_string(&file)
+        .map_err(|_| MmpbsaError::Apbs(format!("no atomic potentials file {}", file.display())))?;
+    let pot: Vec<f64> = content.lines().filter(|l| !l.trim().starts_with('#'))
+        .flat_map(|l| l.split(|c: char| c.is_whitespace() || c == ','))
+        .filter(|v| !v.is_empty())
+        .map(|v| v.parse::<f64>())
+        .collect::<std::result::Result<Vec<f64>, _>>()
+        .map_err(|_| MmpbsaError::Apbs(format!("cannot parse atomic potentials in {}", file.display())))?;
+    match pot.len() == n {
+        true => Ok(pot),
+        false => Err(MmpbsaError::Apbs(format!("{} potentials of {} atoms in {}", pot.len(), n, file.display())))
+    }
+}
+
+// atomic SASA of each APOLAR calculation, from lines of "CALCULATION #n (name): ..." and "SASA for atom i: value"
+fn parse_apbs_sasa(apbs_result: &str) -> HashMap<String, Vec<(usize, f64)>> {
+    let mut sasa: HashMap<String, Vec<(usize, f64)>> = HashMap::new();
+    let mut name: Option<String> = None;
+    for line in apbs_result.lines().map(|l| l.trim()) {
+        if line.starts_with("CALCULATION") {
+            name = line.split_once('(').and_then(|(_, p)| p.split_once(')')).map(|(n, _)| n.to_string());
+            // the last one wins if reported more than once
+            if let Some(name) = &name {
+                sasa.insert(name.to_string(), vec![]);
             }
-        } else {
-            for &i in ndx_com_norm {
-                if ndx_rec_norm.contains(&i) {
-                    pb_res[[idx, aps.atm_resid[i]]] += com_pb[i] - rec_pb[i - offset_lig];
-                    sa_res[[idx, aps.atm_resid[i]]] += com_sa[i] - rec_sa[i - offset_lig];
-                } else {
-                    pb_res[[idx, aps.atm_resid[i]]] += com_pb[i] - lig_pb[i - offset_rec];
-                    sa_res[[idx, aps.atm_resid[i]]] += com_sa[i] - lig_sa[i - offset_rec];
+        } else if let (Some(name), Some(p)) = (&name, line.strip_prefix("SASA for atom")) {
+            if let Some((i, v)) = p.split_once(':') {
+                if let (Ok(i), Some(Ok(v))) = (i.trim().parse::<usize>(), v.split_whitespace().next().map(|v| v.parse::<f64>())) {
+                    sasa.get_mut(name).unwrap().push((i, v));
                 }
             }
         }
     }
+    sasa
 }
 
-fn parse_apbs_line(line: &str) -> f64 {
-    line.split(":")
-        .skip(1)
-        .next().expect("Cannot get information from apbs")
-        .trim_start()
-        .split(" ")
-        .next().expect("Cannot get information from apbs")
-        .parse().expect("Cannot parse value from apbs")
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRMTOP: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/small.prmtop");
+
+    // atoms along x with 1.5 A spacing
+    fn coord() -> Array2<f64> {
+        Array2::from_shape_fn((8, 3), |(i, d)| if d == 0 { 1.5 * i as f64 } else { 0.0 })
+    }
+
+    // total self-energy of atoms, in e^2/A and kJ/mol without DH and cutoff
+    fn self_mm(tpr: &TPR, ndx: &Vec<usize>) -> (AtomProperty, f64, f64) {
+        let ndx_com: Vec<usize> = (0..tpr.n_atoms).collect();
+        let aps = AtomProperty::new(tpr, &ndx_com, 0.0).unwrap();
+        let residues = get_residues(tpr, &ndx_com);
+        let coeff = Coefficients { kj_elec: 1.0, kap: 0.0, pdie: 1.0 };
+        let mut settings = Settings::new();
+        settings.use_dh = false;
+        settings.r_cutoff = f64::INFINITY;
+        let (de_elec, de_vdw) = calc_mm(ndx, ndx, &aps, &coord().view(), &residues, &coeff, &settings);
+        (aps, de_elec.sum(), de_vdw.sum())
+    }
+
+    #[test]
+    fn mm_excluded_pair() {
+        let tpr = TPR::from_prmtop(PRMTOP, &Settings::new()).unwrap();
+        // bonded HH31-HH32 and CB-OG
+        for ndx in [vec![0, 1], vec![3, 4]] {
+            let (_, elec, vdw) = self_mm(&tpr, &ndx);
+            assert_eq!((elec, vdw), (0.0, 0.0));
+        }
+    }
+
+    #[test]
+    fn mm_14_pair() {
+        let tpr = TPR::from_prmtop(PRMTOP, &Settings::new()).unwrap();
+        // HH31-CB is 1-4 with fudgeQQ 1/1.2 and LJ-14 scaled by 1/SCNB
+        let (aps, elec, vdw) = self_mm(&tpr, &vec![0, 3]);
+        let (c6, c12, fudge_qq) = aps.pairs_14[&(0, 3)];
+        assert!((fudge_qq - 1.0 / 1.2).abs() < 1e-9);
+        assert!((elec - fudge_qq * aps.atm_charge[0] * aps.atm_charge[3] / 4.5).abs() < 1e-12);
+        assert!((vdw - (c12 / 0.45f64.powi(12) - c6 / 0.45f64.powi(6))).abs() < 1e-9);
+        assert!((c6 - 30.0 * 4.184 / 1e6 / 2.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn mm_14_global_fudge_qq() {
+        let mut tpr = TPR::from_prmtop(PRMTOP, &Settings::new()).unwrap();
+        // function types without their own fudgeQQ use the global one
+        tpr.lj14_fudge_qq.clear();
+        tpr.fudge_qq = 0.5;
+        let (aps, elec, _) = self_mm(&tpr, &vec![1, 4]);
+        assert_eq!(aps.pairs_14[&(1, 4)].2, 0.5);
+        assert!((elec - 0.5 * aps.atm_charge[1] * aps.atm_charge[4] / 4.5).abs() < 1e-12);
+    }
+}
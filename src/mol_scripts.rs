@@ -0,0 +1,169 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use crate::analyzation::Results;
+use crate::error::{MmpbsaError, Result};
+use crate::pdb::EnergyUnit;
+use crate::plot::top_residues;
+
+// consecutive atom indexes (from 0, the order in pdb) of atoms, as (first, last),
+// selected by index since pdb serials wrap at 100000
+fn index_ranges(ndx: &[usize]) -> Vec<(usize, usize)> {
+    let mut ndx = ndx.to_vec();
+    ndx.sort();
+    let mut ranges: Vec<(usize, usize)> = vec![];
+    for i in ndx {
+        match ranges.last_mut() {
+            Some(r) if r.1 + 1 == i => r.1 = i,
+            _ => ranges.push((i, i))
+        }
+    }
+    ranges
+}
+
+// CA of the residue, or its first atom if no CA
+fn label_atom(results: &Results, res_id: usize) -> usize {
+    let atoms: Vec<usize> = (0..results.aps.atm_resid.len()).filter(|&i| results.aps.atm_resid[i] == res_id).collect();
+    *atoms.iter().find(|&&i| results.aps.atm_name[i] == "CA").unwrap_or(&atoms[0])
+}
+
+fn has_ligand(results: &Results) -> bool {
+    results.ndx_lig[0] != results.ndx_rec[0]
+}
+
+fn write_script(out: &Path, s: &str) -> Result<()> {
+    let f = File::create(out).map_err(MmpbsaError::io(out.to_string_lossy()))?;
+    let mut f = BufWriter::new(f);
+    f.write_all(s.as_bytes()).map_err(MmpbsaError::io(out.to_string_lossy()))?;
+    Ok(())
+}
+
+// PyMOL loads the pdb files or models as states of one object, colors are by B-factors of the first state
+// PyMOL index starts at 1 in order of atoms in the object
+fn write_pml(results: &Results, pdbs: &[String], frames: usize, b_max: f64, unit: &str, labels: &[(usize, String)], out: &Path) -> Result<()> {
+    let id_sel = |ndx: &[usize]| index_ranges(ndx).iter()
+        .map(|(a, b)| if a == b { (a + 1).to_string() } else { format!("{}-{}", a + 1, b + 1) })
+        .collect::<Vec<String>>().join("+");
+    let mut s = String::new();
+    s.push_str("# PyMOL script written by s_mmpbsa, run by: pymol this_file.pml\n");
+    s.push_str(&format!("# B-factor is the INVERSED residue-wised ΔH in {}, blue: favorable, red: unfavorable\n", unit));
+    if frames > 1 {
+        s.push_str("# Frames are loaded as states, colors are by B-factors of the first state since PyMOL keeps one B-factor per atom\n");
+    }
+    match pdbs.len() {
+        1 => s.push_str(&format!("load {}, complex\n", pdbs[0])),
+        _ => for (i, pdb) in pdbs.iter().enumerate() {
+            s.push_str(&format!("load {}, complex, {}\n", pdb, i + 1));
+        }
+    }
+    s.push_str(&format!("select rec, complex and index {}\n", id_sel(&results.ndx_rec)));
+    if has_ligand(results) {
+        s.push_str(&format!("select lig, complex and index {}\n", id_sel(&results.ndx_lig)));
+    }
+    s.push_str("hide everything, complex\n");
+    s.push_str("show cartoon, rec\n");
+    if has_ligand(results) {
+        s.push_str("show sticks, lig\n");
+    }
+    s.push_str("set cartoon_discrete_colors, 1\n");
+    s.push_str(&format!("spectrum b, red_white_blue, complex, minimum={:.3}, maximum={:.3}\n", -b_max, b_max));
+    s.push_str(&format!("ramp_new energy_scale, complex, [{:.3}, 0, {:.3}], [red, white, blue]\n", -b_max, b_max));
+    for (atom, text) in labels {
+        s.push_str(&format!("label complex and index {}, \"{}\"\n", atom + 1, text));
+    }
+    s.push_str("set label_size, 14\n");
+    s.push_str("set label_position, (0, 0, 3)\n");
+    s.push_str("deselect\n");
+    s.push_str(match has_ligand(results) {
+        true => "orient lig\n",
+        false => "orient complex\n"
+    });
+    write_script(out, &s)
+}
+
+// VMD loads the pdb files or models as frames of one molecule, B-factors of each model are copied to the
+// user field of its frame so that colors follow the frame
+// VMD index starts at 0 in order of atoms in the molecule
+fn write_vmd_tcl(results: &Results, pdbs: &[String], b_max: f64, unit: &str, labels: &[(usize, String)], out: &Path) -> Result<()> {
+    let index_sel = |ndx: &[usize]| index_ranges(ndx).iter()
+        .map(|(a, b)| if a == b { a.to_string() } else { format!("{} to {}", a, b) })
+        .collect::<Vec<String>>().join(" ");
+    let mut s = String::new();
+    s.push_str("# VMD script written by s_mmpbsa, run by: vmd -e this_file.tcl\n");
+    s.push_str(&format!("# B-factor is the INVERSED residue-wised ΔH in {}, blue: favorable, red: unfavorable\n", unit));
+    s.push_str(&format!("set files {{{}}}\n", pdbs.iter().map(|p| format!("{{{}}}", p)).collect::<Vec<String>>().join(" ")));
+    s.push_str("set mol [mol new [lindex $files 0] type pdb waitfor all]\n");
+    s.push_str("foreach f [lrange $files 1 end] {\n    mol addfile $f type pdb waitfor all molid $mol\n}\n");
+    s.push_str("# VMD reads B-factors only from the first model, copy those of each model to the user field of its frame\n");
+    s.push_str("set all [atomselect $mol all]\n");
+    s.push_str("set frame 0\n");
+    s.push_str("foreach f $files {\n");
+    s.push_str("    set fp [open $f r]\n");
+    s.push_str("    set b {}\n");
+    s.push_str("    while {[gets $fp line] >= 0} {\n");
+    s.push_str("        set rec [string range $line 0 5]\n");
+    s.push_str("        if {$rec eq \"ATOM  \" || $rec eq \"HETATM\"} {\n");
+    s.push_str("            lappend b [string trim [string range $line 60 65]]\n");
+    s.push_str("        } elseif {$rec eq \"ENDMDL\" || $rec eq \"END\"} {\n");
+    s.push_str("            if {[llength $b] > 0} {\n");
+    s.push_str("                $all frame $frame\n");
+    s.push_str("                $all set user $b\n");
+    s.push_str("                incr frame\n");
+    s.push_str("                set b {}\n");
+    s.push_str("            }\n");
+    s.push_str("        }\n");
+    s.push_str("    }\n");
+    s.push_str("    close $fp\n");
+    s.push_str("}\n");
+    s.push_str("mol top $mol\n");
+    s.push_str("mol delrep 0 $mol\n");
+    s.push_str("color scale method RWB\n");
+    let mut reps = vec![("NewCartoon", index_sel(&results.ndx_rec))];
+    if has_ligand(results) {
+        reps.push(("Licorice", index_sel(&results.ndx_lig)));
+    }
+    for (i, (rep, sel)) in reps.iter().enumerate() {
+        s.push_str(&format!("mol representation {}\n", rep));
+        s.push_str(&format!("mol selection {{index {}}}\n", sel));
+        s.push_str("mol color User\n");
+        s.push_str("mol addrep $mol\n");
+        s.push_str(&format!("mol scaleminmax $mol {} {:.3} {:.3}\n", i, -b_max, b_max));
+        s.push_str(&format!("mol colupdate {} $mol 1\n", i));
+    }
+    s.push_str("label delete Atoms all\n");
+    for (i, (atom, text)) in labels.iter().enumerate() {
+        s.push_str(&format!("label add Atoms $mol/{}\n", atom));
+        s.push_str(&format!("label textformat Atoms {} {{{}}}\n", i, text));
+    }
+    s.push_str("animate goto 0\n");
+    write_script(out, &s)
+}
+
+// PyMOL and VMD scripts to view the pdb files of write_bf_pdb, of frames ts_ids, colored by -ΔH in unit
+// of residues on a symmetric scale, with labels of the top n residues
+pub fn write_view_scripts(results: &Results, sys_name: &str, pdbs: &[String], ts_ids: &[usize],
+                          unit: EnergyUnit, top_n: usize, wd: &Path) -> Result<Vec<PathBuf>> {
+    let b_max = ts_ids.iter()
+        .flat_map(|&t| results.dh_res.row(t).to_vec())
+        .fold(0.0, |a: f64, b| a.max(b.abs() / unit.factor()))
+        .max(0.01);
+    let labels: Vec<(usize, String)> = top_residues(results, top_n).into_iter()
+        .map(|i| (label_atom(results, i), format!("{}{}", results.residues[i].name, results.residues[i].nr)))
+        .collect();
+    let pml = wd.join(format!("MMPBSA_binding_energy_{}.pml", sys_name));
+    let tcl = wd.join(format!("MMPBSA_binding_energy_{}.tcl", sys_name));
+    write_pml(results, pdbs, ts_ids.len(), b_max, unit.name(), &labels, &pml)?;
+    write_vmd_tcl(results, pdbs, b_max, unit.name(), &labels, &tcl)?;
+    Ok(vec![pml, tcl])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_of_indexes() {
+        assert_eq!(index_ranges(&[5, 0, 1, 2, 7, 6, 9]), vec![(0, 2), (5, 7), (9, 9)]);
+        assert_eq!(index_ranges(&[99999, 100000]), vec![(99999, 100000)]);
+    }
+}
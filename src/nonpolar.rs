@@ -0,0 +1,118 @@
+use ndarray::{Array1, ArrayView2};
+use rayon::prelude::*;
+use crate::apbs_param::PBASet;
+use crate::atom_property::AtomProperty;
+
+// grid spacing (A) for volume and dispersion integration
+const GRID_SPACING: f64 = 0.5;
+// cutoff (A) of dispersion integration on grid, the longer range is integrated analytically
+const DISP_CUTOFF: f64 = 10.0;
+// LJ parameters of TIP3P oxygen as solvent
+const WATER_SIGMA: f64 = 3.15061;       // A
+const WATER_EPSILON: f64 = 0.6364;      // kJ/mol
+
+// cavity (press x SAV) and WCA dispersion energies of atoms in ndx (kJ/mol), in the order of ndx
+// SAV is partitioned to atoms by power diagram, i.e. a grid point inside the SAV belongs to the atom
+// with the least d^2 - (r + srad)^2
+// dispersion is the WCA attractive potential between atom and solvent oxygen integrated over
+// the solvent region outside the SAV, with solvent density bconc
+pub fn calc_nonpolar(ndx: &Vec<usize>, coord: &ArrayView2<f64>, aps: &AtomProperty, pba_set: &PBASet) -> (Array1<f64>, Array1<f64>) {
+    let mut cav: Array1<f64> = Array1::zeros(ndx.len());
+    let mut disp: Array1<f64> = Array1::zeros(ndx.len());
+    if pba_set.press == 0.0 && pba_set.bconc == 0.0 {
+        return (cav, disp);
+    }
+    let h = GRID_SPACING;
+    let dv = h.powi(3);
+    let rad: Vec<f64> = ndx.iter().map(|&i| aps.atm_radius[i] + pba_set.srad).collect();
+    let margin = match pba_set.bconc == 0.0 {
+        true => rad.iter().fold(0.0, |a: f64, &b| a.max(b)),
+        false => DISP_CUTOFF
+    } + h;
+    let mut min = [f64::INFINITY; 3];
+    let mut max = [f64::NEG_INFINITY; 3];
+    for &i in ndx {
+        for d in 0..3 {
+            min[d] = min[d].min(coord[[i, d]] - margin);
+            max[d] = max[d].max(coord[[i, d]] + margin);
+        }
+    }
+    let n: Vec<usize> = (0..3).map(|d| ((max[d] - min[d]) / h).ceil() as usize + 1).collect();
+    let grid_index = |p: [usize; 3]| (p[0] * n[1] + p[1]) * n[2] + p[2];
+    // grid points around x within r
+    let grid_range = |x: f64, r: f64, d: usize| {
+        let lb = ((x - r - min[d]) / h).floor().max(0.0) as usize;
+        let ub = (((x + r - min[d]) / h).ceil() as usize).min(n[d] - 1);
+        lb..=ub
+    };
+
+    // owner atom of each grid point inside the SAV, -1 for solvent
+    let mut owner: Vec<i32> = vec![-1; n[0] * n[1] * n[2]];
+    let mut power: Vec<f64> = vec![0.0; n[0] * n[1] * n[2]];
+    for (k, &i) in ndx.iter().enumerate() {
+        let x = [coord[[i, 0]], coord[[i, 1]], coord[[i, 2]]];
+        for gx in grid_range(x[0], rad[k], 0) {
+            let dx = min[0] + gx as f64 * h - x[0];
+            for gy in grid_range(x[1], rad[k], 1) {
+                let dy = min[1] + gy as f64 * h - x[1];
+                for gz in grid_range(x[2], rad[k], 2) {
+                    let dz = min[2] + gz as f64 * h - x[2];
+                    let p = dx * dx + dy * dy + dz * dz - rad[k] * rad[k];
+                    let g = grid_index([gx, gy, gz]);
+                    if p < 0.0 && (owner[g] < 0 || p < power[g]) {
+                        owner[g] = k as i32;
+                        power[g] = p;
+                    }
+                }
+            }
+        }
+    }
+
+    if pba_set.press != 0.0 {
+        for &o in owner.iter().filter(|&&o| o >= 0) {
+            cav[o as usize] += pba_set.press * dv;
+        }
+    }
+
+    if pba_set.bconc != 0.0 {
+        let rho = pba_set.bconc;
+        let disp_vec: Vec<f64> = ndx.par_iter().map(|&i| {
+            let (c6, c12) = aps.get_lj_params(i, i);
+            if c6 == 0.0 || c12 == 0.0 {
+                return 0.0;
+            }
+            // Lorentz-Berthelot combination with solvent oxygen
+            let sigma = ((10.0 * (c12 / c6).powf(1.0 / 6.0)) + WATER_SIGMA) / 2.0;
+            let eps = (c6 * c6 / (4.0 * c12) * WATER_EPSILON).sqrt();
+            let r_min = 2.0_f64.powf(1.0 / 6.0) * sigma;
+            let x = [coord[[i, 0]], coord[[i, 1]], coord[[i, 2]]];
+            let mut e = 0.0;
+            for gx in grid_range(x[0], DISP_CUTOFF, 0) {
+                let dx = min[0] + gx as f64 * h - x[0];
+                for gy in grid_range(x[1], DISP_CUTOFF, 1) {
+                    let dy = min[1] + gy as f64 * h - x[1];
+                    for gz in grid_range(x[2], DISP_CUTOFF, 2) {
+                        if owner[grid_index([gx, gy, gz])] >= 0 {
+                            continue;
+                        }
+                        let dz = min[2] + gz as f64 * h - x[2];
+                        let r = (dx * dx + dy * dy + dz * dz).sqrt();
+                        if r > DISP_CUTOFF {
+                            continue;
+                        }
+                        e += match r < r_min {
+                            true => -eps,
+                            false => 4.0 * eps * ((sigma / r).powi(12) - (sigma / r).powi(6))
+                        };
+                    }
+                }
+            }
+            // analytical integration beyond cutoff
+            let tail = 16.0 * std::f64::consts::PI * eps
+                * (sigma.powi(12) / (9.0 * DISP_CUTOFF.powi(9)) - sigma.powi(6) / (3.0 * DISP_CUTOFF.powi(3)));
+            rho * (e * dv + tail)
+        }).collect();
+        disp = Array1::from_vec(disp_vec);
+    }
+    (cav, disp)
+}
@@ -0,0 +1,293 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use regex::Regex;
+use crate::parse_tpr::{FlatTopology, LJType, TPR, get_lj_radius};
+use crate::error::{MmpbsaError, Result, get_cap};
+use crate::settings::Settings;
+
+const AMBER_CHARGE_FACTOR: f64 = 18.2223;   // sqrt(332.0522173), prmtop charge to e
+const KCAL_TO_KJ: f64 = 4.184;
+
+impl TPR {
+    // AMBER prmtop (parm7) file
+    pub fn from_prmtop(prmtop: &str, settings: &Settings) -> Result<TPR> {
+        println!("Loading prmtop file: {}\n", prmtop);
+        let flags = read_prmtop_flags(prmtop)?;
+        let pointers: Vec<usize> = get_flag(&flags, "POINTERS", prmtop)?;
+        if pointers.len() < 12 {
+            return Err(MmpbsaError::parse(prmtop, "POINTERS not found or incomplete"));
+        }
+        let atoms_num = pointers[0];
+        let atom_types_num = pointers[1];
+        let res_num = pointers[11];
+        println!("Total atoms number: {}", atoms_num);
+        println!("Total atom types: {}.", atom_types_num);
+
+        let name = match flags.get("TITLE").or(flags.get("CTITLE")) {
+            Some(t) => t.join("").trim().to_string(),
+            None => String::from("AMBER_system")
+        };
+        let name = if name.is_empty() { String::from("AMBER_system") } else { name };
+        println!("System name: {}", name);
+
+        let atom_names: Vec<String> = get_flag(&flags, "ATOM_NAME", prmtop)?;
+        let atom_charges: Vec<f64> = get_flag::<f64>(&flags, "CHARGE", prmtop)?.iter().map(|q| q / AMBER_CHARGE_FACTOR).collect();
+        let atom_masses: Vec<f64> = get_flag(&flags, "MASS", prmtop)?;
+        let atom_numbers: Vec<i32> = match flags.contains_key("ATOMIC_NUMBER") {
+            true => get_flag(&flags, "ATOMIC_NUMBER", prmtop)?,
+            false => vec![-1; atoms_num]
+        };
+        let atom_types: Vec<usize> = get_flag::<usize>(&flags, "ATOM_TYPE_INDEX", prmtop)?.iter().map(|t| t - 1).collect();
+        let res_names: Vec<String> = get_flag(&flags, "RESIDUE_LABEL", prmtop)?;
+        let res_pointers: Vec<usize> = get_flag::<usize>(&flags, "RESIDUE_POINTER", prmtop)?.iter().map(|p| p - 1).collect();
+
+        // LJ parameters, A = c12 and B = c6 in kcal/mol and A
+        let nb_index: Vec<i64> = get_flag(&flags, "NONBONDED_PARM_INDEX", prmtop)?;
+        let acoef: Vec<f64> = get_flag(&flags, "LENNARD_JONES_ACOEF", prmtop)?;
+        let bcoef: Vec<f64> = get_flag(&flags, "LENNARD_JONES_BCOEF", prmtop)?;
+        let get_c6_c12 = |ti: usize, tj: usize| -> (f64, f64) {
+            match nb_index[ti * atom_types_num + tj] {
+                ico if ico > 0 => (bcoef[ico as usize - 1] * KCAL_TO_KJ / 1e6,
+                                   acoef[ico as usize - 1] * KCAL_TO_KJ / 1e12),
+                _ => (0.0, 0.0)     // 10-12 hydrogen bond terms are not supported
+            }
+        };
+        let mut lj_sr_params: Vec<LJType> = vec![];
+        for i in 0..atom_types_num {
+            for j in 0..atom_types_num {
+                let (c6, c12) = get_c6_c12(i, j);
+                lj_sr_params.push(LJType::new(i * atom_types_num + j, c6, c12));
+            }
+        }
+        println!("Total LJ function types: {}", lj_sr_params.len());
+
+        // radius: intrinsic Born radii in prmtop if exist, otherwise from LJ parameters
+        let atom_radii: Vec<f64> = match flags.contains_key("RADII") {
+            true => {
+                println!("Using RADII in prmtop as force field radius.");
+                get_flag::<f64>(&flags, "RADII", prmtop)?.iter().zip(&atom_types).map(|(&r, &t)| match r {
+                    r if r > 0.0 => r,
+                    _ => {
+                        let lj = &lj_sr_params[t * atom_types_num + t];
+                        get_lj_radius(lj.c6, lj.c12, settings.rad_ff_default)
+                    }
+                }).collect()
+            }
+            false => atom_types.iter().map(|&t| {
+                let lj = &lj_sr_params[t * atom_types_num + t];
+                get_lj_radius(lj.c6, lj.c12, settings.rad_ff_default)
+            }).collect()
+        };
+
+        // residues
+        let mut atom_resids: Vec<usize> = vec![0; atoms_num];
+        for r in 0..res_num {
+            let last = match res_pointers.get(r + 1) {
+                Some(&p) => p,
+                None => atoms_num
+            };
+            atom_resids[res_pointers[r]..last].fill(r);
+        }
+        let res_nrs: Vec<i32> = (1..=res_num as i32).collect();
+
+        // molecules, only defined in prmtop with periodic box
+        let mol_ranges: Vec<(usize, usize)> = match flags.contains_key("ATOMS_PER_MOLECULE") {
+            true => {
+                let mut first = 0;
+                get_flag::<usize>(&flags, "ATOMS_PER_MOLECULE", prmtop)?.iter().map(|&n| {
+                    first += n;
+                    (first - n, first)
+                }).collect()
+            }
+            false => vec![(0, atoms_num)]
+        };
+
+        // exclusions, only j > i listed, 0 as placeholder of empty list
+        let excls_num: Vec<usize> = get_flag(&flags, "NUMBER_EXCLUDED_ATOMS", prmtop)?;
+        let excls_list: Vec<usize> = get_flag(&flags, "EXCLUDED_ATOMS_LIST", prmtop)?;
+        let mut excls: Vec<Vec<usize>> = vec![vec![]; atoms_num];
+        let mut p = 0;
+        for i in 0..atoms_num {
+            for &j in &excls_list[p..p + excls_num[i]] {
+                if j > 0 {
+                    excls[i].push(j - 1);
+                    excls[j - 1].push(i);
+                }
+            }
+            p += excls_num[i];
+        }
+
+        // bonds, atom indexes are saved as 3 * (i - 1)
+        let mut bonds: Vec<(usize, usize)> = vec![];
+        for flag in ["BONDS_INC_HYDROGEN", "BONDS_WITHOUT_HYDROGEN"] {
+            bonds.extend(get_flag::<usize>(&flags, flag, prmtop)?.chunks(3).map(|b| (b[0] / 3, b[1] / 3)));
+        }
+
+        // 1-4 pairs from dihedrals, negative 3rd atom means the 1-4 interaction is skipped
+        let dih_types = get_flag::<f64>(&flags, "DIHEDRAL_FORCE_CONSTANT", prmtop)?.len();
+        let scee: Vec<f64> = match flags.contains_key("SCEE_SCALE_FACTOR") {
+            true => get_flag(&flags, "SCEE_SCALE_FACTOR", prmtop)?,
+            false => vec![1.2; dih_types]
+        };
+        let scnb: Vec<f64> = match flags.contains_key("SCNB_SCALE_FACTOR") {
+            true => get_flag(&flags, "SCNB_SCALE_FACTOR", prmtop)?,
+            false => vec![2.0; dih_types]
+        };
+        let mut dihedrals: Vec<i64> = get_flag(&flags, "DIHEDRALS_INC_HYDROGEN", prmtop)?;
+        dihedrals.extend(get_flag::<i64>(&flags, "DIHEDRALS_WITHOUT_HYDROGEN", prmtop)?);
+        let mut pairs: Vec<(usize, usize, f64, f64, f64)> = vec![];
+        let mut paired: HashSet<(usize, usize)> = HashSet::new();
+        let mut scee_used: HashMap<u64, usize> = HashMap::new();
+        for d in dihedrals.chunks(5) {
+            if d[2] < 0 || d[3] < 0 {
+                continue;
+            }
+            let i = d[0].unsigned_abs() as usize / 3;
+            let j = d[3].unsigned_abs() as usize / 3;
+            let t = d[4] as usize - 1;
+            if !paired.insert((i.min(j), i.max(j))) {
+                continue;
+            }
+            let (c6, c12) = get_c6_c12(atom_types[i], atom_types[j]);
+            pairs.push((i, j, c6 / scnb[t], c12 / scnb[t], 1.0 / scee[t]));
+            *scee_used.entry(scee[t].to_bits()).or_insert(0) += 1;
+        }
+        // each pair keeps its own 1/SCEE, the most common one is reported as fudgeQQ
+        let fudge_qq = match scee_used.iter().max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(a.0))) {
+            Some((&s, _)) => 1.0 / f64::from_bits(s),
+            None => 1.0 / 1.2
+        };
+        if scee_used.len() > 1 {
+            println!("Note: {} different SCEE scale factors found in dihedrals, each 1-4 pair uses its own.", scee_used.len());
+        }
+        println!("Total 1-4 pairs: {}, fudgeQQ: {:.4}", pairs.len(), fudge_qq);
+
+        Ok(TPR::from_flat(FlatTopology {
+            name,
+            atom_names,
+            atom_types,
+            atom_charges,
+            atom_masses,
+            atom_numbers,
+            atom_radii,
+            atom_resids,
+            res_names,
+            res_nrs,
+            mol_ranges,
+            atom_types_num,
+            lj_sr_params,
+            excls,
+            bonds,
+            pairs,
+            fudge_qq,
+        }))
+    }
+}
+
+// %FLAG name -> fixed-width fields
+fn read_prmtop_flags(prmtop: &str) -> Result<HashMap<String, Vec<String>>> {
+    let content = fs::read_to_string(prmtop).map_err(MmpbsaError::io(prmtop))?;
+    let re = Regex::new(r"\((\d+)?[aAiIeEfF](\d+)").unwrap();
+    let mut flags: HashMap<String, Vec<String>> = HashMap::new();
+    let mut flag = String::new();
+    let mut width = 80;
+    for line in content.lines() {
+        if line.starts_with("%FLAG") {
+            flag = line[5..].trim().to_string();
+            flags.insert(flag.to_string(), vec![]);
+        } else if line.starts_with("%FORMAT") {
+            width = get_cap(&re, line, 2, prmtop)?;
+        } else if !line.starts_with('%') && !flag.is_empty() {
+            let fields = flags.get_mut(&flag).unwrap();
+            let chars: Vec<char> = line.chars().collect();
+            for field in chars.chunks(width) {
+                fields.push(field.iter().collect::<String>());
+            }
+        }
+    }
+    Ok(flags)
+}
+
+fn get_flag<T: std::str::FromStr>(flags: &HashMap<String, Vec<String>>, flag: &str, prmtop: &str) -> Result<Vec<T>> {
+    match flags.get(flag) {
+        Some(fields) => fields.iter().filter(|p| !p.trim().is_empty())
+            .map(|p| p.trim().parse().map_err(|_| MmpbsaError::parse(prmtop, format!("invalid {}: {}", flag, p))))
+            .collect(),
+        None => Ok(vec![])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRMTOP: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/small.prmtop");
+
+    fn load() -> TPR {
+        TPR::from_prmtop(PRMTOP, &Settings::new()).unwrap()
+    }
+
+    #[test]
+    fn fixed_width_fields() {
+        let flags = read_prmtop_flags(PRMTOP).unwrap();
+        // 20a4 names without spaces between them
+        let names: Vec<String> = get_flag(&flags, "ATOM_NAME", PRMTOP).unwrap();
+        assert_eq!(names, vec!["HH31", "HH32", "CA", "CB", "OG", "HG", "NA", "CL"]);
+        let title: Vec<String> = get_flag(&flags, "TITLE", PRMTOP).unwrap();
+        assert_eq!(title.join(""), "small test");
+        // 10I8 over several lines
+        let pointers: Vec<usize> = get_flag(&flags, "POINTERS", PRMTOP).unwrap();
+        assert_eq!(pointers.len(), 31);
+        assert_eq!((pointers[0], pointers[1], pointers[11]), (8, 2, 2));
+        // absent flag is empty
+        assert!(get_flag::<f64>(&flags, "RADII", PRMTOP).unwrap().is_empty());
+    }
+
+    #[test]
+    fn atoms_and_molecules() {
+        let tpr = load();
+        assert_eq!(tpr.name, "small_test");
+        assert_eq!(tpr.n_atoms, 8);
+        assert_eq!(tpr.molecules.len(), 2);
+        assert_eq!(tpr.molecules[0].atoms_num, 6);
+        assert_eq!(tpr.molecules[1].atoms_num, 2);
+        let charges: Vec<f64> = tpr.molecules.iter().flat_map(|m| m.atoms.iter().map(|a| a.charge)).collect();
+        for (q, r) in charges.iter().zip([0.1, 0.1, -0.2, 0.3, -0.5, 0.2, 1.0, -1.0]) {
+            assert!((q - r).abs() < 1e-6);
+        }
+        assert_eq!(tpr.molecules[1].atoms[0].atomic_number, 11);
+        assert_eq!(tpr.molecules[1].residues[0].name, "SLT");
+        assert_eq!(tpr.molecules[1].bonds, vec![(0, 1)]);
+    }
+
+    #[test]
+    fn exclusions() {
+        let tpr = load();
+        let mut excls = tpr.molecules[0].excls.clone();
+        excls.iter_mut().for_each(|e| e.sort());
+        assert_eq!(excls[0], vec![1, 2, 3]);
+        assert_eq!(excls[3], vec![0, 1, 2, 4, 5]);
+        // 0 placeholder of the last atom is not an exclusion
+        assert_eq!(excls[5], vec![2, 3, 4]);
+        assert_eq!(tpr.molecules[1].excls, vec![vec![1], vec![0]]);
+    }
+
+    #[test]
+    fn pairs_14() {
+        let tpr = load();
+        let mut pairs: Vec<(usize, usize, usize)> = tpr.molecules[0].pairs.iter().map(|p| (p.i, p.j, p.func_id)).collect();
+        pairs.sort();
+        // duplicated and skipped (negative 3rd atom) dihedrals give no pairs
+        assert_eq!(pairs.iter().map(|p| (p.0, p.1)).collect::<Vec<_>>(), vec![(0, 3), (1, 4), (2, 5)]);
+        assert!(tpr.molecules[1].pairs.is_empty());
+        let qq = |f: usize| tpr.lj14_fudge_qq[&f];
+        assert!((qq(pairs[0].2) - 1.0 / 1.2).abs() < 1e-9);
+        assert!((qq(pairs[1].2) - 1.0).abs() < 1e-9);
+        assert!((qq(pairs[2].2) - 1.0 / 1.2).abs() < 1e-9);
+        assert!((tpr.fudge_qq - 1.0 / 1.2).abs() < 1e-9);
+        // type 2 - type 1 scaled by 1/SCNB
+        let lj = &tpr.lj14_params[&pairs[0].2];
+        assert!((lj.c6 - 30.0 * KCAL_TO_KJ / 1e6 / 2.0).abs() < 1e-12);
+        assert!((lj.c12 - 1.0e4 * KCAL_TO_KJ / 1e12 / 2.0).abs() < 1e-18);
+    }
+}
@@ -0,0 +1,337 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use crate::parse_tpr::{FlatTopology, LJType, TPR, get_lj_radius};
+use crate::error::{MmpbsaError, Result};
+use crate::settings::Settings;
+
+const KCAL_TO_KJ: f64 = 4.184;
+
+// section keywords of CHARMM parameter files, ending the NONBONDED or NBFIX section
+const PRM_SECTIONS: [&str; 12] = ["ATOMS", "BONDS", "ANGLES", "THETAS", "DIHEDRALS", "PHI",
+    "IMPROPER", "IMPHI", "CMAP", "HBOND", "END", "RETURN"];
+
+// epsilon (kcal/mol, negative) and Rmin/2 (A), and those for 1-4 interactions
+#[derive(Clone, Copy)]
+struct CharmmLJ {
+    eps: f64,
+    rmin_2: f64,
+    eps14: f64,
+    rmin14_2: f64,
+}
+
+impl TPR {
+    // CHARMM/X-PLOR psf file, with LJ parameters from CHARMM parameter files
+    pub fn from_psf(psf: &str, prm_files: &Vec<String>, settings: &Settings) -> Result<TPR> {
+        println!("Loading psf file: {}\n", psf);
+        let content = fs::read_to_string(psf).map_err(MmpbsaError::io(psf))?;
+        let mut lines = content.lines();
+        if !lines.next().unwrap_or("").starts_with("PSF") {
+            println!("Warning: {} does not start with PSF, may not be a valid psf file.", psf);
+        }
+
+        let mut name = String::from("CHARMM_system");
+        let mut atom_names: Vec<String> = vec![];
+        let mut atom_type_names: Vec<String> = vec![];
+        let mut atom_charges: Vec<f64> = vec![];
+        let mut atom_masses: Vec<f64> = vec![];
+        let mut atom_segids: Vec<String> = vec![];
+        let mut atom_resids: Vec<usize> = vec![];
+        let mut res_names: Vec<String> = vec![];
+        let mut res_nrs: Vec<i32> = vec![];
+        let mut bonds: Vec<(usize, usize)> = vec![];
+
+        while let Some(line) = lines.next() {
+            if line.contains("!NTITLE") {
+                let titles: usize = get_count(line, psf)?;
+                let title: Vec<&str> = (0..titles).filter_map(|_| lines.next())
+                    .map(|l| l.trim().trim_start_matches('*').trim()).filter(|l| !l.is_empty()).collect();
+                if let Some(&t) = title.first() {
+                    name = t.to_string();
+                }
+            } else if line.contains("!NATOM") {
+                // id segid resid resname name type charge mass
+                let atoms_num: usize = get_count(line, psf)?;
+                let mut last_res = (String::new(), String::new());
+                for _ in 0..atoms_num {
+                    let f: Vec<&str> = lines.next()
+                        .ok_or_else(|| MmpbsaError::parse(psf, "unexpected end of file"))?.split_whitespace().collect();
+                    if f.len() < 8 {
+                        return Err(MmpbsaError::parse(psf, format!("unexpected atom: {}", f.join(" "))));
+                    }
+                    let res = (f[1].to_string(), f[2].to_string());
+                    if res != last_res || res_names.is_empty() {
+                        res_names.push(f[3].to_string());
+                        res_nrs.push(f[2].trim_end_matches(char::is_alphabetic).parse().unwrap_or(0));
+                        last_res = res;
+                    }
+                    atom_segids.push(f[1].to_string());
+                    atom_resids.push(res_names.len() - 1);
+                    atom_names.push(f[4].to_string());
+                    atom_type_names.push(f[5].to_string());
+                    atom_charges.push(f[6].parse().map_err(|_| MmpbsaError::parse(psf, format!("invalid charge: {}", f[6])))?);
+                    atom_masses.push(f[7].parse().map_err(|_| MmpbsaError::parse(psf, format!("invalid mass: {}", f[7])))?);
+                }
+            } else if line.contains("!NBOND") {
+                let bonds_num: usize = get_count(line, psf)?;
+                let mut ids: Vec<usize> = vec![];
+                while ids.len() < bonds_num * 2 {
+                    let l = lines.next().ok_or_else(|| MmpbsaError::parse(psf, "unexpected end of file"))?;
+                    for p in l.split_whitespace() {
+                        match p.parse::<usize>() {
+                            Ok(id) if id > 0 => ids.push(id - 1),
+                            _ => return Err(MmpbsaError::parse(psf, format!("invalid bond: {}", l.trim())))
+                        }
+                    }
+                }
+                bonds.extend(ids.chunks(2).map(|b| (b[0], b[1])));
+                break;
+            }
+        }
+        let atoms_num = atom_names.len();
+        println!("System name: {}", name);
+        println!("Total atoms number: {}", atoms_num);
+
+        // atom types, old psf use numbers while X-PLOR psf use names
+        let mut type_names: Vec<String> = vec![];
+        let mut type_ids: HashMap<String, usize> = HashMap::new();
+        let atom_types: Vec<usize> = atom_type_names.iter().map(|t| {
+            *type_ids.entry(t.to_string()).or_insert_with(|| {
+                type_names.push(t.to_string());
+                type_names.len() - 1
+            })
+        }).collect();
+        let atom_types_num = type_names.len();
+        println!("Total atom types: {}.", atom_types_num);
+
+        // LJ parameters
+        let (nonbonded, nbfix) = read_charmm_lj(prm_files)?;
+        let mut type_lj: Vec<CharmmLJ> = vec![];
+        for t in &type_names {
+            match nonbonded.get(t) {
+                Some(&lj) => type_lj.push(lj),
+                None => {
+                    println!("Warning: LJ parameters of atom type {} not found in parameter files, will use zero.", t);
+                    type_lj.push(CharmmLJ { eps: 0.0, rmin_2: 0.0, eps14: 0.0, rmin14_2: 0.0 });
+                }
+            }
+        }
+        // E = eps * ((Rmin / r)^12 - 2 * (Rmin / r)^6), to c6 and c12 in gromacs units
+        let to_c6_c12 = |eps: f64, rmin: f64| -> (f64, f64) {
+            let eps = eps.abs() * KCAL_TO_KJ;
+            let rmin = rmin / 10.0;
+            (2.0 * eps * rmin.powi(6), eps * rmin.powi(12))
+        };
+        let get_c6_c12 = |ti: usize, tj: usize, is_14: bool| -> (f64, f64) {
+            let key = (type_names[ti].to_string(), type_names[tj].to_string());
+            if let Some(&(eps, rmin)) = nbfix.get(&key) {
+                return to_c6_c12(eps, rmin);
+            }
+            let (li, lj) = (type_lj[ti], type_lj[tj]);
+            match is_14 {
+                true => to_c6_c12((li.eps14 * lj.eps14).sqrt(), li.rmin14_2 + lj.rmin14_2),
+                false => to_c6_c12((li.eps * lj.eps).sqrt(), li.rmin_2 + lj.rmin_2)
+            }
+        };
+        let mut lj_sr_params: Vec<LJType> = vec![];
+        for i in 0..atom_types_num {
+            for j in 0..atom_types_num {
+                let (c6, c12) = get_c6_c12(i, j, false);
+                lj_sr_params.push(LJType::new(i * atom_types_num + j, c6, c12));
+            }
+        }
+        println!("Total LJ function types: {}", lj_sr_params.len());
+        let atom_radii: Vec<f64> = atom_types.iter().map(|&t| {
+            let lj = &lj_sr_params[t * atom_types_num + t];
+            get_lj_radius(lj.c6, lj.c12, settings.rad_ff_default)
+        }).collect();
+
+        // exclusions (1-2, 1-3) and 1-4 pairs from bonds, as CHARMM nbxmod 5
+        let mut neighbors: Vec<HashSet<usize>> = vec![HashSet::new(); atoms_num];
+        for &(i, j) in &bonds {
+            neighbors[i].insert(j);
+            neighbors[j].insert(i);
+        }
+        let mut excls: Vec<Vec<usize>> = vec![vec![]; atoms_num];
+        let mut pairs: Vec<(usize, usize, f64, f64, f64)> = vec![];
+        for i in 0..atoms_num {
+            let n12 = &neighbors[i];
+            let n13: HashSet<usize> = n12.iter().flat_map(|&j| neighbors[j].iter().cloned()).collect();
+            let n14: HashSet<usize> = n13.iter().flat_map(|&j| neighbors[j].iter().cloned()).collect();
+            excls[i].extend(n12.union(&n13).cloned().filter(|&j| j != i));
+            for &j in &n14 {
+                if j > i && !n12.contains(&j) && !n13.contains(&j) {
+                    let (c6, c12) = get_c6_c12(atom_types[i], atom_types[j], true);
+                    pairs.push((i, j, c6, c12, 1.0));
+                    excls[i].push(j);
+                    excls[j].push(i);
+                }
+            }
+        }
+        println!("Total 1-4 pairs: {}", pairs.len());
+
+        // molecules by segment
+        let mut mol_ranges: Vec<(usize, usize)> = vec![];
+        for i in 0..atoms_num {
+            if i == 0 || atom_segids[i] != atom_segids[i - 1] {
+                mol_ranges.push((i, i + 1));
+            } else {
+                mol_ranges.last_mut().unwrap().1 = i + 1;
+            }
+        }
+
+        Ok(TPR::from_flat(FlatTopology {
+            name,
+            atom_names,
+            atom_types,
+            atom_charges,
+            atom_masses,
+            atom_numbers: vec![-1; atoms_num],     // psf has no elements, guessed by masses
+            atom_radii,
+            atom_resids,
+            res_names,
+            res_nrs,
+            mol_ranges,
+            atom_types_num,
+            lj_sr_params,
+            excls,
+            bonds,
+            pairs,
+            fudge_qq: 1.0,      // CHARMM does not scale 1-4 electrostatics
+        }))
+    }
+}
+
+// NONBONDED and NBFIX sections of CHARMM parameter (prm, par, str) files
+fn read_charmm_lj(prm_files: &Vec<String>) -> Result<(HashMap<String, CharmmLJ>, HashMap<(String, String), (f64, f64)>)> {
+    let mut nonbonded: HashMap<String, CharmmLJ> = HashMap::new();
+    let mut nbfix: HashMap<(String, String), (f64, f64)> = HashMap::new();
+    for prm in prm_files {
+        println!("Loading CHARMM parameter file: {}", prm);
+        let content = fs::read_to_string(prm).map_err(MmpbsaError::io(prm))?;
+        let mut section = String::new();
+        let mut continued = false;      // NONBONDED header may continue with "-"
+        for line in content.lines() {
+            let line = line.split('!').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let first = line.split_whitespace().next().unwrap().to_uppercase();
+            if continued {
+                continued = line.ends_with('-');
+                continue;
+            }
+            if first.starts_with("NONB") || first.starts_with("NBON") {
+                section = String::from("NONBONDED");
+                continued = line.ends_with('-');
+                continue;
+            } else if first.starts_with("NBFIX") {
+                section = String::from("NBFIX");
+                continue;
+            } else if PRM_SECTIONS.iter().any(|s| first.starts_with(s)) {
+                section.clear();
+                continue;
+            }
+            let f: Vec<&str> = line.split_whitespace().collect();
+            match section.as_str() {
+                // type ignored epsilon Rmin/2 [ignored eps,1-4 Rmin/2,1-4]
+                "NONBONDED" if f.len() >= 4 => {
+                    let values: Vec<f64> = f[1..].iter().filter_map(|p| p.parse().ok()).collect();
+                    if values.len() < 3 {
+                        continue;
+                    }
+                    let (eps, rmin_2) = (values[1], values[2]);
+                    let (eps14, rmin14_2) = match values.len() >= 6 {
+                        true => (values[4], values[5]),
+                        false => (eps, rmin_2)
+                    };
+                    nonbonded.insert(f[0].to_string(), CharmmLJ { eps, rmin_2, eps14, rmin14_2 });
+                }
+                // type1 type2 Emin Rmin
+                "NBFIX" if f.len() >= 4 => {
+                    if let (Ok(eps), Ok(rmin)) = (f[2].parse::<f64>(), f[3].parse::<f64>()) {
+                        nbfix.insert((f[0].to_string(), f[1].to_string()), (eps, rmin));
+                        nbfix.insert((f[1].to_string(), f[0].to_string()), (eps, rmin));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    println!("Found LJ parameters of {} atom types and {} NBFIX pairs.", nonbonded.len(), nbfix.len() / 2);
+    Ok((nonbonded, nbfix))
+}
+
+// count at the beginning of psf section header, e.g. "    5 !NATOM"
+fn get_count(line: &str, psf: &str) -> Result<usize> {
+    line.split_whitespace().next().and_then(|p| p.parse().ok())
+        .ok_or_else(|| MmpbsaError::parse(psf, format!("invalid section header: {}", line.trim())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PSF: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/small.psf");
+    const PRM: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/small.prm");
+
+    fn load() -> TPR {
+        TPR::from_psf(PSF, &vec![PRM.to_string()], &Settings::new()).unwrap()
+    }
+
+    // c6 and c12 of epsilon and Rmin in kcal/mol and A
+    fn c6_c12(eps: f64, rmin: f64) -> (f64, f64) {
+        let eps = eps * KCAL_TO_KJ;
+        (2.0 * eps * (rmin / 10.0).powi(6), eps * (rmin / 10.0).powi(12))
+    }
+
+    #[test]
+    fn atoms_and_molecules() {
+        let tpr = load();
+        assert_eq!(tpr.name, "small_test_system");
+        assert_eq!(tpr.n_atoms, 6);
+        assert_eq!(tpr.atom_types_num, 6);
+        // molecules by segment, residues by resid
+        assert_eq!(tpr.molecules.len(), 2);
+        assert_eq!(tpr.molecules[0].atoms_num, 5);
+        assert_eq!(tpr.molecules[0].residues.len(), 2);
+        assert_eq!(tpr.molecules[0].atoms[3].resind, 1);
+        assert_eq!(tpr.molecules[1].residues[0].name, "SOD");
+        let charges: Vec<f64> = tpr.molecules.iter().flat_map(|m| m.atoms.iter().map(|a| a.charge)).collect();
+        assert_eq!(charges, vec![-0.47, 0.07, -0.27, 0.51, -0.51, 1.0]);
+        assert!((tpr.molecules[0].atoms[4].mass - 15.999).abs() < 1e-9);
+        assert_eq!(tpr.fudge_qq, 1.0);
+    }
+
+    #[test]
+    fn exclusions_and_pairs() {
+        let tpr = load();
+        let mut excls = tpr.molecules[0].excls.clone();
+        excls.iter_mut().for_each(|e| e.sort());
+        // 1-2, 1-3 and 1-4 are excluded
+        assert_eq!(excls[0], vec![1, 2, 3]);
+        assert_eq!(excls[2], vec![0, 1, 3, 4]);
+        assert!(tpr.molecules[1].excls[0].is_empty());
+        let mut pairs: Vec<(usize, usize, usize)> = tpr.molecules[0].pairs.iter().map(|p| (p.i, p.j, p.func_id)).collect();
+        pairs.sort();
+        assert_eq!(pairs.iter().map(|p| (p.0, p.1)).collect::<Vec<_>>(), vec![(0, 3), (1, 4)]);
+        assert_eq!(tpr.lj14_fudge_qq[&pairs[0].2], 1.0);
+        // NH1 1-4 parameters with C, which has no 1-4 parameters
+        let (c6, c12) = c6_c12((0.2f64 * 0.11).sqrt(), 1.55 + 2.0);
+        let lj = &tpr.lj14_params[&pairs[0].2];
+        assert!((lj.c6 - c6).abs() < 1e-12 && (lj.c12 - c12).abs() < 1e-18);
+    }
+
+    #[test]
+    fn lj_params() {
+        let tpr = load();
+        // types in order of appearance: NH1 CT1 CT3 C O SOD
+        let (c6, c12) = c6_c12((0.2f64 * 0.032).sqrt(), 1.85 + 2.0);
+        let lj = &tpr.lj_sr_params[1];
+        assert!((lj.c6 - c6).abs() < 1e-12 && (lj.c12 - c12).abs() < 1e-18);
+        // NBFIX of SOD and O in both orders
+        let (c6, c12) = c6_c12(0.07502, 3.23);
+        for lj in [&tpr.lj_sr_params[5 * 6 + 4], &tpr.lj_sr_params[4 * 6 + 5]] {
+            assert!((lj.c6 - c6).abs() < 1e-12 && (lj.c12 - c12).abs() < 1e-18);
+        }
+    }
+}
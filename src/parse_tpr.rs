@@ -1,10 +1,13 @@
+use std::collections::HashMap;
 use std::fmt::Formatter;
 use std::fmt;
 use std::io::BufReader;
 use regex::Regex;
 use std::fs::File;
 use std::io::BufRead;
+use std::str::FromStr;
 
+use crate::error::{MmpbsaError, Result, get_cap, parse_cap};
 use crate::settings::Settings;
 
 pub struct TPR {
@@ -14,11 +17,21 @@ pub struct TPR {
     pub molecule_types: Vec<MolType>,
     pub atom_types_num: usize,
     pub lj_sr_params: Vec<LJType>,
+    pub lj14_params: HashMap<usize, LJType>,   // functype id -> LJ-14 parameters (fudgeLJ already applied)
+    pub lj14_params_b: HashMap<usize, LJType>, // B-state of LJ-14 parameters
+    pub lj14_fudge_qq: HashMap<usize, f64>,    // functype id -> scale of 1-4 electrostatics, fudge_qq if not listed
+    pub fudge_qq: f64,
     pub molecules: Vec<Molecule>,
+    pub tinit: f64,
     pub dt: f64,
     pub nsteps: u64,
-    pub nstxout: u32,
-    pub temp: f64
+    pub nstxout: u32,               // output interval of trr
+    pub nstxout_compressed: u32,    // output interval of xtc
+    pub ref_t: Vec<f64>,            // reference temperature of each coupling group
+    pub temp: f64,
+    pub coulombtype: String,
+    pub rcoulomb: f64,
+    pub epsilon_r: f64,
 }
 
 impl fmt::Display for TPR {
@@ -31,102 +44,106 @@ impl fmt::Display for TPR {
 }
 
 impl TPR {
-    pub fn new(mdp: &str, settings: &Settings) -> TPR {
+    pub fn new(mdp: &str, settings: &Settings) -> Result<TPR> {
         let mut name = String::new();
         let mut atoms_num = 0;
         let mut molecule_types_num = 0;
         let mut atom_types_num = 0;
         let mut molecule_types: Vec<MolType> = vec![];
 
-        let file = File::open(mdp).unwrap();
+        let file = File::open(mdp).map_err(MmpbsaError::io(mdp))?;
         let mut reader = BufReader::new(file);
         let mut buf = String::from("");
 
         let mut fun_type: Vec<LJType> = vec![];
+        let mut lj14_params: HashMap<usize, LJType> = HashMap::new();
+        let mut lj14_params_b: HashMap<usize, LJType> = HashMap::new();
+        let mut fudge_qq = 1.0;
         let mut sigma: Vec<f64> = vec![];
         let mut epsilon: Vec<f64> = vec![];
         let mut radius: Vec<f64> = vec![];
 
         let mut atom_resids: Vec<usize> = vec![];   // residue ids of each atom
         let mut atom_types: Vec<usize> = vec![];    // atom type
+        let mut atom_types_b: Vec<usize> = vec![];  // atom type of B-state
         let mut atom_radii: Vec<f64> = vec![];      // atom radius
+        let mut atom_radii_b: Vec<f64> = vec![];    // atom radius of B-state
         // let mut atom_sigmas: Vec<f64> = vec![];     // atom sigma
         // let mut atom_epsilons: Vec<f64> = vec![];   // atom epsilon
         let mut atom_charges: Vec<f64> = vec![];    // atom charge
+        let mut atom_charges_b: Vec<f64> = vec![];  // atom charge of B-state
+        let mut atom_masses: Vec<f64> = vec![];     // atom mass
+        let mut atom_masses_b: Vec<f64> = vec![];   // atom mass of B-state
+        let mut atom_numbers: Vec<i32> = vec![];    // atomic number
         let mut atom_names: Vec<String> = vec![];   // atom name
 
         let mut molecules: Vec<Molecule> = vec![];
 
-        // simulation time parameters
-        let mut dt = 0.0;
-        let mut nsteps = 0;
-        let mut nstxout = 0;
-        let mut temp = 0.0;
+        // simulation parameters
+        let mut inputrec: HashMap<String, String> = HashMap::new();
+        let mut ref_t: Vec<f64> = vec![];
 
         println!("Loading dump file: {}\n", mdp);
         loop {
-            let bytes = read_line(&mut reader, &mut buf);
+            let bytes = read_line(&mut reader, &mut buf, mdp)?;
             if bytes == 0 {
                 break;
             }
 
-            // MD steps
+            // MD parameters
             if buf.starts_with("inputrec:") {
+                // only the top-level "   key = value" lines, nested groups are more indented
+                let re = Regex::new(r"^   ([\w-]+)\s*=\s*(.*)$").unwrap();
                 loop {
-                    read_line(&mut reader, &mut buf);
-                    if buf.trim().starts_with("dt") {
-                        let re = Regex::new(r"dt\s+=\s*(.*)").unwrap();
-                        dt = re.captures(&buf).unwrap().get(1).unwrap().as_str().trim().parse().unwrap();
-                        read_line(&mut reader, &mut buf);
-                        let re = Regex::new(r"nsteps\s+=\s*(.*)").unwrap();
-                        nsteps = re.captures(&buf).unwrap().get(1).unwrap().as_str().trim().parse().unwrap();
-                    } else if buf.trim().starts_with("nstxout-compressed") {
-                        let re = Regex::new(r"nstxout-compressed\s+=\s*(.*)").unwrap();
-                        nstxout = re.captures(&buf).unwrap().get(1).unwrap().as_str().trim().parse().unwrap();
-                        break
+                    if read_line(&mut reader, &mut buf, mdp)? == 0 || buf.starts_with("grpopts:") {
+                        break;
+                    }
+                    if let Some(c) = re.captures(buf.trim_end()) {
+                        inputrec.insert(c.get(1).unwrap().as_str().to_string(),
+                                        c.get(2).unwrap().as_str().trim().to_string());
                     }
                 }
             }
 
+            // temperature coupling groups
+            // ref-t:      298.15      298.15
             if buf.trim().starts_with("ref-t:") {
-                let ref_t: Vec<&str> = buf.split(" ").filter_map(|p| match p.trim().len() {
-                    0 => None,
-                    _ => Some(p)
-                }).collect();
-                temp = ref_t[1].trim().parse().expect("Failed to get temperature.");
+                ref_t = buf.trim()["ref-t:".len()..].split_whitespace()
+                    .map(|p| p.parse().map_err(|_| MmpbsaError::parse(mdp, format!("invalid ref-t: {}", buf.trim()))))
+                    .collect::<Result<Vec<f64>>>()?;
             }
 
             // molecules define
             if buf.starts_with("topology:") {
                 // name
-                read_line(&mut reader, &mut buf);       // name="Protein in water"
+                read_line(&mut reader, &mut buf, mdp)?;       // name="Protein in water"
                 let re = Regex::new("name\\s*=\\s*\"(.*)\"").unwrap();
-                name = re.captures(&buf).unwrap().get(1).unwrap().as_str().trim().to_string();
+                name = get_cap::<String>(&re, &buf, 1, mdp)?;
                 name = name.replace(" ", "_");
                 println!("System name: {}", name);
 
                 // atom num
-                read_line(&mut reader, &mut buf);       // #atoms = 3218
+                read_line(&mut reader, &mut buf, mdp)?;       // #atoms = 3218
                 let re = Regex::new(r"#atoms\s*=\s*(\d+)").unwrap();
-                atoms_num = re.captures(&buf).unwrap().get(1).unwrap().as_str().trim().parse().unwrap();
+                atoms_num = get_cap(&re, &buf, 1, mdp)?;
                 println!("Total atoms number: {}", atoms_num);
 
                 // molecule types num
-                read_line(&mut reader, &mut buf);
+                read_line(&mut reader, &mut buf, mdp)?;
                 let re = Regex::new(r"#molblock\s*=\s*(\d+)").unwrap();
-                molecule_types_num = re.captures(&buf).unwrap().get(1).unwrap().as_str().trim().parse().unwrap();
+                molecule_types_num = get_cap(&re, &buf, 1, mdp)?;
 
                 println!("System molecular types:");
                 for mt_id in 0..molecule_types_num {
                     loop {
-                        read_line(&mut reader, &mut buf);
+                        read_line(&mut reader, &mut buf, mdp)?;
                         if buf.trim().starts_with("molblock (") {
-                            read_line(&mut reader, &mut buf);
+                            read_line(&mut reader, &mut buf, mdp)?;
                             let re = Regex::new("moltype\\s*=\\s*\\d+\\s*\"(.*)\"").unwrap();
-                            let name = re.captures(&buf).unwrap().get(1).unwrap().as_str().to_string();
-                            read_line(&mut reader, &mut buf);
+                            let name = get_cap::<String>(&re, &buf, 1, mdp)?;
+                            read_line(&mut reader, &mut buf, mdp)?;
                             let re = Regex::new(r"#molecules\s*=\s*(\d+)").unwrap();
-                            let molecules_num: i64 = re.captures(&buf).unwrap().get(1).unwrap().as_str().parse().unwrap();
+                            let molecules_num: i64 = get_cap(&re, &buf, 1, mdp)?;
                             let moltype = MolType::new(mt_id, name, molecules_num);
                             println!("{}", moltype);
                             molecule_types.push(moltype);
@@ -138,21 +155,21 @@ impl TPR {
 
             // force field parameters (atom radius here)
             if buf.trim().starts_with("ffparams:") {
-                read_line(&mut reader, &mut buf);
+                read_line(&mut reader, &mut buf, mdp)?;
                 let re = Regex::new(r"atnr\s*=\s*(\d+)").unwrap();
-                atom_types_num = re.captures(&buf).unwrap().get(1).unwrap().as_str().parse().unwrap();
+                atom_types_num = get_cap(&re, &buf, 1, mdp)?;
                 println!("Total atom types: {}.", atom_types_num);
 
-                read_line(&mut reader, &mut buf);
+                read_line(&mut reader, &mut buf, mdp)?;
                 // functype[0]=LJ_SR, c6= 2.07413384e-03, c12= 1.51207360e-06
                 let re = Regex::new(r"functype\[(\d*)]=LJ_SR,\s*c6\s*=\s*(\S*),\s*c12\s*=\s*(\S*)").unwrap();
                 for i in 0..atom_types_num {
                     for j in 0..atom_types_num {
-                        read_line(&mut reader, &mut buf);
-                        let m = re.captures(&buf).unwrap();
-                        let func_id: usize = m.get(1).unwrap().as_str().parse().unwrap();
-                        let c6: f64 = m.get(2).unwrap().as_str().parse().unwrap();
-                        let c12: f64 = m.get(3).unwrap().as_str().parse().unwrap();
+                        read_line(&mut reader, &mut buf, mdp)?;
+                        let m = re.captures(&buf).ok_or_else(|| MmpbsaError::parse(mdp, format!("unexpected line: {}", buf.trim())))?;
+                        let func_id: usize = parse_cap(&m, 1, mdp)?;
+                        let c6: f64 = parse_cap(&m, 2, mdp)?;
+                        let c12: f64 = parse_cap(&m, 3, mdp)?;
                         fun_type.push(LJType::new(func_id, c6, c12));
                         // calculate σ, ε, radius for each atom
                         if j == i {
@@ -171,6 +188,26 @@ impl TPR {
                 println!("Total LJ function types: {}", fun_type.len());
             }
 
+            // LJ-14 parameters, the fudgeLJ has been applied by grompp
+            // functype[228]=LJ14, c6A= 1.03706692e-03, c12A= 7.56036798e-07, c6B= 1.03706692e-03, c12B= 7.56036798e-07
+            if buf.trim().starts_with("functype[") && buf.contains("=LJ14,") {
+                let re = Regex::new(r"functype\[(\d+)]=LJ14,\s*c6A\s*=\s*([^,]+),\s*c12A\s*=\s*([^,]+),\s*c6B\s*=\s*([^,]+),\s*c12B\s*=\s*(\S+)").unwrap();
+                let m = re.captures(&buf).ok_or_else(|| MmpbsaError::parse(mdp, format!("unexpected line: {}", buf.trim())))?;
+                let func_id: usize = parse_cap(&m, 1, mdp)?;
+                let c6: f64 = parse_cap(&m, 2, mdp)?;
+                let c12: f64 = parse_cap(&m, 3, mdp)?;
+                let c6_b: f64 = parse_cap(&m, 4, mdp)?;
+                let c12_b: f64 = parse_cap(&m, 5, mdp)?;
+                lj14_params.insert(func_id, LJType::new(func_id, c6, c12));
+                lj14_params_b.insert(func_id, LJType::new(func_id, c6_b, c12_b));
+            }
+
+            if buf.trim().starts_with("fudgeQQ") {
+                let re = Regex::new(r"fudgeQQ\s*=\s*(\S+)").unwrap();
+                fudge_qq = get_cap(&re, &buf, 1, mdp)?;
+                println!("Total LJ-14 function types: {}, fudgeQQ: {}", lj14_params.len(), fudge_qq);
+            }
+
             if buf.trim().starts_with("moltype (") {
                 let mut atoms: Vec<Atom> = vec![];
                 let mut residues: Vec<Residue> = vec![];
@@ -178,55 +215,74 @@ impl TPR {
                 let offset: usize = molecules.iter().map(|p| p.atoms_num).sum();
 
                 let re = Regex::new(r"moltype \((\d+)\)").unwrap();
-                let molecule_type_id: usize = re.captures(&buf).unwrap().get(1).unwrap().as_str().parse().unwrap();
+                let molecule_type_id: usize = get_cap(&re, &buf, 1, mdp)?;
                 println!("Reading molecule {} information...", molecule_type_id);
-                read_line(&mut reader, &mut buf);
+                read_line(&mut reader, &mut buf, mdp)?;
                 let re = Regex::new("name\\s*=\\s*\"(.*)\"").unwrap();
-                let molecule_name = re.captures(&buf).unwrap().get(1).unwrap().as_str().to_string();
-                read_line(&mut reader, &mut buf);
-                read_line(&mut reader, &mut buf);
+                let molecule_name = get_cap::<String>(&re, &buf, 1, mdp)?;
+                read_line(&mut reader, &mut buf, mdp)?;
+                read_line(&mut reader, &mut buf, mdp)?;
                 let re = Regex::new(r"atom \((\d+)\):").unwrap();
-                let atoms_num: usize = re.captures(&buf).unwrap().get(1).unwrap().as_str().parse().unwrap();
+                let atoms_num: usize = get_cap(&re, &buf, 1, mdp)?;
 
                 // atom parameters
                 // atom[     0]={type=  0, typeB=  0, ptype=    Atom, m= 1.60000e+01,
                 // q=-4.91104e-01, mB= 1.60000e+01, qB=-4.91104e-01, resind=    0, atomnumber= -1}
-                let re = Regex::new(r".*type=\s*(\d+).*q=\s*([^,]+),.*resind=\s*(\d+).*").unwrap();
+                let re = Regex::new(r"type=\s*(\d+),\s*typeB=\s*(\d+),.*\bm=\s*([^,]+),\s*q=\s*([^,]+),\s*mB=\s*([^,]+),\s*qB=\s*([^,]+),\s*resind=\s*(\d+)(?:,\s*atomnumber=\s*(-?\d+))?").unwrap();
                 for _ in 0..atoms_num {
-                    read_line(&mut reader, &mut buf);
-                    let c = re.captures(&buf).unwrap();
-                    let atom_type_id: usize = c.get(1).unwrap().as_str().parse().unwrap();
-                    let atom_charge: f64 = c.get(2).unwrap().as_str().parse().unwrap();
-                    let residue_index: usize = c.get(3).unwrap().as_str().parse().unwrap();
+                    read_line(&mut reader, &mut buf, mdp)?;
+                    let c = re.captures(&buf).ok_or_else(|| MmpbsaError::parse(mdp, format!("unexpected line: {}", buf.trim())))?;
+                    let atom_type_id: usize = parse_cap(&c, 1, mdp)?;
+                    let atom_type_id_b: usize = parse_cap(&c, 2, mdp)?;
+                    let atom_mass: f64 = parse_cap(&c, 3, mdp)?;
+                    let atom_charge: f64 = parse_cap(&c, 4, mdp)?;
+                    let atom_mass_b: f64 = parse_cap(&c, 5, mdp)?;
+                    let atom_charge_b: f64 = parse_cap(&c, 6, mdp)?;
+                    let residue_index: usize = parse_cap(&c, 7, mdp)?;
+                    let atomic_number: i32 = match c.get(8) {
+                        Some(_) => parse_cap(&c, 8, mdp)?,
+                        None => -1
+                    };
+                    atom_numbers.push(atomic_number);
                     atom_resids.push(residue_index);
                     atom_types.push(atom_type_id);
+                    atom_types_b.push(atom_type_id_b);
                     atom_radii.push(radius[atom_type_id]);
+                    atom_radii_b.push(radius[atom_type_id_b]);
                     atom_charges.push(atom_charge);
+                    atom_charges_b.push(atom_charge_b);
+                    atom_masses.push(atom_mass);
+                    atom_masses_b.push(atom_mass_b);
+                }
+                let perturbed = (offset..offset + atoms_num).filter(|&i| atom_types[i] != atom_types_b[i]
+                    || atom_charges[i] != atom_charges_b[i]).count();
+                if perturbed > 0 {
+                    println!("Found {} atoms with different A- and B-state parameters.", perturbed);
                 }
 
                 // atom names
-                read_line(&mut reader, &mut buf);
+                read_line(&mut reader, &mut buf, mdp)?;
                 // atom[0]={name="O1"}
                 let re = Regex::new("name=\"(.*)\"").unwrap();
                 for _ in 0..atoms_num {
-                    read_line(&mut reader, &mut buf);
-                    let name = re.captures(&buf).unwrap().get(1).unwrap().as_str();
+                    read_line(&mut reader, &mut buf, mdp)?;
+                    let name = get_cap::<String>(&re, &buf, 1, mdp)?;
                     atom_names.push(name.to_string());
                 }
 
                 loop {
-                    read_line(&mut reader, &mut buf);
+                    read_line(&mut reader, &mut buf, mdp)?;
                     if buf.trim().starts_with("residue (") {
                         // residues
                         let re = Regex::new(r"\s*residue \((\d+)\)").unwrap();
-                        let res_num: i32 = re.captures(&buf).unwrap().get(1).unwrap().as_str().parse().unwrap();
+                        let res_num: i32 = get_cap(&re, &buf, 1, mdp)?;
                         let re = Regex::new("residue\\[(\\d+)]=\\{name=\"(.+)\",.*nr=([\\d\\-]+).*").unwrap();
                         for _ in 0..res_num {
-                            read_line(&mut reader, &mut buf);
-                            let m = re.captures(&buf).unwrap();
-                            let id: usize = m.get(1).unwrap().as_str().parse().unwrap();
-                            let name = m.get(2).unwrap().as_str().to_string();
-                            let nr: i32 = m.get(3).unwrap().as_str().parse().unwrap();
+                            read_line(&mut reader, &mut buf, mdp)?;
+                            let m = re.captures(&buf).ok_or_else(|| MmpbsaError::parse(mdp, format!("unexpected line: {}", buf.trim())))?;
+                            let id: usize = parse_cap(&m, 1, mdp)?;
+                            let name = parse_cap::<String>(&m, 2, mdp)?;
+                            let nr: i32 = parse_cap(&m, 3, mdp)?;
                             residues.push(Residue::new(id, name, nr));
                         }
                     }
@@ -235,36 +291,74 @@ impl TPR {
                     }
                 }
 
-                // angles
+                // exclusions, one list per atom, may wrap over several lines
+                // excls[0][num=13]={0, 1, 15, 16, 18, 21, 22, 24, 61, 64, 65, 66,
+                //    67}
+                read_line(&mut reader, &mut buf, mdp)?;       // numLists=117 (or nr: 117 for old versions)
+                let re = Regex::new(r"(?:numLists\s*=|nr:)\s*(\d+)").unwrap();
+                let excls_num: usize = get_cap(&re, &buf, 1, mdp)?;
+                read_line(&mut reader, &mut buf, mdp)?;       // numElements=1433 (or nra: 1433)
+                let mut excls: Vec<Vec<usize>> = vec![];
+                for _ in 0..excls_num {
+                    let mut excl: Vec<usize> = vec![];
+                    read_line(&mut reader, &mut buf, mdp)?;
+                    let mut line = buf.split_once("={")
+                        .ok_or_else(|| MmpbsaError::parse(mdp, format!("unexpected exclusions: {}", buf.trim())))?.1.to_string();
+                    loop {
+                        let finished = line.contains('}');
+                        excl.extend(line.split(['{', '}', ','])
+                            .filter_map(|p| p.trim().parse::<usize>().ok()));
+                        if finished {
+                            break;
+                        }
+                        read_line(&mut reader, &mut buf, mdp)?;
+                        line = buf.to_string();
+                    }
+                    excls.push(excl);
+                }
+
+                // bonds, constraints and settles for atom connections, and LJ-14 pairs
+                //    0 type=121 (BONDS)   0  18
+                //    0 type=228 (LJ14)   0   1
+                let mut bonds: Vec<(usize, usize)> = vec![];
+                let mut pairs: Vec<Pair> = vec![];
+                let re = Regex::new(r"type=(\d+)\s+\((\w+)\)\s+([\d\s]+)").unwrap();
                 loop {
-                    read_line(&mut reader, &mut buf);
-                    if buf.trim().starts_with("Angle:") {
-                        read_line(&mut reader, &mut buf);
+                    if read_line(&mut reader, &mut buf, mdp)? == 0 {
+                        break;
+                    }
+                    if let Some(c) = re.captures(&buf) {
+                        let func_id: usize = parse_cap(&c, 1, mdp)?;
+                        let ids: Vec<usize> = c.get(3).unwrap().as_str().split_whitespace()
+                            .filter_map(|p| p.parse().ok()).collect();
+                        match c.get(2).unwrap().as_str() {
+                            "BONDS" | "G96BONDS" | "MORSE" | "CUBICBONDS" | "CONNBONDS" | "HARMONIC" |
+                            "FENEBONDS" | "TABBONDS" | "RESTRAINTPOT" | "CONSTR" => bonds.push((ids[0], ids[1])),
+                            "LJ14" => pairs.push(Pair::new(ids[0], ids[1], func_id)),
+                            _ => {}
+                        }
+                    }
+                    // settle is the last interaction related to connections
+                    if buf.trim().starts_with("Settle:") {
+                        read_line(&mut reader, &mut buf, mdp)?;
                         let re = Regex::new(r"nr\s*:\s*(\d+)").unwrap();
-                        let angles: i32 = re.captures(&buf).unwrap().get(1).unwrap().as_str().parse().unwrap();
-                        if angles > 0 {
-                            read_line(&mut reader, &mut buf);
-                            let re = Regex::new(r"\(ANGLES\)\s+(\d+)\s+(\d+)\s+(\d+)").unwrap();
-                            // assign H types by connection atoms from angle information
-                            for _ in 0..angles / 4 {
-                                read_line(&mut reader, &mut buf);
-                                if re.is_match(&buf) {
-                                    let c = re.captures(&buf).unwrap();
-                                    let i: usize = c.get(1).unwrap().as_str().parse().unwrap();
-                                    let j: usize = c.get(2).unwrap().as_str().parse().unwrap();
-                                    let k: usize = c.get(3).unwrap().as_str().trim().parse().unwrap();
-                                    if atom_names[offset + i].starts_with(['H', 'h']) {
-                                        atom_names[offset + i] = format!("H{}", atom_names[offset + j]);
-                                    }
-                                    if atom_names[offset + k].starts_with(['H', 'h']) {
-                                        atom_names[offset + k] = format!("H{}", atom_names[offset + j]);
-                                    }
-                                } else {
-                                    break;
-                                }
-                            }
+                        let settles: usize = get_cap(&re, &buf, 1, mdp)?;
+                        if settles == 0 {
                             break;
-                        } else { break; }
+                        }
+                        read_line(&mut reader, &mut buf, mdp)?;
+                        for _ in 0..settles / 4 {
+                            // 0 type=269 (SETTLE)   0   1   2
+                            read_line(&mut reader, &mut buf, mdp)?;
+                            let ids: Vec<usize> = buf.split(')').nth(1).unwrap_or("").split_whitespace()
+                                .filter_map(|p| p.parse().ok()).collect();
+                            if ids.len() < 3 {
+                                return Err(MmpbsaError::parse(mdp, format!("unexpected settle: {}", buf.trim())));
+                            }
+                            bonds.push((ids[0], ids[1]));
+                            bonds.push((ids[0], ids[2]));
+                        }
+                        break;
                     }
                 }
 
@@ -272,33 +366,227 @@ impl TPR {
                     let id = id + offset;
                     atoms.push(Atom::new(id,
                                          atom_types[id],
+                                         atom_types_b[id],
                                          atom_charges[id],
+                                         atom_charges_b[id],
+                                         atom_masses[id],
+                                         atom_masses_b[id],
+                                         atom_numbers[id],
                                          atom_resids[id],
                                          atom_names[id].to_string(),
-                                         atom_radii[id]));
+                                         atom_radii[id],
+                                         atom_radii_b[id]));
                 }
 
                 molecules.push(Molecule::new(molecule_type_id, molecule_name, atoms_num,
-                                             &atoms, &residues));
+                                             &atoms, &residues, &excls, &bonds, &pairs));
             }
         }
         println!("System molecular composition:");
         for mol in &molecules {
             println!("Molecule {}: {}", mol.molecule_type_id, mol);
         }
-        TPR {
+
+        let tinit = get_inputrec(&inputrec, &["tinit"], 0.0);
+        let dt = get_inputrec(&inputrec, &["dt"], 0.0);
+        let nsteps = get_inputrec(&inputrec, &["nsteps"], 0);
+        let nstxout = get_inputrec(&inputrec, &["nstxout"], 0);
+        let nstxout_compressed = get_inputrec(&inputrec, &["nstxout-compressed", "nstxtcout"], 0);
+        let coulombtype = get_inputrec(&inputrec, &["coulombtype"], String::from("Cut-off"));
+        let rcoulomb = get_inputrec(&inputrec, &["rcoulomb"], 0.0);
+        let epsilon_r = match get_inputrec(&inputrec, &["epsilon-r"], 1.0) {
+            e if e == 0.0 => 1.0,     // epsilon-r = 0 means infinity in gromacs, not useful for MM
+            e => e
+        };
+        println!("Simulation parameters: tinit = {} ps, dt = {} ps, nsteps = {}, nstxout = {}, nstxout-compressed = {}",
+                 tinit, dt, nsteps, nstxout, nstxout_compressed);
+        println!("Electrostatics: coulombtype = {}, rcoulomb = {} nm, epsilon-r = {}", coulombtype, rcoulomb, epsilon_r);
+
+        let temp = match ref_t.first() {
+            Some(&t) => t,
+            None => {
+                println!("Warning: ref-t not found, will use 298.15 K.");
+                298.15
+            }
+        };
+        if ref_t.iter().any(|&t| (t - temp).abs() > 1e-6) {
+            println!("Warning: temperature coupling groups have different ref-t: {:?} K, \
+                will use the first one ({} K).", ref_t, temp);
+        } else {
+            println!("Temperature: {} K ({} coupling group(s))", temp, ref_t.len());
+        }
+
+        Ok(TPR {
             name,
             n_atoms: atoms_num,
             molecule_types_num,
             molecule_types,
             atom_types_num,
             lj_sr_params: fun_type,
+            lj14_params,
+            lj14_params_b,
+            lj14_fudge_qq: HashMap::new(),
+            fudge_qq,
             molecules,
+            tinit,
             dt,
             nsteps,
             nstxout,
-            temp
+            nstxout_compressed,
+            ref_t,
+            temp,
+            coulombtype,
+            rcoulomb,
+            epsilon_r,
+        })
+    }
+}
+
+// Flat topology read from other programs (AMBER prmtop, CHARMM psf), all indexes are global
+pub struct FlatTopology {
+    pub name: String,
+    pub atom_names: Vec<String>,
+    pub atom_types: Vec<usize>,
+    pub atom_charges: Vec<f64>,
+    pub atom_masses: Vec<f64>,
+    pub atom_numbers: Vec<i32>,                 // atomic numbers, -1 if unknown
+    pub atom_radii: Vec<f64>,
+    pub atom_resids: Vec<usize>,
+    pub res_names: Vec<String>,
+    pub res_nrs: Vec<i32>,
+    pub mol_ranges: Vec<(usize, usize)>,        // [first, last) atom of each molecule
+    pub atom_types_num: usize,
+    pub lj_sr_params: Vec<LJType>,
+    pub excls: Vec<Vec<usize>>,
+    pub bonds: Vec<(usize, usize)>,
+    pub pairs: Vec<(usize, usize, f64, f64, f64)>,  // i, j, c6, c12, fudgeQQ of 1-4 pairs
+    pub fudge_qq: f64,
+}
+
+impl TPR {
+    // build TPR from flat topology, identical neighbouring molecules are merged into one molecule type
+    pub fn from_flat(top: FlatTopology) -> TPR {
+        let mut molecule_types: Vec<MolType> = vec![];
+        let mut molecules: Vec<Molecule> = vec![];
+        let mut lj14_params: HashMap<usize, LJType> = HashMap::new();
+        let mut lj14_fudge_qq: HashMap<usize, f64> = HashMap::new();
+        let mut lj14_ids: HashMap<(u64, u64, u64), usize> = HashMap::new();
+
+        let mut atom_mol: Vec<usize> = vec![0; top.atom_names.len()];
+        for (m, &(first, last)) in top.mol_ranges.iter().enumerate() {
+            atom_mol[first..last].fill(m);
+        }
+        let mut mol_pairs: Vec<Vec<&(usize, usize, f64, f64, f64)>> = vec![vec![]; top.mol_ranges.len()];
+        let mut cross_pairs = 0;
+        for pair in &top.pairs {
+            match atom_mol[pair.0] == atom_mol[pair.1] {
+                true => mol_pairs[atom_mol[pair.0]].push(pair),
+                false => cross_pairs += 1
+            }
+        }
+        if cross_pairs > 0 {
+            println!("Warning: {} 1-4 pairs between different molecules are ignored.", cross_pairs);
         }
+
+        let mut offset = 0;
+        let mut last_signature = None;
+        for (m, &(first, last)) in top.mol_ranges.iter().enumerate() {
+            let excls: Vec<Vec<usize>> = (first..last).map(|i| top.excls[i].iter()
+                .filter(|&&j| j >= first && j < last)
+                .map(|j| j - first).collect()).collect();
+            let bonds: Vec<(usize, usize)> = top.bonds.iter()
+                .filter(|&&(i, j)| i >= first && i < last && j >= first && j < last)
+                .map(|&(i, j)| (i - first, j - first)).collect();
+            // merge only if names, parameters, exclusions, bonds and 1-4 pairs are all the same
+            let signature = (
+                (first..last).map(|i| (top.atom_names[i].as_str(), top.res_names[top.atom_resids[i]].as_str(),
+                    top.atom_types[i], top.atom_charges[i].to_bits(), top.atom_masses[i].to_bits(),
+                    top.atom_numbers[i], top.atom_radii[i].to_bits())).collect::<Vec<_>>(),
+                excls.clone(),
+                bonds.clone(),
+                mol_pairs[m].iter().map(|&&(i, j, c6, c12, qq)|
+                    (i - first, j - first, c6.to_bits(), c12.to_bits(), qq.to_bits())).collect::<Vec<_>>()
+            );
+            if last_signature.as_ref() == Some(&signature) {
+                molecule_types.last_mut().unwrap().molecules_num += 1;
+                continue;
+            }
+            last_signature = Some(signature);
+
+            let res_first = top.atom_resids[first];
+            let mut residues: Vec<Residue> = vec![];
+            for r in res_first..=top.atom_resids[last - 1] {
+                residues.push(Residue::new(r - res_first, top.res_names[r].to_string(), top.res_nrs[r]));
+            }
+            let atoms: Vec<Atom> = (first..last).map(|i| Atom::new(offset + i - first,
+                top.atom_types[i], top.atom_types[i],
+                top.atom_charges[i], top.atom_charges[i],
+                top.atom_masses[i], top.atom_masses[i],
+                top.atom_numbers[i],
+                top.atom_resids[i] - res_first,
+                top.atom_names[i].to_string(),
+                top.atom_radii[i], top.atom_radii[i])).collect();
+            let mut pairs: Vec<Pair> = vec![];
+            for &&(i, j, c6, c12, qq) in &mol_pairs[m] {
+                let next_id = top.lj_sr_params.len() + lj14_ids.len();
+                let func_id = *lj14_ids.entry((c6.to_bits(), c12.to_bits(), qq.to_bits())).or_insert(next_id);
+                lj14_params.entry(func_id).or_insert(LJType::new(func_id, c6, c12));
+                lj14_fudge_qq.insert(func_id, qq);
+                pairs.push(Pair::new(i - first, j - first, func_id));
+            }
+
+            let mol_id = molecule_types.len();
+            let name = match residues.len() {
+                1 => residues[0].name.to_string(),
+                _ => format!("Molecule_{}", mol_id)
+            };
+            molecule_types.push(MolType::new(mol_id, name.to_string(), 1));
+            molecules.push(Molecule::new(mol_id, name, last - first, &atoms, &residues, &excls, &bonds, &pairs));
+            offset += last - first;
+        }
+
+        println!("System molecular types:");
+        for mt in &molecule_types {
+            println!("{}", mt);
+        }
+        println!("System molecular composition:");
+        for mol in &molecules {
+            println!("Molecule {}: {}", mol.molecule_type_id, mol);
+        }
+        println!("Warning: no temperature information in topology, will use 298.15 K.");
+
+        TPR {
+            name: top.name.replace(" ", "_"),
+            n_atoms: top.atom_names.len(),
+            molecule_types_num: molecule_types.len(),
+            molecule_types,
+            atom_types_num: top.atom_types_num,
+            lj_sr_params: top.lj_sr_params,
+            lj14_params_b: lj14_params.iter().map(|(&k, v)| (k, LJType::new(k, v.c6, v.c12))).collect(),
+            lj14_params,
+            lj14_fudge_qq,
+            fudge_qq: top.fudge_qq,
+            molecules,
+            tinit: 0.0,
+            dt: 0.0,
+            nsteps: 0,
+            nstxout: 0,
+            nstxout_compressed: 0,
+            ref_t: vec![],
+            temp: 298.15,
+            coulombtype: String::from("Unknown"),
+            rcoulomb: 0.0,
+            epsilon_r: 1.0,
+        }
+    }
+}
+
+// radius (A) from c6 and c12 (gromacs units), half of sigma
+pub fn get_lj_radius(c6: f64, c12: f64, rad_default: f64) -> f64 {
+    if c6 != 0.0 && c12 != 0.0 {
+        10.0 * (c12 / c6).powf(1.0 / 6.0) / 2.0
+    } else {
+        rad_default
     }
 }
 
@@ -309,7 +597,7 @@ pub struct MolType {
 }
 
 impl MolType {
-    fn new(id: usize, name: String, molecules_num: i64) -> MolType {
+    pub fn new(id: usize, name: String, molecules_num: i64) -> MolType {
         MolType {
             id,
             name,
@@ -331,7 +619,7 @@ pub struct LJType {
 }
 
 impl LJType {
-    fn new(func_id: usize, c6: f64, c12: f64) -> LJType {
+    pub fn new(func_id: usize, c6: f64, c12: f64) -> LJType {
         LJType {
             func_id,
             c6,
@@ -346,17 +634,24 @@ pub struct Molecule {
     pub atoms_num: usize,
     pub atoms: Vec<Atom>,
     pub residues: Vec<Residue>,
+    pub excls: Vec<Vec<usize>>,     // excluded atoms of each atom, local index in molecule
+    pub bonds: Vec<(usize, usize)>, // chemical bonds (including constraints), local index in molecule
+    pub pairs: Vec<Pair>,           // LJ-14 pairs, local index in molecule
 }
 
 impl Molecule {
-    fn new(molecule_type_id: usize, molecule_name: String, atoms_num: usize,
-           atoms: &Vec<Atom>, residues: &Vec<Residue>) -> Molecule {
+    pub fn new(molecule_type_id: usize, molecule_name: String, atoms_num: usize,
+           atoms: &Vec<Atom>, residues: &Vec<Residue>,
+           excls: &Vec<Vec<usize>>, bonds: &Vec<(usize, usize)>, pairs: &Vec<Pair>) -> Molecule {
         Molecule {
             molecule_type_id,
             molecule_name,
             atoms_num,
             atoms: atoms.to_vec(),
             residues: residues.to_vec(),
+            excls: excls.to_vec(),
+            bonds: bonds.to_vec(),
+            pairs: pairs.to_vec(),
         }
     }
 }
@@ -372,29 +667,60 @@ impl fmt::Display for Molecule {
 pub struct Atom {
     pub id: usize,
     pub type_id: usize,
+    pub type_id_b: usize,
     pub charge: f64,
+    pub charge_b: f64,
+    pub mass: f64,
+    pub mass_b: f64,
+    pub atomic_number: i32,     // -1 if not defined in topology
     pub resind: usize,
     pub name: String,
     pub radius: f64,
+    pub radius_b: f64,
 }
 
 impl fmt::Display for Atom {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "Atom {}: {} with type {}, charge {}, radius {}, in residue {}",
-               self.id, self.name, self.type_id, self.charge, self.radius, self.resind)
+        write!(f, "Atom {}: {} with type {} (B: {}), charge {} (B: {}), mass {} (B: {}), radius {} (B: {}), in residue {}",
+               self.id, self.name, self.type_id, self.type_id_b, self.charge, self.charge_b,
+               self.mass, self.mass_b, self.radius, self.radius_b, self.resind)
     }
 }
 
 impl Atom {
-    fn new(id: usize, type_id: usize, charge: f64, residue_index: usize, name: String,
-           radius: f64) -> Atom {
+    pub fn new(id: usize, type_id: usize, type_id_b: usize, charge: f64, charge_b: f64,
+           mass: f64, mass_b: f64, atomic_number: i32, residue_index: usize, name: String,
+           radius: f64, radius_b: f64) -> Atom {
         Atom {
             id,
             type_id,
+            type_id_b,
             charge,
+            charge_b,
+            mass,
+            mass_b,
+            atomic_number,
             resind: residue_index,
             name,
             radius,
+            radius_b,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Pair {
+    pub i: usize,
+    pub j: usize,
+    pub func_id: usize,
+}
+
+impl Pair {
+    pub fn new(i: usize, j: usize, func_id: usize) -> Pair {
+        Pair {
+            i,
+            j,
+            func_id,
         }
     }
 }
@@ -413,7 +739,7 @@ impl fmt::Display for Residue {
 }
 
 impl Residue {
-    fn new(id: usize, name: String, nr: i32) -> Residue {
+    pub fn new(id: usize, name: String, nr: i32) -> Residue {
         Residue {
             id,
             name,
@@ -422,7 +748,58 @@ impl Residue {
     }
 }
 
-fn read_line(reader: &mut BufReader<File>, buf: &mut String) -> usize {
+// get inputrec value by the first existing key, or default if not found
+fn get_inputrec<T: FromStr>(inputrec: &HashMap<String, String>, keys: &[&str], default: T) -> T {
+    for key in keys {
+        if let Some(v) = inputrec.get(*key) {
+            if let Ok(v) = v.parse() {
+                return v;
+            }
+        }
+    }
+    default
+}
+
+fn read_line(reader: &mut BufReader<File>, buf: &mut String, file: &str) -> Result<usize> {
     buf.clear();
-    reader.read_line(buf).unwrap()
+    reader.read_line(buf).map_err(MmpbsaError::io(file))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // single-atom ions of the same name with the given charges
+    fn ions(charges: &[f64]) -> FlatTopology {
+        let n = charges.len();
+        FlatTopology {
+            name: String::from("ions"),
+            atom_names: vec![String::from("NA"); n],
+            atom_types: vec![0; n],
+            atom_charges: charges.to_vec(),
+            atom_masses: vec![22.99; n],
+            atom_numbers: vec![11; n],
+            atom_radii: vec![1.5; n],
+            atom_resids: (0..n).collect(),
+            res_names: vec![String::from("NA"); n],
+            res_nrs: (1..=n as i32).collect(),
+            mol_ranges: (0..n).map(|i| (i, i + 1)).collect(),
+            atom_types_num: 1,
+            lj_sr_params: vec![LJType::new(0, 1e-3, 1e-6)],
+            excls: vec![vec![]; n],
+            bonds: vec![],
+            pairs: vec![],
+            fudge_qq: 1.0,
+        }
+    }
+
+    #[test]
+    fn merge_identical_molecules() {
+        let tpr = TPR::from_flat(ions(&[1.0, 1.0, 0.5]));
+        assert_eq!(tpr.molecule_types.len(), 2);
+        assert_eq!(tpr.molecule_types[0].molecules_num, 2);
+        assert_eq!(tpr.molecule_types[1].molecules_num, 1);
+        assert_eq!(tpr.molecules[1].atoms[0].charge, 0.5);
+        assert_eq!(tpr.n_atoms, 3);
+    }
 }
@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use crate::analyzation::Results;
+use crate::element::get_element_symbol;
+use crate::error::{MmpbsaError, Result};
+
+const KCAL_TO_KJ: f64 = 4.184;
+
+// candidates of chain IDs, reused from the beginning if there are more molecules
+const CHAIN_IDS: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+
+// energy unit of the B-factor column
+#[derive(Clone, Copy)]
+pub enum EnergyUnit {
+    KJ,
+    Kcal,
+}
+
+impl EnergyUnit {
+    pub fn factor(&self) -> f64 {
+        match self {
+            EnergyUnit::KJ => 1.0,
+            EnergyUnit::Kcal => KCAL_TO_KJ,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            EnergyUnit::KJ => "kJ/mol",
+            EnergyUnit::Kcal => "kcal/mol",
+        }
+    }
+}
+
+// chain ID of each molecule in the topology, from molecule type names like Protein_chain_B given by pdb2gmx,
+// otherwise the next unused ID in order of molecules
+pub fn get_chain_ids(results: &Results) -> Vec<char> {
+    let aps = &results.aps;
+    let suffix_id = |name: &str| {
+        let mut chars = name.chars();
+        let c = chars.next_back()?;
+        match chars.as_str().ends_with("chain_") && c.is_ascii_alphanumeric() {
+            true => Some(c),
+            false => None
+        }
+    };
+    let used: Vec<char> = aps.atm_molname.iter().filter_map(|n| suffix_id(n)).collect();
+    let mut free = CHAIN_IDS.chars().filter(|c| !used.contains(c)).collect::<Vec<char>>().into_iter().cycle();
+    let mut mol_chain: HashMap<usize, char> = HashMap::new();
+    aps.atm_molid.iter().zip(aps.atm_molname.iter()).map(|(&molid, name)| {
+        *mol_chain.entry(molid).or_insert_with(|| suffix_id(name).unwrap_or_else(|| free.next().unwrap_or('A')))
+    }).collect()
+}
+
+// atom name in columns 13-16, starting from column 14 unless 4 characters or a 2-letter element
+fn pdb_atom_name(name: &str, element: &str) -> String {
+    match name.len() >= 4 || (element.len() == 2 && name.to_uppercase().starts_with(&element.to_uppercase())) {
+        true => format!("{:<4}", name.chars().take(4).collect::<String>()),
+        false => format!(" {:<3}", name)
+    }
+}
+
+// atoms of the complex at frame ts_id, with the INVERSED residue-wised ΔH of that frame in B-factor column
+fn write_model<W: Write>(f: &mut W, results: &Results, ts_id: usize, unit: EnergyUnit, chains: &[char]) -> io::Result<()> {
+    let aps = &results.aps;
+    let coord = &results.coord;
+    for atom_id in 0..aps.atm_name.len() {
+        let res_id = aps.atm_resid[atom_id];
+        let element = match aps.atm_element[atom_id] {
+            0 => "",
+            e => get_element_symbol(e)
+        };
+        writeln!(f, "ATOM  {:5} {}{:>4} {}{:4}    {:8.3}{:8.3}{:8.3}{:6.2}{:6.2}          {:>2}",
+            (atom_id + 1) % 100000, pdb_atom_name(&aps.atm_name[atom_id], element),
+            results.residues[res_id].name.chars().take(4).collect::<String>(), chains[atom_id],
+            results.residues[res_id].nr % 10000,
+            coord[[ts_id, atom_id, 0]], coord[[ts_id, atom_id, 1]], coord[[ts_id, atom_id, 2]],
+            1.0, -results.dh_res[[ts_id, res_id]] / unit.factor(), element.to_uppercase())?;
+    }
+    Ok(())
+}
+
+fn write_models<W: Write>(f: &mut W, results: &Results, ts_ids: &[usize], unit: EnergyUnit) -> io::Result<()> {
+    let chains = get_chain_ids(results);
+    writeln!(f, "REMARK   1 The B-factor column is filled with the INVERSED residue-wised binding energy (ΔH), in {}", unit.name())?;
+    writeln!(f, "REMARK   1 Written by s_mmpbsa {}, chain IDs by molecules in topology", env!("CARGO_PKG_VERSION"))?;
+    match ts_ids.len() {
+        1 => {
+            writeln!(f, "REMARK   1 Time: {} ns", results.times[ts_ids[0]] / 1000.0)?;
+            write_model(f, results, ts_ids[0], unit, &chains)?;
+            writeln!(f, "TER")?;
+        }
+        _ => {
+            for (m, &ts_id) in ts_ids.iter().enumerate() {
+                writeln!(f, "REMARK   1 Model {}: {} ns", m + 1, results.times[ts_id] / 1000.0)?;
+            }
+            for (m, &ts_id) in ts_ids.iter().enumerate() {
+                writeln!(f, "MODEL     {:4}", m + 1)?;
+                write_model(f, results, ts_id, unit, &chains)?;
+                writeln!(f, "TER")?;
+                writeln!(f, "ENDMDL")?;
+            }
+        }
+    }
+    writeln!(f, "END")
+}
+
+// frames ts_ids in one pdb file, as models if more than one frame
+pub fn write_bf_pdb(results: &Results, ts_ids: &[usize], unit: EnergyUnit, out: &Path) -> Result<()> {
+    let f = File::create(out).map_err(MmpbsaError::io(out.to_string_lossy()))?;
+    let mut f = BufWriter::new(f);
+    write_models(&mut f, results, ts_ids, unit).map_err(MmpbsaError::io(out.to_string_lossy()))?;
+    f.flush().map_err(MmpbsaError::io(out.to_string_lossy()))?;
+    Ok(())
+}
@@ -0,0 +1,250 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use ndarray::Array1;
+use plotters::coord::Shift;
+use plotters::prelude::*;
+use crate::analyzation::Results;
+use crate::error::{MmpbsaError, Result};
+
+type DrawResult<DB> = std::result::Result<(), DrawingAreaErrorKind<<DB as DrawingBackend>::ErrorType>>;
+
+// colors of energy terms in time series, favorable and unfavorable contributions in residue plots
+const TERM_COLORS: [RGBColor; 6] = [
+    RGBColor(0, 0, 0), RGBColor(31, 119, 180), RGBColor(214, 39, 40),
+    RGBColor(44, 160, 44), RGBColor(148, 103, 189), RGBColor(255, 127, 14),
+];
+const FAVORABLE: RGBColor = RGBColor(33, 102, 172);
+const UNFAVORABLE: RGBColor = RGBColor(178, 24, 43);
+
+pub const PLOT_SIZE: (u32, u32) = (900, 600);
+
+pub enum Plot<'a> {
+    Traj,                       // ΔH and other terms by time, with running average
+    ResTop(&'a Vec<usize>),     // time averaged ΔH of residues with std as error bar
+    Heatmap(&'a Vec<usize>),    // ΔH of residues by time
+}
+
+// residues (columns in per-residue arrays) with the largest |ΔH| in average, most favorable first
+pub fn top_residues(results: &Results, n: usize) -> Vec<usize> {
+    let avg: Vec<f64> = (0..results.residues.len()).map(|i| results.dh_res.column(i).mean().unwrap()).collect();
+    let mut res: Vec<usize> = (0..avg.len()).collect();
+    res.sort_by(|&a, &b| avg[b].abs().total_cmp(&avg[a].abs()));
+    res.truncate(n);
+    res.sort_by(|&a, &b| avg[a].total_cmp(&avg[b]));
+    res
+}
+
+fn running_avg(v: &Array1<f64>) -> Vec<f64> {
+    let mut sum = 0.0;
+    v.iter().enumerate().map(|(i, x)| {
+        sum += x;
+        sum / (i + 1) as f64
+    }).collect()
+}
+
+fn res_label(results: &Results, i: usize) -> String {
+    format!("{}{}", results.residues[i].name, results.residues[i].nr)
+}
+
+// range with some margin, and not empty
+fn padded(min: f64, max: f64) -> (f64, f64) {
+    match max - min {
+        d if d < 1e-6 => (min - 1.0, max + 1.0),
+        d => (min - d * 0.05, max + d * 0.05)
+    }
+}
+
+// white to blue for negative and white to red for positive, v in [-1, 1]
+fn diverging_color(v: f64) -> RGBColor {
+    let v = v.clamp(-1.0, 1.0);
+    let c = if v < 0.0 { FAVORABLE } else { UNFAVORABLE };
+    let mix = |a: u8| (255.0 - (255.0 - a as f64) * v.abs()) as u8;
+    RGBColor(mix(c.0), mix(c.1), mix(c.2))
+}
+
+pub fn draw<DB: DrawingBackend>(root: &DrawingArea<DB, Shift>, plot: &Plot, results: &Results, sys_name: &str) -> DrawResult<DB> {
+    root.fill(&WHITE)?;
+    match plot {
+        Plot::Traj => draw_traj(root, results, sys_name),
+        Plot::ResTop(res) => draw_res_top(root, res, results, sys_name),
+        Plot::Heatmap(res) => draw_heatmap(root, res, results, sys_name),
+    }
+}
+
+fn draw_traj<DB: DrawingBackend>(root: &DrawingArea<DB, Shift>, results: &Results, sys_name: &str) -> DrawResult<DB> {
+    let times: Vec<f64> = results.times.iter().map(|t| t / 1000.0).collect();
+    // terms always zero are not drawn, e.g. ΔCAV and ΔDISP if not calculated
+    let terms: Vec<(&str, &Array1<f64>, RGBColor)> = [
+        ("ΔH", &results.dh), ("ΔMM", &results.mm), ("ΔPB", &results.pb),
+        ("ΔSA", &results.sa), ("ΔCAV", &results.cav), ("ΔDISP", &results.disp),
+    ].into_iter().zip(TERM_COLORS)
+        .filter(|((name, v), _)| *name == "ΔH" || v.iter().any(|&x| x != 0.0))
+        .map(|((name, v), c)| (name, v, c))
+        .collect();
+    let (y_min, y_max) = padded(
+        terms.iter().flat_map(|t| t.1.iter()).fold(f64::INFINITY, |a, &b| a.min(b)),
+        terms.iter().flat_map(|t| t.1.iter()).fold(f64::NEG_INFINITY, |a, &b| a.max(b)));
+    let (x_min, x_max) = padded(times[0], times[times.len() - 1]);
+
+    let mut chart = ChartBuilder::on(root)
+        .caption(format!("Binding energy terms of {} (thick: running average)", sys_name), ("sans-serif", 22))
+        .margin(15)
+        .x_label_area_size(45)
+        .y_label_area_size(70)
+        .build_cartesian_2d(x_min..x_max, y_min..y_max)?;
+    chart.configure_mesh()
+        .x_desc("Time (ns)")
+        .y_desc("Energy (kJ/mol)")
+        .light_line_style(WHITE)
+        .draw()?;
+    for (name, v, color) in terms {
+        chart.draw_series(LineSeries::new(times.iter().cloned().zip(v.iter().cloned()), color.mix(0.35)))?;
+        chart.draw_series(LineSeries::new(times.iter().cloned().zip(running_avg(v)), color.stroke_width(2)))?
+            .label(name)
+            .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], color.stroke_width(2)));
+    }
+    chart.configure_series_labels()
+        .background_style(WHITE.mix(0.8))
+        .border_style(BLACK)
+        .position(SeriesLabelPosition::UpperRight)
+        .draw()?;
+    Ok(())
+}
+
+fn draw_res_top<DB: DrawingBackend>(root: &DrawingArea<DB, Shift>, res: &[usize], results: &Results, sys_name: &str) -> DrawResult<DB> {
+    let n = results.times.len() as f64;
+    let stats: Vec<(f64, f64)> = res.iter().map(|&i| {
+        let v = results.dh_res.column(i);
+        let mean = v.mean().unwrap();
+        let std = match v.len() {
+            1 => 0.0,
+            _ => (v.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1.0)).sqrt()
+        };
+        (mean, std)
+    }).collect();
+    let (x_min, x_max) = padded(
+        stats.iter().fold(0.0, |a, s| f64::min(a, s.0 - s.1)),
+        stats.iter().fold(0.0, |a, s| f64::max(a, s.0 + s.1)));
+
+    // horizontal bars, so that residue labels are not rotated
+    let labels: Vec<String> = res.iter().map(|&i| res_label(results, i)).collect();
+    let mut chart = ChartBuilder::on(root)
+        .caption(format!("Top {} residues by ΔH of {}", res.len(), sys_name), ("sans-serif", 22))
+        .margin(15)
+        .x_label_area_size(45)
+        .y_label_area_size(80)
+        .build_cartesian_2d(x_min..x_max, (0..res.len() as i32 - 1).into_segmented())?;
+    chart.configure_mesh()
+        .disable_y_mesh()
+        .light_line_style(WHITE)
+        .y_labels(res.len())
+        .y_label_formatter(&|v| match v {
+            SegmentValue::CenterOf(i) => labels.get(*i as usize).cloned().unwrap_or_default(),
+            _ => String::new()
+        })
+        .x_desc("ΔH (kJ/mol), error bar: std")
+        .draw()?;
+    chart.draw_series(stats.iter().enumerate().map(|(i, &(mean, _))| {
+        let color = if mean < 0.0 { FAVORABLE } else { UNFAVORABLE };
+        let mut bar = Rectangle::new([(0.0, SegmentValue::Exact(i as i32)), (mean, SegmentValue::Exact(i as i32 + 1))], color.filled());
+        bar.set_margin(3, 3, 0, 0);
+        bar
+    }))?;
+    chart.draw_series(stats.iter().enumerate().map(|(i, &(mean, std))| {
+        ErrorBar::new_horizontal(SegmentValue::CenterOf(i as i32), mean - std, mean, mean + std, BLACK.filled(), 8)
+    }))?;
+    Ok(())
+}
+
+fn draw_heatmap<DB: DrawingBackend>(root: &DrawingArea<DB, Shift>, res: &[usize], results: &Results, sys_name: &str) -> DrawResult<DB> {
+    let times: Vec<f64> = results.times.iter().map(|t| t / 1000.0).collect();
+    let dt = match times.len() {
+        1 => 1.0,
+        _ => times[1] - times[0]
+    };
+    let v_max = res.iter()
+        .flat_map(|&i| results.dh_res.column(i).to_vec())
+        .fold(0.0, |a: f64, b| a.max(b.abs()))
+        .max(1e-6);
+
+    let (left, right) = root.split_horizontally(root.dim_in_pixel().0 - 110);
+    let labels: Vec<String> = res.iter().map(|&i| res_label(results, i)).collect();
+    let mut chart = ChartBuilder::on(&left)
+        .caption(format!("Residue-wised ΔH of {} by time", sys_name), ("sans-serif", 22))
+        .margin(15)
+        .x_label_area_size(45)
+        .y_label_area_size(80)
+        .build_cartesian_2d(times[0]..times[times.len() - 1] + dt, (0..res.len() as i32 - 1).into_segmented())?;
+    chart.configure_mesh()
+        .disable_mesh()
+        .x_desc("Time (ns)")
+        .y_labels(res.len())
+        .y_label_formatter(&|v| match v {
+            SegmentValue::CenterOf(i) => labels.get(*i as usize).cloned().unwrap_or_default(),
+            _ => String::new()
+        })
+        .draw()?;
+    chart.draw_series(res.iter().enumerate().flat_map(|(j, &i)| {
+        let times = &times;
+        results.dh_res.column(i).to_vec().into_iter().enumerate().map(move |(k, v)| {
+            Rectangle::new([(times[k], SegmentValue::Exact(j as i32)), (times[k] + dt, SegmentValue::Exact(j as i32 + 1))],
+                           diverging_color(v / v_max).filled())
+        })
+    }))?;
+
+    // color bar
+    let mut bar = ChartBuilder::on(&right)
+        .margin_top(50)
+        .margin_bottom(60)
+        .margin_right(15)
+        .y_label_area_size(55)
+        .build_cartesian_2d(0.0..1.0, -v_max..v_max)?;
+    bar.configure_mesh()
+        .disable_mesh()
+        .disable_x_axis()
+        .y_label_style(("sans-serif", 12))
+        .axis_desc_style(("sans-serif", 13))
+        .y_desc("ΔH (kJ/mol)")
+        .draw()?;
+    let steps = 100;
+    bar.draw_series((0..steps).map(|k| {
+        let v0 = -v_max + 2.0 * v_max * k as f64 / steps as f64;
+        let v1 = v0 + 2.0 * v_max / steps as f64;
+        Rectangle::new([(0.0, v0), (1.0, v1)], diverging_color((v0 + v1) / 2.0 / v_max).filled())
+    }))?;
+    Ok(())
+}
+
+// svg or png by extension of out
+pub fn write_plot(out: &Path, plot: &Plot, results: &Results, sys_name: &str) -> Result<()> {
+    let to_err = |e: String| MmpbsaError::io(out.to_string_lossy())(io::Error::other(e));
+    match out.extension().and_then(|e| e.to_str()) {
+        Some("png") => {
+            let root = BitMapBackend::new(out, PLOT_SIZE).into_drawing_area();
+            draw(&root, plot, results, sys_name).map_err(|e| to_err(e.to_string()))?;
+            root.present().map_err(|e| to_err(e.to_string()))
+        }
+        _ => {
+            let root = SVGBackend::new(out, PLOT_SIZE).into_drawing_area();
+            draw(&root, plot, results, sys_name).map_err(|e| to_err(e.to_string()))?;
+            root.present().map_err(|e| to_err(e.to_string()))
+        }
+    }
+}
+
+// time series, top residues and heat map of top residues, as svg or png
+pub fn write_plots(results: &Results, sys_name: &str, wd: &Path, ext: &str, top_n: usize) -> Result<Vec<PathBuf>> {
+    let res = top_residues(results, top_n.max(1));
+    let plots = [
+        (format!("MMPBSA_{}_traj.{}", sys_name, ext), Plot::Traj),
+        (format!("MMPBSA_{}_res_top.{}", sys_name, ext), Plot::ResTop(&res)),
+        (format!("MMPBSA_{}_res_heatmap.{}", sys_name, ext), Plot::Heatmap(&res)),
+    ];
+    let mut outs = vec![];
+    for (name, plot) in &plots {
+        let out = wd.join(name);
+        write_plot(&out, plot, results, sys_name)?;
+        outs.push(out);
+    }
+    Ok(outs)
+}
@@ -0,0 +1,52 @@
+use std::fs;
+use std::path::Path;
+use ndarray::s;
+use crate::apbs_param::{PBASet, PBESet};
+use crate::atom_property::AtomProperty;
+use crate::dx::DxGrid;
+use crate::error::{MmpbsaError, Result};
+use crate::membrane::MembraneSet;
+use crate::mmpbsa::{get_atoms_trj, run_apbs_frame};
+use crate::prepare_apbs::prepare_pqr;
+use crate::settings::Settings;
+use crate::trajectory::read_trajectory;
+
+// potential (kT/e), dielectric and ion accessibility maps of com, rec and lig at the frame nearest to time (ns),
+// with the PB settings of the calculation, written to {sys_name}_maps/ together with pqr files of the frame
+pub fn write_pot_maps(trj: &String, wd: &Path, sys_name: &String, aps: &AtomProperty,
+                      ndx_com: &Vec<usize>, ndx_rec: &Vec<usize>, ndx_lig: &Vec<usize>, time: f64,
+                      pbe_set: &PBESet, pba_set: &PBASet, mem_set: Option<&MembraneSet>, settings: &Settings) -> Result<()> {
+    let apbs = settings.apbs.as_ref().ok_or_else(|| MmpbsaError::Apbs("APBS not found".to_string()))?;
+    let frames = read_trajectory(trj)?;
+    let (coordinates, _) = get_atoms_trj(&frames);
+    let cur_frm = (0..frames.len())
+        .min_by(|&a, &b| (frames[a].time as f64 - time * 1000.0).abs().total_cmp(&(frames[b].time as f64 - time * 1000.0).abs()))
+        .unwrap();
+
+    let out_dir = wd.join(format!("{}_maps", sys_name));
+    fs::create_dir_all(&out_dir).map_err(MmpbsaError::io(out_dir.to_string_lossy()))?;
+    println!("Writing potential maps at {} ns to {}/...", frames[cur_frm].time / 1000.0, out_dir.display());
+    prepare_pqr(&frames, cur_frm, cur_frm, 1, 1, &out_dir, sys_name, &coordinates, ndx_com, ndx_rec, ndx_lig, aps)?;
+
+    let f_name = format!("{}_{}ns", sys_name, frames[cur_frm].time / 1000.0);
+    let coord = coordinates.slice(s![cur_frm, .., ..]);
+    let (_, grids) = run_apbs_frame(apbs, ndx_rec, ndx_lig, &coord, aps, &out_dir, &f_name,
+                                    pbe_set, pba_set, mem_set, true, settings)?;
+
+    // range of potential on the fine grid, to check the odd ΔPB
+    for (sys, plan) in &grids {
+        let pot_file = out_dir.join(format!("{}_{}_pot.dx", f_name, sys));
+        if !pot_file.is_file() {
+            println!("Warning: {} not found.", pot_file.display());
+            continue;
+        }
+        let pot = DxGrid::from(&pot_file)?;
+        let min = pot.data.iter().fold(f64::INFINITY, |a, &b| a.min(b));
+        let max = pot.data.iter().fold(f64::NEG_INFINITY, |a, &b| a.max(b));
+        let [d_x, d_y, d_z] = plan.fg_spacing();
+        println!("{:>4}: potential {:.3} ~ {:.3} kT/e, fine grid spacing {:.3} {:.3} {:.3} A", sys, min, max, d_x, d_y, d_z);
+    }
+    println!("Maps {}_({})_(pot/dielx/diely/dielz/kappa).dx and pqr files have been written to {}/",
+        f_name, grids.iter().map(|(sys, _)| sys.as_str()).collect::<Vec<&str>>().join("/"), out_dir.display());
+    Ok(())
+}
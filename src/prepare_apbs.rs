@@ -1,5 +1,5 @@
 use std::fs::File;
-use std::io::Write;
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use indicatif::ProgressBar;
@@ -8,25 +8,29 @@ use xdrfile::Frame;
 use crate::apbs_param::*;
 use crate::atom_property::AtomProperty;
 use crate::mmpbsa::set_style;
-use crate::settings::Settings;
+use crate::membrane::MembraneSet;
+use crate::apbs_grid::GridPlan;
+use crate::error::{MmpbsaError, Result};
 
 pub fn prepare_pqr(frames: &Vec<Rc<Frame>>, bf: usize, ef: usize, dframe: usize, total_frames: usize,
                    temp_dir: &Path, sys_name: &String, coordinates: &Array3<f64>,
                    ndx_com_norm: &Vec<usize>, ndx_rec_norm: &Vec<usize>, ndx_lig_norm: &Vec<usize>,
-                   aps: &AtomProperty) {
+                   aps: &AtomProperty) -> Result<()> {
     let pb = ProgressBar::new(total_frames as u64);
     set_style(&pb);
     for cur_frm in (bf..=ef).step_by(dframe) {
         let f_name = format!("{}_{}ns", sys_name, frames[cur_frm].time / 1000.0);
+        let pqr_file = |sys: &str| temp_dir.join(format!("{}_{}.pqr", f_name, sys)).to_string_lossy().to_string();
+        let create = |sys: &str| File::create(pqr_file(sys)).map_err(MmpbsaError::io(pqr_file(sys)));
         let mut pqr_com = match ndx_lig_norm[0] != ndx_rec_norm[0] {
-            true => Some(File::create(&temp_dir.join(format!("{}_com.pqr", f_name))).unwrap()),
+            true => Some(create("com")?),
             false => None
         };
         let mut pqr_lig = match ndx_lig_norm[0] != ndx_rec_norm[0] {
-            true => Some(File::create(&temp_dir.join(format!("{}_lig.pqr", f_name))).unwrap()),
+            true => Some(create("lig")?),
             false => None
         };
-        let mut pqr_rec = File::create(&temp_dir.join(format!("{}_rec.pqr", f_name))).unwrap();
+        let mut pqr_rec = create("rec")?;
         
         // loop atoms and write pqr information (from pqr)
         for &at_id in ndx_com_norm {
@@ -45,78 +49,107 @@ pub fn prepare_pqr(frames: &Vec<Rc<Frame>>, bf: usize, ef: usize, dframe: usize,
             // write qrv files
             // if has ligand
             if let Some(pqr_com) = &mut pqr_com {
-                pqr_com.write_all(atom_line.as_bytes()).unwrap();
+                pqr_com.write_all(atom_line.as_bytes()).map_err(MmpbsaError::io(pqr_file("com")))?;
             }
             if let Some(pqr_lig) = &mut pqr_lig {
                 if ndx_lig_norm.contains(&at_id) {
-                    pqr_lig.write_all(atom_line.as_bytes()).unwrap();
+                    pqr_lig.write_all(atom_line.as_bytes()).map_err(MmpbsaError::io(pqr_file("lig")))?;
                 }
             }
             if ndx_rec_norm.contains(&at_id) {
-                pqr_rec.write_all(atom_line.as_bytes()).unwrap();
+                pqr_rec.write_all(atom_line.as_bytes()).map_err(MmpbsaError::io(pqr_file("rec")))?;
             }
         }
 
         pb.inc(1);
     }
     pb.finish();
+    Ok(())
 }
 
-pub fn write_apbs_input(ndx_rec: &Vec<usize>, ndx_lig: &Vec<usize>, coord: &ArrayView2<f64>,
-                  atm_radius: &Array1<f64>, pbe_set: &PBESet, pba_set: &PBASet,
-                  temp_dir: &PathBuf, f_name: &String, settings: &Settings) {
-    let mut input_apbs = File::create(temp_dir.join(format!("{}.apbs", f_name))).unwrap();
-    writeln!(input_apbs, "read").expect("Failed writing apbs file.");
-    if ndx_lig[0] != ndx_rec[0] {
-        writeln!(input_apbs, "  mol pqr {}_com.pqr", f_name).expect("Failed writing apbs file.");
-    }
-    writeln!(input_apbs, "  mol pqr {}_rec.pqr", f_name).expect("Failed writing apbs file.");
-    if ndx_lig[0] != ndx_rec[0] {
-        writeln!(input_apbs, "  mol pqr {}_lig.pqr", f_name).expect("Failed writing apbs file.");
-    }
-    writeln!(input_apbs, "end\n").expect("Failed writing apbs file.");
-    
-    let (rec_box, lig_box, com_box) =
-        gen_mesh_params(ndx_rec, ndx_lig, coord, atm_radius);
+pub fn write_apbs_input(ndx_rec: &Vec<usize>, ndx_lig: &Vec<usize>, grids: &Vec<(String, GridPlan)>,
+                  pbe_set: &PBESet, pba_set: &PBASet,
+                  temp_dir: &PathBuf, f_name: &String, mem_set: Option<&MembraneSet>, write_maps: bool) -> Result<()> {
+    let apbs_file = |name: &str| temp_dir.join(format!("{}.apbs", name)).to_string_lossy().to_string();
+    let input_file = apbs_file(f_name);
+    let mut input_apbs = File::create(&input_file).map_err(MmpbsaError::io(&input_file))?;
+    write_read_section(&mut input_apbs, f_name, ndx_rec, ndx_lig, mem_set).map_err(MmpbsaError::io(&input_file))?;
 
     let mut pbe_set0 = PBESet::from(pbe_set);
     pbe_set0.sdie = 1.0;
 
-    if ndx_lig[0] != ndx_rec[0] {
-        input_apbs.write_all(dim_apbs(format!("{}_com", f_name).as_str(), 1,
-                                    com_box[0], com_box[3], 
-                                    com_box[1], com_box[4], 
-                                    com_box[2], com_box[5],
-                                    settings,
-                                    pbe_set, &pbe_set0, pba_set).as_bytes()).
-            expect("Failed writing apbs file.");
+    // the order of calculations should be the same as get_membrane_maps
+    for (i, (sys, plan)) in grids.iter().enumerate() {
+        input_apbs.write_all(dim_apbs(format!("{}_{}", f_name, sys).as_str(), i as i32 + 1, plan,
+                                    pbe_set, &pbe_set0, pba_set,
+                                    mem_set.map(|_| 2 * i + 1), write_maps).as_bytes())
+            .map_err(MmpbsaError::io(&input_file))?;
+    }
+
+    // mg-para partitions are run one by one with async, partition i of all molecules in {f_name}_PE{i}.apbs
+    let partitions = grids.iter().map(|(_, plan)| plan.partitions()).max().unwrap();
+    if partitions > 1 {
+        for async_id in 0..partitions {
+            let para_file = apbs_file(&format!("{}_PE{}", f_name, async_id));
+            let mut input_para = File::create(&para_file).map_err(MmpbsaError::io(&para_file))?;
+            write_read_section(&mut input_para, f_name, ndx_rec, ndx_lig, None).map_err(MmpbsaError::io(&para_file))?;
+            for (i, (sys, plan)) in grids.iter().enumerate() {
+                if plan.is_para() && async_id < plan.partitions() {
+                    input_para.write_all(para_apbs(format!("{}_{}", f_name, sys).as_str(), i as i32 + 1, plan,
+                                                   pbe_set, &pbe_set0, async_id).as_bytes())
+                        .map_err(MmpbsaError::io(&para_file))?;
+                }
+            }
+        }
+    }
+
+    // mg-dummy calculations to write dielectric and kappa maps to be modified by membrane
+    if mem_set.is_some() {
+        let maps_file = apbs_file(&format!("{}_maps", f_name));
+        let mut input_maps = File::create(&maps_file).map_err(MmpbsaError::io(&maps_file))?;
+        write_read_section(&mut input_maps, f_name, ndx_rec, ndx_lig, None).map_err(MmpbsaError::io(&maps_file))?;
+        for (i, (sys, plan)) in grids.iter().enumerate() {
+            for (level, coarse) in [("cg", true), ("fg", false)] {
+                let map_name = format!("{}_{}_{}", f_name, sys, level);
+                writeln!(input_maps, "ELEC name {}_MAP\n{}\n{}\n  \
+                    write dielx dx {3}_dielx\n  write diely dx {3}_diely\n  write dielz dx {3}_dielz\n  \
+                    write kappa dx {3}_kappa\nend\n", map_name,
+                    manual_grid("mg-dummy", i as i32 + 1, plan, coarse), pbe_set.to_string(), map_name)
+                    .map_err(MmpbsaError::io(&maps_file))?;
+            }
+        }
     }
+    Ok(())
+}
+
+fn write_read_section(input_apbs: &mut File, f_name: &String, ndx_rec: &Vec<usize>, ndx_lig: &Vec<usize>, mem_set: Option<&MembraneSet>) -> io::Result<()> {
+    writeln!(input_apbs, "read")?;
     if ndx_lig[0] != ndx_rec[0] {
-        input_apbs.write_all(dim_apbs(format!("{}_rec", f_name).as_str(), 2,
-                                    rec_box[0], rec_box[3], 
-                                    rec_box[1], rec_box[4], 
-                                    rec_box[2], rec_box[5],
-                                    settings,
-                                    pbe_set, &pbe_set0, pba_set).as_bytes()).
-            expect("Failed writing apbs file.");
-    } else {
-        input_apbs.write_all(dim_apbs(format!("{}_rec", f_name).as_str(), 1,
-                                    rec_box[0], rec_box[3], 
-                                    rec_box[1], rec_box[4], 
-                                    rec_box[2], rec_box[5],
-                                    settings,
-                                    pbe_set, &pbe_set0, pba_set).as_bytes()).
-            expect("Failed writing apbs file.");
+        writeln!(input_apbs, "  mol pqr {}_com.pqr", f_name)?;
     }
+    writeln!(input_apbs, "  mol pqr {}_rec.pqr", f_name)?;
     if ndx_lig[0] != ndx_rec[0] {
-        input_apbs.write_all(dim_apbs(format!("{}_lig", f_name).as_str(), 3,
-                                    lig_box[0], lig_box[3], 
-                                    lig_box[1], lig_box[4], 
-                                    lig_box[2], lig_box[5],
-                                    settings,
-                                    pbe_set, &pbe_set0, pba_set).as_bytes()).
-            expect("Failed writing apbs file.");
+        writeln!(input_apbs, "  mol pqr {}_lig.pqr", f_name)?;
     }
+    // membrane maps of coarse and fine grids, numbered in the order of read
+    if mem_set.is_some() {
+        for map_name in get_membrane_maps(f_name, ndx_rec, ndx_lig) {
+            writeln!(input_apbs, "  diel dx {0}_dielx.dx {0}_diely.dx {0}_dielz.dx", map_name)?;
+        }
+        for map_name in get_membrane_maps(f_name, ndx_rec, ndx_lig) {
+            writeln!(input_apbs, "  kappa dx {}_kappa.dx", map_name)?;
+        }
+    }
+    writeln!(input_apbs, "end\n")
+}
+
+// names of membrane maps of each system and grid, e.g. _system_0ns_com_cg
+pub fn get_membrane_maps(f_name: &str, ndx_rec: &Vec<usize>, ndx_lig: &Vec<usize>) -> Vec<String> {
+    let systems = match ndx_lig[0] != ndx_rec[0] {
+        true => vec!["com", "rec", "lig"],
+        false => vec!["rec"]
+    };
+    systems.iter().flat_map(|s| ["cg", "fg"].map(|l| format!("{}_{}_{}", f_name, s, l))).collect()
 }
 
 fn get_lb(ndx: &Vec<usize>, axis: usize, coord: &ArrayView2<f64>, atm_radius: &Array1<f64>) -> f64 {
@@ -161,54 +194,73 @@ pub fn gen_mesh_params(ndx_rec: &Vec<usize>, ndx_lig: &Vec<usize>, coord: &Array
     return (rec_box, lig_box, com_box);
 }
 
-pub fn dim_apbs(file: &str, mol_index: i32, min_x: f64, max_x: f64, min_y: f64, max_y: f64, min_z: f64, max_z: f64,
-                settings: &Settings, pbe_set: &PBESet, pbe_set0: &PBESet, pba_set: &PBASet) -> String {
-    let cfac = settings.cfac;
-    let fadd = settings.fadd;
-    let df = settings.df;
-
-    let x_len = (max_x - min_x).max(0.1);
-    let x_center = (max_x + min_x) / 2.0;
-    let y_len = (max_y - min_y).max(0.1);
-    let y_center = (max_y + min_y) / 2.0;
-    let z_len = (max_z - min_z).max(0.1);
-    let z_center = (max_z + min_z) / 2.0;
-
-    let c_x = x_len * cfac;
-    let c_y = y_len * cfac;
-    let c_z = z_len * cfac;
-    let f_x = (x_len + fadd).min(c_x);
-    let f_y = (y_len + fadd).min(c_y);
-    let f_z = (z_len + fadd).min(c_z);
-
-    // 格点数为32的倍数, apbs的特殊要求
-    let t = 32.0;
-    let n_x = ((f_x / df / t).round() * t) as i32 + 1;
-    let n_y = ((f_y / df / t).round() * t) as i32 + 1;
-    let n_z = ((f_z / df / t).round() * t) as i32 + 1;
-
-    let mg_set = "mg-auto";
-
-    let xyz_set = format!("  {mg_set}\n  mol    {mol_index:7}\
+// single grid for mg-manual and mg-dummy, coarse or fine grid of the plan
+fn manual_grid(mg_set: &str, mol_index: i32, plan: &GridPlan, coarse: bool) -> String {
+    let [n_x, n_y, n_z] = plan.dime;
+    let [l_x, l_y, l_z] = match coarse {
+        true => plan.cglen,
+        false => plan.fglen
+    };
+    let [x_center, y_center, z_center] = match coarse {
+        true => plan.cgcent,
+        false => plan.fgcent
+    };
+    format!("  {mg_set}\n  mol    {mol_index:7}\
         \n  dime   {n_x:7}  {n_y:7}  {n_z:7}\
-        \n  cglen  {c_x:7.3}  {c_y:7.3}  {c_z:7.3}\
-        \n  fglen  {f_x:7.3}  {f_y:7.3}  {f_z:7.3}\
-        \n  fgcent {x_center:7.3}  {y_center:7.3}  {z_center:7.3}\
-        \n  cgcent {x_center:7.3}  {y_center:7.3}  {z_center:7.3}\n");
+        \n  nlev   {:7}\
+        \n  glen   {l_x:7.3}  {l_y:7.3}  {l_z:7.3}\
+        \n  gcent  {x_center:7.3}  {y_center:7.3}  {z_center:7.3}\n", plan.nlev)
+}
 
-    return format!("\nELEC name {}_SOL\n\
-    {}\n\
-    {}\n\
-    end\n\n\
+// mem_map: index of coarse grid membrane map, the solvated calculation is then focused manually with maps
+// electrostatics of mg-para grids are written to partition files by para_apbs
+// write_maps: write potential and dielectric maps of the solvated fine grid to {file}_pot.dx, {file}_dielx.dx...
+pub fn dim_apbs(file: &str, mol_index: i32, plan: &GridPlan,
+                pbe_set: &PBESet, pbe_set0: &PBESet, pba_set: &PBASet, mem_map: Option<usize>, write_maps: bool) -> String {
+    let apolar_set = format!("APOLAR name {0}_SAS\n  \
+        mol    {1:7}\n{2}\n\
+        end\n\n", file, mol_index, pba_set.to_apbs().to_string());
+    if plan.is_para() {
+        return format!("\n{}print apolEnergy {}_SAS end\n\n", apolar_set, file);
+    }
+
+    let xyz_set = plan.to_apbs(mol_index, None);
+    let maps_set = match write_maps {
+        true => format!("  write pot dx {0}_pot\n  write dielx dx {0}_dielx\n  write diely dx {0}_diely\n  \
+            write dielz dx {0}_dielz\n  write kappa dx {0}_kappa\n", file),
+        false => String::new()
+    };
+
+    let sol_set = match mem_map {
+        None => format!("ELEC name {0}_SOL\n{1}\n{2}\n  write atompot flat {0}_SOL_atompot\n{3}end\n\n", file, xyz_set, pbe_set.to_string(), maps_set),
+        Some(map_id) => {
+            let mut pbe_set_cg = PBESet::from(pbe_set);
+            pbe_set_cg.calc_energy = "no".to_string();
+            let mut pbe_set_fg = PBESet::from(pbe_set);
+            pbe_set_fg.bcfl = "focus".to_string();
+            format!("ELEC name {0}_MEM\n{1}\n{2}\n  usemap diel  {3}\n  usemap kappa {3}\nend\n\n\
+                ELEC name {0}_SOL\n{4}\n{5}\n  usemap diel  {6}\n  usemap kappa {6}\n  write atompot flat {0}_SOL_atompot\n{7}end\n\n", file,
+                manual_grid("mg-manual", mol_index, plan, true), pbe_set_cg.to_string(), map_id,
+                manual_grid("mg-manual", mol_index, plan, false), pbe_set_fg.to_string(), map_id + 1, maps_set)
+        }
+    };
+
+    return format!("\n{}\
     ELEC name {}_VAC\n\
     {}\n\
-    {}\n\
-    end\n\n\
-    APOLAR name {}_SAS\n  \
-    mol    {:7}\n{}\n\
+    {}\n  \
+    write atompot flat {}_VAC_atompot\n\
     end\n\n\
+    {}\
     print elecEnergy {}_SOL - {}_VAC end\n\
-    print apolEnergy {}_SAS end\n\n", file, xyz_set, pbe_set.to_string(), file,
-                   xyz_set, pbe_set0.to_string(), file, mol_index,
-                   pba_set.to_string(), file, file, file);
-}
\ No newline at end of file
+    print apolEnergy {}_SAS end\n\n", sol_set, file,
+                   xyz_set, pbe_set0.to_string(), file, apolar_set, file, file, file);
+}
+
+// solvated and vacuum calculations of mg-para partition async_id
+fn para_apbs(file: &str, mol_index: i32, plan: &GridPlan, pbe_set: &PBESet, pbe_set0: &PBESet, async_id: usize) -> String {
+    let xyz_set = plan.to_apbs(mol_index, Some(async_id));
+    format!("ELEC name {0}_SOL\n{1}\n{2}\n  write atompot flat {0}_SOL_atompot_PE{4}\nend\n\n\
+        ELEC name {0}_VAC\n{1}\n{3}\n  write atompot flat {0}_VAC_atompot_PE{4}\nend\n\n",
+        file, xyz_set, pbe_set.to_string(), pbe_set0.to_string(), async_id)
+}
@@ -0,0 +1,380 @@
+// Python extension module (feature "python"), built with maturin:
+//     import s_mmpbsa
+//     system = s_mmpbsa.System("complex.prmtop", "md.dcd")
+//     results = system.run("Protein", "LIG", pbe=s_mmpbsa.PBESet(pdie=4.0), apbs="apbs")
+// the trajectory is used as is, it should be PBC-fixed by other programs (e.g. cpptraj, VMD, gmx trjconv)
+use std::fs;
+use std::path::{Path, PathBuf};
+use numpy::{PyArray1, PyArray2, ToPyArray};
+use pyo3::exceptions::{PyIOError, PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use crate::apbs_param::{Ion, PBASet, PBESet};
+use crate::atom_override::load_overrides;
+use crate::atom_property::AtomProperty;
+use crate::atom_radius::{RADIUS_TYPES, get_radius_type};
+use crate::error::MmpbsaError;
+use crate::index_parser::{Index, normalize_index};
+use crate::membrane::MembraneSet;
+use crate::metal_site::assign_metals;
+use crate::mmpbsa::{fun_mmpbsa_calculations, get_residues};
+use crate::parse_tpr::TPR;
+use crate::settings::{Settings, get_settings_in_use};
+use crate::trajectory::{read_trajectory, write_xtc};
+
+impl From<MmpbsaError> for PyErr {
+    fn from(e: MmpbsaError) -> PyErr {
+        match e {
+            MmpbsaError::Io { .. } => PyIOError::new_err(e.to_string()),
+            MmpbsaError::Index(_) | MmpbsaError::Parse { .. } => PyValueError::new_err(e.to_string()),
+            _ => PyRuntimeError::new_err(e.to_string())
+        }
+    }
+}
+
+// PB parameters, same as the PB parameters file of the interactive program
+#[pyclass(name = "PBESet", module = "s_mmpbsa")]
+#[derive(Clone)]
+pub struct PyPBESet {
+    pub inner: PBESet,
+}
+
+#[pymethods]
+impl PyPBESet {
+    #[new]
+    #[pyo3(signature = (temp=298.15, pdie=None, sdie=None))]
+    fn new(temp: f64, pdie: Option<f64>, sdie: Option<f64>) -> Self {
+        let mut inner = PBESet::new(temp);
+        inner.pdie = pdie.unwrap_or(inner.pdie);
+        inner.sdie = sdie.unwrap_or(inner.sdie);
+        PyPBESet { inner }
+    }
+
+    #[staticmethod]
+    fn load(file: &str) -> PyResult<Self> {
+        Ok(PyPBESet { inner: PBESet::load_params(file)? })
+    }
+
+    fn save(&self, file: &str) -> PyResult<()> {
+        Ok(self.inner.save_params(file)?)
+    }
+
+    #[getter]
+    fn temp(&self) -> f64 { self.inner.temp }
+    #[setter]
+    fn set_temp(&mut self, v: f64) { self.inner.temp = v }
+    #[getter]
+    fn pdie(&self) -> f64 { self.inner.pdie }
+    #[setter]
+    fn set_pdie(&mut self, v: f64) { self.inner.pdie = v }
+    #[getter]
+    fn sdie(&self) -> f64 { self.inner.sdie }
+    #[setter]
+    fn set_sdie(&mut self, v: f64) { self.inner.sdie = v }
+    #[getter]
+    fn srad(&self) -> f64 { self.inner.srad }
+    #[setter]
+    fn set_srad(&mut self, v: f64) { self.inner.srad = v }
+    #[getter]
+    fn pb_solver(&self) -> String { self.inner.pb_solver.to_string() }
+    #[setter]
+    fn set_pb_solver(&mut self, v: String) { self.inner.pb_solver = v }
+
+    // (charge, conc (M), radius (A)) of each ion
+    #[getter]
+    fn ions(&self) -> Vec<(f64, f64, f64)> {
+        self.inner.ions.iter().map(|ion| (ion.charge, ion.conc, ion.radius)).collect()
+    }
+    #[setter]
+    fn set_ions(&mut self, v: Vec<(f64, f64, f64)>) {
+        self.inner.ions = v.iter().map(|&(charge, conc, radius)| Ion { charge, conc, radius }).collect();
+    }
+
+    fn __repr__(&self) -> String {
+        format!("PBESet(\n{}\n)", self.inner)
+    }
+}
+
+// SA parameters, same as the SA parameters file of the interactive program
+#[pyclass(name = "PBASet", module = "s_mmpbsa")]
+#[derive(Clone)]
+pub struct PyPBASet {
+    pub inner: PBASet,
+}
+
+#[pymethods]
+impl PyPBASet {
+    #[new]
+    #[pyo3(signature = (temp=298.15))]
+    fn new(temp: f64) -> Self {
+        PyPBASet { inner: PBASet::new(temp) }
+    }
+
+    #[staticmethod]
+    fn load(file: &str) -> PyResult<Self> {
+        Ok(PyPBASet { inner: PBASet::load_params(file)? })
+    }
+
+    fn save(&self, file: &str) -> PyResult<()> {
+        Ok(self.inner.save_params(file)?)
+    }
+
+    #[getter]
+    fn srad(&self) -> f64 { self.inner.srad }
+    #[setter]
+    fn set_srad(&mut self, v: f64) { self.inner.srad = v }
+    #[getter]
+    fn gamma(&self) -> f64 { self.inner.gamma }
+    #[setter]
+    fn set_gamma(&mut self, v: f64) { self.inner.gamma = v }
+    #[getter]
+    fn bias(&self) -> f64 { self.inner.bias }
+    #[setter]
+    fn set_bias(&mut self, v: f64) { self.inner.bias = v }
+    #[getter]
+    fn press(&self) -> f64 { self.inner.press }
+    #[setter]
+    fn set_press(&mut self, v: f64) { self.inner.press = v }
+    #[getter]
+    fn bconc(&self) -> f64 { self.inner.bconc }
+    #[setter]
+    fn set_bconc(&mut self, v: f64) { self.inner.bconc = v }
+
+    fn __repr__(&self) -> String {
+        format!("PBASet(\n{}\n)", self.inner)
+    }
+}
+
+// energies (kJ/mol) of each frame, and of each residue (frames x residues)
+#[pyclass(name = "Results", module = "s_mmpbsa", get_all)]
+pub struct PyResults {
+    pub times: Py<PyArray1<f64>>,       // ps
+    pub dh: Py<PyArray1<f64>>,
+    pub mm: Py<PyArray1<f64>>,
+    pub pb: Py<PyArray1<f64>>,
+    pub sa: Py<PyArray1<f64>>,
+    pub cav: Py<PyArray1<f64>>,
+    pub disp: Py<PyArray1<f64>>,
+    pub elec: Py<PyArray1<f64>>,
+    pub vdw: Py<PyArray1<f64>>,
+    pub dh_res: Py<PyArray2<f64>>,
+    pub mm_res: Py<PyArray2<f64>>,
+    pub pb_res: Py<PyArray2<f64>>,
+    pub sa_res: Py<PyArray2<f64>>,
+    pub cav_res: Py<PyArray2<f64>>,
+    pub disp_res: Py<PyArray2<f64>>,
+    pub elec_res: Py<PyArray2<f64>>,
+    pub vdw_res: Py<PyArray2<f64>>,
+    pub res_id: Vec<usize>,             // residue index in topology
+    pub res_name: Vec<String>,
+    pub res_nr: Vec<i32>,               // residue number
+    pub res_is_ligand: Vec<bool>,
+}
+
+#[pymethods]
+impl PyResults {
+    fn __repr__(&self) -> String {
+        format!("Results({} residues)", self.res_id.len())
+    }
+}
+
+// topology, trajectory and index groups of a system
+#[pyclass(name = "System", module = "s_mmpbsa")]
+pub struct PySystem {
+    tpr: TPR,
+    trj: String,
+    ndx: Index,
+    settings: Settings,
+}
+
+#[pymethods]
+impl PySystem {
+    // topology: .prmtop/.parm7, .psf (with CHARMM parameter files in prm), or .dump of gmx dump -s md.tpr,
+    // index: gromacs index file, default groups of molecules if None,
+    // settings: settings.ini, that in current directory if None
+    #[new]
+    #[pyo3(signature = (topology, trajectory, index=None, prm=None, settings=None))]
+    fn new(topology: &str, trajectory: &str, index: Option<&str>, prm: Option<Vec<String>>,
+           settings: Option<&str>) -> PyResult<Self> {
+        let settings = match settings {
+            Some(settings) => Settings::from(&PathBuf::from(settings))?,
+            None => match get_settings_in_use() {
+                Some(settings) => Settings::from(&settings)?,
+                None => Settings::new()
+            }
+        };
+        let ext = Path::new(topology).extension().and_then(|e| e.to_str()).unwrap_or("");
+        let mut tpr = match ext {
+            "prmtop" | "parm7" => TPR::from_prmtop(topology, &settings)?,
+            "psf" => TPR::from_psf(topology, &prm.unwrap_or_default(), &settings)?,
+            "dump" => TPR::new(topology, &settings)?,
+            _ => return Err(PyValueError::new_err(format!("unsupported topology: {}, use .prmtop, .psf or .dump", topology)))
+        };
+        let ndx = match index {
+            Some(index) => Index::from(&index.to_string())?,
+            None => Index::from_tpr(&tpr)
+        };
+        // time of frames, as the interactive program
+        let frames = read_trajectory(trajectory)?;
+        if frames.len() > 1 {
+            tpr.tinit = (frames[0].time as f64 * 1000.0).round() / 1000.0;
+            tpr.dt = ((frames[1].time - frames[0].time) as f64 * 1000.0).round() / 1000.0;
+            tpr.nsteps = frames.len() as u64 - 1;
+        }
+        Ok(PySystem { tpr, trj: trajectory.to_string(), ndx, settings })
+    }
+
+    // (name, atoms number) of each index group
+    fn groups(&self) -> Vec<(String, usize)> {
+        self.ndx.groups.iter().map(|g| (g.name.to_string(), g.indexes.len())).collect()
+    }
+
+    // receptor and ligand: index group number, group name, or list of atom indexes (from 0),
+    // each should be a continuous range of atoms;
+    // bt, et and dt in ps; GB is not implemented by the engine, solvation is by PB (APBS) and SA
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (receptor, ligand=None, pbe=None, pba=None, bt=None, et=None, dt=None,
+                        work_dir=".", name="_system", radius=None, apbs=None, membrane=None))]
+    fn run(&self, py: Python<'_>, receptor: &Bound<'_, PyAny>, ligand: Option<&Bound<'_, PyAny>>,
+           pbe: Option<PyPBESet>, pba: Option<PyPBASet>, bt: Option<f64>, et: Option<f64>, dt: Option<f64>,
+           work_dir: &str, name: &str, radius: Option<&str>, apbs: Option<String>,
+           membrane: Option<&str>) -> PyResult<PyResults> {
+        let ndx_rec = self.select(receptor)?;
+        let ndx_lig = match ligand {
+            Some(ligand) => Some(self.select(ligand)?),
+            None => None
+        };
+        if let Some(ndx_lig) = &ndx_lig {
+            if ndx_lig.iter().any(|i| ndx_rec.binary_search(i).is_ok()) {
+                return Err(MmpbsaError::Index("receptor and ligand overlap".to_string()).into());
+            }
+        }
+        let mut settings = self.settings.clone();
+        if let Some(radius) = radius {
+            settings.rad_type = get_radius_type(radius)
+                .ok_or_else(|| PyValueError::new_err(format!("unknown radius type: {}", radius)))?;
+        }
+        if apbs.is_some() {
+            settings.apbs = apbs;
+        }
+        // solute dielectric is epsilon-r of simulation if larger than 1, as the interactive program
+        let pbe_set = pbe.map(|p| p.inner).unwrap_or_else(|| {
+            let mut pbe_set = PBESet::new(self.tpr.temp);
+            if self.tpr.epsilon_r > 1.0 {
+                pbe_set.pdie = self.tpr.epsilon_r;
+            }
+            pbe_set
+        });
+        let pba_set = pba.map(|p| p.inner).unwrap_or_else(|| PBASet::new(self.tpr.temp));
+        let mem_set = membrane.map(MembraneSet::load_params).transpose()?;
+
+        // release GIL during the calculation
+        let wd = PathBuf::from(work_dir);
+        let sys_name = name.to_string();
+        let results = py.detach(|| -> crate::Result<crate::analyzation::Results> {
+            let ndx_com = match &ndx_lig {
+                Some(ndx_lig) if ndx_lig[0] < ndx_rec[0] => [ndx_lig.as_slice(), &ndx_rec].concat(),
+                Some(ndx_lig) => [ndx_rec.as_slice(), ndx_lig].concat(),
+                None => ndx_rec.to_vec()
+            };
+            let mut aps = AtomProperty::new(&self.tpr, &ndx_com, settings.fep_lambda)?;
+            let residues = get_residues(&self.tpr, &ndx_com);
+            let ndx_com_sys = ndx_com.to_vec();
+
+            let trj_mmpbsa = wd.join(format!("_MMPBSA_{}.xtc", sys_name)).to_string_lossy().to_string();
+            let frames = read_trajectory(&self.trj)?;
+            write_xtc(&frames, &ndx_com, &trj_mmpbsa)?;
+            let (ndx_com, ndx_rec, ndx_lig) = normalize_index(&ndx_rec, ndx_lig.as_ref());
+
+            aps.apply_radius(settings.rad_type, &RADIUS_TYPES.to_vec())?;
+            if !settings.metal_radius.is_empty() {
+                aps.apply_metal_radius(&settings.metal_radius)?;
+            }
+            if !settings.atom_override.is_empty() {
+                let overrides = load_overrides(&settings.atom_override)?;
+                aps.apply_overrides(&overrides, &ndx_com_sys, &wd.join("atom_override_report.txt"))?;
+            }
+            let (ndx_rec, ndx_lig) = assign_metals(&aps.get_metals(), &ndx_rec, &ndx_lig, settings.metal_site);
+            let mut mem_set = mem_set;
+            if let Some(mem_set) = &mut mem_set {
+                if mem_set.auto_detect {
+                    mem_set.detect(&self.tpr, &self.trj, &trj_mmpbsa, &ndx_com_sys)?;
+                }
+            }
+
+            let temp_dir = wd.join(&sys_name);
+            if settings.apbs.is_some() {
+                fs::create_dir_all(&temp_dir).map_err(MmpbsaError::io(temp_dir.to_string_lossy()))?;
+            }
+            let t_end = self.tpr.tinit + self.tpr.dt * self.tpr.nsteps as f64;
+            let results = fun_mmpbsa_calculations(&trj_mmpbsa, &temp_dir, &sys_name, &aps,
+                                                  &ndx_com, &ndx_rec, &ndx_lig, &residues,
+                                                  bt.unwrap_or(self.tpr.tinit), et.unwrap_or(t_end),
+                                                  dt.unwrap_or(self.tpr.dt), &pbe_set, &pba_set,
+                                                  mem_set.as_ref(), None, &settings);
+            if !settings.debug_mode {
+                fs::remove_file(&trj_mmpbsa).map_err(MmpbsaError::io(&trj_mmpbsa))?;
+            }
+            results
+        })?;
+
+        Ok(PyResults {
+            times: results.times.to_pyarray(py).unbind(),
+            dh: results.dh.to_pyarray(py).unbind(),
+            mm: results.mm.to_pyarray(py).unbind(),
+            pb: results.pb.to_pyarray(py).unbind(),
+            sa: results.sa.to_pyarray(py).unbind(),
+            cav: results.cav.to_pyarray(py).unbind(),
+            disp: results.disp.to_pyarray(py).unbind(),
+            elec: results.elec.to_pyarray(py).unbind(),
+            vdw: results.vdw.to_pyarray(py).unbind(),
+            dh_res: results.dh_res.to_pyarray(py).unbind(),
+            mm_res: results.mm_res.to_pyarray(py).unbind(),
+            pb_res: results.pb_res.to_pyarray(py).unbind(),
+            sa_res: results.sa_res.to_pyarray(py).unbind(),
+            cav_res: results.cav_res.to_pyarray(py).unbind(),
+            disp_res: results.disp_res.to_pyarray(py).unbind(),
+            elec_res: results.elec_res.to_pyarray(py).unbind(),
+            vdw_res: results.vdw_res.to_pyarray(py).unbind(),
+            res_id: results.residues.iter().map(|r| r.id).collect(),
+            res_name: results.residues.iter().map(|r| r.name.to_string()).collect(),
+            res_nr: results.residues.iter().map(|r| r.nr).collect(),
+            res_is_ligand: results.ligand_residues(),
+        })
+    }
+}
+
+impl PySystem {
+    // sorted atom indexes of a selection
+    fn select(&self, sel: &Bound<'_, PyAny>) -> PyResult<Vec<usize>> {
+        let mut atoms = if let Ok(grp) = sel.extract::<usize>() {
+            self.ndx.groups.get(grp).map(|g| g.indexes.to_vec())
+                .ok_or_else(|| MmpbsaError::Index(format!("group {} not found", grp)))?
+        } else if let Ok(grp) = sel.extract::<String>() {
+            self.ndx.groups.iter().find(|g| g.name == grp).map(|g| g.indexes.to_vec())
+                .ok_or_else(|| MmpbsaError::Index(format!("group {} not found", grp)))?
+        } else if let Ok(atoms) = sel.extract::<Vec<usize>>() {
+            atoms
+        } else {
+            return Err(PyValueError::new_err("selection should be group number, group name or list of atom indexes"));
+        };
+        atoms.sort();
+        atoms.dedup();
+        match atoms.last() {
+            None => Err(MmpbsaError::Index("empty selection".to_string()).into()),
+            Some(&i) if i >= self.tpr.n_atoms => Err(MmpbsaError::Index(format!("atom {} out of topology", i)).into()),
+            // atoms are renumbered by offset from the first one, as index groups of molecules
+            Some(&i) if i - atoms[0] + 1 != atoms.len() => Err(MmpbsaError::Index(
+                format!("atoms {} to {} are not contiguous, receptor and ligand should each be a continuous range", atoms[0], i)).into()),
+            _ => Ok(atoms)
+        }
+    }
+}
+
+#[pymodule]
+fn s_mmpbsa(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PySystem>()?;
+    m.add_class::<PyPBESet>()?;
+    m.add_class::<PyPBASet>()?;
+    m.add_class::<PyResults>()?;
+    Ok(())
+}
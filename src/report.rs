@@ -0,0 +1,300 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use chrono::Local;
+use ndarray::{Array1, ArrayView1};
+use plotters::prelude::*;
+use crate::analyzation::{Results, RunInfo};
+use crate::error::{MmpbsaError, Result};
+use crate::metal_site::METAL_SITES;
+use crate::plot::{PLOT_SIZE, Plot, draw, top_residues};
+use crate::results_json::Stat;
+
+// number of blocks in block averaging, only with at least 2 frames per block
+const BLOCKS: usize = 5;
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 2em auto; max-width: 1000px; color: #222; }
+h1 { font-size: 1.6em; } h2 { font-size: 1.25em; border-bottom: 1px solid #ccc; padding-bottom: 0.2em; margin-top: 1.8em; }
+table { border-collapse: collapse; margin: 0.6em 0; font-size: 0.9em; }
+th, td { border: 1px solid #ccc; padding: 0.25em 0.6em; text-align: right; }
+th { background: #f0f0f0; } td.l, th.l { text-align: left; }
+tr.lig td { background: #fff6e0; } td.warn { background: #fbe3e4; }
+pre { background: #f7f7f7; padding: 0.6em; font-size: 0.85em; overflow-x: auto; }
+svg { max-width: 100%; height: auto; display: block; margin: 0.6em 0; }
+p.note { font-size: 0.85em; color: #555; }
+";
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+// statistical inefficiency g by the integrated autocorrelation, stopped at the first non-positive correlation,
+// so that N/g frames are effectively independent
+fn inefficiency(v: ArrayView1<f64>) -> f64 {
+    let n = v.len();
+    let mean = v.sum() / n as f64;
+    let var = v.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / n as f64;
+    if n < 3 || var < 1e-12 {
+        return 1.0
+    }
+    let mut g = 1.0;
+    for t in 1..n - 1 {
+        let c = (0..n - t).map(|i| (v[i] - mean) * (v[i + t] - mean)).sum::<f64>() / ((n - t) as f64 * var);
+        if c <= 0.0 {
+            break
+        }
+        g += 2.0 * c * (1.0 - t as f64 / n as f64);
+    }
+    g.max(1.0)
+}
+
+// sem corrected by the statistical inefficiency
+fn corrected_sem(v: ArrayView1<f64>) -> f64 {
+    Stat::from(v).std * (inefficiency(v) / v.len() as f64).sqrt()
+}
+
+// energy terms in tables, ΔCAV and ΔDISP only if calculated
+fn terms(results: &Results) -> Vec<(&'static str, &Array1<f64>)> {
+    [("ΔH", &results.dh), ("ΔMM", &results.mm), ("ΔPB", &results.pb), ("ΔSA", &results.sa),
+     ("ΔCAV", &results.cav), ("ΔDISP", &results.disp), ("Δelec", &results.elec), ("ΔvdW", &results.vdw)]
+        .into_iter()
+        .filter(|(name, v)| *name == "ΔH" || v.iter().any(|&x| x != 0.0))
+        .collect()
+}
+
+fn svg_plot(plot: &Plot, results: &Results, sys_name: &str, out: &Path) -> Result<String> {
+    let to_err = |e: String| MmpbsaError::io(out.to_string_lossy())(io::Error::other(e));
+    let mut s = String::new();
+    {
+        let root = SVGBackend::with_string(&mut s, PLOT_SIZE).into_drawing_area();
+        draw(&root, plot, results, sys_name).map_err(|e| to_err(e.to_string()))?;
+        root.present().map_err(|e| to_err(e.to_string()))?;
+    }
+    Ok(s)
+}
+
+fn table_row(s: &mut String, class: &str, cells: &[String]) {
+    s.push_str(&format!("<tr class=\"{}\">", class));
+    for (i, c) in cells.iter().enumerate() {
+        match i {
+            0 => s.push_str(&format!("<td class=\"l\">{}</td>", c)),
+            _ => s.push_str(&format!("<td>{}</td>", c)),
+        }
+    }
+    s.push_str("</tr>\n");
+}
+
+fn table_head(s: &mut String, heads: &[&str]) {
+    s.push_str("<table>\n<tr>");
+    for (i, h) in heads.iter().enumerate() {
+        match i {
+            0 => s.push_str(&format!("<th class=\"l\">{}</th>", h)),
+            _ => s.push_str(&format!("<th>{}</th>", h)),
+        }
+    }
+    s.push_str("</tr>\n");
+}
+
+fn write_parameters(s: &mut String, results: &Results, temperature: f64, info: &RunInfo) {
+    let settings = &info.settings;
+    let ligand = info.ligand.clone().unwrap_or("None".to_string());
+    s.push_str("<h2>Input parameters</h2>\n");
+    table_head(s, &["Item", "Value"]);
+    for (k, v) in [
+        ("Topology", info.topology.to_string()),
+        ("Trajectory", info.trajectory.to_string()),
+        ("Index", info.index.to_string()),
+        ("Receptor group", info.receptor.to_string()),
+        ("Ligand group", ligand),
+        ("Receptor / ligand atoms", match results.ndx_lig[0] == results.ndx_rec[0] {
+            true => format!("{} / 0", results.ndx_rec.len()),
+            false => format!("{} / {}", results.ndx_rec.len(), results.ndx_lig.len())
+        }),
+        ("Residues", results.residues.len().to_string()),
+        ("Frames", format!("{}, {} - {} ns", results.times.len(),
+            results.times[0] / 1000.0, results.times[results.times.len() - 1] / 1000.0)),
+        ("Temperature (K)", temperature.to_string()),
+        ("Started", info.started.to_string()),
+        ("Finished", info.finished.to_string()),
+    ] {
+        table_row(s, "", &[k.to_string(), escape(&v)]);
+    }
+    s.push_str("</table>\n");
+
+    s.push_str("<h3>Structural parameters</h3>\n");
+    table_head(s, &["Item", "Value"]);
+    for (k, v) in [
+        ("Atom radius type", info.radius_type.to_string()),
+        ("Topology state (FEP lambda)", settings.fep_lambda.to_string()),
+        ("Atom override file", match settings.atom_override.is_empty() {
+            true => "None".to_string(),
+            false => settings.atom_override.to_string()
+        }),
+        ("Metal site", format!("{}, metal radius: {}", METAL_SITES[settings.metal_site], match settings.metal_radius.is_empty() {
+            true => "default",
+            false => settings.metal_radius.as_str()
+        })),
+    ] {
+        table_row(s, "", &[k.to_string(), escape(&v)]);
+    }
+    s.push_str("</table>\n");
+
+    s.push_str("<h3>PBSA parameters</h3>\n");
+    table_head(s, &["Item", "Value"]);
+    for (k, v) in [
+        ("Use Debye-Huckel shielding method", settings.use_dh.to_string()),
+        ("Use entropy contribution", settings.use_ts.to_string()),
+        ("Atom distance cutoff for MM calculation (A)", settings.r_cutoff.to_string()),
+        ("Coarse grid expand factor (cfac)", settings.cfac.to_string()),
+        ("Fine grid expand amount (fadd, A)", settings.fadd.to_string()),
+        ("Fine mesh spacing (df, A)", settings.df.to_string()),
+        ("Multigrid levels (nlev)", settings.nlev.to_string()),
+        ("Memory limit per APBS process (MB)", settings.max_mem.to_string()),
+        ("SA constant (bias, kJ/mol)", info.pba_set.bias.to_string()),
+    ] {
+        table_row(s, "", &[k.to_string(), escape(&v)]);
+    }
+    s.push_str("</table>\n");
+    s.push_str(&format!("<p>PB settings:</p>\n<pre>{}</pre>\n", escape(&info.pbe_set.to_string())));
+    s.push_str(&format!("<p>SA settings:</p>\n<pre>{}</pre>\n", escape(&info.pba_set.to_string())));
+    if let Some(mem_set) = &info.mem_set {
+        s.push_str(&format!("<p>Membrane settings:</p>\n<pre>{}</pre>\n", escape(&mem_set.to_string())));
+    }
+
+    // ligand atoms as in paras_structure.txt, receptor atoms are usually too many for a report
+    if results.ndx_lig[0] != results.ndx_rec[0] {
+        let aps = &results.aps;
+        s.push_str("<details><summary>Ligand atoms</summary>\n<pre>     id   name   type   charge   radius   resnum  resname\n");
+        for &idx in &results.ndx_lig {
+            s.push_str(&escape(&format!("{:7}{:>7}{:7}{:9.2}{:9.2}{:9}{:>9}\n",
+                aps.atm_index[idx], aps.atm_name[idx], aps.atm_typeindex[idx],
+                aps.atm_charge[idx], aps.atm_radius[idx], aps.atm_resid[idx] + 1, aps.atm_resname[idx])));
+        }
+        s.push_str("</pre></details>\n");
+    }
+}
+
+fn write_summary(s: &mut String, results: &Results, temperature: f64, info: &RunInfo) {
+    let (_, _, _, _, _, _, _, _, tds, dg, ki) = results.summary(temperature, &info.settings);
+    s.push_str("<h2>Binding energy summary</h2>\n");
+    table_head(s, &["Term (kJ/mol)", "Mean", "Std", "SEM", "SEM (corrected)", "Min", "Max"]);
+    for (name, v) in terms(results) {
+        let stat = Stat::from(v.view());
+        table_row(s, "", &[name.to_string(), format!("{:.3}", stat.mean), format!("{:.3}", stat.std),
+            format!("{:.3}", stat.sem), format!("{:.3}", corrected_sem(v.view())),
+            format!("{:.3}", stat.min), format!("{:.3}", stat.max)]);
+    }
+    s.push_str("</table>\n");
+    let dh_sem = corrected_sem(results.dh.view());
+    table_head(s, &["Term", "Value"]);
+    table_row(s, "", &["TΔS (kJ/mol)".to_string(), format!("{:.3}", tds)]);
+    table_row(s, "", &["ΔG = ΔH - TΔS (kJ/mol)".to_string(), format!("{:.3} ± {:.3}", dg, dh_sem)]);
+    table_row(s, "", &["Ki (nM)".to_string(), format!("{:.3e}", ki)]);
+    s.push_str("</table>\n");
+    s.push_str("<p class=\"note\">ΔH = ΔMM + ΔPB + ΔSA + ΔCAV + ΔDISP, ΔMM = Δelec + ΔvdW. \
+        SEM = std / sqrt(N); the corrected SEM uses N / g effectively independent frames, \
+        with g the statistical inefficiency from the autocorrelation. \
+        The uncertainty of ΔG is the corrected SEM of ΔH, TΔS by interaction entropy is taken as exact.</p>\n");
+}
+
+fn write_hotspots(s: &mut String, results: &Results, res: &[usize]) {
+    let is_lig = results.ligand_residues();
+    s.push_str(&format!("<h2>Hot-spot residues</h2>\n<p>Top {} residues by |ΔH| averaged over frames, most favorable first, \
+        ligand residues highlighted.</p>\n", res.len()));
+    let mut heads = vec!["Residue", "Nr", "Group", "ΔH ± std", "SEM (corrected)"];
+    let res_terms = [("ΔMM", &results.mm_res), ("ΔPB", &results.pb_res), ("ΔSA", &results.sa_res),
+                     ("ΔCAV", &results.cav_res), ("ΔDISP", &results.disp_res),
+                     ("Δelec", &results.elec_res), ("ΔvdW", &results.vdw_res)];
+    let res_terms: Vec<_> = res_terms.into_iter()
+        .filter(|(_, v)| v.iter().any(|&x| x != 0.0))
+        .collect();
+    heads.extend(res_terms.iter().map(|t| t.0));
+    table_head(s, &heads);
+    for &i in res {
+        let stat = Stat::from(results.dh_res.column(i));
+        let mut cells = vec![
+            escape(&results.residues[i].name),
+            results.residues[i].nr.to_string(),
+            if is_lig[i] { "ligand" } else { "receptor" }.to_string(),
+            format!("{:.3} ± {:.3}", stat.mean, stat.std),
+            format!("{:.3}", corrected_sem(results.dh_res.column(i))),
+        ];
+        cells.extend(res_terms.iter().map(|t| format!("{:.3}", t.1.column(i).mean().unwrap())));
+        table_row(s, if is_lig[i] { "lig" } else { "" }, &cells);
+    }
+    s.push_str("</table>\n<p class=\"note\">Energies in kJ/mol.</p>\n");
+}
+
+fn write_convergence(s: &mut String, results: &Results) {
+    let n = results.times.len();
+    s.push_str("<h2>Convergence diagnostics</h2>\n");
+    if n < 4 {
+        s.push_str(&format!("<p>Only {} frame(s), too few for convergence diagnostics.</p>\n", n));
+        return
+    }
+    let half = n / 2;
+    table_head(s, &["Term (kJ/mol)", "g", "N effective", "1st half", "2nd half", "Drift"]);
+    for (name, v) in terms(results) {
+        let g = inefficiency(v.view());
+        let first = v.slice(ndarray::s![..half]);
+        let second = v.slice(ndarray::s![half..]);
+        let (m1, m2) = (Stat::from(first).mean, Stat::from(second).mean);
+        // drift larger than twice its uncertainty is flagged
+        let drift_sem = (corrected_sem(first).powi(2) + corrected_sem(second).powi(2)).sqrt();
+        let drift = m2 - m1;
+        s.push_str(&format!("<tr><td class=\"l\">{}</td><td>{:.2}</td><td>{:.1}</td><td>{:.3}</td><td>{:.3}</td>\
+            <td class=\"{}\">{:.3} ± {:.3}</td></tr>\n",
+            name, g, n as f64 / g, m1, m2, if drift.abs() > 2.0 * drift_sem { "warn" } else { "" }, drift, drift_sem));
+    }
+    s.push_str("</table>\n");
+
+    let frames_per_block = n / BLOCKS;
+    if frames_per_block >= 2 {
+        let mut heads = vec!["Term (kJ/mol)".to_string()];
+        heads.extend((1..=BLOCKS).map(|b| format!("Block {}", b)));
+        heads.push("Block SEM".to_string());
+        table_head(s, &heads.iter().map(|h| h.as_str()).collect::<Vec<&str>>());
+        for (name, v) in terms(results) {
+            // the remaining frames go to the last block
+            let means: Array1<f64> = (0..BLOCKS).map(|b| {
+                let end = if b == BLOCKS - 1 { n } else { (b + 1) * frames_per_block };
+                Stat::from(v.slice(ndarray::s![b * frames_per_block..end])).mean
+            }).collect();
+            let mut cells = vec![name.to_string()];
+            cells.extend(means.iter().map(|m| format!("{:.3}", m)));
+            cells.push(format!("{:.3}", Stat::from(means.view()).sem));
+            table_row(s, "", &cells);
+        }
+        s.push_str("</table>\n");
+    }
+    s.push_str("<p class=\"note\">g: statistical inefficiency, N / g frames are effectively independent. \
+        Drift: mean of the 2nd half minus the 1st half, highlighted if larger than twice its corrected SEM, \
+        which suggests the trajectory is not equilibrated or too short. \
+        Block SEM: SEM of the means of consecutive blocks, close to the corrected SEM if the blocks are long enough.</p>\n");
+}
+
+// one HTML file with parameters, summary, plots, hot-spot residues and convergence diagnostics,
+// plots are inline SVG so that nothing else is needed to view it
+pub fn write_report(results: &Results, temperature: f64, sys_name: &str, info: &RunInfo, top_n: usize, out: &Path) -> Result<()> {
+    let res = top_residues(results, top_n.max(1));
+    let mut s = String::new();
+    s.push_str(&format!("<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n\
+        <title>MM/PB-SA report of {}</title>\n<style>{}</style>\n</head>\n<body>\n", escape(sys_name), STYLE));
+    s.push_str(&format!("<h1>MM/PB-SA report of {}</h1>\n<p class=\"note\">Created by s_mmpbsa {} at {}</p>\n",
+        escape(sys_name), env!("CARGO_PKG_VERSION"), Local::now().format("%Y-%m-%d %H:%M:%S")));
+    write_summary(&mut s, results, temperature, info);
+    s.push_str("<h2>Binding energy by time</h2>\n");
+    s.push_str(&svg_plot(&Plot::Traj, results, sys_name, out)?);
+    write_hotspots(&mut s, results, &res);
+    s.push_str(&svg_plot(&Plot::ResTop(&res), results, sys_name, out)?);
+    s.push_str(&svg_plot(&Plot::Heatmap(&res), results, sys_name, out)?);
+    write_convergence(&mut s, results);
+    write_parameters(&mut s, results, temperature, info);
+    s.push_str("</body>\n</html>\n");
+
+    let f = File::create(out).map_err(MmpbsaError::io(out.to_string_lossy()))?;
+    let mut f = BufWriter::new(f);
+    f.write_all(s.as_bytes()).map_err(MmpbsaError::io(out.to_string_lossy()))?;
+    Ok(())
+}
@@ -0,0 +1,199 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use chrono::Local;
+use ndarray::{Array1, Array2, ArrayView1};
+use serde::Serialize;
+use crate::analyzation::{Results, RunInfo};
+use crate::error::{MmpbsaError, Result};
+
+// name and version of the JSON results schema, the version is raised when fields are changed or removed
+pub const SCHEMA: &str = "s_mmpbsa-results";
+pub const SCHEMA_VERSION: u32 = 1;
+
+// the same set of energy terms, ΔH = ΔMM + ΔPB + ΔSA + ΔCAV + ΔDISP, ΔMM = Δelec + ΔvdW
+#[derive(Serialize)]
+pub struct Terms<T> {
+    pub dh: T,
+    pub mm: T,
+    pub pb: T,
+    pub sa: T,
+    pub cav: T,
+    pub disp: T,
+    pub elec: T,
+    pub vdw: T,
+}
+
+impl<T> Terms<T> {
+    fn new<F: Fn(&Array1<f64>) -> T>(results: &Results, f: F) -> Terms<T> {
+        Terms {
+            dh: f(&results.dh), mm: f(&results.mm), pb: f(&results.pb), sa: f(&results.sa),
+            cav: f(&results.cav), disp: f(&results.disp), elec: f(&results.elec), vdw: f(&results.vdw),
+        }
+    }
+
+    fn new_res<F: Fn(&Array2<f64>) -> T>(results: &Results, f: F) -> Terms<T> {
+        Terms {
+            dh: f(&results.dh_res), mm: f(&results.mm_res), pb: f(&results.pb_res), sa: f(&results.sa_res),
+            cav: f(&results.cav_res), disp: f(&results.disp_res), elec: f(&results.elec_res), vdw: f(&results.vdw_res),
+        }
+    }
+}
+
+// statistics over frames, std with n - 1, sem = std / sqrt(n)
+#[derive(Serialize)]
+pub struct Stat {
+    pub mean: f64,
+    pub std: f64,
+    pub sem: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl Stat {
+    pub fn from(v: ArrayView1<f64>) -> Stat {
+        let n = v.len() as f64;
+        let mean = v.sum() / n;
+        let std = match v.len() {
+            1 => 0.0,
+            _ => (v.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1.0)).sqrt()
+        };
+        Stat {
+            mean,
+            std,
+            sem: std / n.sqrt(),
+            min: v.iter().fold(f64::INFINITY, |a, &b| a.min(b)),
+            max: v.iter().fold(f64::NEG_INFINITY, |a, &b| a.max(b)),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Software {
+    pub name: &'static str,
+    pub version: &'static str,
+}
+
+#[derive(Serialize)]
+pub struct Units {
+    pub energy: &'static str,
+    pub time: &'static str,
+    pub ki: &'static str,
+}
+
+#[derive(Serialize)]
+pub struct Summary {
+    pub frames: usize,
+    pub terms: Terms<Stat>,
+    pub tds: f64,           // TΔS by interaction entropy, 0 if not used
+    pub dg: f64,            // ΔG = ΔH - TΔS
+    pub ki: f64,
+}
+
+#[derive(Serialize)]
+pub struct ResidueRecord {
+    pub index: usize,       // column of the residue in per-residue arrays
+    pub id: usize,          // residue index in topology
+    pub nr: i32,            // residue number
+    pub name: String,
+    pub ligand: bool,
+    pub terms: Terms<Stat>,
+}
+
+#[derive(Serialize)]
+pub struct FrameRecord {
+    pub record: &'static str,       // "frame"
+    pub index: usize,
+    pub time: f64,
+    pub totals: Terms<f64>,
+    pub residues: Terms<Vec<f64>>,  // in the order of residues in header
+}
+
+#[derive(Serialize)]
+pub struct Header<'a> {
+    pub record: &'static str,       // "header"
+    pub schema: &'static str,
+    pub schema_version: u32,
+    pub software: Software,
+    pub created: String,
+    pub units: Units,
+    pub system: &'a str,
+    pub metadata: &'a RunInfo,
+    pub summary: Summary,
+    pub residues: Vec<ResidueRecord>,
+}
+
+#[derive(Serialize)]
+pub struct ResultsJson<'a> {
+    #[serde(flatten)]
+    pub header: Header<'a>,
+    pub times: Vec<f64>,
+    pub totals: Terms<Vec<f64>>,            // each term of frames
+    pub residue_frames: Terms<Vec<Vec<f64>>>,   // each term of frames x residues
+}
+
+fn get_header<'a>(results: &Results, temperature: f64, sys_name: &'a str, info: &'a RunInfo) -> Header<'a> {
+    let (_, _, _, _, _, _, _, _, tds, dg, ki) = results.summary(temperature, &info.settings);
+    let is_lig = results.ligand_residues();
+    Header {
+        record: "header",
+        schema: SCHEMA,
+        schema_version: SCHEMA_VERSION,
+        software: Software { name: "s_mmpbsa", version: env!("CARGO_PKG_VERSION") },
+        created: Local::now().to_rfc3339(),
+        units: Units { energy: "kJ/mol", time: "ns", ki: "nM" },
+        system: sys_name,
+        metadata: info,
+        summary: Summary {
+            frames: results.times.len(),
+            terms: Terms::new(results, |v| Stat::from(v.view())),
+            tds,
+            dg,
+            ki,
+        },
+        residues: results.residues.iter().enumerate().map(|(i, r)| ResidueRecord {
+            index: i,
+            id: r.id,
+            nr: r.nr,
+            name: r.name.to_string(),
+            ligand: is_lig[i],
+            terms: Terms::new_res(results, |v| Stat::from(v.column(i))),
+        }).collect(),
+    }
+}
+
+// all results in one JSON object: header fields, times, totals and per-residue values of frames
+pub fn write_results_json(results: &Results, temperature: f64, sys_name: &str, info: &RunInfo, out: &Path) -> Result<()> {
+    let json = ResultsJson {
+        header: get_header(results, temperature, sys_name, info),
+        times: results.times.iter().map(|t| t / 1000.0).collect(),
+        totals: Terms::new(results, |v| v.to_vec()),
+        residue_frames: Terms::new_res(results, |v| v.outer_iter().map(|r| r.to_vec()).collect()),
+    };
+    let f = File::create(out).map_err(MmpbsaError::io(out.to_string_lossy()))?;
+    let mut f = BufWriter::new(f);
+    serde_json::to_writer_pretty(&mut f, &json).map_err(|e| MmpbsaError::parse(out.to_string_lossy(), e.to_string()))?;
+    writeln!(f).map_err(MmpbsaError::io(out.to_string_lossy()))?;
+    Ok(())
+}
+
+// header in the first line, then a line of each frame
+pub fn write_results_jsonl(results: &Results, temperature: f64, sys_name: &str, info: &RunInfo, out: &Path) -> Result<()> {
+    let f = File::create(out).map_err(MmpbsaError::io(out.to_string_lossy()))?;
+    let mut f = BufWriter::new(f);
+    let to_json_err = |e: serde_json::Error| MmpbsaError::parse(out.to_string_lossy(), e.to_string());
+    serde_json::to_writer(&mut f, &get_header(results, temperature, sys_name, info)).map_err(to_json_err)?;
+    writeln!(f).map_err(MmpbsaError::io(out.to_string_lossy()))?;
+    for i in 0..results.times.len() {
+        let frame = FrameRecord {
+            record: "frame",
+            index: i,
+            time: results.times[i] / 1000.0,
+            totals: Terms::new(results, |v| v[i]),
+            residues: Terms::new_res(results, |v| v.row(i).to_vec()),
+        };
+        serde_json::to_writer(&mut f, &frame).map_err(to_json_err)?;
+        writeln!(f).map_err(MmpbsaError::io(out.to_string_lossy()))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,94 @@
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::data_type::{BoolType, ByteArray, ByteArrayType, DoubleType, Int32Type};
+use parquet::errors::ParquetError;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::format::KeyValue;
+use parquet::schema::parser::parse_message_type;
+use crate::analyzation::{Results, RunInfo};
+use crate::error::{MmpbsaError, Result};
+
+// one row of each residue at each frame, energies in kJ/mol and time in ns
+const SCHEMA: &str = "
+    message s_mmpbsa_residues {
+        REQUIRED INT32 frame;
+        REQUIRED DOUBLE time;
+        REQUIRED INT32 residue;
+        REQUIRED INT32 id;
+        REQUIRED INT32 nr;
+        REQUIRED BYTE_ARRAY name (UTF8);
+        REQUIRED BOOLEAN ligand;
+        REQUIRED DOUBLE dh;
+        REQUIRED DOUBLE mm;
+        REQUIRED DOUBLE pb;
+        REQUIRED DOUBLE sa;
+        REQUIRED DOUBLE cav;
+        REQUIRED DOUBLE disp;
+        REQUIRED DOUBLE elec;
+        REQUIRED DOUBLE vdw;
+    }";
+
+// rows in each row group, so that large trajectories are not held in memory twice
+const ROWS_PER_GROUP: usize = 1 << 20;
+
+// per-residue energies of all frames in a zstd compressed Parquet file, with system name, units and
+// the JSON of run metadata in key-value metadata, e.g. pandas.read_parquet(f).pivot(index="time", columns="residue", values="dh")
+pub fn write_results_parquet(results: &Results, sys_name: &str, info: &RunInfo, out: &Path) -> Result<()> {
+    let to_err = |e: ParquetError| MmpbsaError::io(out.to_string_lossy())(io::Error::other(e));
+    let schema = Arc::new(parse_message_type(SCHEMA).map_err(to_err)?);
+    let metadata = serde_json::to_string(info).map_err(|e| MmpbsaError::io(out.to_string_lossy())(io::Error::other(e)))?;
+    let props = WriterProperties::builder()
+        .set_compression(Compression::ZSTD(ZstdLevel::default()))
+        .set_key_value_metadata(Some(vec![
+            KeyValue::new("s_mmpbsa.version".to_string(), env!("CARGO_PKG_VERSION").to_string()),
+            KeyValue::new("s_mmpbsa.system".to_string(), sys_name.to_string()),
+            KeyValue::new("s_mmpbsa.units".to_string(), "energy: kJ/mol, time: ns".to_string()),
+            KeyValue::new("s_mmpbsa.metadata".to_string(), metadata),
+        ]))
+        .build();
+    let f = File::create(out).map_err(MmpbsaError::io(out.to_string_lossy()))?;
+    let mut writer = SerializedFileWriter::new(f, schema, Arc::new(props)).map_err(to_err)?;
+
+    let n_res = results.residues.len();
+    let is_lig = results.ligand_residues();
+    let terms = [&results.dh_res, &results.mm_res, &results.pb_res, &results.sa_res,
+                 &results.cav_res, &results.disp_res, &results.elec_res, &results.vdw_res];
+    let frames_per_group = (ROWS_PER_GROUP / n_res.max(1)).max(1);
+    for bf in (0..results.times.len()).step_by(frames_per_group) {
+        let ef = (bf + frames_per_group).min(results.times.len());
+        let frame: Vec<i32> = (bf..ef).flat_map(|i| vec![i as i32; n_res]).collect();
+        let time: Vec<f64> = (bf..ef).flat_map(|i| vec![results.times[i] / 1000.0; n_res]).collect();
+        let residue: Vec<i32> = (bf..ef).flat_map(|_| 0..n_res as i32).collect();
+        let id: Vec<i32> = (bf..ef).flat_map(|_| results.residues.iter().map(|r| r.id as i32)).collect();
+        let nr: Vec<i32> = (bf..ef).flat_map(|_| results.residues.iter().map(|r| r.nr)).collect();
+        let name: Vec<ByteArray> = (bf..ef).flat_map(|_| results.residues.iter().map(|r| ByteArray::from(r.name.as_str()))).collect();
+        let ligand: Vec<bool> = (bf..ef).flat_map(|_| is_lig.iter().cloned()).collect();
+
+        let mut rg = writer.next_row_group().map_err(to_err)?;
+        let mut i_col = 0;
+        while let Some(mut col) = rg.next_column().map_err(to_err)? {
+            match i_col {
+                0 => col.typed::<Int32Type>().write_batch(&frame, None, None),
+                1 => col.typed::<DoubleType>().write_batch(&time, None, None),
+                2 => col.typed::<Int32Type>().write_batch(&residue, None, None),
+                3 => col.typed::<Int32Type>().write_batch(&id, None, None),
+                4 => col.typed::<Int32Type>().write_batch(&nr, None, None),
+                5 => col.typed::<ByteArrayType>().write_batch(&name, None, None),
+                6 => col.typed::<BoolType>().write_batch(&ligand, None, None),
+                k => {
+                    let values: Vec<f64> = (bf..ef).flat_map(|i| terms[k - 7].row(i).to_vec()).collect();
+                    col.typed::<DoubleType>().write_batch(&values, None, None)
+                }
+            }.map_err(to_err)?;
+            col.close().map_err(to_err)?;
+            i_col += 1;
+        }
+        rg.close().map_err(to_err)?;
+    }
+    writer.close().map_err(to_err)?;
+    Ok(())
+}
@@ -2,8 +2,13 @@ use std::{env, fs};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use regex::Regex;
+use serde::Serialize;
 use toml::Value;
+use crate::atom_radius::get_radius_type;
+use crate::error::{MmpbsaError, Result};
+use crate::metal_site::get_metal_site;
 
+#[derive(Clone, Serialize)]
 pub struct Settings {
     pub rad_type: usize,
     pub rad_ff_default: f64,
@@ -13,12 +18,18 @@ pub struct Settings {
     pub fadd: f64,
     pub r_cutoff: f64,
     pub df: f64,
+    pub nlev: usize,
+    pub max_mem: f64,
     pub nkernels: i32,
     pub fix_pbc: bool,
     pub gmx: Option<String>,
     pub apbs: Option<String>,
     pub last_opened: String,
     pub if_alanine_scanning: bool,
+    pub fep_lambda: f64,
+    pub atom_override: String,
+    pub metal_site: usize,
+    pub metal_radius: String,
     pub debug_mode: bool,
 }
 
@@ -33,24 +44,38 @@ impl Settings {
             fadd: 10.0,
             r_cutoff: 0.0,
             df: 0.5,
+            nlev: 4,
+            max_mem: 2048.0,
             nkernels: 1,
             fix_pbc: true,
             gmx: Some("gmx".to_string()),
             apbs: None,
             last_opened: String::new(),
             if_alanine_scanning: false,
+            fep_lambda: 0.0,
+            atom_override: String::new(),
+            metal_site: 0,
+            metal_radius: String::new(),
             debug_mode: false,
         }
     }
 
-    pub fn from(settings_file: &PathBuf) -> Settings {
-        let settings_file = fs::read_to_string(settings_file).unwrap();
+    pub fn from(settings_file: &PathBuf) -> Result<Settings> {
+        let file = settings_file.to_string_lossy().to_string();
+        let settings_file = fs::read_to_string(settings_file).map_err(MmpbsaError::io(&file))?;
         let settings_file = Regex::new(r"\\").unwrap().replace_all(settings_file.as_str(), "/").to_string();
-        let setting_values: Value = toml::from_str(settings_file.as_str()).expect("Error with settings.ini's grammar.");
+        let setting_values: Value = toml::from_str(settings_file.as_str()).map_err(|e| MmpbsaError::parse(&file, e.to_string()))?;
         let default_settings = Settings::new();
         
         // Read settings
-        let rad_type = parse_param(&setting_values, "radType", default_settings.rad_type);
+        let rad_type = parse_param(&setting_values, "radType", default_settings.rad_type.to_string());
+        let rad_type = match get_radius_type(rad_type.trim_matches('"')) {
+            Some(t) => t,
+            None => {
+                println!("Warning: radius type {} not supported, will use mBondi.", rad_type);
+                default_settings.rad_type
+            }
+        };
         let rad_ff_default = parse_param(&setting_values, "radDef", default_settings.rad_ff_default);
         let cfac = parse_param(&setting_values, "cfac", default_settings.cfac);
         let fadd = parse_param(&setting_values, "fadd", default_settings.fadd);
@@ -61,6 +86,22 @@ impl Settings {
             r_cutoff
         };
         let df = parse_param(&setting_values, "df", default_settings.df);
+        let nlev = parse_param(&setting_values, "nlev", default_settings.nlev);
+        let nlev = match nlev {
+            1..=8 => nlev,
+            _ => {
+                println!("Warning: nlev {} not in 1-8, will use {}.", nlev, default_settings.nlev);
+                default_settings.nlev
+            }
+        };
+        let max_mem = parse_param(&setting_values, "max_mem", default_settings.max_mem);
+        let max_mem = match max_mem > 0.0 {
+            true => max_mem,
+            false => {
+                println!("Warning: max_mem {} not positive, will use {}.", max_mem, default_settings.max_mem);
+                default_settings.max_mem
+            }
+        };
         let nkernels = parse_param(&setting_values, "nkernels", default_settings.nkernels);
         let fix_pbc = parse_param(&setting_values, "fix_pbc", "\"y\"".to_string());
         let fix_pbc = match fix_pbc[1..2].to_string().as_str() {
@@ -69,17 +110,30 @@ impl Settings {
             _ => false
         };
         let gmx = parse_param(&setting_values, "gmx", "gmx".to_string());
-        let gmx = Some(gmx[1..gmx.len() - 1].to_string());
+        let gmx = Some(gmx.trim_matches('"').to_string());
         let apbs = parse_param(&setting_values, "apbs", "".to_string());
-        let apbs = Some(apbs[1..apbs.len() - 1].to_string());
+        let apbs = Some(apbs.trim_matches('"').to_string());
         let last_opened = parse_param(&setting_values, "last_opened", "\"\"".to_string());
-        let last_opened = last_opened[1..last_opened.len() - 1].to_string();
+        let last_opened = last_opened.trim_matches('"').to_string();
         let if_alanine_scanning = parse_param(&setting_values, "alanine_scanning", "\"y\"".to_string());
         let if_alanine_scanning = match if_alanine_scanning[1..2].to_string().as_str() {
             "y" => true,
             "Y" => true,
             _ => false
         };
+        let fep_lambda = parse_param(&setting_values, "fep_lambda", default_settings.fep_lambda);
+        let atom_override = parse_param(&setting_values, "atom_override", "\"\"".to_string());
+        let atom_override = atom_override.trim_matches('"').to_string();
+        let metal_site = parse_param(&setting_values, "metal_site", default_settings.metal_site.to_string());
+        let metal_site = match get_metal_site(metal_site.trim_matches('"')) {
+            Some(t) => t,
+            None => {
+                println!("Warning: metal site {} not supported, will keep index groups.", metal_site);
+                default_settings.metal_site
+            }
+        };
+        let metal_radius = parse_param(&setting_values, "metal_radius", "\"\"".to_string());
+        let metal_radius = metal_radius.trim_matches('"').to_string();
         let debug_mode = parse_param(&setting_values, "debug_mode", "\"y\"".to_string());
         let debug_mode = match debug_mode[1..2].to_string().as_str() {
             "y" => true,
@@ -89,7 +143,7 @@ impl Settings {
 
         println!("Note: found settings.ini, will use {} kernels.", nkernels);
 
-        Settings {
+        Ok(Settings {
             rad_type,
             rad_ff_default,
             use_dh: true,
@@ -98,14 +152,20 @@ impl Settings {
             fadd,
             r_cutoff,
             df,
+            nlev,
+            max_mem,
             nkernels,
             fix_pbc,
             gmx,
             apbs,
             last_opened,
             if_alanine_scanning,
+            fep_lambda,
+            atom_override,
+            metal_site,
+            metal_radius,
             debug_mode,
-        }
+        })
     }
 }
 
@@ -138,3 +198,14 @@ pub fn get_base_settings() -> PathBuf {
             .parent().expect("Cannot get current s_mmpbsa program directory.")
             .join("settings.ini")
 }
+
+// radius data files, S_MMPBSA_DAT if set (e.g. by library users), otherwise dat/ beside the program
+pub fn get_dat_dir() -> PathBuf {
+    match env::var("S_MMPBSA_DAT") {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => env::current_exe()
+            .expect("Cannot get current s_mmpbsa program path.")
+            .parent().expect("Cannot get current s_mmpbsa program directory.")
+            .join("dat")
+    }
+}
@@ -0,0 +1,348 @@
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::rc::Rc;
+use xdrfile::*;
+use crate::error::{MmpbsaError, Result};
+
+const AKMA_TIME_TO_PS: f64 = 0.04888821;    // CHARMM time unit in DCD
+
+// read trajectory frames, coordinates in nm and time in ps as xdrfile does
+pub fn read_trajectory(trj: &str) -> Result<Vec<Rc<Frame>>> {
+    let ext = Path::new(trj).extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase();
+    let xdr_error = |e: xdrfile::Error| MmpbsaError::trajectory(trj, e.to_string());
+    let frames = match ext.as_str() {
+        "xtc" => XTCTrajectory::open_read(trj).map_err(xdr_error)?
+            .into_iter().collect::<std::result::Result<Vec<Rc<Frame>>, _>>().map_err(xdr_error)?,
+        "trr" => TRRTrajectory::open_read(trj).map_err(xdr_error)?
+            .into_iter().collect::<std::result::Result<Vec<Rc<Frame>>, _>>().map_err(xdr_error)?,
+        "dcd" => read_dcd(trj)?,
+        "nc" | "ncdf" | "netcdf" => read_netcdf(trj)?,
+        _ => return Err(MmpbsaError::trajectory(trj, "unsupported format"))
+    };
+    match frames.is_empty() {
+        true => Err(MmpbsaError::trajectory(trj, "no frames")),
+        false => Ok(frames)
+    }
+}
+
+// first frame only, avoiding reading the whole xtc/trr trajectory
+pub fn read_first_frame(trj: &str) -> Result<Rc<Frame>> {
+    let ext = Path::new(trj).extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase();
+    let xdr_error = |e: xdrfile::Error| MmpbsaError::trajectory(trj, e.to_string());
+    match ext.as_str() {
+        "xtc" => XTCTrajectory::open_read(trj).map_err(xdr_error)?
+            .into_iter().next().ok_or_else(|| MmpbsaError::trajectory(trj, "no frames"))?.map_err(xdr_error),
+        "trr" => TRRTrajectory::open_read(trj).map_err(xdr_error)?
+            .into_iter().next().ok_or_else(|| MmpbsaError::trajectory(trj, "no frames"))?.map_err(xdr_error),
+        _ => Ok(read_trajectory(trj)?.swap_remove(0))
+    }
+}
+
+// write selected atoms of frames to xtc
+pub fn write_xtc(frames: &Vec<Rc<Frame>>, atoms: &Vec<usize>, out: &str) -> Result<()> {
+    let xdr_error = |e: xdrfile::Error| MmpbsaError::trajectory(out, e.to_string());
+    let mut xtc = XTCTrajectory::open_write(out).map_err(xdr_error)?;
+    for frame in frames {
+        let mut new_frame = Frame::with_len(atoms.len());
+        new_frame.step = frame.step;
+        new_frame.time = frame.time;
+        new_frame.box_vector = frame.box_vector;
+        for (i, &a) in atoms.iter().enumerate() {
+            new_frame.coords[i] = frame.coords[a];
+        }
+        xtc.write(&new_frame).map_err(xdr_error)?;
+    }
+    xtc.flush().map_err(xdr_error)
+}
+
+// box vectors (nm) from cell lengths (A) and angles (degree)
+fn get_box_vector(a: f64, b: f64, c: f64, alpha: f64, beta: f64, gamma: f64) -> [[f32; 3]; 3] {
+    let (alpha, beta, gamma) = (alpha.to_radians(), beta.to_radians(), gamma.to_radians());
+    let cx = c * beta.cos();
+    let cy = c * (alpha.cos() - beta.cos() * gamma.cos()) / gamma.sin();
+    let cz = (c * c - cx * cx - cy * cy).max(0.0).sqrt();
+    [[(a / 10.0) as f32, 0.0, 0.0],
+     [(b * gamma.cos() / 10.0) as f32, (b * gamma.sin() / 10.0) as f32, 0.0],
+     [(cx / 10.0) as f32, (cy / 10.0) as f32, (cz / 10.0) as f32]]
+}
+
+// CHARMM/NAMD DCD, a series of fortran unformatted records
+fn read_dcd(trj: &str) -> Result<Vec<Rc<Frame>>> {
+    let mut reader = BufReader::new(File::open(trj).map_err(MmpbsaError::io(trj))?);
+    let mut head = [0u8; 4];
+    reader.read_exact(&mut head).map_err(MmpbsaError::io(trj))?;
+    let big_endian = match (i32::from_le_bytes(head), i32::from_be_bytes(head)) {
+        (84, _) => false,
+        (_, 84) => true,
+        _ => return Err(MmpbsaError::trajectory(trj, "not a valid DCD file"))
+    };
+    reader.seek(SeekFrom::Start(0)).map_err(MmpbsaError::io(trj))?;
+    let to_i32 = |b: &[u8]| match big_endian {
+        true => i32::from_be_bytes(b.try_into().unwrap()),
+        false => i32::from_le_bytes(b.try_into().unwrap())
+    };
+    let to_f32 = |b: &[u8]| match big_endian {
+        true => f32::from_be_bytes(b.try_into().unwrap()),
+        false => f32::from_le_bytes(b.try_into().unwrap())
+    };
+    let to_f64 = |b: &[u8]| match big_endian {
+        true => f64::from_be_bytes(b.try_into().unwrap()),
+        false => f64::from_le_bytes(b.try_into().unwrap())
+    };
+    let read_record = |reader: &mut BufReader<File>| -> Option<Vec<u8>> {
+        let mut len = [0u8; 4];
+        reader.read_exact(&mut len).ok()?;
+        let mut data = vec![0u8; to_i32(&len) as usize];
+        reader.read_exact(&mut data).ok()?;
+        reader.read_exact(&mut len).ok()?;
+        Some(data)
+    };
+
+    // CORD, nset, istart, nsavc, ..., namnf, delta, has_cell, ..., charmm version
+    let dcd_error = |msg: &str| MmpbsaError::trajectory(trj, msg);
+    let header = read_record(&mut reader).filter(|h| h.len() >= 84).ok_or_else(|| dcd_error("broken DCD header"))?;
+    let icntrl: Vec<i32> = header[4..84].chunks(4).map(to_i32).collect();
+    let istart = icntrl[1] as f64;
+    let nsavc = icntrl[2] as f64;
+    let is_charmm = icntrl[19] != 0;
+    let (delta, has_cell) = match is_charmm {
+        true => (to_f32(&header[40..44]) as f64, icntrl[10] != 0),
+        false => (to_f64(&header[40..48]), false)
+    };
+    if icntrl[8] != 0 {
+        return Err(dcd_error("DCD with fixed atoms is not supported"));
+    }
+    read_record(&mut reader).ok_or_else(|| dcd_error("broken DCD title"))?;
+    let atoms_num = to_i32(&read_record(&mut reader).filter(|r| r.len() == 4)
+        .ok_or_else(|| dcd_error("broken DCD atoms number"))?) as usize;
+
+    let mut frames: Vec<Rc<Frame>> = vec![];
+    loop {
+        let mut frame = Frame::with_len(atoms_num);
+        if has_cell {
+            // A, gamma, B, beta, alpha, C, angles may be saved as cosines by NAMD
+            let cell = match read_record(&mut reader) {
+                Some(cell) => cell,
+                None => break
+            };
+            let cell: Vec<f64> = cell.chunks(8).map(to_f64).collect();
+            let angle = |p: f64| if p.abs() <= 1.0 { p.acos().to_degrees() } else { p };
+            frame.box_vector = get_box_vector(cell[0], cell[2], cell[5], angle(cell[4]), angle(cell[3]), angle(cell[1]));
+        }
+        let mut xyz: Vec<Vec<f32>> = vec![];
+        for _ in 0..3 {
+            match read_record(&mut reader) {
+                Some(r) => xyz.push(r.chunks(4).map(to_f32).collect()),
+                None => break
+            }
+        }
+        if xyz.len() < 3 {
+            break;
+        }
+        if xyz.iter().any(|x| x.len() < atoms_num) {
+            return Err(dcd_error("broken DCD coordinates"));
+        }
+        for i in 0..atoms_num {
+            frame.coords[i] = [xyz[0][i] / 10.0, xyz[1][i] / 10.0, xyz[2][i] / 10.0];
+        }
+        let step = match nsavc > 0.0 {
+            true => istart + frames.len() as f64 * nsavc,
+            false => frames.len() as f64
+        };
+        frame.step = step as usize;
+        frame.time = (step * delta * AKMA_TIME_TO_PS) as f32;
+        frames.push(Rc::new(frame));
+    }
+    println!("Read {} frames of {} atoms from DCD file.", frames.len(), atoms_num);
+    Ok(frames)
+}
+
+// variable in NetCDF classic (CDF-1) or 64-bit offset (CDF-2) format
+struct NcVar {
+    name: String,
+    dims: Vec<usize>,
+    nc_type: u32,
+    begin: u64,
+}
+
+// AMBER NetCDF trajectory convention: coordinates(frame, atom, spatial) in A, time(frame) in ps,
+// cell_lengths(frame, cell_spatial) and cell_angles(frame, cell_angular)
+fn read_netcdf(trj: &str) -> Result<Vec<Rc<Frame>>> {
+    let mut reader = BufReader::new(File::open(trj).map_err(MmpbsaError::io(trj))?);
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic).map_err(MmpbsaError::io(trj))?;
+    if &magic[0..3] != b"CDF" {
+        return Err(MmpbsaError::trajectory(trj, "only NetCDF classic and 64-bit offset formats are supported, \
+            NetCDF-4 (HDF5) trajectory should be converted first"));
+    }
+    let offset_64 = magic[3] == 2;
+    let read_u32 = |reader: &mut BufReader<File>| -> Result<u32> {
+        let mut b = [0u8; 4];
+        reader.read_exact(&mut b).map_err(MmpbsaError::io(trj))?;
+        Ok(u32::from_be_bytes(b))
+    };
+    let read_name = |reader: &mut BufReader<File>| -> Result<String> {
+        let len = read_u32(reader)? as usize;
+        let mut b = vec![0u8; (len + 3) / 4 * 4];
+        reader.read_exact(&mut b).map_err(MmpbsaError::io(trj))?;
+        Ok(String::from_utf8_lossy(&b[..len]).to_string())
+    };
+    let type_size = |nc_type: u32| -> Result<usize> {
+        match nc_type {
+            1 | 2 => Ok(1),
+            3 => Ok(2),
+            4 | 5 => Ok(4),
+            6 => Ok(8),
+            _ => Err(MmpbsaError::trajectory(trj, format!("unknown NetCDF data type: {}", nc_type)))
+        }
+    };
+    let skip_atts = |reader: &mut BufReader<File>| -> Result<()> {
+        let _tag = read_u32(reader)?;
+        let num = read_u32(reader)?;
+        for _ in 0..num {
+            read_name(reader)?;
+            let nc_type = read_u32(reader)?;
+            let n = read_u32(reader)? as usize;
+            let mut b = vec![0u8; (n * type_size(nc_type)? + 3) / 4 * 4];
+            reader.read_exact(&mut b).map_err(MmpbsaError::io(trj))?;
+        }
+        Ok(())
+    };
+
+    let frames_num = read_u32(&mut reader)? as usize;
+    // dimensions, length 0 means the record (frame) dimension
+    let _tag = read_u32(&mut reader)?;
+    let dims_num = read_u32(&mut reader)?;
+    let mut dims: Vec<(String, usize)> = vec![];
+    for _ in 0..dims_num {
+        let name = read_name(&mut reader)?;
+        let len = read_u32(&mut reader)? as usize;
+        dims.push((name, len));
+    }
+    skip_atts(&mut reader)?;
+    let _tag = read_u32(&mut reader)?;
+    let vars_num = read_u32(&mut reader)?;
+    let mut vars: Vec<NcVar> = vec![];
+    for _ in 0..vars_num {
+        let name = read_name(&mut reader)?;
+        let n = read_u32(&mut reader)?;
+        let var_dims: Vec<usize> = (0..n).map(|_| read_u32(&mut reader).map(|d| d as usize)).collect::<Result<_>>()?;
+        if var_dims.iter().any(|&d| d >= dims.len()) {
+            return Err(MmpbsaError::trajectory(trj, format!("invalid dimensions of variable {}", name)));
+        }
+        skip_atts(&mut reader)?;
+        let nc_type = read_u32(&mut reader)?;
+        type_size(nc_type)?;
+        let _vsize = read_u32(&mut reader)?;
+        let begin = match offset_64 {
+            true => (read_u32(&mut reader)? as u64) << 32 | read_u32(&mut reader)? as u64,
+            false => read_u32(&mut reader)? as u64
+        };
+        vars.push(NcVar { name, dims: var_dims, nc_type, begin });
+    }
+
+    // size of one record of variable, and size of one record of all record variables
+    let is_record = |v: &NcVar| v.dims.first().map(|&d| dims[d].1 == 0).unwrap_or(false);
+    let slab_size = |v: &NcVar| v.dims.iter().skip(1).map(|&d| dims[d].1).product::<usize>() * type_size(v.nc_type).unwrap();
+    let record_vars: Vec<&NcVar> = vars.iter().filter(|v| is_record(v)).collect();
+    let record_size: usize = match record_vars.len() {
+        1 => slab_size(record_vars[0]),
+        _ => record_vars.iter().map(|v| (slab_size(v) + 3) / 4 * 4).sum()
+    };
+    let get_var = |name: &str| vars.iter().find(|v| v.name == name);
+    let coords = get_var("coordinates").filter(|v| v.dims.len() == 3)
+        .ok_or_else(|| MmpbsaError::trajectory(trj, "no coordinates found in NetCDF trajectory"))?;
+    let atoms_num = dims[coords.dims[1]].1;
+
+    let read_values = |reader: &mut BufReader<File>, v: &NcVar, frame: usize| -> Result<Vec<f64>> {
+        reader.seek(SeekFrom::Start(v.begin + (frame * record_size) as u64)).map_err(MmpbsaError::io(trj))?;
+        let mut b = vec![0u8; slab_size(v)];
+        reader.read_exact(&mut b).map_err(MmpbsaError::io(trj))?;
+        match v.nc_type {
+            5 => Ok(b.chunks(4).map(|p| f32::from_be_bytes(p.try_into().unwrap()) as f64).collect()),
+            6 => Ok(b.chunks(8).map(|p| f64::from_be_bytes(p.try_into().unwrap())).collect()),
+            _ => Err(MmpbsaError::trajectory(trj, format!("unsupported data type of {}", v.name)))
+        }
+    };
+
+    let mut frames: Vec<Rc<Frame>> = vec![];
+    for i in 0..frames_num {
+        let mut frame = Frame::with_len(atoms_num);
+        let xyz = read_values(&mut reader, coords, i)?;
+        for a in 0..atoms_num {
+            frame.coords[a] = [(xyz[a * 3] / 10.0) as f32, (xyz[a * 3 + 1] / 10.0) as f32, (xyz[a * 3 + 2] / 10.0) as f32];
+        }
+        frame.step = i;
+        frame.time = match get_var("time") {
+            Some(v) if is_record(v) => read_values(&mut reader, v, i)?[0] as f32,
+            _ => i as f32
+        };
+        if let (Some(l), Some(a)) = (get_var("cell_lengths"), get_var("cell_angles")) {
+            let l = read_values(&mut reader, l, i)?;
+            let a = read_values(&mut reader, a, i)?;
+            frame.box_vector = get_box_vector(l[0], l[1], l[2], a[0], a[1], a[2]);
+        }
+        frames.push(Rc::new(frame));
+    }
+    println!("Read {} frames of {} atoms from NetCDF file.", frames.len(), atoms_num);
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DCD: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/small.dcd");
+    const NC: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/small.nc");
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-4, "{} != {}", a, b);
+    }
+
+    // both files hold 3 frames of 5 atoms, moving 0.1 A along each axis per frame
+    fn check_coords(frames: &[Rc<Frame>]) {
+        assert_eq!(frames.len(), 3);
+        assert!(frames.iter().all(|f| f.num_atoms() == 5));
+        assert_close(frames[0].coords[1][0], 0.12);
+        assert_close(frames[2].coords[4][0], 0.32);
+        assert_close(frames[2].coords[4][1], 0.22);
+        assert_close(frames[2].coords[4][2], 0.02);
+    }
+
+    #[test]
+    fn dcd() {
+        let frames = read_trajectory(DCD).unwrap();
+        check_coords(&frames);
+        // nsavc 10 and 0.02 ps per step
+        assert_eq!(frames.iter().map(|f| f.step).collect::<Vec<usize>>(), vec![0, 10, 20]);
+        assert_close(frames[1].time, 0.2);
+        // A 30, B 40, C 50, gamma saved as cosine 0.5
+        let b = frames[0].box_vector;
+        assert_close(b[0][0], 3.0);
+        assert_close(b[1][0], 2.0);
+        assert_close(b[1][1], 4.0 * 60f32.to_radians().sin());
+        assert_close(b[2][2], 5.0);
+        assert_close(b[2][0], 0.0);
+    }
+
+    #[test]
+    fn netcdf() {
+        let frames = read_trajectory(NC).unwrap();
+        check_coords(&frames);
+        assert_close(frames[2].time, 20.0);
+        // A 30, B 40, C 50, gamma 120
+        let b = frames[1].box_vector;
+        assert_close(b[0][0], 3.0);
+        assert_close(b[1][0], -2.0);
+        assert_close(b[1][1], 4.0 * 120f32.to_radians().sin());
+        assert_close(b[2][2], 5.0);
+    }
+
+    #[test]
+    fn first_frame() {
+        let frame = read_first_frame(DCD).unwrap();
+        assert_close(frame.coords[0][0], 0.0);
+        assert!(read_trajectory("small.pdb").is_err());
+    }
+}
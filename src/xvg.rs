@@ -0,0 +1,87 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use chrono::Local;
+use ndarray::Array2;
+use crate::analyzation::Results;
+use crate::error::{MmpbsaError, Result};
+
+// energy terms with xmgrace legends, Δ is \xD\f{} as gmx energy writes
+pub const XVG_TERMS: [(&str, &str); 8] = [
+    ("ΔH", "\\xD\\f{}H"), ("ΔMM", "\\xD\\f{}MM"), ("ΔPB", "\\xD\\f{}PB"), ("ΔSA", "\\xD\\f{}SA"),
+    ("ΔCAV", "\\xD\\f{}CAV"), ("ΔDISP", "\\xD\\f{}DISP"), ("Δelec", "\\xD\\f{}elec"), ("ΔvdW", "\\xD\\f{}vdW"),
+];
+
+// xvg file of x and columns of series, with title, axis labels and a legend of each series
+pub fn write_xvg(out: &Path, title: &str, x_label: &str, y_label: &str,
+                 legends: &[String], x: &[f64], series: &[Vec<f64>]) -> Result<()> {
+    let f = File::create(out).map_err(MmpbsaError::io(out.to_string_lossy()))?;
+    let mut f = BufWriter::new(f);
+    let mut s = String::new();
+    s.push_str(&format!("# This file was created by s_mmpbsa {}\n", env!("CARGO_PKG_VERSION")));
+    s.push_str(&format!("# Created: {}\n", Local::now().format("%Y-%m-%d %H:%M:%S")));
+    s.push_str(&format!("@    title \"{}\"\n", title));
+    s.push_str(&format!("@    xaxis  label \"{}\"\n", x_label));
+    s.push_str(&format!("@    yaxis  label \"{}\"\n", y_label));
+    s.push_str("@TYPE xy\n");
+    s.push_str("@ view 0.15, 0.15, 0.75, 0.85\n");
+    s.push_str("@ legend on\n");
+    s.push_str("@ legend box on\n");
+    s.push_str("@ legend loctype view\n");
+    s.push_str("@ legend 0.78, 0.8\n");
+    s.push_str("@ legend length 2\n");
+    for (i, legend) in legends.iter().enumerate() {
+        s.push_str(&format!("@ s{} legend \"{}\"\n", i, legend));
+    }
+    for (i, x) in x.iter().enumerate() {
+        s.push_str(&format!("{:12.4}", x));
+        for y in series {
+            s.push_str(&format!(" {:12.3}", y[i]));
+        }
+        s.push('\n');
+    }
+    f.write_all(s.as_bytes()).map_err(MmpbsaError::io(out.to_string_lossy()))?;
+    Ok(())
+}
+
+fn res_terms(results: &Results) -> [&Array2<f64>; 8] {
+    [&results.dh_res, &results.mm_res, &results.pb_res, &results.sa_res,
+     &results.cav_res, &results.disp_res, &results.elec_res, &results.vdw_res]
+}
+
+fn term_legends() -> Vec<String> {
+    XVG_TERMS.iter().map(|t| t.1.to_string()).collect()
+}
+
+// time of frames in ns
+fn frame_times(results: &Results) -> Vec<f64> {
+    results.times.iter().map(|t| t / 1000.0).collect()
+}
+
+// binding energy terms of each frame
+pub fn write_traj_xvg(results: &Results, sys_name: &str, out: &Path) -> Result<()> {
+    let series: Vec<Vec<f64>> = [&results.dh, &results.mm, &results.pb, &results.sa,
+                                 &results.cav, &results.disp, &results.elec, &results.vdw]
+        .iter().map(|v| v.to_vec()).collect();
+    write_xvg(out, &format!("Binding energy terms of {}", sys_name), "Time (ns)", "Energy (kJ/mol)",
+              &term_legends(), &frame_times(results), &series)
+}
+
+// time averaged energy terms of residues (columns in per-residue arrays), by residue number
+pub fn write_res_avg_xvg(results: &Results, sys_name: &str, target_res: &[usize], out: &Path) -> Result<()> {
+    let series: Vec<Vec<f64>> = res_terms(results).iter()
+        .map(|v| target_res.iter().map(|&i| v.column(i).mean().unwrap()).collect())
+        .collect();
+    let res_nr: Vec<f64> = target_res.iter().map(|&i| results.residues[i].nr as f64).collect();
+    write_xvg(out, &format!("Residue-wised binding energy terms of {}", sys_name), "Residue", "Energy (kJ/mol)",
+              &term_legends(), &res_nr, &series)
+}
+
+// one energy term (index of XVG_TERMS) of residues by time, a series of each residue
+pub fn write_res_traj_xvg(results: &Results, sys_name: &str, term: usize, target_res: &[usize], out: &Path) -> Result<()> {
+    let v = res_terms(results)[term];
+    let series: Vec<Vec<f64>> = target_res.iter().map(|&i| v.column(i).to_vec()).collect();
+    let legends: Vec<String> = target_res.iter().map(|&i| format!("{}{}", results.residues[i].name, results.residues[i].nr)).collect();
+    write_xvg(out, &format!("{} of residues of {}", XVG_TERMS[term].1, sys_name), "Time (ns)", "Energy (kJ/mol)",
+              &legends, &frame_times(results), &series)
+}